@@ -10,6 +10,9 @@ use steps::*;
 
 mod vct;
 
+mod rng;
+pub use rng::{shared_random_float, shared_random_long, NonSharedRng};
+
 /// Result of a trace operation.
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct TraceResult {
@@ -129,6 +132,21 @@ impl State {
     pub fn player(&self) -> Player {
         self.player
     }
+
+    /// Returns the type of player's position in the world.
+    pub fn place(&self) -> Place {
+        self.place
+    }
+
+    /// Returns `true` if the player jumped on the last simulated frame.
+    pub fn jumped(&self) -> bool {
+        self.jumped
+    }
+
+    /// Returns the final input of the last simulated frame.
+    pub fn prev_frame_input(&self) -> Input {
+        self.prev_frame_input
+    }
 }
 
 impl State {
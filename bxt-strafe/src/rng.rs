@@ -0,0 +1,254 @@
+//! The engine's non-shared and the game's shared random number generators.
+
+use serde::{Deserialize, Serialize};
+
+const IA: i32 = 16807;
+const IM: i32 = 2147483647;
+const IQ: i32 = 127773;
+const IR: i32 = 2836;
+const NTAB: usize = 32;
+const NDIV: i32 = 1 + (IM - 1) / NTAB as i32;
+const MAX_RANDOM_RANGE: u32 = 0x7FFFFFFF;
+
+/// `seed_table` from the HLSDK `util.cpp`.
+#[rustfmt::skip]
+const SEED_TABLE: [u32; 256] = [
+    28985, 27138, 26457, 9451, 17764, 10909, 28790, 8716,
+    6361, 4853, 17798, 21977, 19643, 20662, 10834, 20103,
+    27067, 28634, 18623, 25849, 8576, 26234, 23887, 18228,
+    32587, 4836, 3306, 1811, 3035, 24559, 18399, 315,
+    26766, 907, 24102, 12370, 9674, 2972, 10472, 16492,
+    22683, 11529, 27968, 30406, 13213, 2319, 23620, 16823,
+    10013, 23772, 21567, 1251, 19579, 20313, 18241, 30130,
+    8402, 20807, 27354, 7169, 21211, 17293, 5410, 19223,
+    10255, 22480, 27388, 9946, 15628, 24389, 17308, 2370,
+    9530, 31683, 25927, 23567, 11694, 26397, 32602, 15031,
+    18255, 17582, 1422, 28835, 23607, 12597, 20602, 10138,
+    5212, 1252, 10074, 23166, 19823, 31667, 5902, 24630,
+    18948, 14330, 14950, 8939, 23540, 21311, 22428, 22391,
+    3583, 29004, 30498, 18714, 4278, 2437, 22430, 3439,
+    28313, 23161, 25396, 13471, 19324, 15287, 2563, 18901,
+    13103, 16867, 9714, 14322, 15197, 26889, 19372, 26241,
+    31925, 14640, 11497, 8941, 10056, 6451, 28656, 10737,
+    13874, 17356, 8281, 25937, 1661, 4850, 7448, 12744,
+    21826, 5477, 10167, 16705, 26897, 8839, 30947, 27978,
+    27283, 24685, 32298, 3525, 12398, 28726, 9475, 10208,
+    617, 13467, 22287, 2376, 6097, 26312, 2974, 9114,
+    21787, 28010, 4725, 15387, 3274, 10762, 31695, 17320,
+    18324, 12441, 16801, 27376, 22464, 7500, 5666, 18144,
+    15314, 31914, 31627, 6495, 5226, 31203, 2331, 4668,
+    12650, 18275, 351, 7268, 31319, 30119, 7600, 2905,
+    13826, 11343, 13053, 15583, 30055, 31093, 5067, 761,
+    9685, 11070, 21369, 27155, 3663, 26542, 20169, 12161,
+    15411, 30401, 7580, 31784, 8985, 29367, 20989, 14203,
+    29694, 21167, 10337, 1706, 28578, 887, 3373, 19477,
+    14382, 675, 7033, 15111, 26138, 12252, 30996, 21409,
+    25678, 18555, 13256, 23316, 22407, 16727, 991, 9236,
+    5373, 29402, 6117, 15241, 27715, 19291, 19888, 19847,
+];
+
+/// State of the engine's non-shared random number generator (`ran1()`).
+///
+/// This is the generator behind the engine's `RandomLong()` and `RandomFloat()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct NonSharedRng {
+    pub idum: i32,
+    pub iy: i32,
+    pub iv: [i32; NTAB],
+}
+
+impl NonSharedRng {
+    /// Creates a generator seeded the same way as `SeedRandomNumberGenerator()` does, but with
+    /// `seed` instead of the current time.
+    pub fn seeded(seed: i32) -> Self {
+        let mut idum = seed.wrapping_neg();
+        if 1000 < idum {
+            idum = -idum;
+        } else if -1000 < idum {
+            idum -= 22261048;
+        }
+
+        Self {
+            idum,
+            ..Self::default()
+        }
+    }
+
+    fn next_idum(&mut self) {
+        let k = self.idum / IQ;
+        self.idum = IA * (self.idum - k * IQ) - IR * k;
+        if self.idum < 0 {
+            self.idum += IM;
+        }
+    }
+
+    /// Returns the next number, same as the engine's `ran1()`.
+    pub fn ran1(&mut self) -> i32 {
+        if self.idum <= 0 || self.iy == 0 {
+            self.idum = if self.idum.wrapping_neg() < 1 {
+                1
+            } else {
+                self.idum.wrapping_neg()
+            };
+
+            for j in (0..NTAB + 8).rev() {
+                self.next_idum();
+                if j < NTAB {
+                    self.iv[j] = self.idum;
+                }
+            }
+
+            self.iy = self.iv[0];
+        }
+
+        self.next_idum();
+
+        let j = (self.iy / NDIV) as usize;
+        self.iy = self.iv[j];
+        self.iv[j] = self.idum;
+
+        self.iy
+    }
+
+    /// Returns a number between 0 and 1, same as the engine's `fran1()`.
+    fn fran1(&mut self) -> f32 {
+        const AM: f32 = 1. / IM as f32;
+        const RNMX: f32 = 1. - 1.2e-7;
+
+        (AM * self.ran1() as f32).min(RNMX)
+    }
+
+    /// Returns a number between `low` and `high`, same as the engine's `RandomFloat()`.
+    pub fn random_float(&mut self, low: f32, high: f32) -> f32 {
+        self.fran1() * (high - low) + low
+    }
+
+    /// Returns a number between `low` and `high` inclusive, same as the engine's `RandomLong()`.
+    pub fn random_long(&mut self, low: i32, high: i32) -> i32 {
+        let x = (high as u32).wrapping_sub(low as u32).wrapping_add(1);
+        if x == 0 || MAX_RANDOM_RANGE < x - 1 {
+            return low;
+        }
+
+        let max_acceptable = MAX_RANDOM_RANGE - ((MAX_RANDOM_RANGE + 1) % x);
+
+        loop {
+            let n = self.ran1() as u32;
+            if n <= max_acceptable {
+                return low.wrapping_add((n % x) as i32);
+            }
+        }
+    }
+}
+
+/// The game's shared random number generator (`U_Random()`).
+///
+/// Every call to the shared random functions reseeds it, so its state never outlives a call.
+struct SharedRng(u32);
+
+impl SharedRng {
+    /// Seeds the generator, same as `U_Srand()`.
+    fn seeded(seed: u32) -> Self {
+        Self(SEED_TABLE[(seed & 0xff) as usize])
+    }
+
+    /// Returns the next number, same as `U_Random()`.
+    fn next(&mut self) -> u32 {
+        self.0 = self.0.wrapping_mul(69069);
+        self.0 = self.0.wrapping_add(SEED_TABLE[(self.0 & 0xff) as usize]);
+        self.0 = self.0.wrapping_add(1);
+        self.0 & 0x0fffffff
+    }
+}
+
+/// Returns a number between `low` and `high` inclusive, same as `UTIL_SharedRandomLong()`.
+///
+/// `seed` is the shared seed of the command, which weapons usually offset by a per-shot value.
+pub fn shared_random_long(seed: u32, low: i32, high: i32) -> i32 {
+    let mut rng = SharedRng::seeded(seed.wrapping_add(low as u32).wrapping_add(high as u32));
+
+    let range = (high as u32).wrapping_sub(low as u32).wrapping_add(1);
+    if range == 1 {
+        return low;
+    }
+
+    let offset = rng.next() % range;
+    low.wrapping_add(offset as i32)
+}
+
+/// Returns a number between `low` and `high`, same as `UTIL_SharedRandomFloat()`.
+///
+/// Like in the game, the range is truncated to an integer, so ranges shorter than 1 always return
+/// `low`.
+pub fn shared_random_float(seed: u32, low: f32, high: f32) -> f32 {
+    let mut rng = SharedRng::seeded(
+        seed.wrapping_add(low.to_bits())
+            .wrapping_add(high.to_bits()),
+    );
+    rng.next();
+    rng.next();
+
+    let range = (high - low) as u32;
+    if range == 0 {
+        return low;
+    }
+
+    let offset = (rng.next() & 65535) as f32 / 65536.;
+    low + offset * range as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    proptest! {
+        #[test]
+        fn random_long_in_range(seed: i32, low in -1000i32..1000, len in 0i32..1000) {
+            let mut rng = NonSharedRng::seeded(seed);
+            let value = rng.random_long(low, low + len);
+            prop_assert!(value >= low && value <= low + len);
+        }
+
+        #[test]
+        fn random_float_in_range(seed: i32) {
+            let mut rng = NonSharedRng::seeded(seed);
+            let value = rng.random_float(-1., 1.);
+            prop_assert!((-1. ..=1.).contains(&value));
+        }
+
+        #[test]
+        fn shared_random_long_in_range(seed: u32, low in -1000i32..1000, len in 0i32..1000) {
+            let value = shared_random_long(seed, low, low + len);
+            prop_assert!(value >= low && value <= low + len);
+        }
+
+        #[test]
+        fn shared_random_float_in_range(seed: u32, low in -1000f32..1000., len in 1f32..1000.) {
+            let value = shared_random_float(seed, low, low + len);
+            prop_assert!(value >= low && value <= low + len);
+        }
+
+        #[test]
+        fn same_shared_seed_same_values(seed: u32) {
+            prop_assert_eq!(
+                shared_random_long(seed, 0, 100),
+                shared_random_long(seed, 0, 100)
+            );
+            prop_assert_eq!(
+                shared_random_float(seed, -0.5, 0.5).to_bits(),
+                shared_random_float(seed, -0.5, 0.5).to_bits()
+            );
+        }
+
+        #[test]
+        fn same_seed_same_sequence(seed: i32) {
+            let mut a = NonSharedRng::seeded(seed);
+            let mut b = NonSharedRng::seeded(seed);
+            for _ in 0..100 {
+                prop_assert_eq!(a.ran1(), b.ran1());
+            }
+        }
+    }
+}
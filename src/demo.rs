@@ -0,0 +1,714 @@
+//! GoldSrc demo parsing.
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+
+use thiserror::Error;
+
+use crate::ffi::usercmd::usercmd_s;
+
+const MAGIC: &[u8; 8] = b"HLDEMO\0\0";
+const HEADER_SIZE: usize = 544;
+const DIRECTORY_ENTRY_SIZE: usize = 92;
+
+/// Size of the client state block in front of network messages.
+const NET_MSG_INFO_SIZE: usize = 436;
+/// Size of the netchan sequence numbers after the client state block.
+const NET_MSG_SEQUENCE_SIZE: usize = 28;
+
+#[derive(Error, Debug)]
+pub enum DemoError {
+    #[error("could not read the demo: {0}")]
+    Read(#[from] std::io::Error),
+    #[error("not a demo file")]
+    InvalidMagic,
+    #[error("the demo is cut off at offset {0}")]
+    UnexpectedEnd(usize),
+    #[error("unknown frame type {frame_type} at offset {offset}")]
+    UnknownFrameType { frame_type: u8, offset: usize },
+}
+
+/// A parsed demo.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Demo {
+    pub header: Header,
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub demo_protocol: i32,
+    pub network_protocol: i32,
+    pub map_name: String,
+    pub game_dir: String,
+    pub map_crc: i32,
+}
+
+/// A directory entry: the loading segment or the playback segment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub entry_type: i32,
+    pub description: String,
+    pub flags: i32,
+    pub cd_track: i32,
+    /// Duration in seconds.
+    pub track_time: f32,
+    pub frame_count: i32,
+    pub frames: Vec<Frame>,
+}
+
+impl Demo {
+    /// Returns the playback segments, skipping the loading segment.
+    pub fn playback_entries(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.iter().filter(|entry| !entry.is_loading())
+    }
+
+    /// Returns the duration of the playback segments in seconds.
+    pub fn duration(&self) -> f32 {
+        self.playback_entries().map(|entry| entry.track_time).sum()
+    }
+
+    /// Returns the number of frames in the playback segments.
+    pub fn frame_count(&self) -> i32 {
+        self.playback_entries().map(|entry| entry.frame_count).sum()
+    }
+
+    /// Returns the network messages of the playback segments with the game time in seconds at
+    /// the end of each one.
+    ///
+    /// The game time is the sum of the frame times of the messages, except for the paused ones, so
+    /// loads aren't counted. Messages written in the same host frame share its frame time, so it's
+    /// only counted once.
+    pub fn timed_net_msgs(&self) -> impl Iterator<Item = (f64, &NetMsg)> {
+        self.playback_entries()
+            .flat_map(|entry| {
+                entry
+                    .frames
+                    .iter()
+                    .filter_map(|frame| match &frame.data {
+                        FrameData::NetMsg(msg) => Some((frame.frame, &**msg)),
+                        _ => None,
+                    })
+                    // Host frame numbers restart in every entry.
+                    .scan(None, |last_frame, (frame, msg)| {
+                        let is_new_frame = *last_frame != Some(frame);
+                        *last_frame = Some(frame);
+                        Some((is_new_frame, msg))
+                    })
+            })
+            .scan(0., |time, (is_new_frame, msg)| {
+                if is_new_frame && !msg.paused {
+                    *time += f64::from(msg.frame_time);
+                }
+
+                Some((*time, msg))
+            })
+    }
+
+    /// Returns the game time of the playback segments in seconds.
+    pub fn game_time(&self) -> f64 {
+        self.timed_net_msgs().last().map_or(0., |(time, _)| time)
+    }
+}
+
+impl Entry {
+    /// Returns `true` for the segment recorded while connecting to the server.
+    pub fn is_loading(&self) -> bool {
+        self.entry_type == 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    /// Time since the start of the entry in seconds.
+    pub time: f32,
+    /// Host frame number since the start of the entry.
+    pub frame: i32,
+    pub data: FrameData,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrameData {
+    NetMsg(Box<NetMsg>),
+    DemoStart,
+    ConsoleCommand(String),
+    ClientData(ClientData),
+    /// Marks the end of an entry.
+    NextSection,
+    Event,
+    WeaponAnim,
+    Sound,
+    DemoBuffer,
+}
+
+/// Client state of a frame with a network message.
+#[derive(Debug, Clone, Copy)]
+pub struct NetMsg {
+    pub timestamp: f32,
+    /// Client frame time.
+    pub frame_time: f32,
+    pub paused: bool,
+    pub on_ground: bool,
+    pub sim_velocity: [f32; 3],
+    pub sim_origin: [f32; 3],
+    pub view_angles: [f32; 3],
+    /// The last command sent to the server.
+    pub usercmd: usercmd_s,
+    pub incoming_sequence: i32,
+    pub outgoing_sequence: i32,
+}
+
+// usercmd_s doesn't implement PartialEq.
+impl PartialEq for NetMsg {
+    fn eq(&self, other: &Self) -> bool {
+        let cmd = |msg: &Self| {
+            let cmd = &msg.usercmd;
+            (
+                cmd.msec,
+                cmd.viewangles,
+                [cmd.forwardmove, cmd.sidemove, cmd.upmove],
+                cmd.buttons,
+                cmd.impulse,
+                cmd.weaponselect,
+            )
+        };
+
+        self.timestamp == other.timestamp
+            && self.frame_time == other.frame_time
+            && self.paused == other.paused
+            && self.on_ground == other.on_ground
+            && self.sim_velocity == other.sim_velocity
+            && self.sim_origin == other.sim_origin
+            && self.view_angles == other.view_angles
+            && cmd(self) == cmd(other)
+            && self.incoming_sequence == other.incoming_sequence
+            && self.outgoing_sequence == other.outgoing_sequence
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientData {
+    pub origin: [f32; 3],
+    pub view_angles: [f32; 3],
+    pub weapon_bits: i32,
+    pub fov: f32,
+}
+
+/// Little-endian reader over the demo bytes.
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], offset: usize) -> Self {
+        Self { data, offset }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], DemoError> {
+        let bytes = self
+            .offset
+            .checked_add(len)
+            .and_then(|end| self.data.get(self.offset..end))
+            .ok_or(DemoError::UnexpectedEnd(self.offset))?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> Result<(), DemoError> {
+        self.bytes(len).map(drop)
+    }
+
+    fn u8(&mut self) -> Result<u8, DemoError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn i32(&mut self) -> Result<i32, DemoError> {
+        Ok(i32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn f32(&mut self) -> Result<f32, DemoError> {
+        Ok(f32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn vec3(&mut self) -> Result<[f32; 3], DemoError> {
+        Ok([self.f32()?, self.f32()?, self.f32()?])
+    }
+
+    /// Reads a length-prefixed block, treating negative lengths as empty.
+    fn skip_block(&mut self) -> Result<(), DemoError> {
+        let len = self.i32()?;
+        self.skip(len.max(0) as usize)
+    }
+
+    /// Reads a fixed-size null-terminated string.
+    fn string(&mut self, len: usize) -> Result<String, DemoError> {
+        let bytes = self.bytes(len)?;
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(len);
+        Ok(String::from_utf8_lossy(&bytes[..end]).into_owned())
+    }
+}
+
+/// Reads and parses a demo file.
+pub fn open(path: &Path) -> Result<Demo, DemoError> {
+    parse(&fs::read(path)?)
+}
+
+/// Reads only the header of a demo file.
+pub fn open_header(path: &Path) -> Result<Header, DemoError> {
+    let mut data = Vec::with_capacity(HEADER_SIZE);
+    File::open(path)?
+        .take(HEADER_SIZE as u64)
+        .read_to_end(&mut data)?;
+    parse_header(&data)
+}
+
+/// Parses a demo.
+pub fn parse(data: &[u8]) -> Result<Demo, DemoError> {
+    let header = parse_header(data)?;
+
+    let mut reader = Reader::new(data, HEADER_SIZE - 4);
+    let directory_offset = reader.i32()?;
+
+    let mut reader = Reader::new(data, directory_offset.max(0) as usize);
+    let entry_count = reader.i32()?;
+
+    let mut entries = Vec::new();
+    for _ in 0..entry_count.max(0) {
+        let mut entry_reader = Reader::new(data, reader.offset);
+        reader.skip(DIRECTORY_ENTRY_SIZE)?;
+
+        let entry_type = entry_reader.i32()?;
+        let description = entry_reader.string(64)?;
+        let flags = entry_reader.i32()?;
+        let cd_track = entry_reader.i32()?;
+        let track_time = entry_reader.f32()?;
+        let frame_count = entry_reader.i32()?;
+        let offset = entry_reader.i32()?;
+        let _file_length = entry_reader.i32()?;
+
+        entries.push(Entry {
+            entry_type,
+            description,
+            flags,
+            cd_track,
+            track_time,
+            frame_count,
+            frames: parse_frames(data, offset.max(0) as usize)?,
+        });
+    }
+
+    Ok(Demo { header, entries })
+}
+
+/// Parses only the demo header.
+pub fn parse_header(data: &[u8]) -> Result<Header, DemoError> {
+    let mut reader = Reader::new(data, 0);
+    if reader.bytes(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+        return Err(DemoError::InvalidMagic);
+    }
+
+    Ok(Header {
+        demo_protocol: reader.i32()?,
+        network_protocol: reader.i32()?,
+        map_name: reader.string(260)?,
+        game_dir: reader.string(260)?,
+        map_crc: reader.i32()?,
+    })
+}
+
+/// Parses frames starting at `offset` up to and including the next section frame.
+fn parse_frames(data: &[u8], offset: usize) -> Result<Vec<Frame>, DemoError> {
+    let mut reader = Reader::new(data, offset);
+    let mut frames = Vec::new();
+
+    loop {
+        let frame_offset = reader.offset;
+        let frame_type = reader.u8()?;
+        let time = reader.f32()?;
+        let frame = reader.i32()?;
+
+        let data = match frame_type {
+            0 | 1 => FrameData::NetMsg(Box::new(parse_net_msg(&mut reader)?)),
+            2 => FrameData::DemoStart,
+            3 => FrameData::ConsoleCommand(reader.string(64)?),
+            4 => FrameData::ClientData(ClientData {
+                origin: reader.vec3()?,
+                view_angles: reader.vec3()?,
+                weapon_bits: reader.i32()?,
+                fov: reader.f32()?,
+            }),
+            5 => FrameData::NextSection,
+            6 => {
+                reader.skip(84)?;
+                FrameData::Event
+            }
+            7 => {
+                reader.skip(8)?;
+                FrameData::WeaponAnim
+            }
+            8 => {
+                reader.skip(4)?;
+                reader.skip_block()?;
+                reader.skip(16)?;
+                FrameData::Sound
+            }
+            9 => {
+                reader.skip_block()?;
+                FrameData::DemoBuffer
+            }
+            frame_type => {
+                return Err(DemoError::UnknownFrameType {
+                    frame_type,
+                    offset: frame_offset,
+                })
+            }
+        };
+
+        let is_last = data == FrameData::NextSection;
+        frames.push(Frame { time, frame, data });
+
+        if is_last {
+            return Ok(frames);
+        }
+    }
+}
+
+fn parse_net_msg(reader: &mut Reader) -> Result<NetMsg, DemoError> {
+    let info = reader.bytes(NET_MSG_INFO_SIZE)?;
+    let mut info = Reader::new(info, 0);
+
+    let timestamp = info.f32()?;
+
+    // ref_params_s
+    info.skip(60)?;
+    let frame_time = info.f32()?;
+    info.skip(8)?;
+    let paused = info.i32()? != 0;
+    info.skip(4)?;
+    let on_ground = info.i32()? != 0;
+    info.skip(4)?;
+    let sim_velocity = info.vec3()?;
+    let sim_origin = info.vec3()?;
+    info.skip(16)?;
+    let view_angles = info.vec3()?;
+    info.skip(92)?;
+
+    // usercmd_s
+    let cmd = Reader::new(info.bytes(52)?, 0);
+    let usercmd = parse_usercmd(cmd)?;
+
+    let sequence = reader.bytes(NET_MSG_SEQUENCE_SIZE)?;
+    let mut sequence = Reader::new(sequence, 0);
+    let incoming_sequence = sequence.i32()?;
+    sequence.skip(12)?;
+    let outgoing_sequence = sequence.i32()?;
+
+    reader.skip_block()?;
+
+    Ok(NetMsg {
+        timestamp,
+        frame_time,
+        paused,
+        on_ground,
+        sim_velocity,
+        sim_origin,
+        view_angles,
+        usercmd,
+        incoming_sequence,
+        outgoing_sequence,
+    })
+}
+
+fn parse_usercmd(mut cmd: Reader) -> Result<usercmd_s, DemoError> {
+    let lerp_msec = i16::from_le_bytes(cmd.bytes(2)?.try_into().unwrap());
+    let msec = cmd.u8()?;
+    cmd.skip(1)?;
+    let viewangles = cmd.vec3()?;
+    let forwardmove = cmd.f32()?;
+    let sidemove = cmd.f32()?;
+    let upmove = cmd.f32()?;
+    let lightlevel = cmd.u8()?;
+    cmd.skip(1)?;
+    let buttons = u16::from_le_bytes(cmd.bytes(2)?.try_into().unwrap());
+    let impulse = cmd.u8()?;
+    let weaponselect = cmd.u8()?;
+    cmd.skip(2)?;
+    let impact_index = cmd.i32()?;
+    let impact_position = cmd.vec3()?;
+
+    Ok(usercmd_s {
+        lerp_msec,
+        msec,
+        viewangles,
+        forwardmove,
+        sidemove,
+        upmove,
+        lightlevel,
+        buttons,
+        impulse,
+        weaponselect,
+        impact_index,
+        impact_position,
+    })
+}
+
+/// Writer of small synthetic demos for tests.
+#[cfg(test)]
+pub mod test_demo {
+    use super::*;
+
+    /// A network message frame of the synthetic demo.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct TestNetMsg {
+        pub frame_time: f32,
+        pub paused: bool,
+        pub sim_origin: [f32; 3],
+        pub msec: u8,
+        pub view_angles: [f32; 3],
+        pub forward_move: f32,
+        pub side_move: f32,
+        pub buttons: u16,
+        pub outgoing_sequence: i32,
+        /// Whether the message is written in the same host frame as the previous frame.
+        pub same_host_frame: bool,
+    }
+
+    /// A frame of the synthetic demo.
+    #[derive(Debug, Clone)]
+    pub enum TestFrame {
+        NetMsg(TestNetMsg),
+        ConsoleCommand(&'static str),
+    }
+
+    fn push_string(out: &mut Vec<u8>, value: &str, len: usize) {
+        let start = out.len();
+        out.extend_from_slice(value.as_bytes());
+        out.resize(start + len, 0);
+    }
+
+    fn push_f32s(out: &mut Vec<u8>, values: &[f32]) {
+        for value in values {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    fn push_frame(out: &mut Vec<u8>, frame_type: u8, time: f32, frame: i32) {
+        out.push(frame_type);
+        out.extend_from_slice(&time.to_le_bytes());
+        out.extend_from_slice(&frame.to_le_bytes());
+    }
+
+    fn push_net_msg(out: &mut Vec<u8>, time: f32, msg: &TestNetMsg) {
+        let start = out.len();
+        out.extend_from_slice(&time.to_le_bytes());
+
+        // ref_params_s
+        out.resize(start + 64, 0);
+        push_f32s(out, &[msg.frame_time, time]);
+        out.extend_from_slice(&0i32.to_le_bytes());
+        out.extend_from_slice(&(msg.paused as i32).to_le_bytes());
+        out.resize(start + 104, 0);
+        push_f32s(out, &msg.sim_origin);
+        out.resize(start + 132, 0);
+        push_f32s(out, &msg.view_angles);
+        out.resize(start + 236, 0);
+
+        // usercmd_s
+        out.extend_from_slice(&0i16.to_le_bytes());
+        out.push(msg.msec);
+        out.push(0);
+        push_f32s(out, &msg.view_angles);
+        push_f32s(out, &[msg.forward_move, msg.side_move, 0.]);
+        out.extend_from_slice(&[0, 0]);
+        out.extend_from_slice(&msg.buttons.to_le_bytes());
+        out.resize(start + NET_MSG_INFO_SIZE, 0);
+
+        // Sequence numbers.
+        out.resize(start + NET_MSG_INFO_SIZE + 16, 0);
+        out.extend_from_slice(&msg.outgoing_sequence.to_le_bytes());
+        out.resize(start + NET_MSG_INFO_SIZE + NET_MSG_SEQUENCE_SIZE, 0);
+
+        // A message with a single nop.
+        out.extend_from_slice(&1i32.to_le_bytes());
+        out.push(1);
+    }
+
+    /// Returns a demo on `map` with a loading segment and a playback segment with `frames`.
+    pub fn write(map: &str, frames: &[TestFrame]) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&5i32.to_le_bytes());
+        out.extend_from_slice(&48i32.to_le_bytes());
+        push_string(&mut out, map, 260);
+        push_string(&mut out, "valve", 260);
+        out.extend_from_slice(&0i32.to_le_bytes());
+        out.extend_from_slice(&0i32.to_le_bytes()); // Directory offset, filled in below.
+
+        let loading_offset = out.len();
+        push_frame(&mut out, 5, 0., 0);
+
+        let playback_offset = out.len();
+        push_frame(&mut out, 2, 0., 0);
+
+        let mut time = 0.;
+        let mut host_frame = -1;
+        for frame in frames {
+            match frame {
+                TestFrame::NetMsg(msg) => {
+                    if !msg.same_host_frame {
+                        time += msg.frame_time;
+                        host_frame += 1;
+                    }
+                    push_frame(&mut out, 1, time, host_frame);
+                    push_net_msg(&mut out, time, msg);
+                }
+                TestFrame::ConsoleCommand(command) => {
+                    host_frame += 1;
+                    push_frame(&mut out, 3, time, host_frame);
+                    push_string(&mut out, command, 64);
+                }
+            }
+        }
+        push_frame(&mut out, 5, time, frames.len() as i32);
+
+        let directory_offset = out.len() as i32;
+        out[HEADER_SIZE - 4..HEADER_SIZE].copy_from_slice(&directory_offset.to_le_bytes());
+
+        out.extend_from_slice(&2i32.to_le_bytes());
+        for (entry_type, description, track_time, frame_count, offset) in [
+            (0i32, "LOADING", 0., 0, loading_offset),
+            (1, "Playback", time, frames.len() as i32, playback_offset),
+        ] {
+            out.extend_from_slice(&entry_type.to_le_bytes());
+            push_string(&mut out, description, 64);
+            out.extend_from_slice(&0i32.to_le_bytes());
+            out.extend_from_slice(&(-1i32).to_le_bytes());
+            push_f32s(&mut out, &[track_time]);
+            out.extend_from_slice(&frame_count.to_le_bytes());
+            out.extend_from_slice(&(offset as i32).to_le_bytes());
+            out.extend_from_slice(&0i32.to_le_bytes());
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_demo::*;
+    use super::*;
+
+    #[test]
+    fn parse_synthetic_demo() {
+        let msg = TestNetMsg {
+            frame_time: 0.01,
+            sim_origin: [1., 2., 3.],
+            msec: 10,
+            view_angles: [5., 90., 0.],
+            forward_move: 400.,
+            buttons: 8,
+            outgoing_sequence: 100,
+            ..Default::default()
+        };
+        let data = write(
+            "c1a0",
+            &[
+                TestFrame::NetMsg(msg),
+                TestFrame::ConsoleCommand("say hi"),
+                TestFrame::NetMsg(msg),
+            ],
+        );
+
+        let demo = parse(&data).unwrap();
+        assert_eq!(demo.header.map_name, "c1a0");
+        assert_eq!(demo.header.game_dir, "valve");
+        assert_eq!(demo.entries.len(), 2);
+        assert_eq!(demo.entries[0].frames.len(), 1);
+
+        let playback = &demo.entries[1];
+        assert_eq!(playback.description, "Playback");
+        assert_eq!(playback.frame_count, 3);
+        assert_eq!(demo.playback_entries().count(), 1);
+        assert_eq!(demo.duration(), playback.track_time);
+        assert_eq!(demo.frame_count(), 3);
+        assert_eq!(playback.frames.len(), 5);
+        assert_eq!(
+            playback.frames[2].data,
+            FrameData::ConsoleCommand("say hi".to_string())
+        );
+
+        let msg = match &playback.frames[1].data {
+            FrameData::NetMsg(msg) => msg,
+            data => panic!("expected a network message, got {:?}", data),
+        };
+        assert_eq!(msg.frame_time, 0.01);
+        assert_eq!(msg.sim_origin, [1., 2., 3.]);
+        assert_eq!(msg.view_angles, [5., 90., 0.]);
+        assert_eq!(msg.usercmd.msec, 10);
+        assert_eq!(msg.usercmd.forwardmove, 400.);
+        assert_eq!(msg.usercmd.buttons, 8);
+        assert_eq!(msg.outgoing_sequence, 100);
+    }
+
+    #[test]
+    fn game_time_without_pauses() {
+        let msg = TestNetMsg {
+            frame_time: 0.25,
+            ..Default::default()
+        };
+        let paused = TestNetMsg {
+            paused: true,
+            ..msg
+        };
+        let data = write(
+            "c1a0",
+            &[
+                TestFrame::NetMsg(msg),
+                TestFrame::NetMsg(msg),
+                TestFrame::NetMsg(paused),
+                TestFrame::NetMsg(paused),
+                TestFrame::NetMsg(msg),
+            ],
+        );
+
+        assert_eq!(parse(&data).unwrap().game_time(), 0.75);
+    }
+
+    #[test]
+    fn game_time_with_messages_in_one_host_frame() {
+        let msg = TestNetMsg {
+            frame_time: 0.25,
+            ..Default::default()
+        };
+        let same_frame = TestNetMsg {
+            same_host_frame: true,
+            ..msg
+        };
+        let data = write(
+            "c1a0",
+            &[
+                TestFrame::NetMsg(msg),
+                TestFrame::NetMsg(same_frame),
+                TestFrame::NetMsg(msg),
+            ],
+        );
+
+        let demo = parse(&data).unwrap();
+        let times: Vec<_> = demo.timed_net_msgs().map(|(time, _)| time).collect();
+        assert_eq!(times, [0.25, 0.25, 0.5]);
+        assert_eq!(demo.game_time(), 0.5);
+    }
+
+    #[test]
+    fn parse_invalid_demo() {
+        assert!(matches!(parse(b"not a demo"), Err(DemoError::InvalidMagic)));
+
+        let data = write("c1a0", &[]);
+        assert!(matches!(
+            parse(&data[..data.len() - 10]),
+            Err(DemoError::UnexpectedEnd(_))
+        ));
+    }
+}
@@ -17,5 +17,6 @@ pub mod physent;
 pub mod playermove;
 pub mod pmplane;
 pub mod pmtrace;
+pub mod sizebuf;
 pub mod triangleapi;
 pub mod usercmd;
@@ -0,0 +1,13 @@
+#![allow(unused)]
+
+use std::os::raw::*;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct sizebuf_s {
+    pub buffername: *const c_char,
+    pub flags: c_ushort,
+    pub data: *mut c_uchar,
+    pub maxsize: c_int,
+    pub cursize: c_int,
+}
@@ -5,18 +5,35 @@ use std::ptr::NonNull;
 
 use hltas::HLTAS;
 
-use crate::utils::{MainThreadMarker, Pointer, PointerTrait};
+use crate::modules::tas_logging;
+use crate::utils::{abort_on_panic, MainThreadMarker, Pointer, PointerTrait};
 
 pub static BXT_SIMULATION_IPC_IS_CLIENT_INITIALIZED: Pointer<unsafe extern "C" fn() -> c_int> =
     Pointer::empty(b"bxt_simulation_ipc_is_client_initialized\0");
 pub static BXT_TAS_LOAD_SCRIPT_FROM_STRING: Pointer<unsafe extern "C" fn(*const c_char)> =
     Pointer::empty(b"bxt_tas_load_script_from_string\0");
+pub static BXT_ON_TAS_PLAYBACK_FRAME: Pointer<
+    *mut Option<unsafe extern "C" fn(OnTasPlaybackFrameData) -> c_int>,
+> = Pointer::empty(b"bxt_on_tas_playback_frame\0");
+pub static BXT_ON_TAS_PLAYBACK_STOPPED: Pointer<*mut Option<unsafe extern "C" fn()>> =
+    Pointer::empty(b"bxt_on_tas_playback_stopped\0");
 
 static POINTERS: &[&dyn PointerTrait] = &[
     &BXT_SIMULATION_IPC_IS_CLIENT_INITIALIZED,
     &BXT_TAS_LOAD_SCRIPT_FROM_STRING,
+    &BXT_ON_TAS_PLAYBACK_FRAME,
+    &BXT_ON_TAS_PLAYBACK_STOPPED,
 ];
 
+/// Data that Bunnymod XT passes on every frame of `.hltas` playback.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct OnTasPlaybackFrameData {
+    pub strafe_cycle_frame_count: u32,
+    pub prev_predicted_trace_fractions: [f32; 4],
+    pub prev_predicted_trace_normal_zs: [f32; 4],
+}
+
 #[cfg(unix)]
 fn open_library() -> Option<libloading::Library> {
     use libc::{RTLD_NOLOAD, RTLD_NOW};
@@ -52,6 +69,33 @@ pub unsafe fn find_pointers(marker: MainThreadMarker) {
         pointer.set(marker, ptr);
         pointer.log(marker);
     }
+
+    // Bunnymod XT calls these during .hltas playback if they are set.
+    if let Some(on_tas_playback_frame) = BXT_ON_TAS_PLAYBACK_FRAME.get_opt(marker) {
+        *on_tas_playback_frame = Some(my_on_tas_playback_frame);
+    }
+    if let Some(on_tas_playback_stopped) = BXT_ON_TAS_PLAYBACK_STOPPED.get_opt(marker) {
+        *on_tas_playback_stopped = Some(my_on_tas_playback_stopped);
+    }
+}
+
+unsafe extern "C" fn my_on_tas_playback_frame(_data: OnTasPlaybackFrameData) -> c_int {
+    abort_on_panic(move || {
+        let marker = MainThreadMarker::new();
+
+        tas_logging::on_tas_playback_frame(marker);
+
+        // Continue the playback.
+        0
+    })
+}
+
+unsafe extern "C" fn my_on_tas_playback_stopped() {
+    abort_on_panic(move || {
+        let marker = MainThreadMarker::new();
+
+        tas_logging::on_tas_playback_stopped(marker);
+    })
 }
 
 pub unsafe fn tas_load_script(marker: MainThreadMarker, script: &HLTAS) {
@@ -2,7 +2,7 @@
 
 #![allow(non_snake_case, non_upper_case_globals)]
 
-use std::ffi::CString;
+use std::ffi::{CStr, CString};
 use std::fmt;
 use std::num::ParseIntError;
 use std::os::raw::*;
@@ -17,6 +17,7 @@ use crate::ffi::command::cmd_function_s;
 use crate::ffi::cvar::cvar_s;
 use crate::ffi::edict::edict_s;
 use crate::ffi::playermove::playermove_s;
+use crate::ffi::sizebuf::sizebuf_s;
 use crate::ffi::triangleapi::triangleapi_s;
 use crate::ffi::usercmd::usercmd_s;
 #[cfg(windows)]
@@ -151,6 +152,9 @@ pub static ClientDLL_UpdateClientData: Pointer<unsafe extern "C" fn()> = Pointer
     ]),
     my_ClientDLL_UpdateClientData as _,
 );
+// Only found by symbol: there are no patterns yet, so on Windows the capture metadata has no
+// player state during demo playback.
+pub static cl: Pointer<*mut client_state_t> = Pointer::empty(b"cl\0");
 pub static cls: Pointer<*mut client_static_s> = Pointer::empty(b"cls\0");
 pub static cls_demos: Pointer<*mut client_static_s_demos> = Pointer::empty(
     // Not a real symbol name.
@@ -176,6 +180,15 @@ pub static Cmd_Argc: Pointer<unsafe extern "C" fn() -> c_int> = Pointer::empty(b
 pub static Cmd_Argv: Pointer<unsafe extern "C" fn(c_int) -> *const c_char> =
     Pointer::empty(b"Cmd_Argv\0");
 pub static cmd_functions: Pointer<*mut *mut cmd_function_s> = Pointer::empty(b"cmd_functions\0");
+// Only found by symbol: there are no patterns yet, so on Windows TAS logs have no command buffer.
+pub static cmd_text: Pointer<*mut sizebuf_s> = Pointer::empty(b"cmd_text\0");
+pub static Con_Print: Pointer<unsafe extern "C" fn(*const c_char)> = Pointer::empty_patterns(
+    b"Con_Print\0",
+    // Only found by symbol: there are no patterns yet, so on Windows TAS logs have no console
+    // output.
+    Patterns(&[]),
+    my_Con_Print as _,
+);
 pub static Con_Printf: Pointer<unsafe extern "C" fn(*const c_char, ...)> = Pointer::empty_patterns(
     b"Con_Printf\0",
     // To find, search for "qconsole.log". One of the three usages is Con_Printf (the one that
@@ -244,6 +257,7 @@ pub static GL_BeginRendering: Pointer<
     ]),
     null_mut(),
 );
+pub static gGlobalVariables: Pointer<*mut globalvars_t> = Pointer::empty(b"gGlobalVariables\0");
 pub static gEntityInterface: Pointer<*mut DllFunctions> = Pointer::empty(b"gEntityInterface\0");
 pub static g_svmove: Pointer<*mut playermove_s> = Pointer::empty(b"g_svmove\0");
 pub static Key_Event: Pointer<unsafe extern "C" fn(c_int, c_int)> = Pointer::empty_patterns(
@@ -304,6 +318,7 @@ pub static Host_FilterTime: Pointer<unsafe extern "C" fn(c_float) -> c_int> =
         ]),
         my_Host_FilterTime as _,
     );
+pub static host_framecount: Pointer<*mut c_int> = Pointer::empty(b"host_framecount\0");
 pub static host_frametime: Pointer<*mut c_double> = Pointer::empty(b"host_frametime\0");
 pub static Host_InitializeGameDLL: Pointer<unsafe extern "C" fn()> = Pointer::empty_patterns(
     b"Host_InitializeGameDLL\0",
@@ -415,6 +430,19 @@ pub static Mem_Free: Pointer<unsafe extern "C" fn(*mut c_void)> = Pointer::empty
 pub static paintbuffer: Pointer<*mut [portable_samplepair_t; 1026]> =
     Pointer::empty(b"paintbuffer\0");
 pub static paintedtime: Pointer<*mut c_int> = Pointer::empty(b"paintedtime\0");
+pub static PF_MessageBegin_I: Pointer<
+    unsafe extern "C" fn(c_int, c_int, *const c_float, *mut edict_s),
+> = Pointer::empty_patterns(
+    b"PF_MessageBegin_I\0",
+    // Only found by symbol, like the other user message functions below: there are no patterns
+    // yet, so on Windows TAS logs have no damage types.
+    Patterns(&[]),
+    my_PF_MessageBegin_I as _,
+);
+pub static PF_MessageEnd_I: Pointer<unsafe extern "C" fn()> =
+    Pointer::empty_patterns(b"PF_MessageEnd_I\0", Patterns(&[]), my_PF_MessageEnd_I as _);
+pub static PF_WriteLong_I: Pointer<unsafe extern "C" fn(c_int)> =
+    Pointer::empty_patterns(b"PF_WriteLong_I\0", Patterns(&[]), my_PF_WriteLong_I as _);
 pub static pmove: Pointer<*mut *mut playermove_s> = Pointer::empty(b"pmove\0");
 pub static ran1: Pointer<unsafe extern "C" fn() -> c_int> = Pointer::empty_patterns(
     b"ran1\0",
@@ -491,6 +519,8 @@ pub static R_SetFrustum: Pointer<unsafe extern "C" fn()> = Pointer::empty_patter
     ]),
     my_R_SetFrustum as _,
 );
+pub static RegUserMsg: Pointer<unsafe extern "C" fn(*const c_char, c_int) -> c_int> =
+    Pointer::empty_patterns(b"RegUserMsg\0", Patterns(&[]), my_RegUserMsg as _);
 pub static ReleaseEntityDlls: Pointer<unsafe extern "C" fn()> = Pointer::empty_patterns(
     b"ReleaseEntityDlls\0",
     // Find Host_Shutdown(). It has a Mem_Free() if. The 3-rd function above that if is
@@ -713,12 +743,15 @@ static POINTERS: &[&dyn PointerTrait] = &[
     &ClientDLL_HudRedraw,
     &ClientDLL_HudVidInit,
     &ClientDLL_UpdateClientData,
+    &cl,
     &cls,
     &cls_demos,
     &Cmd_AddMallocCommand,
     &Cmd_Argc,
     &Cmd_Argv,
     &cmd_functions,
+    &cmd_text,
+    &Con_Print,
     &Con_Printf,
     &Con_ToggleConsole_f,
     &com_gamedir,
@@ -727,12 +760,14 @@ static POINTERS: &[&dyn PointerTrait] = &[
     &DrawCrosshair,
     &frametime_remainder,
     &GL_BeginRendering,
+    &gGlobalVariables,
     &gEntityInterface,
     &g_svmove,
     &Key_Event,
     &LoadEntityDLLs,
     &Mod_LeafPVS,
     &Host_FilterTime,
+    &host_framecount,
     &host_frametime,
     &Host_InitializeGameDLL,
     &Host_NextDemo,
@@ -745,12 +780,16 @@ static POINTERS: &[&dyn PointerTrait] = &[
     &Mem_Free,
     &paintbuffer,
     &paintedtime,
+    &PF_MessageBegin_I,
+    &PF_MessageEnd_I,
+    &PF_WriteLong_I,
     &pmove,
     &ran1,
     &ran1_iy,
     &ran1_iv,
     &realtime,
     &R_SetFrustum,
+    &RegUserMsg,
     &ReleaseEntityDlls,
     &R_Clear,
     &R_DrawSequentialPoly,
@@ -831,6 +870,37 @@ pub struct dma_t {
     pub buffer: *mut c_uchar,
 }
 
+/// Only the size matters, the fields aren't used.
+#[repr(C)]
+pub struct resource_t {
+    pub data: [u8; 136],
+}
+
+/// The start of the client state, up to the fields we need.
+#[repr(C)]
+pub struct client_state_t {
+    pub max_edicts: c_int,
+    pub resourcesonhand: resource_t,
+    pub resourcesneeded: resource_t,
+    pub resourcelist: [resource_t; 1280],
+    pub num_resources: c_int,
+    pub need_force_consistency_response: c_int,
+    pub serverinfo: [c_char; 512],
+    pub servercount: c_int,
+    pub validsequence: c_int,
+    pub parsecount: c_int,
+    pub parsecountmod: c_int,
+    pub stats: [c_int; 32],
+    pub weapons: c_int,
+    pub cmd: usercmd_s,
+    pub viewangles: [f32; 3],
+    pub punchangle: [f32; 3],
+    pub crosshairangle: [f32; 3],
+    pub simorg: [f32; 3],
+    pub simvel: [f32; 3],
+    pub simangles: [f32; 3],
+}
+
 #[repr(C)]
 pub struct client_static_s {
     pub state: c_int,
@@ -844,6 +914,51 @@ pub struct client_static_s_demos {
     pub demoplayback: c_int,
 }
 
+#[repr(C)]
+pub struct server_s {
+    pub active: c_int,
+    pub paused: c_int,
+    pub loadgame: c_int,
+    pub time: c_double,
+    pub oldtime: c_double,
+    pub lastcheck: c_int,
+    pub lastchecktime: c_double,
+    pub name: [c_char; 64],
+}
+
+#[repr(C)]
+pub struct globalvars_t {
+    pub time: c_float,
+    pub frametime: c_float,
+    pub force_retouch: c_float,
+    pub mapname: c_int,
+    pub startspot: c_int,
+    pub deathmatch: c_float,
+    pub coop: c_float,
+    pub teamplay: c_float,
+    pub serverflags: c_float,
+    pub found_secrets: c_float,
+    pub v_forward: [c_float; 3],
+    pub v_up: [c_float; 3],
+    pub v_right: [c_float; 3],
+    pub trace_allsolid: c_float,
+    pub trace_startsolid: c_float,
+    pub trace_fraction: c_float,
+    pub trace_endpos: [c_float; 3],
+    pub trace_plane_normal: [c_float; 3],
+    pub trace_plane_dist: c_float,
+    pub trace_ent: *mut edict_s,
+    pub trace_inopen: c_float,
+    pub trace_inwater: c_float,
+    pub trace_hitgroup: c_int,
+    pub trace_flags: c_int,
+    pub msg_entity: c_int,
+    pub cdAudioTrack: c_int,
+    pub maxClients: c_int,
+    pub maxEntities: c_int,
+    pub pStringBase: *const c_char,
+}
+
 #[repr(C)]
 pub struct server_static_s {
     pub dll_initialized: c_int,
@@ -1000,6 +1115,51 @@ pub unsafe fn player_edict(marker: MainThreadMarker) -> Option<NonNull<edict_s>>
     }
 }
 
+/// Returns the string with the given `string_t` index from the game DLL.
+pub unsafe fn string_from_index(marker: MainThreadMarker, index: c_int) -> Option<String> {
+    // SAFETY: we're not calling any engine functions while the reference is alive.
+    let globals = &*gGlobalVariables.get_opt(marker)?;
+    if globals.pStringBase.is_null() {
+        None
+    } else {
+        Some(
+            CStr::from_ptr(globals.pStringBase.offset(index as isize))
+                .to_string_lossy()
+                .into_owned(),
+        )
+    }
+}
+
+/// Returns the server edicts, starting with the world.
+///
+/// # Safety
+///
+/// The returned slice must not be used after the server changes the map or shuts down.
+pub unsafe fn edicts(marker: MainThreadMarker) -> Option<&'static [edict_s]> {
+    // The player is always the edict right after the world.
+    let player = player_edict(marker)?;
+    let max_entities = (*gGlobalVariables.get_opt(marker)?).maxEntities;
+    Some(std::slice::from_raw_parts(
+        player.as_ptr().offset(-1),
+        max_entities.max(0) as usize,
+    ))
+}
+
+/// Returns the name of the map the server is running.
+pub unsafe fn map_name(marker: MainThreadMarker) -> Option<String> {
+    // SAFETY: we're not calling any engine functions while the reference is alive.
+    let sv_ = &*sv.get_opt(marker)?.cast::<server_s>();
+    if sv_.active == 0 {
+        None
+    } else {
+        Some(
+            CStr::from_ptr(sv_.name.as_ptr())
+                .to_string_lossy()
+                .into_owned(),
+        )
+    }
+}
+
 /// # Safety
 ///
 /// [`reset_pointers()`] must be called before hw is unloaded so the pointers don't go stale.
@@ -1569,6 +1729,8 @@ pub mod exported {
         abort_on_panic(move || {
             let marker = MainThreadMarker::new();
 
+            capture::on_scr_draw_loading(marker);
+
             if disable_loading_text::is_active(marker) {
                 return;
             }
@@ -1677,7 +1839,13 @@ pub mod exported {
             let rv = if skip {
                 1
             } else {
-                Host_FilterTime.get(marker)(time)
+                let rv = Host_FilterTime.get(marker)(time);
+
+                if rv != 0 {
+                    demo_playback::on_host_filter_time(marker);
+                }
+
+                rv
             };
 
             if rv != 0 {
@@ -1697,6 +1865,7 @@ pub mod exported {
             let marker = MainThreadMarker::new();
 
             capture::on_cl_disconnect(marker);
+            demo_playback::on_cl_disconnect(marker);
 
             CL_Disconnect.get(marker)();
         })
@@ -1717,6 +1886,68 @@ pub mod exported {
         })
     }
 
+    #[export_name = "Con_Print"]
+    pub unsafe extern "C" fn my_Con_Print(text: *const c_char) {
+        abort_on_panic(move || {
+            let marker = MainThreadMarker::new();
+
+            tas_logging::on_con_print(marker, CStr::from_ptr(text));
+
+            Con_Print.get(marker)(text);
+        })
+    }
+
+    #[export_name = "RegUserMsg"]
+    pub unsafe extern "C" fn my_RegUserMsg(name: *const c_char, size: c_int) -> c_int {
+        abort_on_panic(move || {
+            let marker = MainThreadMarker::new();
+
+            let index = RegUserMsg.get(marker)(name, size);
+
+            tas_logging::on_reg_user_msg(marker, CStr::from_ptr(name), index);
+
+            index
+        })
+    }
+
+    #[export_name = "PF_MessageBegin_I"]
+    pub unsafe extern "C" fn my_PF_MessageBegin_I(
+        dest: c_int,
+        type_: c_int,
+        origin: *const c_float,
+        edict: *mut edict_s,
+    ) {
+        abort_on_panic(move || {
+            let marker = MainThreadMarker::new();
+
+            tas_logging::on_message_begin(marker, type_, edict);
+
+            PF_MessageBegin_I.get(marker)(dest, type_, origin, edict);
+        })
+    }
+
+    #[export_name = "PF_WriteLong_I"]
+    pub unsafe extern "C" fn my_PF_WriteLong_I(value: c_int) {
+        abort_on_panic(move || {
+            let marker = MainThreadMarker::new();
+
+            tas_logging::on_write_long(marker, value);
+
+            PF_WriteLong_I.get(marker)(value);
+        })
+    }
+
+    #[export_name = "PF_MessageEnd_I"]
+    pub unsafe extern "C" fn my_PF_MessageEnd_I() {
+        abort_on_panic(move || {
+            let marker = MainThreadMarker::new();
+
+            tas_logging::on_message_end(marker);
+
+            PF_MessageEnd_I.get(marker)();
+        })
+    }
+
     #[export_name = "Con_ToggleConsole_f"]
     pub unsafe extern "C" fn my_Con_ToggleConsole_f() {
         abort_on_panic(move || {
@@ -8,7 +8,7 @@ use std::ptr::NonNull;
 use crate::ffi::playermove::playermove_s;
 use crate::ffi::usercmd::usercmd_s;
 use crate::hooks::engine;
-use crate::modules::{tas_editor, tas_logging, tas_recording, tas_server_time_fix};
+use crate::modules::{ghost, tas_editor, tas_logging, tas_recording, tas_server_time_fix};
 use crate::utils::*;
 
 pub static CmdStart: Pointer<unsafe extern "C" fn(*mut c_void, *mut usercmd_s, c_uint)> =
@@ -66,9 +66,10 @@ pub unsafe extern "C" fn my_CmdStart(
     abort_on_panic(move || {
         let marker = MainThreadMarker::new();
 
-        tas_logging::begin_cmd_frame(marker, *cmd, random_seed);
+        tas_logging::begin_cmd_frame(marker, player.cast(), *cmd, random_seed);
         tas_recording::on_cmd_start(marker, *cmd, random_seed);
-        tas_editor::on_cmd_start(marker);
+        tas_editor::on_cmd_start(marker, random_seed);
+        ghost::on_cmd_start(marker);
 
         CmdStart.get(marker)(player, cmd, random_seed);
     })
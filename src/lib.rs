@@ -4,6 +4,7 @@
 #[macro_use]
 extern crate tracing;
 
+mod demo;
 mod ffi;
 mod gl;
 mod hooks;
@@ -0,0 +1,165 @@
+//! Per-frame metadata sidecar.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::Serialize;
+
+/// Game state shown on a video frame.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct FrameMetadata {
+    /// The engine `realtime`.
+    pub realtime: f64,
+    pub origin: [f32; 3],
+    pub velocity: [f32; 3],
+    pub viewangles: [f32; 3],
+    /// Name of the current map, or empty if unknown.
+    pub map: String,
+    /// Name of the demo being played back, or empty if none.
+    pub demo: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataFormat {
+    JsonLines,
+    Csv,
+}
+
+impl MetadataFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            MetadataFormat::JsonLines => "jsonl",
+            MetadataFormat::Csv => "csv",
+        }
+    }
+}
+
+impl FromStr for MetadataFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jsonl" | "json" => Ok(MetadataFormat::JsonLines),
+            "csv" => Ok(MetadataFormat::Csv),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Row<'a> {
+    frame: u64,
+    #[serde(flatten)]
+    metadata: &'a FrameMetadata,
+}
+
+/// Writes one row of [`FrameMetadata`] for every video frame.
+pub struct MetadataWriter<W = BufWriter<File>> {
+    writer: W,
+    format: MetadataFormat,
+}
+
+impl MetadataWriter {
+    pub fn create(path: &Path, format: MetadataFormat) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?), format)
+    }
+}
+
+impl<W: Write> MetadataWriter<W> {
+    pub fn new(mut writer: W, format: MetadataFormat) -> io::Result<Self> {
+        if format == MetadataFormat::Csv {
+            writeln!(
+                writer,
+                "frame,realtime,origin_x,origin_y,origin_z,velocity_x,velocity_y,velocity_z,\
+                 pitch,yaw,roll,map,demo"
+            )?;
+        }
+
+        Ok(Self { writer, format })
+    }
+
+    /// Writes the metadata of the video frame with the given index.
+    pub fn write(&mut self, frame: u64, metadata: &FrameMetadata) -> io::Result<()> {
+        match self.format {
+            MetadataFormat::JsonLines => {
+                serde_json::to_writer(&mut self.writer, &Row { frame, metadata })?;
+                writeln!(self.writer)
+            }
+            MetadataFormat::Csv => {
+                let [x, y, z] = metadata.origin;
+                let [vx, vy, vz] = metadata.velocity;
+                let [pitch, yaw, roll] = metadata.viewangles;
+                writeln!(
+                    self.writer,
+                    "{frame},{},{x},{y},{z},{vx},{vy},{vz},{pitch},{yaw},{roll},{},{}",
+                    metadata.realtime,
+                    csv_field(&metadata.map),
+                    csv_field(&metadata.demo),
+                )
+            }
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Quotes a CSV field if needed.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata() -> FrameMetadata {
+        FrameMetadata {
+            realtime: 1.5,
+            origin: [1., 2., 3.],
+            velocity: [4., 5., 6.],
+            viewangles: [7., 8., 9.],
+            map: "c1a0".to_string(),
+            demo: "run,1".to_string(),
+        }
+    }
+
+    #[test]
+    fn metadata_csv() {
+        let mut writer = MetadataWriter::new(Vec::new(), MetadataFormat::Csv).unwrap();
+        writer.write(3, &metadata()).unwrap();
+
+        let output = String::from_utf8(writer.writer).unwrap();
+        let mut lines = output.lines();
+        assert!(lines.next().unwrap().starts_with("frame,realtime,"));
+        assert_eq!(
+            lines.next().unwrap(),
+            "3,1.5,1,2,3,4,5,6,7,8,9,c1a0,\"run,1\""
+        );
+        assert_eq!(lines.next(), None);
+    }
+
+    #[test]
+    fn metadata_json_lines() {
+        let mut writer = MetadataWriter::new(Vec::new(), MetadataFormat::JsonLines).unwrap();
+        writer.write(0, &metadata()).unwrap();
+        writer.write(1, &metadata()).unwrap();
+
+        let output = String::from_utf8(writer.writer).unwrap();
+        let rows: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1]["frame"], 1);
+        assert_eq!(rows[1]["map"], "c1a0");
+        assert_eq!(rows[1]["origin"][2], 3.);
+    }
+}
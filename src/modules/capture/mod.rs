@@ -1,15 +1,18 @@
 //! Video capture.
 
+use std::ffi::CStr;
 use std::mem;
+use std::path::{Path, PathBuf};
 
 use color_eyre::eyre::Context;
 
 use super::cvars::CVar;
+use super::demo_playback::{self, DemoPlayback};
 use super::Module;
 use crate::hooks::engine::{self, con_print};
 use crate::modules::commands::Command;
 use crate::utils::*;
-use crate::{gl, handler};
+use crate::{demo, gl, handler};
 
 pub struct Capture;
 impl Module for Capture {
@@ -18,7 +21,13 @@ impl Module for Capture {
     }
 
     fn commands(&self) -> &'static [&'static Command] {
-        static COMMANDS: &[&Command] = &[&BXT_CAP_START, &BXT_CAP_STOP];
+        static COMMANDS: &[&Command] = &[
+            &BXT_CAP_START,
+            &BXT_CAP_STOP,
+            &BXT_CAP_PAUSE,
+            &BXT_CAP_RESUME,
+            &BXT_RENDER_RUN,
+        ];
         COMMANDS
     }
 
@@ -28,6 +37,16 @@ impl Module for Capture {
             &BXT_CAP_VOLUME,
             &BXT_CAP_SOUND_EXTRA,
             &BXT_CAP_SLOWDOWN,
+            &BXT_CAP_SAMPLING_MULTIPLIER,
+            &BXT_CAP_SAMPLING_EXPOSURE,
+            &BXT_CAP_SAMPLE_RATE,
+            &BXT_CAP_SEGMENT_DURATION,
+            &BXT_CAP_SEGMENT_SIZE,
+            &BXT_CAP_SEGMENT_ON_MAP_CHANGE,
+            &BXT_CAP_PAUSE_ON_LOADING,
+            &BXT_CAP_METADATA,
+            &BXT_CAP_SEPARATE_AUDIO,
+            &BXT_CAP_PRESET,
             &BXT_CAP_FORCE_FALLBACK,
             &BXT_CAP_OVERRIDE_FFMPEG_ARGS,
         ];
@@ -51,26 +70,56 @@ impl Module for Capture {
     }
 }
 
+mod metadata;
+use metadata::{FrameMetadata, MetadataFormat, MetadataWriter};
 mod muxer;
 mod opengl;
 mod recorder;
+use muxer::AudioFormat;
+mod presets;
+use presets::Preset;
 use recorder::{CaptureType, Recorder};
+mod resample;
+mod sampling;
+mod scale;
+mod segments;
+use segments::SegmentLimits;
 mod vulkan;
+mod wav;
 
 #[cfg(unix)]
 pub type ExternalObject = std::os::unix::io::RawFd;
 #[cfg(windows)]
 pub type ExternalObject = *mut std::os::raw::c_void;
 
+/// Number of samples the game paints into the paint buffer at once.
+const PAINT_BUFFER_SIZE: i32 = 512;
+
 static BXT_CAP_FPS: CVar = CVar::new(b"bxt_cap_fps\0", b"60\0");
 static BXT_CAP_SOUND_EXTRA: CVar = CVar::new(b"bxt_cap_sound_extra\0", b"0\0");
 static BXT_CAP_VOLUME: CVar = CVar::new(b"bxt_cap_volume\0", b"0.4\0");
 static BXT_CAP_SLOWDOWN: CVar = CVar::new(b"bxt_cap_slowdown\0", b"1\0");
+static BXT_CAP_SAMPLING_MULTIPLIER: CVar = CVar::new(b"bxt_cap_sampling_multiplier\0", b"1\0");
+static BXT_CAP_SAMPLING_EXPOSURE: CVar = CVar::new(b"bxt_cap_sampling_exposure\0", b"1\0");
+static BXT_CAP_SAMPLE_RATE: CVar = CVar::new(b"bxt_cap_sample_rate\0", b"0\0");
+static BXT_CAP_SEGMENT_DURATION: CVar = CVar::new(b"bxt_cap_segment_duration\0", b"0\0");
+static BXT_CAP_SEGMENT_SIZE: CVar = CVar::new(b"bxt_cap_segment_size\0", b"0\0");
+static BXT_CAP_SEGMENT_ON_MAP_CHANGE: CVar = CVar::new(b"bxt_cap_segment_on_map_change\0", b"0\0");
+static BXT_CAP_PAUSE_ON_LOADING: CVar = CVar::new(b"bxt_cap_pause_on_loading\0", b"0\0");
+static BXT_CAP_METADATA: CVar = CVar::new(b"bxt_cap_metadata\0", b"\0");
+static BXT_CAP_SEPARATE_AUDIO: CVar = CVar::new(b"bxt_cap_separate_audio\0", b"0\0");
+static BXT_CAP_PRESET: CVar = CVar::new(b"bxt_cap_preset\0", b"\0");
 static BXT_CAP_FORCE_FALLBACK: CVar = CVar::new(b"_bxt_cap_force_fallback\0", b"0\0");
 static BXT_CAP_OVERRIDE_FFMPEG_ARGS: CVar = CVar::new(b"_bxt_cap_override_ffmpeg_args\0", b"\0");
 
 static HAVE_REQUIRED_GL_EXTENSIONS: MainThreadCell<bool> = MainThreadCell::new(false);
 
+/// Whether the loading plaque was drawn since the last captured frame.
+static DREW_LOADING: MainThreadCell<bool> = MainThreadCell::new(false);
+
+/// Whether the recording was paused automatically because of loading.
+static AUTO_PAUSED: MainThreadCell<bool> = MainThreadCell::new(false);
+
 pub fn check_gl_extensions(marker: MainThreadMarker, is_supported: impl Fn(&'static str) -> bool) {
     let mut have_everything = true;
 
@@ -131,7 +180,12 @@ pub enum SoundCaptureMode {
 #[allow(clippy::large_enum_variant)]
 enum State {
     Idle,
-    Starting(String),
+    Starting {
+        filename: String,
+        preset: Option<Preset>,
+        /// Whether to wait until the first demo of the queued run starts playing before recording.
+        wait_for_demo: bool,
+    },
     Recording(Recorder),
 }
 
@@ -141,37 +195,142 @@ static BXT_CAP_START: Command = Command::new(
     b"bxt_cap_start\0",
     handler!(
         "Usage: bxt_cap_start [filename.mp4]\n \
-          Starts capturing video. The default filename is \"output.mp4\".\n \
-          If the filename ends with \".wav\", captures only the sound.\n",
+          Starts capturing video. The default filename is \"output.mp4\", or \"output\" with the \
+          container of bxt_cap_preset if it is set.\n \
+          If the filename ends with \".wav\", captures only the sound.\n \
+          If the filename ends with \".nut\", writes uncompressed video and sound without FFmpeg.\n",
         cap_start as fn(_),
         cap_start_with_filename as fn(_, _)
     ),
 );
 
 fn cap_start(marker: MainThreadMarker) {
-    cap_start_with_filename(marker, "output.mp4".to_string());
+    if !Capture.is_enabled(marker) {
+        return;
+    }
+
+    let extension = match find_preset(marker) {
+        Ok(Some(preset)) => preset.container,
+        Ok(None) => "mp4".to_string(),
+        Err(err) => {
+            con_print(marker, &format!("Error: {}.\n", err));
+            return;
+        }
+    };
+
+    cap_start_with_filename(marker, format!("output.{}", extension));
+}
+
+/// Returns the preset selected with `bxt_cap_preset`, if any.
+fn find_preset(marker: MainThreadMarker) -> Result<Option<Preset>, presets::PresetError> {
+    let name = BXT_CAP_PRESET.to_string(marker);
+    let name = name.trim();
+    if name.is_empty() {
+        return Ok(None);
+    }
+
+    presets::find(name).map(Some)
 }
 
 fn cap_start_with_filename(marker: MainThreadMarker, filename: String) {
+    start(marker, filename, false);
+}
+
+/// Starts capturing into `filename`, returns `false` on error.
+fn start(marker: MainThreadMarker, filename: String, wait_for_demo: bool) -> bool {
     if !Capture.is_enabled(marker) {
-        return;
+        return false;
     }
 
-    if !filename.ends_with(".mp4") && !filename.ends_with(".wav") {
+    let extension = Path::new(&filename)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("");
+    let is_video = presets::VIDEO_EXTENSIONS.contains(&extension);
+    if !is_video && extension != "wav" && extension != "nut" {
         con_print(
             marker,
-            "Error: the filename must end with \".mp4\" or \".wav\".\n",
+            &format!(
+                "Error: the filename must end with {}, \".wav\" or \".nut\".\n",
+                presets::VIDEO_EXTENSIONS
+                    .iter()
+                    .map(|extension| format!("\".{}\"", extension))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
         );
-        return;
+        return false;
+    }
+
+    // Presets only apply to the FFmpeg video output.
+    let preset = if is_video {
+        match find_preset(marker) {
+            Ok(preset) => preset,
+            Err(err) => {
+                con_print(marker, &format!("Error: {}.\n", err));
+                return false;
+            }
+        }
+    } else {
+        None
+    };
+
+    if let Some(preset) = &preset {
+        if preset.container != extension {
+            con_print(
+                marker,
+                &format!(
+                    "Error: the filename must end with \".{}\" for the selected preset.\n",
+                    preset.container
+                ),
+            );
+            return false;
+        }
     }
 
     let mut state = STATE.borrow_mut(marker);
     if !matches!(*state, State::Idle) {
         // Already capturing.
+        return false;
+    }
+
+    DREW_LOADING.set(marker, false);
+    *state = State::Starting {
+        filename,
+        preset,
+        wait_for_demo,
+    };
+    true
+}
+
+static BXT_RENDER_RUN: Command = Command::new(
+    b"bxt_render_run\0",
+    handler!(
+        "Usage: bxt_render_run <name> <filename.mp4>\n \
+          Plays back all name_N.dem demos in order and records them into one video. Recording \
+          starts on the first frame of the first demo and stops after the last demo. Set \
+          bxt_cap_segment_on_map_change to 1 to get a separate video for every demo.\n",
+        render_run as fn(_, _, _)
+    ),
+);
+
+fn render_run(marker: MainThreadMarker, prefix: PathBuf, filename: String) {
+    if !Capture.is_enabled(marker) || !DemoPlayback.is_enabled(marker) {
+        return;
+    }
+
+    if !matches!(*STATE.borrow(marker), State::Idle) {
+        con_print(marker, "Error: already recording.\n");
+        return;
+    }
+
+    if !start(marker, filename, true) {
         return;
     }
 
-    *state = State::Starting(filename);
+    if !demo_playback::queue_run(marker, prefix) {
+        *STATE.borrow_mut(marker) = State::Idle;
+    }
 }
 
 static BXT_CAP_STOP: Command = Command::new(
@@ -198,6 +357,8 @@ fn cap_stop(marker: MainThreadMarker) {
         }
     }
 
+    AUTO_PAUSED.set(marker, false);
+
     // Couldn't replace above because capture_sound() needs the state in place.
     let old_state = mem::replace(&mut *STATE.borrow_mut(marker), State::Idle);
     let stopped = !matches!(old_state, State::Idle);
@@ -216,6 +377,63 @@ fn cap_stop(marker: MainThreadMarker) {
     }
 }
 
+static BXT_CAP_PAUSE: Command = Command::new(
+    b"bxt_cap_pause\0",
+    handler!(
+        "Usage: bxt_cap_pause\n \
+          Pauses capturing video without closing the output file.\n",
+        cap_pause as fn(_)
+    ),
+);
+
+fn cap_pause(marker: MainThreadMarker) {
+    let mut state = STATE.borrow_mut(marker);
+    let recorder = match *state {
+        State::Recording(ref mut recorder) => recorder,
+        _ => return,
+    };
+
+    AUTO_PAUSED.set(marker, false);
+
+    if let Err(err) = unsafe { recorder.pause() } {
+        error!("{:?}", err);
+        con_print(marker, "Error during recording, stopping.\n");
+        drop(state);
+        cap_stop(marker);
+        return;
+    }
+
+    con_print(marker, "Recording paused.\n");
+}
+
+static BXT_CAP_RESUME: Command = Command::new(
+    b"bxt_cap_resume\0",
+    handler!(
+        "Usage: bxt_cap_resume\n \
+          Resumes capturing video after bxt_cap_pause.\n",
+        cap_resume as fn(_)
+    ),
+);
+
+fn cap_resume(marker: MainThreadMarker) {
+    let mut state = STATE.borrow_mut(marker);
+    let recorder = match *state {
+        State::Recording(ref mut recorder) => recorder,
+        _ => return,
+    };
+
+    AUTO_PAUSED.set(marker, false);
+
+    if recorder.is_paused() {
+        recorder.resume();
+        con_print(marker, "Recording resumed.\n");
+    }
+}
+
+pub fn on_scr_draw_loading(marker: MainThreadMarker) {
+    DREW_LOADING.set(marker, true);
+}
+
 pub unsafe fn capture_frame(marker: MainThreadMarker) {
     if !Capture.is_enabled(marker) {
         return;
@@ -231,12 +449,67 @@ pub unsafe fn capture_frame(marker: MainThreadMarker) {
     let (width, height) = engine::get_resolution(marker);
 
     // Initialize the recording if needed.
-    if let State::Starting(ref filename) = *state {
+    if let State::Starting {
+        ref filename,
+        ref preset,
+        wait_for_demo,
+    } = *state
+    {
+        if wait_for_demo {
+            // A demo that was playing before the run was queued is still playing until the engine
+            // switches to the first demo of the run, so wait for it to be reported as started.
+            let run_started = demo_playback::current_demo(marker) == Some(0);
+            let demo_playing = (*engine::cls_demos.get(marker)).demoplayback != 0;
+            let client_active = engine::cls
+                .get_opt(marker)
+                .map_or(true, |cls| (*cls).state == 5);
+            if !run_started || !demo_playing || !client_active {
+                return;
+            }
+        }
+
         let fps = BXT_CAP_FPS.as_u64(marker).max(1);
         let slowdown = BXT_CAP_SLOWDOWN.as_f32(marker).max(0.1) as f64;
+        let sampling_multiplier = BXT_CAP_SAMPLING_MULTIPLIER.as_u64(marker).max(1);
+        let sampling_exposure = BXT_CAP_SAMPLING_EXPOSURE.as_f32(marker).clamp(0., 1.);
+
+        let shm = *engine::shm.get(marker);
+        let (sample_rate, output_channels) = if shm.is_null() {
+            // Running with -nosound, we'll be writing blank samples.
+            (22050, 2)
+        } else {
+            (
+                (*shm).speed.max(1) as u64,
+                if (*shm).channels == 1 { 1 } else { 2 },
+            )
+        };
+
+        // We always capture the stereo paint buffer, mono sound is downmixed on the recording
+        // thread.
+        let audio_format = AudioFormat {
+            sample_rate,
+            channels: 2,
+        };
+        let output_audio_format = AudioFormat {
+            // 0 means keep the game's sample rate.
+            sample_rate: match BXT_CAP_SAMPLE_RATE.as_u64(marker) {
+                0 => sample_rate,
+                rate => rate,
+            },
+            channels: output_channels,
+        };
+
+        let segment_limits = SegmentLimits {
+            duration: BXT_CAP_SEGMENT_DURATION.as_f32(marker).max(0.) as f64,
+            // The cvar is in megabytes.
+            size: BXT_CAP_SEGMENT_SIZE.as_u64(marker) * 1024 * 1024,
+            on_map_change: BXT_CAP_SEGMENT_ON_MAP_CHANGE.as_bool(marker),
+        };
 
         let capture_type = if HAVE_REQUIRED_GL_EXTENSIONS.get(marker)
             && !BXT_CAP_FORCE_FALLBACK.as_bool(marker)
+            // Sub-frame blending is only implemented for the glReadPixels capture.
+            && sampling_multiplier == 1
             // Check Vulkan last.
             //
             // On some Windows AMD GPU setups initializing Vulkan and then doing anything with
@@ -256,13 +529,55 @@ pub unsafe fn capture_frame(marker: MainThreadMarker) {
             CaptureType::ReadPixels
         };
 
+        let metadata_format = BXT_CAP_METADATA.to_string(marker);
+        let metadata_format = metadata_format.trim();
+        let metadata_writer = if metadata_format.is_empty() {
+            None
+        } else {
+            let format = match metadata_format.parse::<MetadataFormat>() {
+                Ok(format) => format,
+                Err(()) => {
+                    con_print(
+                        marker,
+                        "Error: bxt_cap_metadata must be empty, \"jsonl\" or \"csv\".\n",
+                    );
+                    *state = State::Idle;
+                    return;
+                }
+            };
+
+            let path = Path::new(filename).with_extension(format.extension());
+            match MetadataWriter::create(&path, format) {
+                Ok(writer) => Some(writer),
+                Err(err) => {
+                    con_print(
+                        marker,
+                        &format!("Error creating {}: {}.\n", path.display(), err),
+                    );
+                    *state = State::Idle;
+                    return;
+                }
+            }
+        };
+
+        // The .wav output already is the audio track.
+        let audio_track_path =
+            if BXT_CAP_SEPARATE_AUDIO.as_bool(marker) && !filename.ends_with(".wav") {
+                Some(Path::new(filename).with_extension("wav"))
+            } else {
+                None
+            };
+
         let custom_ffmpeg_args = BXT_CAP_OVERRIDE_FFMPEG_ARGS.to_string(marker);
+        let preset_ffmpeg_args = preset.as_ref().map(Preset::ffmpeg_args);
         let custom_ffmpeg_args: Option<Vec<&str>> = {
             let args = custom_ffmpeg_args.trim();
-            if args.is_empty() {
-                None
-            } else {
+            if !args.is_empty() {
                 Some(args.split_ascii_whitespace().collect())
+            } else {
+                preset_ffmpeg_args
+                    .as_ref()
+                    .map(|args| args.iter().map(String::as_str).collect())
             }
         };
         let custom_ffmpeg_args = custom_ffmpeg_args.as_deref();
@@ -272,6 +587,13 @@ pub unsafe fn capture_frame(marker: MainThreadMarker) {
             height,
             fps,
             slowdown,
+            sampling_multiplier,
+            sampling_exposure,
+            audio_format,
+            output_audio_format,
+            segment_limits,
+            metadata_writer,
+            audio_track_path.as_deref(),
             capture_type,
             filename,
             custom_ffmpeg_args,
@@ -280,6 +602,17 @@ pub unsafe fn capture_frame(marker: MainThreadMarker) {
                 if matches!(recorder.capture_type(), CaptureType::ReadPixels) {
                     con_print(marker, "Recording in slower fallback mode.\n");
                 }
+                if let Some(path) = recorder.raw_output_path() {
+                    if !filename.ends_with(".nut") {
+                        con_print(
+                            marker,
+                            &format!(
+                                "FFmpeg was not found, writing uncompressed video to {}.\n",
+                                path.display()
+                            ),
+                        );
+                    }
+                }
                 *state = State::Recording(recorder)
             }
             Err(err) => {
@@ -296,29 +629,69 @@ pub unsafe fn capture_frame(marker: MainThreadMarker) {
         _ => unreachable!(),
     };
 
-    // Now that we have the duration of the last frame, record it.
-    if let Err(err) = recorder.record_last_frame() {
-        error!("{:?}", err);
-        con_print(marker, "Error during recording, stopping.\n");
-        drop(state);
-        cap_stop(marker);
+    // Pause while the loading plaque is shown.
+    let loading = DREW_LOADING.get(marker);
+    DREW_LOADING.set(marker, false);
+    if BXT_CAP_PAUSE_ON_LOADING.as_bool(marker) || AUTO_PAUSED.get(marker) {
+        if loading && !recorder.is_paused() {
+            if let Err(err) = recorder.pause() {
+                error!("{:?}", err);
+                con_print(marker, "Error during recording, stopping.\n");
+                drop(state);
+                cap_stop(marker);
+                return;
+            }
+
+            AUTO_PAUSED.set(marker, true);
+        } else if !loading && AUTO_PAUSED.get(marker) {
+            recorder.resume();
+            AUTO_PAUSED.set(marker, false);
+        }
+    }
+
+    let resumed = recorder.take_resumed();
+    if !resumed && recorder.is_paused() {
         return;
     }
 
-    // Check for resolution changes.
-    if recorder.width() != width || recorder.height() != height {
-        con_print(
-            marker,
-            &format!(
-                "Resolution has changed: {}×{} => {}×{}, stopping recording.\n",
-                recorder.width(),
-                recorder.height(),
-                width,
-                height
-            ),
+    // Now that we have the duration of the last frame, record it. Right after resuming there's no
+    // last frame to record, so only capture this one.
+    if !resumed {
+        if let Err(err) = recorder.record_last_frame() {
+            error!("{:?}", err);
+            con_print(marker, "Error during recording, stopping.\n");
+            drop(state);
+            cap_stop(marker);
+            return;
+        }
+    }
+
+    // Check for resolution changes. Frames of a different resolution are scaled to the video size.
+    if recorder.source_resolution() != (width, height) {
+        let (old_width, old_height) = recorder.source_resolution();
+        let message = format!(
+            "Resolution has changed at frame {}: {}×{} => {}×{}, {}.\n",
+            recorder.video_frame(),
+            old_width,
+            old_height,
+            width,
+            height,
+            if recorder.width() == width && recorder.height() == height {
+                "recording at the original size"
+            } else {
+                "scaling to the original size"
+            }
         );
-        cap_stop(marker);
-        return;
+        info!("{}", message.trim_end());
+        con_print(marker, &message);
+
+        if let Err(err) = recorder.set_source_resolution(width, height) {
+            error!("{:?}", err);
+            con_print(marker, "Error during recording, stopping.\n");
+            drop(state);
+            cap_stop(marker);
+            return;
+        }
     }
 
     // Capture this frame for recording later.
@@ -327,17 +700,100 @@ pub unsafe fn capture_frame(marker: MainThreadMarker) {
         con_print(marker, "Error during recording, stopping.\n");
         drop(state);
         cap_stop(marker);
+        return;
     }
+
+    recorder.set_last_frame_metadata(frame_metadata(marker));
+}
+
+/// Returns the metadata of the frame that's about to be shown.
+unsafe fn frame_metadata(marker: MainThreadMarker) -> FrameMetadata {
+    let mut metadata = FrameMetadata {
+        realtime: *engine::realtime.get(marker),
+        ..FrameMetadata::default()
+    };
+
+    // demo_playback keeps the next demo of the run in the first cls.demos entry, so ask it first.
+    // Otherwise, Host_NextDemo() increments demonum after starting a demo.
+    let cls_demos = &*engine::cls_demos.get(marker);
+    if let Some(name) = demo_playback::current_demo_name(marker) {
+        metadata.demo = name;
+    } else if cls_demos.demoplayback != 0 && cls_demos.demonum > 0 {
+        if let Some(name) = cls_demos.demos.get(cls_demos.demonum as usize - 1) {
+            metadata.demo = CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned();
+        }
+    }
+
+    if cls_demos.demoplayback != 0 {
+        // There's no server during demo playback, and if one is left over, its player isn't the
+        // one being shown. The client state holds the view from the demo.
+        if !metadata.demo.is_empty() {
+            metadata.map = demo_map_name(marker, &metadata.demo);
+        }
+
+        if let Some(cl) = engine::cl.get_opt(marker) {
+            // SAFETY: we're not calling any engine functions while the reference is alive.
+            let cl = &*cl;
+            metadata.origin = cl.simorg;
+            metadata.velocity = cl.simvel;
+            metadata.viewangles = cl.viewangles;
+        }
+    } else {
+        metadata.map = engine::map_name(marker).unwrap_or_default();
+
+        if let Some(edict) = engine::player_edict(marker) {
+            // SAFETY: we're not calling any engine functions while the reference is alive.
+            let edict = edict.as_ref();
+            metadata.origin = edict.v.origin;
+            metadata.velocity = edict.v.velocity;
+            metadata.viewangles = edict.v.v_angle;
+        }
+    }
+
+    metadata
+}
+
+/// Demo whose map name was read last, and that map name.
+static DEMO_MAP: MainThreadRefCell<Option<(String, String)>> = MainThreadRefCell::new(None);
+
+/// Returns the map name from the header of `demo`, or an empty string on error.
+fn demo_map_name(marker: MainThreadMarker, demo: &str) -> String {
+    let mut cached = DEMO_MAP.borrow_mut(marker);
+    if let Some((cached_demo, map)) = &*cached {
+        if cached_demo == demo {
+            return map.clone();
+        }
+    }
+
+    let mut path = demo_playback::game_dir(marker).join(demo);
+    if path.extension().is_none() {
+        path.set_extension("dem");
+    }
+
+    let map = match demo::open_header(&path) {
+        Ok(header) => header.map_name,
+        Err(err) => {
+            warn!(
+                "error reading the demo header of {}: {}",
+                path.display(),
+                err
+            );
+            String::new()
+        }
+    };
+
+    *cached = Some((demo.to_owned(), map.clone()));
+    map
 }
 
 pub unsafe fn skip_paint_channels(marker: MainThreadMarker) -> bool {
     // During recording we're capturing sound manually and don't want the game to mess with it.
-    matches!(*STATE.borrow_mut(marker), State::Recording(_))
+    matches!(*STATE.borrow_mut(marker), State::Recording(ref recorder) if !recorder.is_paused())
 }
 
 #[instrument(skip(marker))]
 pub unsafe fn capture_sound(marker: MainThreadMarker, mode: SoundCaptureMode) {
-    let end_time = {
+    let (end_time, transfers_stereo_16) = {
         let mut state = STATE.borrow_mut(marker);
         let recorder = match *state {
             State::Recording(ref mut recorder) => recorder,
@@ -346,19 +802,43 @@ pub unsafe fn capture_sound(marker: MainThreadMarker, mode: SoundCaptureMode) {
 
         if (*engine::shm.get(marker)).is_null() {
             // If we're running with -nosound, write blank samples.
-            let samples = recorder.samples_to_capture(22050, mode);
-            recorder.write_audio_frame(vec![0; samples as usize * 4]);
+            let audio_format = recorder.audio_format();
+            let samples = recorder.samples_to_capture(audio_format.sample_rate as i32, mode);
+            let len = samples as usize * 2 * audio_format.channels as usize;
+            recorder.write_audio_frame(vec![0; len]);
             return;
         }
 
-        let samples_per_second = (**engine::shm.get(marker)).speed;
-        let samples = recorder.samples_to_capture(samples_per_second, mode);
+        let shm = &**engine::shm.get(marker);
+        let samples = recorder.samples_to_capture(shm.speed, mode);
 
         let painted_time = *engine::paintedtime.get(marker);
-        painted_time + samples as i32
+
+        // The game only calls S_TransferStereo16() for 16-bit stereo sound.
+        let transfers_stereo_16 = shm.samplebits == 16 && shm.channels == 2;
+
+        (painted_time + samples as i32, transfers_stereo_16)
     };
 
-    engine::S_PaintChannels.get(marker)(end_time);
+    if transfers_stereo_16 {
+        engine::S_PaintChannels.get(marker)(end_time);
+        return;
+    }
+
+    // Otherwise, paint at most one paint buffer at a time and capture it after painting.
+    loop {
+        let painted_time = *engine::paintedtime.get(marker);
+        if painted_time >= end_time {
+            break;
+        }
+
+        let end = end_time.min(painted_time + PAINT_BUFFER_SIZE);
+        engine::S_PaintChannels.get(marker)(end);
+
+        if let State::Recording(ref mut recorder) = *STATE.borrow_mut(marker) {
+            write_paint_buffer(marker, recorder, painted_time, end);
+        }
+    }
 }
 
 pub unsafe fn on_s_transfer_stereo_16(marker: MainThreadMarker, end: i32) {
@@ -368,7 +848,22 @@ pub unsafe fn on_s_transfer_stereo_16(marker: MainThreadMarker, end: i32) {
         _ => return,
     };
 
+    // While paused the game mixes sound as usual, but it shouldn't go into the recording.
+    if recorder.is_paused() {
+        return;
+    }
+
     let painted_time = *engine::paintedtime.get(marker);
+    write_paint_buffer(marker, recorder, painted_time, end);
+}
+
+/// Writes the stereo paint buffer painted from `painted_time` to `end` as audio.
+unsafe fn write_paint_buffer(
+    marker: MainThreadMarker,
+    recorder: &mut Recorder,
+    painted_time: i32,
+    end: i32,
+) {
     let paint_buffer = &*engine::paintbuffer.get(marker);
     let sample_count = (end - painted_time) as usize * 2;
 
@@ -398,34 +893,45 @@ pub unsafe fn on_host_filter_time(marker: MainThreadMarker) -> bool {
         _ => return false,
     };
 
+    if recorder.is_paused() {
+        return false;
+    }
+
     if (*engine::cls_demos.get(marker)).demoplayback == 0 {
         return false;
     }
 
     *engine::host_frametime.get(marker) = recorder.frame_time();
+    drop(state);
+
+    // Host_FilterTime() is skipped, so let demo playback seek, pause and change the speed on top
+    // of the forced frame time.
+    demo_playback::on_host_filter_time(marker);
+
     let realtime = engine::realtime.get(marker);
-    *realtime += recorder.frame_time();
+    *realtime += *engine::host_frametime.get(marker);
 
     true
 }
 
 pub unsafe fn on_cl_disconnect(marker: MainThreadMarker) {
-    {
+    let keep_recording = {
         // Safety: no engine functions are called while the reference is active.
         let cls_demos = &*engine::cls_demos.get(marker);
 
-        // Wasn't playing back a demo.
-        if cls_demos.demoplayback == 0 {
-            return;
-        }
+        // Wasn't playing back a demo, or will play another demo right after.
+        cls_demos.demoplayback == 0 || (cls_demos.demonum != -1 && cls_demos.demos[0][0] != 0)
+    };
 
-        // Will play another demo right after.
-        if cls_demos.demonum != -1 && cls_demos.demos[0][0] != 0 {
-            return;
-        }
+    if !keep_recording {
+        cap_stop(marker);
+        return;
     }
 
-    cap_stop(marker);
+    // The map is changing.
+    if let State::Recording(ref mut recorder) = *STATE.borrow_mut(marker) {
+        recorder.on_map_change();
+    }
 }
 
 static INSIDE_KEY_EVENT: MainThreadCell<bool> = MainThreadCell::new(false);
@@ -449,6 +955,10 @@ pub unsafe fn time_passed(marker: MainThreadMarker) {
         _ => return,
     };
 
+    if recorder.is_paused() {
+        return;
+    }
+
     // Accumulate time for the last frame.
     let time = *engine::host_frametime.get(marker);
     recorder.time_passed(time);
@@ -1,14 +1,68 @@
-use std::io::{self, Write};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
 #[cfg(windows)]
 use std::os::windows::process::CommandExt;
+use std::path::{Path, PathBuf};
 use std::process::{Child, Command, Stdio};
 
 use thiserror::Error;
 
 pub struct Muxer {
-    child: Child,
+    output: Output,
+    path: PathBuf,
+    width: u64,
+    pixel_format: PixelFormat,
+    audio_format: AudioFormat,
+    fps: u64,
     video_pts: u64,
     audio_pts: u64,
+    /// Number of bytes of the NUT stream written so far.
+    bytes_written: u64,
+    /// Size of the file encoded by FFmpeg when it was last checked.
+    encoded_size: u64,
+}
+
+/// Where the NUT stream goes.
+enum Output {
+    /// Piped into FFmpeg for encoding.
+    Ffmpeg(Child),
+    /// Written as-is into a file.
+    File {
+        writer: BufWriter<File>,
+        path: PathBuf,
+    },
+}
+
+/// Counts the bytes written through it.
+struct CountingWriter<'a> {
+    writer: &'a mut dyn Write,
+    count: &'a mut u64,
+}
+
+impl Write for CountingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        *self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl Output {
+    fn create_file(path: PathBuf) -> Result<Self, io::Error> {
+        let writer = BufWriter::new(File::create(&path)?);
+        Ok(Self::File { writer, path })
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Output::Ffmpeg(child) => child.stdin.as_mut().unwrap(),
+            Output::File { writer, .. } => writer,
+        }
+    }
 }
 
 #[derive(Error, Debug)]
@@ -26,6 +80,15 @@ pub enum PixelFormat {
     Rgb24Flipped,
 }
 
+/// Format of the interleaved little-endian 16-bit audio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioFormat {
+    /// Samples per second.
+    pub sample_rate: u64,
+    /// Number of interleaved channels.
+    pub channels: u64,
+}
+
 impl PixelFormat {
     fn fourcc(self) -> &'static [u8; 4] {
         match self {
@@ -96,15 +159,74 @@ fn packet<W: Write>(mut writer: W, startcode: u64, data: &[u8]) -> Result<(), io
 }
 
 impl Muxer {
+    /// Creates a new [`Muxer`].
+    ///
+    /// If `filename` ends with `.nut`, or if FFmpeg can't be found, the uncompressed NUT stream is
+    /// written directly into a file instead of being piped into FFmpeg.
     #[instrument(name = "Muxer::new")]
     pub fn new(
         width: u64,
         height: u64,
         fps: u64,
         pixel_format: PixelFormat,
+        audio_format: AudioFormat,
         filename: &str,
         custom_ffmpeg_args: Option<&[&str]>,
     ) -> Result<Self, MuxerInitError> {
+        let mut output = if filename.ends_with(".nut") {
+            Output::create_file(filename.into())?
+        } else {
+            match Self::spawn_ffmpeg(pixel_format, filename, custom_ffmpeg_args) {
+                Ok(child) => Output::Ffmpeg(child),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    let path = Path::new(filename).with_extension("nut");
+                    warn!(
+                        "ffmpeg not found, writing uncompressed NUT to {}",
+                        path.display()
+                    );
+                    Output::create_file(path)?
+                }
+                Err(err) => return Err(MuxerInitError::FfmpegSpawn(err)),
+            }
+        };
+
+        let path = match &output {
+            Output::Ffmpeg(_) => PathBuf::from(filename),
+            Output::File { path, .. } => path.clone(),
+        };
+
+        let mut bytes_written = 0;
+        write_headers(
+            CountingWriter {
+                writer: output.writer(),
+                count: &mut bytes_written,
+            },
+            width,
+            height,
+            fps,
+            pixel_format,
+            audio_format,
+        )?;
+
+        Ok(Self {
+            output,
+            path,
+            width,
+            pixel_format,
+            audio_format,
+            fps,
+            video_pts: 0,
+            audio_pts: 0,
+            bytes_written,
+            encoded_size: 0,
+        })
+    }
+
+    fn spawn_ffmpeg(
+        pixel_format: PixelFormat,
+        filename: &str,
+        custom_ffmpeg_args: Option<&[&str]>,
+    ) -> Result<Child, io::Error> {
         #[rustfmt::skip]
         let mut args = vec![
             "-loglevel", "error",
@@ -132,12 +254,12 @@ impl Muxer {
             ]);
         }
 
-        #[rustfmt::skip]
-        args.extend_from_slice(&[
-            "-movflags", "+faststart",
-            "-y",
-            filename,
-        ]);
+        // Only the MP4 and MOV muxers know about this flag.
+        if filename.ends_with(".mp4") || filename.ends_with(".mov") {
+            args.extend_from_slice(&["-movflags", "+faststart"]);
+        }
+
+        args.extend_from_slice(&["-y", filename]);
 
         let mut command = Command::new("ffmpeg");
         command
@@ -150,90 +272,59 @@ impl Muxer {
         #[cfg(windows)]
         command.creation_flags(winapi::um::winbase::CREATE_NO_WINDOW);
 
-        let mut child = command.spawn().map_err(MuxerInitError::FfmpegSpawn)?;
-        let mut writer = child.stdin.as_mut().unwrap();
-
-        const MAIN_STARTCODE: u64 = 0x4e4d7a561f5f04ad;
-        const STREAM_STARTCODE: u64 = 0x4e5311405bf2f9db;
-
-        writer.write_all(b"nut/multimedia container\0")?;
+        command.spawn()
+    }
 
-        // Main header.
-        let mut buf = Vec::new();
-        v(&mut buf, 3)?; // version
-        v(&mut buf, 2)?; // stream_count
-        v(&mut buf, 65536)?; // max_distance
-        v(&mut buf, 2)?; // time_base_count
-        v(&mut buf, 1)?; // time_base_num
-        v(&mut buf, fps)?; // time_base_denom
-        v(&mut buf, 1)?; // time_base_num
-        v(&mut buf, 22050)?; // time_base_denom
-
-        for _ in 0..255 {
-            // Not 256 because 'N' is skipped.
-            v(&mut buf, 1 << 12)?; // tmp_flag = FLAG_CODED
-            v(&mut buf, 0)?; // tmp_fields
+    /// Returns the path of the NUT file if the stream is written without FFmpeg.
+    pub fn raw_output_path(&self) -> Option<&Path> {
+        match &self.output {
+            Output::Ffmpeg(_) => None,
+            Output::File { path, .. } => Some(path),
         }
+    }
 
-        v(&mut buf, 0)?; // header_count_minus1
-        v(&mut buf, 0)?; // main_flags
-
-        packet(&mut writer, MAIN_STARTCODE, &buf)?;
-
-        // Stream header (video).
-        buf.clear();
-        v(&mut buf, 0)?; // stream_id
-        v(&mut buf, 0)?; // stream_class = video
-        vb(&mut buf, pixel_format.fourcc())?; // fourcc
-        v(&mut buf, 0)?; // time_base_id
-        v(&mut buf, 0)?; // msb_pts_shift
-        v(&mut buf, 1)?; // max_pts_distance
-        v(&mut buf, 0)?; // decode_delay
-        v(&mut buf, 1)?; // stream_flags = FLAG_FIXED_FPS
-        vb(&mut buf, &[])?; // codec_specific_data
-
-        v(&mut buf, width)?; // width
-        v(&mut buf, height)?; // height
-        v(&mut buf, 1)?; // sample_width
-        v(&mut buf, 1)?; // sample_height
-        v(&mut buf, 2)?; // colorspace_type = narrow-range 709
-
-        packet(&mut writer, STREAM_STARTCODE, &buf)?;
-
-        // Stream header (audio).
-        buf.clear();
-        v(&mut buf, 1)?; // stream_id
-        v(&mut buf, 1)?; // stream_class = audio
-        vb(&mut buf, b"PSD\x10")?; // fourcc = little-endian signed interleaved 16-bit
-        v(&mut buf, 1)?; // time_base_id
-        v(&mut buf, 0)?; // msb_pts_shift
-        v(&mut buf, 1)?; // max_pts_distance
-        v(&mut buf, 0)?; // decode_delay
-        v(&mut buf, 1)?; // stream_flags = FLAG_FIXED_FPS
-        vb(&mut buf, &[])?; // codec_specific_data
+    /// Returns the path of the output file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
 
-        v(&mut buf, 22050)?; // samplerate_num
-        v(&mut buf, 1)?; // samplerate_denom
-        v(&mut buf, 2)?; // channel_count
+    /// Returns the number of video frames written so far.
+    pub fn video_frames(&self) -> u64 {
+        self.video_pts
+    }
 
-        packet(&mut writer, STREAM_STARTCODE, &buf)?;
+    /// Returns the size of the output file so far.
+    ///
+    /// Without FFmpeg, this is the number of bytes written. FFmpeg writes the encoded file on its
+    /// own, so its size is checked once per second of video.
+    pub fn output_size(&self) -> u64 {
+        match self.output {
+            Output::Ffmpeg(_) => self.encoded_size,
+            Output::File { .. } => self.bytes_written,
+        }
+    }
 
-        Ok(Self {
-            child,
-            video_pts: 0,
-            audio_pts: 0,
-        })
+    fn writer(&mut self) -> CountingWriter<'_> {
+        CountingWriter {
+            writer: self.output.writer(),
+            count: &mut self.bytes_written,
+        }
     }
 
     #[instrument(name = "Muxer::write_video_frame", skip_all)]
     pub fn write_video_frame(&mut self, data: &[u8]) -> Result<(), io::Error> {
         const SYNCPOINT_STARTCODE: u64 = 0x4e4be4adeeca4569;
 
-        let mut writer = self.child.stdin.as_mut().unwrap();
+        // Without FFmpeg there's no vflip filter, so flip the rows here.
+        let flip = self.pixel_format == PixelFormat::Rgb24Flipped
+            && matches!(self.output, Output::File { .. });
+        let stride = self.width as usize * 3;
+        let video_pts = self.video_pts;
+        let mut writer = self.writer();
 
         // Syncpoint.
         let mut buf = Vec::new();
-        v(&mut buf, self.video_pts * 2)?; // global_key_pts
+        v(&mut buf, video_pts * 2)?; // global_key_pts
         v(&mut buf, 0)?; // back_ptr_div16, ???
 
         packet(&mut writer, SYNCPOINT_STARTCODE, &buf)?;
@@ -251,7 +342,7 @@ impl Muxer {
             ;
         v(&mut buf, flags)?; // coded_flags
         v(&mut buf, 0)?; // stream_id
-        v(&mut buf, self.video_pts + (1 << 0))?; // coded_pts = pts + (1 << msb_pts_shift)
+        v(&mut buf, video_pts + (1 << 0))?; // coded_pts = pts + (1 << msb_pts_shift)
         v(&mut buf, data.len() as u64)?; // data_size_msb
 
         writer.write_all(&buf)?;
@@ -259,11 +350,24 @@ impl Muxer {
 
         {
             let _span = info_span!("write video data").entered();
-            writer.write_all(data)?;
+
+            if flip {
+                for row in data.chunks_exact(stride).rev() {
+                    writer.write_all(row)?;
+                }
+            } else {
+                writer.write_all(data)?;
+            }
         }
 
         self.video_pts += 1;
 
+        if matches!(self.output, Output::Ffmpeg(_)) && self.video_pts % self.fps.max(1) == 0 {
+            if let Ok(metadata) = fs::metadata(&self.path) {
+                self.encoded_size = metadata.len();
+            }
+        }
+
         Ok(())
     }
 
@@ -271,11 +375,12 @@ impl Muxer {
     pub fn write_audio_frame(&mut self, data: &[u8]) -> Result<(), io::Error> {
         const SYNCPOINT_STARTCODE: u64 = 0x4e4be4adeeca4569;
 
-        let mut writer = self.child.stdin.as_mut().unwrap();
+        let audio_pts = self.audio_pts;
+        let mut writer = self.writer();
 
         // Syncpoint.
         let mut buf = Vec::new();
-        v(&mut buf, self.audio_pts * 2 + 1)?; // global_key_pts
+        v(&mut buf, audio_pts * 2 + 1)?; // global_key_pts
         v(&mut buf, 0)?; // back_ptr_div16, ???
 
         packet(&mut writer, SYNCPOINT_STARTCODE, &buf)?;
@@ -293,23 +398,223 @@ impl Muxer {
             ;
         v(&mut buf, flags)?; // coded_flags
         v(&mut buf, 1)?; // stream_id
-        v(&mut buf, self.audio_pts + (1 << 0))?; // coded_pts = pts + (1 << msb_pts_shift)
+        v(&mut buf, audio_pts + (1 << 0))?; // coded_pts = pts + (1 << msb_pts_shift)
         v(&mut buf, data.len() as u64)?; // data_size_msb
 
         writer.write_all(&buf)?;
         writer.write_all(&crc32(&buf).to_be_bytes()[..])?; // checksum
         writer.write_all(data)?;
 
-        let samples = data.len() as u64 / 4; // 1 sample is 2×i16
+        let samples = data.len() as u64 / (2 * self.audio_format.channels); // 1 sample is N×i16
         self.audio_pts += samples;
 
         Ok(())
     }
 
+    /// Flushes any buffered output.
+    pub fn flush(&mut self) -> Result<(), io::Error> {
+        self.output.writer().flush()
+    }
+
     /// Waits for the child process to exit and returns its output.
     #[instrument(name = "Muxer::close", skip_all)]
     pub fn close(self) -> String {
-        let output = self.child.wait_with_output().unwrap();
-        String::from_utf8_lossy(&output.stderr).into_owned()
+        match self.output {
+            Output::Ffmpeg(child) => {
+                let output = child.wait_with_output().unwrap();
+                String::from_utf8_lossy(&output.stderr).into_owned()
+            }
+            Output::File { .. } => String::new(),
+        }
+    }
+}
+
+/// Writes the NUT file ID string, main header and stream headers.
+fn write_headers<W: Write>(
+    mut writer: W,
+    width: u64,
+    height: u64,
+    fps: u64,
+    pixel_format: PixelFormat,
+    audio_format: AudioFormat,
+) -> Result<(), io::Error> {
+    const MAIN_STARTCODE: u64 = 0x4e4d7a561f5f04ad;
+    const STREAM_STARTCODE: u64 = 0x4e5311405bf2f9db;
+
+    writer.write_all(b"nut/multimedia container\0")?;
+
+    // Main header.
+    let mut buf = Vec::new();
+    v(&mut buf, 3)?; // version
+    v(&mut buf, 2)?; // stream_count
+    v(&mut buf, 65536)?; // max_distance
+    v(&mut buf, 2)?; // time_base_count
+    v(&mut buf, 1)?; // time_base_num
+    v(&mut buf, fps)?; // time_base_denom
+    v(&mut buf, 1)?; // time_base_num
+    v(&mut buf, audio_format.sample_rate)?; // time_base_denom
+
+    for _ in 0..255 {
+        // Not 256 because 'N' is skipped.
+        v(&mut buf, 1 << 12)?; // tmp_flag = FLAG_CODED
+        v(&mut buf, 0)?; // tmp_fields
+    }
+
+    v(&mut buf, 0)?; // header_count_minus1
+    v(&mut buf, 0)?; // main_flags
+
+    packet(&mut writer, MAIN_STARTCODE, &buf)?;
+
+    // Stream header (video).
+    buf.clear();
+    v(&mut buf, 0)?; // stream_id
+    v(&mut buf, 0)?; // stream_class = video
+    vb(&mut buf, pixel_format.fourcc())?; // fourcc
+    v(&mut buf, 0)?; // time_base_id
+    v(&mut buf, 0)?; // msb_pts_shift
+    v(&mut buf, 1)?; // max_pts_distance
+    v(&mut buf, 0)?; // decode_delay
+    v(&mut buf, 1)?; // stream_flags = FLAG_FIXED_FPS
+    vb(&mut buf, &[])?; // codec_specific_data
+
+    v(&mut buf, width)?; // width
+    v(&mut buf, height)?; // height
+    v(&mut buf, 1)?; // sample_width
+    v(&mut buf, 1)?; // sample_height
+    v(&mut buf, 2)?; // colorspace_type = narrow-range 709
+
+    packet(&mut writer, STREAM_STARTCODE, &buf)?;
+
+    // Stream header (audio).
+    buf.clear();
+    v(&mut buf, 1)?; // stream_id
+    v(&mut buf, 1)?; // stream_class = audio
+    vb(&mut buf, b"PSD\x10")?; // fourcc = little-endian signed interleaved 16-bit
+    v(&mut buf, 1)?; // time_base_id
+    v(&mut buf, 0)?; // msb_pts_shift
+    v(&mut buf, 1)?; // max_pts_distance
+    v(&mut buf, 0)?; // decode_delay
+    v(&mut buf, 1)?; // stream_flags = FLAG_FIXED_FPS
+    vb(&mut buf, &[])?; // codec_specific_data
+
+    v(&mut buf, audio_format.sample_rate)?; // samplerate_num
+    v(&mut buf, 1)?; // samplerate_denom
+    v(&mut buf, audio_format.channels)?; // channel_count
+
+    packet(&mut writer, STREAM_STARTCODE, &buf)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_v(data: &mut &[u8]) -> u64 {
+        let mut value = 0;
+        loop {
+            let (&byte, rest) = data.split_first().unwrap();
+            *data = rest;
+
+            value = (value << 7) | (byte & 127) as u64;
+            if byte & 128 == 0 {
+                return value;
+            }
+        }
+    }
+
+    fn read_vb<'a>(data: &mut &'a [u8]) -> &'a [u8] {
+        let len = read_v(data) as usize;
+        let (bytes, rest) = data.split_at(len);
+        *data = rest;
+        bytes
+    }
+
+    /// Reads a packet, checks its checksum and returns its startcode and contents.
+    fn read_packet<'a>(data: &mut &'a [u8]) -> (u64, &'a [u8]) {
+        let (startcode, rest) = data.split_at(8);
+        *data = rest;
+        let startcode = u64::from_be_bytes(startcode.try_into().unwrap());
+
+        let size = read_v(data) as usize;
+        let (contents, rest) = data.split_at(size - 4);
+        let (checksum, rest) = rest.split_at(4);
+        *data = rest;
+
+        assert_eq!(
+            crc32(contents),
+            u32::from_be_bytes(checksum.try_into().unwrap())
+        );
+
+        (startcode, contents)
+    }
+
+    #[test]
+    fn v_round_trip() {
+        for value in [0, 1, 127, 128, 16383, 16384, u64::MAX] {
+            let mut buf = Vec::new();
+            v(&mut buf, value).unwrap();
+            let mut data = &buf[..];
+            assert_eq!(read_v(&mut data), value);
+            assert!(data.is_empty());
+        }
+    }
+
+    #[test]
+    fn headers_round_trip() {
+        let mut buf = Vec::new();
+        let audio_format = AudioFormat {
+            sample_rate: 44100,
+            channels: 1,
+        };
+        write_headers(
+            &mut buf,
+            640,
+            480,
+            60,
+            PixelFormat::Rgb24Flipped,
+            audio_format,
+        )
+        .unwrap();
+
+        let mut data = &buf[..];
+        let (id, rest) = data.split_at(25);
+        assert_eq!(id, b"nut/multimedia container\0");
+        data = rest;
+
+        let (startcode, mut main) = read_packet(&mut data);
+        assert_eq!(startcode, 0x4e4d7a561f5f04ad);
+        assert_eq!(read_v(&mut main), 3); // version
+        assert_eq!(read_v(&mut main), 2); // stream_count
+        assert_eq!(read_v(&mut main), 65536); // max_distance
+        assert_eq!(read_v(&mut main), 2); // time_base_count
+        assert_eq!((read_v(&mut main), read_v(&mut main)), (1, 60));
+        assert_eq!((read_v(&mut main), read_v(&mut main)), (1, 44100));
+
+        let (startcode, mut video) = read_packet(&mut data);
+        assert_eq!(startcode, 0x4e5311405bf2f9db);
+        assert_eq!(read_v(&mut video), 0); // stream_id
+        assert_eq!(read_v(&mut video), 0); // stream_class
+        assert_eq!(read_vb(&mut video), b"24BG");
+        for _ in 0..5 {
+            read_v(&mut video);
+        }
+        assert!(read_vb(&mut video).is_empty());
+        assert_eq!((read_v(&mut video), read_v(&mut video)), (640, 480));
+
+        let (startcode, mut audio) = read_packet(&mut data);
+        assert_eq!(startcode, 0x4e5311405bf2f9db);
+        assert_eq!(read_v(&mut audio), 1); // stream_id
+        assert_eq!(read_v(&mut audio), 1); // stream_class
+        assert_eq!(read_vb(&mut audio), b"PSD\x10");
+        for _ in 0..5 {
+            read_v(&mut audio);
+        }
+        assert!(read_vb(&mut audio).is_empty());
+        assert_eq!(read_v(&mut audio), 44100); // samplerate_num
+        assert_eq!(read_v(&mut audio), 1); // samplerate_denom
+        assert_eq!(read_v(&mut audio), 1); // channel_count
+
+        assert!(data.is_empty());
     }
 }
@@ -2,6 +2,7 @@ use std::ptr::null;
 
 use color_eyre::eyre::{self, eyre, WrapErr};
 
+use super::scale::letterbox;
 use super::ExternalObject;
 use crate::gl;
 use crate::utils::MainThreadMarker;
@@ -64,8 +65,9 @@ unsafe fn reset_gl_error(gl: &gl::Gl) {
 }
 
 impl OpenGl {
+    /// Captures the current frame, scaling it to the video size if the game resolution differs.
     #[instrument(name = "OpenGl::capture", skip_all)]
-    pub unsafe fn capture(&self) -> eyre::Result<()> {
+    pub unsafe fn capture(&self, source_width: i32, source_height: i32) -> eyre::Result<()> {
         let gl = gl::GL.borrow(self.marker);
         let gl = gl.as_ref().unwrap();
 
@@ -107,21 +109,47 @@ impl OpenGl {
                 0,
             )
         )?;
-        check!(
-            gl,
-            gl.BlitFramebuffer(
-                0,
-                0,
-                self.width,
-                self.height,
-                0,
-                0,
-                self.width,
-                self.height,
-                gl::COLOR_BUFFER_BIT,
-                gl::NEAREST,
-            )
-        )?;
+
+        if source_width == self.width && source_height == self.height {
+            check!(
+                gl,
+                gl.BlitFramebuffer(
+                    0,
+                    0,
+                    self.width,
+                    self.height,
+                    0,
+                    0,
+                    self.width,
+                    self.height,
+                    gl::COLOR_BUFFER_BIT,
+                    gl::NEAREST,
+                )
+            )?;
+        } else {
+            // Black bars around the scaled frame.
+            check!(
+                gl,
+                gl.ClearBufferfv(gl::COLOR, 0, [0., 0., 0., 1.].as_ptr())
+            )?;
+
+            let viewport = letterbox(source_width, source_height, self.width, self.height);
+            check!(
+                gl,
+                gl.BlitFramebuffer(
+                    0,
+                    0,
+                    source_width,
+                    source_height,
+                    viewport.x,
+                    viewport.y,
+                    viewport.x + viewport.width,
+                    viewport.y + viewport.height,
+                    gl::COLOR_BUFFER_BIT,
+                    gl::LINEAR,
+                )
+            )?;
+        }
 
         // Restore bound framebuffer.
         check!(
@@ -265,7 +293,13 @@ pub unsafe fn capture_with_read_pixels(
     // HL leaves some GL errors behind.
     reset_gl_error(gl);
 
-    check!(
+    // The default pack alignment of 4 pads BGR rows whose width isn't a multiple of 4, which
+    // would write past the end of the tightly packed buffer.
+    let mut alignment = 0;
+    gl.GetIntegerv(gl::PACK_ALIGNMENT, &mut alignment);
+    gl.PixelStorei(gl::PACK_ALIGNMENT, 1);
+
+    let result = check!(
         gl,
         gl.ReadPixels(
             0,
@@ -276,9 +310,11 @@ pub unsafe fn capture_with_read_pixels(
             gl::UNSIGNED_BYTE,
             buf.as_mut_ptr().cast()
         )
-    )?;
+    );
+
+    gl.PixelStorei(gl::PACK_ALIGNMENT, alignment);
 
-    Ok(())
+    result
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
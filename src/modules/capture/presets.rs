@@ -0,0 +1,232 @@
+//! Encoding presets.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::Deserialize;
+use thiserror::Error;
+
+/// Name of the file with user-defined presets, relative to the Half-Life folder.
+pub const PRESETS_FILENAME: &str = "bxt-rs-capture-presets.json";
+
+/// Output extensions that FFmpeg can encode into.
+pub const VIDEO_EXTENSIONS: &[&str] = &["mp4", "mkv", "mov", "webm", "avi"];
+
+/// FFmpeg encoding settings.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Preset {
+    /// FFmpeg video codec, e.g. `libx264`.
+    pub codec: String,
+
+    /// Constant rate factor.
+    #[serde(default)]
+    pub crf: Option<u32>,
+
+    /// Video bitrate, e.g. `50M`.
+    #[serde(default)]
+    pub bitrate: Option<String>,
+
+    /// Output pixel format, e.g. `yuv420p`.
+    #[serde(default)]
+    pub pixel_format: Option<String>,
+
+    /// FFmpeg audio codec, e.g. `aac`.
+    #[serde(default)]
+    pub audio_codec: Option<String>,
+
+    /// Extension of the output file, e.g. `mp4`.
+    pub container: String,
+
+    /// Any other FFmpeg output arguments.
+    #[serde(default)]
+    pub extra_args: Vec<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum PresetError {
+    #[error("could not read {PRESETS_FILENAME}: {0}")]
+    Read(io::Error),
+    #[error("could not parse {PRESETS_FILENAME}: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("unknown preset \"{0}\"")]
+    Unknown(String),
+    #[error("preset \"{name}\" has unsupported container \"{container}\"")]
+    Container { name: String, container: String },
+}
+
+impl Preset {
+    fn new(codec: &str, container: &str) -> Self {
+        Self {
+            codec: codec.to_string(),
+            crf: None,
+            bitrate: None,
+            pixel_format: None,
+            audio_codec: None,
+            container: container.to_string(),
+            extra_args: Vec::new(),
+        }
+    }
+
+    /// Returns the FFmpeg output arguments for this preset.
+    pub fn ffmpeg_args(&self) -> Vec<String> {
+        let mut args = vec!["-c:v".to_string(), self.codec.clone()];
+
+        if let Some(crf) = self.crf {
+            args.extend(["-crf".to_string(), crf.to_string()]);
+        }
+
+        if let Some(bitrate) = &self.bitrate {
+            args.extend(["-b:v".to_string(), bitrate.clone()]);
+        }
+
+        if let Some(pixel_format) = &self.pixel_format {
+            args.extend(["-pix_fmt".to_string(), pixel_format.clone()]);
+        }
+
+        if let Some(audio_codec) = &self.audio_codec {
+            args.extend(["-c:a".to_string(), audio_codec.clone()]);
+        }
+
+        // The game renders narrow-range BT.709.
+        #[rustfmt::skip]
+        args.extend([
+            "-color_primaries", "bt709",
+            "-color_trc", "bt709",
+            "-colorspace", "bt709",
+            "-color_range", "tv",
+            "-chroma_sample_location", "center",
+        ].map(String::from));
+
+        args.extend(self.extra_args.iter().cloned());
+        args
+    }
+}
+
+/// Returns the built-in presets.
+pub fn builtin() -> BTreeMap<String, Preset> {
+    let mut presets = BTreeMap::new();
+
+    // FFmpeg would pick 4:2:0 for the RGB frames, subsampling the chroma, so ask for 4:4:4. QP 0
+    // is lossless for any bit depth, unlike CRF 0.
+    presets.insert(
+        "lossless".to_string(),
+        Preset {
+            pixel_format: Some("yuv444p".to_string()),
+            extra_args: vec![
+                "-qp".to_string(),
+                "0".to_string(),
+                "-preset".to_string(),
+                "ultrafast".to_string(),
+            ],
+            ..Preset::new("libx264", "mkv")
+        },
+    );
+    presets.insert(
+        "youtube".to_string(),
+        Preset {
+            crf: Some(18),
+            pixel_format: Some("yuv420p".to_string()),
+            audio_codec: Some("aac".to_string()),
+            extra_args: vec!["-preset".to_string(), "slow".to_string()],
+            ..Preset::new("libx264", "mp4")
+        },
+    );
+    presets.insert(
+        "hevc".to_string(),
+        Preset {
+            crf: Some(20),
+            extra_args: vec![
+                "-preset".to_string(),
+                "fast".to_string(),
+                "-tag:v".to_string(),
+                "hvc1".to_string(),
+            ],
+            ..Preset::new("libx265", "mp4")
+        },
+    );
+    presets.insert(
+        "prores".to_string(),
+        Preset {
+            pixel_format: Some("yuv422p10le".to_string()),
+            audio_codec: Some("pcm_s16le".to_string()),
+            extra_args: vec!["-profile:v".to_string(), "3".to_string()],
+            ..Preset::new("prores_ks", "mov")
+        },
+    );
+
+    presets
+}
+
+/// Parses presets from JSON, an object mapping preset names to presets.
+pub fn parse(json: &str) -> Result<BTreeMap<String, Preset>, PresetError> {
+    Ok(serde_json::from_str(json)?)
+}
+
+/// Finds the preset with the given name.
+///
+/// Presets from [`PRESETS_FILENAME`] override the built-in ones.
+pub fn find(name: &str) -> Result<Preset, PresetError> {
+    let mut presets = builtin();
+
+    match fs::read_to_string(Path::new(PRESETS_FILENAME)) {
+        Ok(json) => presets.extend(parse(&json)?),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+        Err(err) => return Err(PresetError::Read(err)),
+    }
+
+    let preset = presets
+        .remove(name)
+        .ok_or_else(|| PresetError::Unknown(name.to_string()))?;
+
+    if !VIDEO_EXTENSIONS.contains(&preset.container.as_str()) {
+        return Err(PresetError::Container {
+            name: name.to_string(),
+            container: preset.container,
+        });
+    }
+
+    Ok(preset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_containers_are_supported() {
+        for preset in builtin().values() {
+            assert!(VIDEO_EXTENSIONS.contains(&preset.container.as_str()));
+        }
+    }
+
+    #[test]
+    fn parse_preset() {
+        let presets = parse(
+            r#"{
+                "small": {
+                    "codec": "libx264",
+                    "bitrate": "2M",
+                    "container": "mp4",
+                    "extra_args": ["-tune", "film"]
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let preset = &presets["small"];
+        assert_eq!(preset.bitrate.as_deref(), Some("2M"));
+        assert_eq!(preset.crf, None);
+
+        let args = preset.ffmpeg_args();
+        assert_eq!(&args[..4], ["-c:v", "libx264", "-b:v", "2M"]);
+        assert_eq!(&args[args.len() - 2..], ["-tune", "film"]);
+    }
+
+    #[test]
+    fn parse_unknown_field() {
+        assert!(parse(r#"{ "x": { "codec": "a", "container": "mp4", "crff": 1 } }"#).is_err());
+    }
+}
@@ -1,11 +1,18 @@
+use std::path::{Path, PathBuf};
 use std::thread::{self, JoinHandle};
 
 use color_eyre::eyre::{self, ensure, eyre, Context};
 use crossbeam_channel::{bounded, Receiver, Sender};
 
-use super::muxer::{Muxer, MuxerInitError, PixelFormat};
+use super::metadata::{FrameMetadata, MetadataWriter};
+use super::muxer::{AudioFormat, Muxer, MuxerInitError, PixelFormat};
 use super::opengl::{self, OpenGl, Uuids};
+use super::resample::{downmix_to_mono, Resampler};
+use super::sampling::Sampler;
+use super::scale;
+use super::segments::{SegmentLimits, Segments};
 use super::vulkan::{self, ExternalHandles, Vulkan};
+use super::wav::WavWriter;
 use super::SoundCaptureMode;
 use crate::utils::*;
 
@@ -16,18 +23,37 @@ pub struct Recorder {
     /// Video height.
     height: i32,
 
+    /// Current game resolution, which is scaled to the video size if it differs.
+    source_width: i32,
+    source_height: i32,
+
     /// The target time base.
     time_base: f64,
 
     /// The slowdown factor. For example, `2` means two times slower.
     slowdown: f64,
 
-    /// Difference, in video frames, between how much time passed in-game and how much video we
-    /// output.
+    /// Number of captured sub-frames blended into every video frame.
+    sampling_multiplier: u64,
+
+    /// Format of the audio captured from the game's paint buffer, which is always stereo.
+    audio_format: AudioFormat,
+
+    /// When to split the output into a new file.
+    segment_limits: SegmentLimits,
+
+    /// Difference, in video sub-frames, between how much time passed in-game and how much video
+    /// we output.
     video_remainder: f64,
 
-    /// Difference, in seconds, between how much time passed in-game and how much audio we output.
-    sound_remainder: f64,
+    /// How much sound to capture.
+    sound_timer: SoundTimer,
+
+    /// Whether the recording is paused.
+    paused: bool,
+
+    /// Whether the recording was resumed and no frame was captured since.
+    resumed: bool,
 
     /// OpenGL state; might be missing if the capturing just started or just after an engine
     /// restart.
@@ -56,6 +82,21 @@ pub struct Recorder {
 
     /// Buffer for capturing with ReadPixels.
     buffer: Option<Box<[u8]>>,
+
+    /// Buffer for capturing with ReadPixels at a different resolution before scaling.
+    source_buffer: Vec<u8>,
+
+    /// Path of the uncompressed NUT file if we're writing it without FFmpeg.
+    raw_output_path: Option<PathBuf>,
+
+    /// Writer for the per-frame metadata sidecar.
+    metadata_writer: Option<MetadataWriter>,
+
+    /// Metadata of the last captured frame.
+    last_metadata: FrameMetadata,
+
+    /// Number of video sub-frames recorded so far.
+    recorded_sub_frames: u64,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -72,6 +113,7 @@ enum MainToThread {
     Record { frames: usize },
     Mux { pixels: Box<[u8]>, frames: usize },
     Audio(Vec<u8>),
+    NextSegment,
 }
 
 #[derive(Debug)]
@@ -84,16 +126,30 @@ enum ThreadToMain {
 }
 
 impl Recorder {
+    #[allow(clippy::too_many_arguments)]
     #[instrument(name = "Recorder::init")]
     pub unsafe fn init(
         width: i32,
         height: i32,
         fps: u64,
         slowdown: f64,
+        sampling_multiplier: u64,
+        sampling_exposure: f32,
+        audio_format: AudioFormat,
+        output_audio_format: AudioFormat,
+        segment_limits: SegmentLimits,
+        metadata_writer: Option<MetadataWriter>,
+        audio_track_path: Option<&Path>,
         mut capture_type: CaptureType,
         filename: &str,
         custom_ffmpeg_args: Option<&[&str]>,
     ) -> eyre::Result<Recorder> {
+        // Sub-frame blending is only implemented for glReadPixels frames.
+        ensure!(
+            sampling_multiplier == 1 || capture_type == CaptureType::ReadPixels,
+            "sampling requires the glReadPixels capture",
+        );
+
         ensure!(
             width % 2 == 0 && height % 2 == 0,
             "can't handle odd game resolutions yet: {}×{}",
@@ -123,15 +179,33 @@ impl Recorder {
             PixelFormat::Rgb24Flipped
         };
 
-        let muxer = match Muxer::new(
-            width as u64,
-            height as u64,
-            fps,
-            pixel_format,
-            filename,
-            custom_ffmpeg_args,
-        ) {
-            Ok(muxer) => muxer,
+        let muxer = if segment_limits.is_enabled() {
+            Segments::new(
+                segment_limits,
+                width as u64,
+                height as u64,
+                fps,
+                pixel_format,
+                output_audio_format,
+                filename,
+                custom_ffmpeg_args,
+            )
+            .map(|(segments, muxer)| (Some(segments), muxer))
+        } else {
+            Muxer::new(
+                width as u64,
+                height as u64,
+                fps,
+                pixel_format,
+                output_audio_format,
+                filename,
+                custom_ffmpeg_args,
+            )
+            .map(|muxer| (None, muxer))
+        };
+
+        let (segments, muxer) = match muxer {
+            Ok(x) => x,
             Err(err @ MuxerInitError::FfmpegSpawn(_)) => {
                 return Err(err).wrap_err(
                     #[cfg(unix)]
@@ -147,20 +221,69 @@ impl Recorder {
             }
         };
 
+        let raw_output_path = muxer.raw_output_path().map(PathBuf::from);
+
+        let sampler = if sampling_multiplier > 1 {
+            Some(Sampler::new(
+                width as usize * height as usize * 3,
+                sampling_multiplier as usize,
+                sampling_exposure,
+            ))
+        } else {
+            None
+        };
+
+        let downmix = output_audio_format.channels == 1 && audio_format.channels == 2;
+        let resampler = if output_audio_format.sample_rate != audio_format.sample_rate {
+            Some(Resampler::new(
+                output_audio_format.channels as usize,
+                audio_format.sample_rate,
+                output_audio_format.sample_rate,
+            ))
+        } else {
+            None
+        };
+
+        let wav = match audio_track_path {
+            Some(path) => Some(
+                WavWriter::create(path, output_audio_format)
+                    .wrap_err("error creating the audio track")?,
+            ),
+            None => None,
+        };
+
         let (to_thread_sender, from_main_receiver) = bounded(2);
         let (to_main_sender, from_thread_receiver) = bounded(2);
         let thread = thread::Builder::new()
             .name("Recording Thread".to_string())
-            .spawn(move || thread(vulkan, muxer, to_main_sender, from_main_receiver))
+            .spawn(move || {
+                let state = ThreadState {
+                    vulkan,
+                    muxer,
+                    segments,
+                    sampler,
+                    downmix,
+                    resampler,
+                    wav,
+                };
+                thread(state, to_main_sender, from_main_receiver)
+            })
             .unwrap();
 
         Ok(Recorder {
             width,
             height,
+            source_width: width,
+            source_height: height,
             time_base,
             slowdown,
+            sampling_multiplier,
+            audio_format,
+            segment_limits,
             video_remainder: 0.,
-            sound_remainder: 0.,
+            sound_timer: SoundTimer::default(),
+            paused: false,
+            resumed: false,
             opengl: None,
             acquired_image: false,
             thread,
@@ -170,6 +293,11 @@ impl Recorder {
             ffmpeg_output: None,
             capture_type,
             buffer: Some(vec![0u8; width as usize * height as usize * 3].into()),
+            source_buffer: Vec::new(),
+            raw_output_path,
+            metadata_writer,
+            last_metadata: FrameMetadata::default(),
+            recorded_sub_frames: 0,
         })
     }
 
@@ -231,7 +359,10 @@ impl Recorder {
                     self.initialize_opengl_capturing(marker)?;
                 }
 
-                self.opengl.as_ref().unwrap().capture()
+                self.opengl
+                    .as_ref()
+                    .unwrap()
+                    .capture(self.source_width, self.source_height)
             }
             CaptureType::ReadPixels => {
                 if self.buffer.is_none() {
@@ -243,13 +374,38 @@ impl Recorder {
                     }
                 }
 
+                if self.source_width == self.width && self.source_height == self.height {
+                    return opengl::capture_with_read_pixels(
+                        marker,
+                        self.width,
+                        self.height,
+                        self.buffer.as_mut().unwrap(),
+                    )
+                    .wrap_err("error capturing with glReadPixels");
+                }
+
+                self.source_buffer.resize(
+                    self.source_width as usize * self.source_height as usize * 3,
+                    0,
+                );
                 opengl::capture_with_read_pixels(
                     marker,
+                    self.source_width,
+                    self.source_height,
+                    &mut self.source_buffer,
+                )
+                .wrap_err("error capturing with glReadPixels")?;
+
+                scale::scale_rgb24(
+                    &self.source_buffer,
+                    self.source_width,
+                    self.source_height,
+                    self.buffer.as_mut().unwrap(),
                     self.width,
                     self.height,
-                    self.buffer.as_mut().unwrap(),
-                )
-                .wrap_err("error capturing with glReadPixels")
+                );
+
+                Ok(())
             }
         }
     }
@@ -306,14 +462,84 @@ impl Recorder {
 
         if frames > 0 {
             self.record(frames)?;
+            self.write_metadata(frames)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sets the metadata of the last captured frame.
+    pub fn set_last_frame_metadata(&mut self, metadata: FrameMetadata) {
+        self.last_metadata = metadata;
+    }
+
+    /// Writes the metadata for every video frame finished by `frames` recorded sub-frames.
+    fn write_metadata(&mut self, frames: usize) -> eyre::Result<()> {
+        let first = self.recorded_sub_frames / self.sampling_multiplier;
+        self.recorded_sub_frames += frames as u64;
+        let last = self.recorded_sub_frames / self.sampling_multiplier;
+
+        if let Some(writer) = &mut self.metadata_writer {
+            for frame in first..last {
+                writer
+                    .write(frame, &self.last_metadata)
+                    .wrap_err("error writing frame metadata")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Pauses the recording, keeping the output open.
+    pub unsafe fn pause(&mut self) -> eyre::Result<()> {
+        if self.paused {
+            return Ok(());
         }
 
+        // Record the last frame for as long as it was shown before pausing.
+        if !self.resumed {
+            self.record_last_frame()?;
+        }
+
+        self.paused = true;
+        self.resumed = false;
+        self.sound_timer.pause();
+
         Ok(())
     }
 
+    /// Resumes the recording.
+    pub fn resume(&mut self) {
+        if !self.paused {
+            return;
+        }
+
+        self.paused = false;
+        self.resumed = true;
+    }
+
+    /// Returns `true` if the in-game time is currently not being recorded.
+    ///
+    /// This is also the case right after resuming, until the first frame is captured.
+    pub fn is_paused(&self) -> bool {
+        self.paused || self.resumed
+    }
+
+    /// Returns `true` if the recording was resumed and clears the flag.
+    ///
+    /// The first frame after resuming is only captured, since there's no previous frame to record.
+    pub fn take_resumed(&mut self) -> bool {
+        let resumed = std::mem::take(&mut self.resumed);
+        if resumed {
+            self.sound_timer.resume();
+        }
+        resumed
+    }
+
     pub fn time_passed(&mut self, time: f64) {
-        self.video_remainder += time / self.time_base * self.slowdown;
-        self.sound_remainder += time * self.slowdown;
+        self.video_remainder +=
+            time / self.time_base * self.sampling_multiplier as f64 * self.slowdown;
+        self.sound_timer.time_passed(time * self.slowdown);
 
         if let CaptureType::Vulkan(_) = self.capture_type {
             unsafe {
@@ -323,26 +549,28 @@ impl Recorder {
     }
 
     pub fn samples_to_capture(&mut self, samples_per_second: i32, mode: SoundCaptureMode) -> i32 {
-        let samples = self.sound_remainder * samples_per_second as f64;
-        let samples_rounded = match mode {
-            SoundCaptureMode::Normal => samples.floor(),
-            SoundCaptureMode::Remaining { extra } => {
-                (samples + extra as f64 * samples_per_second as f64).ceil()
-            }
-        };
-
-        self.sound_remainder = (samples - samples_rounded) / samples_per_second as f64;
-
-        samples_rounded as i32
+        self.sound_timer
+            .samples_to_capture(samples_per_second, mode)
     }
 
     #[instrument(name = "Recorder::write_audio_frame", skip_all)]
     pub fn write_audio_frame(&mut self, samples: Vec<u8>) {
+        // The game keeps mixing sound while paused, but there's no video for it.
+        if self.is_paused() {
+            return;
+        }
+
         self.send_to_thread(MainToThread::Audio(samples));
     }
 
     #[instrument(name = "Recorder::finish", skip_all)]
     pub fn finish(mut self) -> Option<String> {
+        if let Some(mut writer) = self.metadata_writer.take() {
+            if let Err(err) = writer.flush() {
+                error!("error writing frame metadata: {:?}", err);
+            }
+        }
+
         self.send_to_thread(MainToThread::Finish);
 
         while let Ok(message) = self.receiver.recv() {
@@ -376,23 +604,141 @@ impl Recorder {
         self.height
     }
 
+    pub fn source_resolution(&self) -> (i32, i32) {
+        (self.source_width, self.source_height)
+    }
+
+    /// Sets the game resolution that captured frames are scaled from.
+    ///
+    /// Unlike the video size, the game resolution doesn't need to be even as frames of a different
+    /// size are scaled.
+    pub fn set_source_resolution(&mut self, width: i32, height: i32) -> eyre::Result<()> {
+        ensure!(
+            width > 0 && height > 0,
+            "invalid game resolution: {}×{}",
+            width,
+            height,
+        );
+
+        self.source_width = width;
+        self.source_height = height;
+        Ok(())
+    }
+
+    /// Returns the index of the video frame that is currently being recorded.
+    pub fn video_frame(&self) -> u64 {
+        self.recorded_sub_frames / self.sampling_multiplier
+    }
+
+    /// Starts a new output file if splitting on map changes is enabled.
+    pub fn on_map_change(&mut self) {
+        if self.segment_limits.on_map_change {
+            self.send_to_thread(MainToThread::NextSegment);
+        }
+    }
+
+    pub fn audio_format(&self) -> AudioFormat {
+        self.audio_format
+    }
+
     pub fn frame_time(&self) -> f64 {
-        self.time_base / self.slowdown
+        self.time_base / self.slowdown / self.sampling_multiplier as f64
     }
 
     pub fn capture_type(&self) -> &CaptureType {
         &self.capture_type
     }
+
+    pub fn raw_output_path(&self) -> Option<&Path> {
+        self.raw_output_path.as_deref()
+    }
+}
+
+/// Writes audio into the output and the separate audio track.
+fn write_audio(
+    muxer: &mut Muxer,
+    segments: Option<&mut Segments>,
+    wav: Option<&mut WavWriter>,
+    samples: &[u8],
+) -> eyre::Result<()> {
+    match segments {
+        Some(segments) => segments.write_audio(muxer, samples)?,
+        None => muxer.write_audio_frame(samples)?,
+    }
+
+    if let Some(wav) = wav {
+        wav.write(samples)
+            .wrap_err("error writing the audio track")?;
+    }
+
+    Ok(())
+}
+
+/// Tracks how much sound to capture.
+#[derive(Debug, Default)]
+struct SoundTimer {
+    /// Difference, in seconds, between how much time passed in-game and how much audio we output.
+    remainder: f64,
+
+    /// Whether the recording is paused, in which case no sound is captured.
+    paused: bool,
 }
 
-fn thread(
+impl SoundTimer {
+    fn time_passed(&mut self, time: f64) {
+        if !self.paused {
+            self.remainder += time;
+        }
+    }
+
+    fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    /// Resumes capturing sound.
+    ///
+    /// The time from before the pause that wasn't captured yet is dropped, so it doesn't end up
+    /// after the pause.
+    fn resume(&mut self) {
+        self.paused = false;
+        self.remainder = 0.;
+    }
+
+    fn samples_to_capture(&mut self, samples_per_second: i32, mode: SoundCaptureMode) -> i32 {
+        if self.paused {
+            return 0;
+        }
+
+        let samples = self.remainder * samples_per_second as f64;
+        let samples_rounded = match mode {
+            SoundCaptureMode::Normal => samples.floor(),
+            SoundCaptureMode::Remaining { extra } => {
+                (samples + extra as f64 * samples_per_second as f64).ceil()
+            }
+        };
+
+        self.remainder = (samples - samples_rounded) / samples_per_second as f64;
+
+        samples_rounded as i32
+    }
+}
+
+/// State owned by the recording thread.
+struct ThreadState {
     vulkan: Option<Vulkan>,
-    mut muxer: Muxer,
-    s: Sender<ThreadToMain>,
-    r: Receiver<MainToThread>,
-) {
+    muxer: Muxer,
+    segments: Option<Segments>,
+    sampler: Option<Sampler>,
+    /// Whether to downmix the captured stereo audio to mono.
+    downmix: bool,
+    resampler: Option<Resampler>,
+    /// Separate audio track.
+    wav: Option<WavWriter>,
+}
+
+fn thread(mut state: ThreadState, s: Sender<ThreadToMain>, r: Receiver<MainToThread>) {
     while let Ok(message) = r.recv() {
-        match process_message(vulkan.as_ref(), &mut muxer, &s, message) {
+        match process_message(&mut state, &s, message) {
             Ok(done) => {
                 if done {
                     break;
@@ -405,41 +751,84 @@ fn thread(
         }
     }
 
-    let output = muxer.close();
+    if let Some(wav) = state.wav {
+        if let Err(err) = wav.finish() {
+            error!("error writing the audio track: {:?}", err);
+        }
+    }
+
+    let output = state.muxer.close();
     s.send(ThreadToMain::FfmpegOutput(output)).unwrap();
 }
 
 fn process_message(
-    vulkan: Option<&Vulkan>,
-    muxer: &mut Muxer,
+    state: &mut ThreadState,
     s: &Sender<ThreadToMain>,
     message: MainToThread,
 ) -> eyre::Result<bool> {
+    let ThreadState {
+        vulkan,
+        muxer,
+        segments,
+        sampler,
+        downmix,
+        resampler,
+        wav,
+    } = state;
+
     match message {
         MainToThread::Finish => {
+            // The resampler holds back some audio for filtering.
+            let samples = resampler.as_mut().map(|resampler| resampler.finish());
+            if let Some(samples) = samples.filter(|samples| !samples.is_empty()) {
+                write_audio(muxer, segments.as_mut(), wav.as_mut(), &samples)?;
+            }
+
+            if let Some(segments) = segments {
+                segments.finish(muxer)?;
+            }
+
+            muxer.flush()?;
             return Ok(true);
         }
         MainToThread::GiveExternalHandles => {
-            let handles = vulkan.unwrap().external_handles()?;
+            let handles = vulkan.as_ref().unwrap().external_handles()?;
             s.send(ThreadToMain::ExternalHandles(handles)).unwrap();
         }
         MainToThread::AcquireImage => {
             let _span = info_span!("acquire").entered();
 
-            unsafe { vulkan.unwrap().acquire_image() }?;
+            unsafe { vulkan.as_ref().unwrap().acquire_image() }?;
 
             s.send(ThreadToMain::AcquiredImage).unwrap();
         }
         MainToThread::Record { frames } => {
             let _span = info_span!("record").entered();
 
-            unsafe { vulkan.unwrap().convert_colors_and_mux(muxer, frames) }?;
+            if let Some(segments) = segments {
+                segments.before_video(muxer)?;
+            }
+
+            unsafe {
+                vulkan
+                    .as_ref()
+                    .unwrap()
+                    .convert_colors_and_mux(muxer, frames)
+            }?;
         }
         MainToThread::Mux { pixels, frames } => {
             let _span = info_span!("mux").entered();
 
-            for _ in 0..frames {
-                muxer.write_video_frame(&pixels)?;
+            if let Some(segments) = segments {
+                segments.before_video(muxer)?;
+            }
+
+            if let Some(sampler) = sampler {
+                sampler.add(&pixels, frames, |data| muxer.write_video_frame(data))?;
+            } else {
+                for _ in 0..frames {
+                    muxer.write_video_frame(&pixels)?;
+                }
             }
 
             s.send(ThreadToMain::Muxed(pixels)).unwrap();
@@ -447,9 +836,59 @@ fn process_message(
         MainToThread::Audio(samples) => {
             let _span = info_span!("audio").entered();
 
-            muxer.write_audio_frame(&samples)?;
+            let samples = if *downmix {
+                downmix_to_mono(&samples)
+            } else {
+                samples
+            };
+            let samples = match resampler {
+                Some(resampler) => resampler.process(&samples),
+                None => samples,
+            };
+
+            write_audio(muxer, segments.as_mut(), wav.as_mut(), &samples)?;
+        }
+        MainToThread::NextSegment => {
+            if let Some(segments) = segments {
+                segments.request_next();
+            }
         }
     }
 
     Ok(false)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sound_timer() {
+        let mut timer = SoundTimer::default();
+        timer.time_passed(0.5);
+        assert_eq!(timer.samples_to_capture(100, SoundCaptureMode::Normal), 50);
+    }
+
+    #[test]
+    fn sound_timer_paused() {
+        let mut timer = SoundTimer::default();
+        timer.time_passed(0.005);
+
+        timer.pause();
+        timer.time_passed(1.);
+        assert_eq!(timer.samples_to_capture(100, SoundCaptureMode::Normal), 0);
+        assert_eq!(
+            timer.samples_to_capture(100, SoundCaptureMode::Remaining { extra: 1. }),
+            0
+        );
+
+        // Neither the time during the pause nor the leftover from before it is captured.
+        timer.resume();
+        assert_eq!(
+            timer.samples_to_capture(100, SoundCaptureMode::Remaining { extra: 0. }),
+            0
+        );
+        timer.time_passed(0.25);
+        assert_eq!(timer.samples_to_capture(100, SoundCaptureMode::Normal), 25);
+    }
+}
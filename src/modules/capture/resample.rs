@@ -0,0 +1,310 @@
+//! Audio resampling and downmixing.
+
+use std::f64::consts::PI;
+
+/// Number of taps of the low-pass filter applied before downsampling.
+const LOW_PASS_TAPS: usize = 65;
+
+/// Linear-interpolation resampler for interleaved little-endian 16-bit audio.
+///
+/// When downsampling, the input is low-pass filtered below the output Nyquist frequency first so
+/// that higher frequencies don't alias.
+pub struct Resampler {
+    /// Number of interleaved channels.
+    channels: usize,
+
+    /// Input samples per output sample.
+    step: f64,
+
+    /// Position of the next output sample in input samples, where 0 is `last` and 1 is the first
+    /// sample of the next chunk.
+    position: f64,
+
+    /// Last input sample of the previous chunk.
+    last: Vec<i16>,
+
+    /// Anti-aliasing filter, only used when downsampling.
+    low_pass: Option<LowPass>,
+}
+
+impl Resampler {
+    /// Creates a new [`Resampler`] from `input_rate` to `output_rate`.
+    pub fn new(channels: usize, input_rate: u64, output_rate: u64) -> Self {
+        assert!(channels > 0);
+        assert!(input_rate > 0);
+        assert!(output_rate > 0);
+
+        let step = input_rate as f64 / output_rate as f64;
+
+        // Cut off a bit below the output Nyquist frequency to leave room for the transition band.
+        let low_pass = if output_rate < input_rate {
+            Some(LowPass::new(channels, 0.45 / step))
+        } else {
+            None
+        };
+
+        Self {
+            channels,
+            step,
+            position: 1.,
+            last: vec![0; channels],
+            low_pass,
+        }
+    }
+
+    /// Resamples a chunk of audio.
+    pub fn process(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut input: Vec<i16> = data
+            .chunks_exact(2)
+            .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]))
+            .collect();
+        input.truncate(input.len() / self.channels * self.channels);
+
+        if let Some(low_pass) = &mut self.low_pass {
+            input = low_pass.process(&input);
+        }
+
+        self.interpolate(&input)
+    }
+
+    /// Resamples the audio still held back by the filter, to be called after the last chunk.
+    pub fn finish(&mut self) -> Vec<u8> {
+        match &mut self.low_pass {
+            Some(low_pass) => {
+                let input = low_pass.finish();
+                self.interpolate(&input)
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Interpolates whole interleaved samples to the output rate.
+    fn interpolate(&mut self, input: &[i16]) -> Vec<u8> {
+        let count = input.len() / self.channels;
+
+        let sample = |index: usize, channel: usize| {
+            if index == 0 {
+                self.last[channel]
+            } else {
+                input[(index - 1) * self.channels + channel]
+            }
+        };
+
+        let mut output = Vec::new();
+        while self.position <= count as f64 {
+            let index = self.position as usize;
+            let frac = self.position - index as f64;
+
+            for channel in 0..self.channels {
+                let a = sample(index, channel) as f64;
+                let b = if frac > 0. {
+                    sample(index + 1, channel) as f64
+                } else {
+                    a
+                };
+                let value = (a + (b - a) * frac).round() as i16;
+                output.extend_from_slice(&value.to_le_bytes());
+            }
+
+            self.position += self.step;
+        }
+
+        if count > 0 {
+            self.last
+                .copy_from_slice(&input[(count - 1) * self.channels..count * self.channels]);
+            self.position -= count as f64;
+        }
+
+        output
+    }
+}
+
+/// Windowed-sinc low-pass filter for interleaved 16-bit audio.
+///
+/// The output isn't delayed: every output sample is centered on the input sample at the same
+/// position. For that, the filter starts with half a window of silence and holds back the last
+/// half a window of input until the following samples arrive or [`LowPass::finish()`] is called.
+struct LowPass {
+    /// Number of interleaved channels.
+    channels: usize,
+
+    /// Filter coefficients, symmetric and summing to 1.
+    taps: Vec<f64>,
+
+    /// Input samples that aren't in the middle of a full window yet, preceded by up to
+    /// `taps.len() / 2` already filtered ones, interleaved.
+    history: Vec<i16>,
+}
+
+impl LowPass {
+    /// Creates a new [`LowPass`] with the `cutoff` frequency in cycles per sample.
+    fn new(channels: usize, cutoff: f64) -> Self {
+        let n = LOW_PASS_TAPS;
+        let middle = (n - 1) as f64 / 2.;
+
+        let mut taps: Vec<f64> = (0..n)
+            .map(|i| {
+                let m = i as f64 - middle;
+                let sinc = if m == 0. {
+                    2. * cutoff
+                } else {
+                    (2. * PI * cutoff * m).sin() / (PI * m)
+                };
+
+                // Blackman window.
+                let x = 2. * PI * i as f64 / (n - 1) as f64;
+                let window = 0.42 - 0.5 * x.cos() + 0.08 * (2. * x).cos();
+
+                sinc * window
+            })
+            .collect();
+
+        // Unity gain at DC.
+        let sum: f64 = taps.iter().sum();
+        for tap in &mut taps {
+            *tap /= sum;
+        }
+
+        Self {
+            channels,
+            taps,
+            history: vec![0; n / 2 * channels],
+        }
+    }
+
+    /// Filters a chunk of whole interleaved samples.
+    ///
+    /// Returns the samples that have a full window of input around them.
+    fn process(&mut self, input: &[i16]) -> Vec<i16> {
+        let mut samples = std::mem::take(&mut self.history);
+        samples.extend_from_slice(input);
+
+        let frames = samples.len() / self.channels;
+        let output_frames = frames.saturating_sub(self.taps.len() - 1);
+
+        let output = (0..output_frames * self.channels)
+            .map(|i| {
+                let value: f64 = self
+                    .taps
+                    .iter()
+                    .enumerate()
+                    .map(|(k, tap)| tap * samples[i + k * self.channels] as f64)
+                    .sum();
+                value.round().clamp(-32768., 32767.) as i16
+            })
+            .collect();
+
+        self.history = samples.split_off(output_frames * self.channels);
+        output
+    }
+
+    /// Filters the held back samples, padding the input with silence.
+    fn finish(&mut self) -> Vec<i16> {
+        self.process(&vec![0; self.taps.len() / 2 * self.channels])
+    }
+}
+
+/// Downmixes interleaved little-endian 16-bit stereo audio to mono.
+pub fn downmix_to_mono(data: &[u8]) -> Vec<u8> {
+    data.chunks_exact(4)
+        .flat_map(|bytes| {
+            let l16 = i16::from_le_bytes([bytes[0], bytes[1]]);
+            let r16 = i16::from_le_bytes([bytes[2], bytes[3]]);
+            let m16 = ((l16 as i32 + r16 as i32) / 2) as i16;
+            m16.to_le_bytes()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_bytes(samples: &[i16]) -> Vec<u8> {
+        samples.iter().flat_map(|x| x.to_le_bytes()).collect()
+    }
+
+    fn from_bytes(data: &[u8]) -> Vec<i16> {
+        data.chunks_exact(2)
+            .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]))
+            .collect()
+    }
+
+    #[test]
+    fn resampler_same_rate() {
+        let mut resampler = Resampler::new(2, 22050, 22050);
+        let data = to_bytes(&[1, 2, 3, 4, 5, 6]);
+        assert_eq!(resampler.process(&data), data);
+        assert_eq!(resampler.process(&data), data);
+    }
+
+    #[test]
+    fn resampler_upsample() {
+        let mut resampler = Resampler::new(1, 22050, 44100);
+        assert_eq!(
+            resampler.process(&to_bytes(&[100, 200])),
+            to_bytes(&[100, 150, 200])
+        );
+        assert_eq!(resampler.process(&to_bytes(&[300])), to_bytes(&[250, 300]));
+    }
+
+    #[test]
+    fn resampler_downsample_keeps_low_frequencies() {
+        let mut resampler = Resampler::new(2, 44100, 22050);
+        let input: Vec<i16> = [1000, -500].repeat(200);
+
+        let mut output = from_bytes(&resampler.process(&to_bytes(&input)));
+        output.extend(from_bytes(&resampler.finish()));
+        assert_eq!(output.len(), 200);
+
+        // Away from the silence around the ends, the constant signal comes through unchanged.
+        let pairs: Vec<_> = output.chunks_exact(2).collect();
+        for pair in &pairs[LOW_PASS_TAPS / 2..pairs.len() - LOW_PASS_TAPS / 2] {
+            assert_eq!(pair, &[1000, -500]);
+        }
+    }
+
+    #[test]
+    fn resampler_downsample_filters_out_high_frequencies() {
+        let mut resampler = Resampler::new(1, 44100, 22050);
+
+        // Split across chunks to check that the filter state carries over.
+        let tone: Vec<i16> = [10000, -10000].repeat(100);
+        let mut output = from_bytes(&resampler.process(&to_bytes(&tone)));
+        output.extend(from_bytes(&resampler.process(&to_bytes(&tone))));
+        output.extend(from_bytes(&resampler.finish()));
+        assert_eq!(output.len(), 200);
+
+        // Without filtering, every output sample would land on 10000.
+        for &sample in &output[LOW_PASS_TAPS / 2..output.len() - LOW_PASS_TAPS / 2] {
+            assert!(sample.abs() < 10, "{}", sample);
+        }
+    }
+
+    #[test]
+    fn resampler_downsample_without_delay() {
+        let mut resampler = Resampler::new(1, 44100, 22050);
+        let mut input = vec![0; 100];
+        input[40] = 10000;
+
+        // Feed the input in small chunks, so most of it is held back at some point.
+        let mut output = Vec::new();
+        for chunk in input.chunks(7) {
+            output.extend(from_bytes(&resampler.process(&to_bytes(chunk))));
+        }
+        output.extend(from_bytes(&resampler.finish()));
+        assert_eq!(output.len(), 50);
+
+        // The impulse stays at the same time.
+        let peak = (0..output.len()).max_by_key(|&i| output[i]).unwrap();
+        assert_eq!(peak, 20);
+    }
+
+    #[test]
+    fn downmix() {
+        assert_eq!(
+            downmix_to_mono(&to_bytes(&[100, 300, -32768, -32768, 1, 2])),
+            to_bytes(&[200, -32768, 1])
+        );
+    }
+}
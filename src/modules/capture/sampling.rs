@@ -0,0 +1,131 @@
+//! Sub-frame accumulation for motion blur.
+
+/// Blends several captured frames into one output frame.
+pub struct Sampler {
+    /// Number of captured sub-frames per output frame.
+    multiplier: usize,
+
+    /// Number of sub-frames at the end of every output frame which are blended together.
+    exposed: usize,
+
+    /// Sums of the exposed sub-frame pixel values of the current output frame.
+    sums: Vec<u32>,
+
+    /// Number of sub-frames accumulated into the current output frame.
+    position: usize,
+
+    /// Buffer for the blended output frame.
+    output: Vec<u8>,
+}
+
+impl Sampler {
+    /// Creates a new [`Sampler`] for frames of `len` bytes.
+    ///
+    /// Every output frame consists of `multiplier` sub-frames. `exposure` from 0 to 1 specifies the
+    /// fraction of the output frame duration which is blended, akin to a camera shutter.
+    pub fn new(len: usize, multiplier: usize, exposure: f32) -> Self {
+        assert!(multiplier > 0);
+
+        let exposed = ((multiplier as f32 * exposure).round() as usize).clamp(1, multiplier);
+
+        Self {
+            multiplier,
+            exposed,
+            sums: vec![0; len],
+            position: 0,
+            output: vec![0; len],
+        }
+    }
+
+    /// Adds `frames` sub-frames of `pixels`, calling `write` for every finished output frame.
+    pub fn add<E>(
+        &mut self,
+        pixels: &[u8],
+        mut frames: usize,
+        mut write: impl FnMut(&[u8]) -> Result<(), E>,
+    ) -> Result<(), E> {
+        assert_eq!(pixels.len(), self.sums.len());
+
+        while frames > 0 {
+            // The entire output frame is the same image, so there's nothing to blend.
+            if self.position == 0 && frames >= self.multiplier {
+                write(pixels)?;
+                frames -= self.multiplier;
+                continue;
+            }
+
+            let count = frames.min(self.multiplier - self.position);
+
+            // Only the sub-frames within [exposed_start, multiplier) contribute.
+            let exposed_start = self.multiplier - self.exposed;
+            let exposed_count =
+                (self.position + count).saturating_sub(self.position.max(exposed_start));
+            if exposed_count > 0 {
+                for (sum, &pixel) in self.sums.iter_mut().zip(pixels) {
+                    *sum += pixel as u32 * exposed_count as u32;
+                }
+            }
+
+            self.position += count;
+            frames -= count;
+
+            if self.position == self.multiplier {
+                let exposed = self.exposed as u32;
+                for (output, sum) in self.output.iter_mut().zip(&mut self.sums) {
+                    *output = ((*sum + exposed / 2) / exposed) as u8;
+                    *sum = 0;
+                }
+
+                self.position = 0;
+                write(&self.output)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collect(sampler: &mut Sampler, pixels: &[u8], frames: usize) -> Vec<Vec<u8>> {
+        let mut output = Vec::new();
+        sampler
+            .add::<()>(pixels, frames, |data| {
+                output.push(data.to_vec());
+                Ok(())
+            })
+            .unwrap();
+        output
+    }
+
+    #[test]
+    fn sampler_passthrough() {
+        let mut sampler = Sampler::new(1, 1, 1.);
+        assert_eq!(collect(&mut sampler, &[10], 2), [[10], [10]]);
+    }
+
+    #[test]
+    fn sampler_blends() {
+        let mut sampler = Sampler::new(1, 2, 1.);
+        assert!(collect(&mut sampler, &[0], 1).is_empty());
+        assert_eq!(collect(&mut sampler, &[100], 1), [[50]]);
+    }
+
+    #[test]
+    fn sampler_whole_frames() {
+        let mut sampler = Sampler::new(1, 2, 1.);
+        assert!(collect(&mut sampler, &[0], 1).is_empty());
+        assert_eq!(collect(&mut sampler, &[100], 4), [[50], [100]]);
+        assert_eq!(collect(&mut sampler, &[0], 1), [[50]]);
+    }
+
+    #[test]
+    fn sampler_exposure() {
+        let mut sampler = Sampler::new(1, 4, 0.5);
+        assert!(collect(&mut sampler, &[200], 2).is_empty());
+        assert!(collect(&mut sampler, &[0], 1).is_empty());
+        assert_eq!(collect(&mut sampler, &[100], 1), [[50]]);
+    }
+}
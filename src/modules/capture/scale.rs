@@ -0,0 +1,152 @@
+//! Fitting frames of a different resolution into the video.
+
+/// Part of the video frame that the game frame is drawn into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Viewport {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+/// Returns the largest centered viewport of the source aspect ratio that fits into the target.
+pub fn letterbox(
+    source_width: i32,
+    source_height: i32,
+    target_width: i32,
+    target_height: i32,
+) -> Viewport {
+    let source_width = source_width.max(1) as i64;
+    let source_height = source_height.max(1) as i64;
+
+    // Compare source_width / source_height with target_width / target_height.
+    let (width, height) =
+        if source_width * target_height as i64 > target_width as i64 * source_height {
+            // Wider than the target: black bars at the top and bottom.
+            let height = (target_width as i64 * source_height + source_width / 2) / source_width;
+            (target_width, height.max(1) as i32)
+        } else {
+            // Taller than the target: black bars on the sides.
+            let width = (target_height as i64 * source_width + source_height / 2) / source_height;
+            (width.max(1) as i32, target_height)
+        };
+
+    Viewport {
+        x: (target_width - width) / 2,
+        y: (target_height - height) / 2,
+        width,
+        height,
+    }
+}
+
+/// Scales an RGB24 frame with bilinear filtering, letterboxing it into the target frame.
+pub fn scale_rgb24(
+    source: &[u8],
+    source_width: i32,
+    source_height: i32,
+    target: &mut [u8],
+    target_width: i32,
+    target_height: i32,
+) {
+    assert_eq!(
+        source.len(),
+        source_width as usize * source_height as usize * 3
+    );
+    assert_eq!(
+        target.len(),
+        target_width as usize * target_height as usize * 3
+    );
+
+    target.fill(0);
+
+    if source.is_empty() {
+        return;
+    }
+
+    let viewport = letterbox(source_width, source_height, target_width, target_height);
+    let scale_x = source_width as f32 / viewport.width.max(1) as f32;
+    let scale_y = source_height as f32 / viewport.height.max(1) as f32;
+
+    // Returns the two source indices to blend and the weight of the second one.
+    let sample = |target: i32, scale: f32, size: i32| {
+        let position = ((target as f32 + 0.5) * scale - 0.5).clamp(0., (size - 1) as f32);
+        let first = position as usize;
+        let second = (first + 1).min(size as usize - 1);
+        (first, second, position - first as f32)
+    };
+
+    for y in 0..viewport.height {
+        let (y0, y1, fy) = sample(y, scale_y, source_height);
+        let row0 = &source[y0 * source_width as usize * 3..][..source_width as usize * 3];
+        let row1 = &source[y1 * source_width as usize * 3..][..source_width as usize * 3];
+
+        let target_row = (viewport.y + y) as usize * target_width as usize + viewport.x as usize;
+        let target_row = &mut target[target_row * 3..][..viewport.width as usize * 3];
+
+        for (x, pixel) in target_row.chunks_exact_mut(3).enumerate() {
+            let (x0, x1, fx) = sample(x as i32, scale_x, source_width);
+
+            for (c, value) in pixel.iter_mut().enumerate() {
+                let top = row0[x0 * 3 + c] as f32 * (1. - fx) + row0[x1 * 3 + c] as f32 * fx;
+                let bottom = row1[x0 * 3 + c] as f32 * (1. - fx) + row1[x1 * 3 + c] as f32 * fx;
+                *value = (top * (1. - fy) + bottom * fy).round() as u8;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn letterbox_same_aspect() {
+        assert_eq!(
+            letterbox(640, 480, 1280, 960),
+            Viewport {
+                x: 0,
+                y: 0,
+                width: 1280,
+                height: 960
+            }
+        );
+    }
+
+    #[test]
+    fn letterbox_bars() {
+        assert_eq!(
+            letterbox(1920, 1080, 640, 480),
+            Viewport {
+                x: 0,
+                y: 60,
+                width: 640,
+                height: 360
+            }
+        );
+        assert_eq!(
+            letterbox(640, 480, 1920, 1080),
+            Viewport {
+                x: 240,
+                y: 0,
+                width: 1440,
+                height: 1080
+            }
+        );
+    }
+
+    #[test]
+    fn scale_rgb24_pillarbox() {
+        // A 1×1 white frame into a 3×1 frame.
+        let mut target = [1; 9];
+        scale_rgb24(&[255; 3], 1, 1, &mut target, 3, 1);
+        assert_eq!(target, [0, 0, 0, 255, 255, 255, 0, 0, 0]);
+    }
+
+    #[test]
+    fn scale_rgb24_downscale() {
+        let source = [0, 0, 0, 100, 100, 100, 200, 200, 200, 255, 255, 255];
+        let mut target = [0; 6];
+        scale_rgb24(&source, 4, 1, &mut target, 2, 1);
+        assert_eq!(target, [50, 50, 50, 228, 228, 228]);
+    }
+}
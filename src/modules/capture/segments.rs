@@ -0,0 +1,458 @@
+//! Splitting the output into several files.
+
+use std::mem;
+use std::path::Path;
+
+use color_eyre::eyre::{self, Context};
+
+use super::muxer::{AudioFormat, Muxer, MuxerInitError, PixelFormat};
+
+/// When to start a new output file.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SegmentLimits {
+    /// Maximum duration of a file in seconds, or 0 for no limit.
+    pub duration: f64,
+
+    /// Maximum size of a file in bytes, or 0 for no limit.
+    pub size: u64,
+
+    /// Whether to start a new file on every map change.
+    pub on_map_change: bool,
+}
+
+impl SegmentLimits {
+    pub fn is_enabled(&self) -> bool {
+        self.duration > 0. || self.size > 0 || self.on_map_change
+    }
+}
+
+/// Returns the filename of the segment with the given index.
+///
+/// For example, segment 1 of `output.mp4` is `output-001.mp4`.
+pub fn segment_filename(filename: &str, index: usize) -> String {
+    let path = Path::new(filename);
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{:03}.{}", stem, index, extension.to_string_lossy()),
+        None => format!("{}-{:03}", stem, index),
+    };
+
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+/// Tracks where segments end in the video and audio streams.
+///
+/// A segment ends on a video frame boundary. Audio that arrives after the end was requested is
+/// held back until video reaches that point, then split between the two segments so that the
+/// previous segment's audio lasts exactly as long as its video. If audio is behind, it keeps going
+/// into the previous segment until it catches up. Positions are counted from the start of the
+/// recording, so rounding to whole samples doesn't accumulate across segments.
+#[derive(Debug)]
+struct Splitter {
+    fps: u64,
+    sample_rate: u64,
+
+    /// Number of video frames in the finished segments.
+    video_frames: u64,
+
+    /// Number of audio samples written into segments so far.
+    audio_samples: u64,
+
+    /// Number of audio samples held back until the next segment starts.
+    held_samples: u64,
+
+    /// Audio sample where the previous segment ends, if its audio is incomplete.
+    previous_audio_end: Option<u64>,
+
+    /// Whether the current segment should end before the next video frame.
+    split_requested: bool,
+}
+
+/// Where audio samples go.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct AudioSplit {
+    /// Number of samples for the previous segment.
+    previous: u64,
+    /// Number of samples for the current segment.
+    current: u64,
+}
+
+impl Splitter {
+    fn new(fps: u64, sample_rate: u64) -> Self {
+        Self {
+            fps,
+            sample_rate,
+            video_frames: 0,
+            audio_samples: 0,
+            held_samples: 0,
+            previous_audio_end: None,
+            split_requested: false,
+        }
+    }
+
+    /// Returns the number of audio samples that last as long as `video_frames` video frames.
+    fn audio_samples_at(&self, video_frames: u64) -> u64 {
+        (video_frames * self.sample_rate + self.fps / 2) / self.fps
+    }
+
+    /// Returns how many of `samples` go into the previous segment before it ends.
+    fn fill_previous(&mut self, samples: u64) -> u64 {
+        let end = match self.previous_audio_end {
+            Some(end) => end,
+            None => return 0,
+        };
+
+        let previous = (end - self.audio_samples).min(samples);
+        self.audio_samples += previous;
+        if self.audio_samples == end {
+            self.previous_audio_end = None;
+        }
+
+        previous
+    }
+
+    /// Adds `samples` new audio samples and returns where they go. Samples that go nowhere are
+    /// held back.
+    fn add_audio(&mut self, samples: u64) -> AudioSplit {
+        let previous = self.fill_previous(samples);
+        let rest = samples - previous;
+
+        if self.split_requested {
+            self.held_samples += rest;
+            return AudioSplit {
+                previous,
+                current: 0,
+            };
+        }
+
+        self.audio_samples += rest;
+        AudioSplit {
+            previous,
+            current: rest,
+        }
+    }
+
+    /// Returns where the held audio samples go if the next video frame starts a new segment.
+    ///
+    /// `segment_video_frames` is the number of video frames in the current segment. The current
+    /// segment only ends once the audio of the one before it is complete and once the audio
+    /// written so far is not past the current video frame boundary.
+    fn split_before_video(&mut self, segment_video_frames: u64) -> Option<AudioSplit> {
+        if !self.split_requested || self.previous_audio_end.is_some() {
+            return None;
+        }
+
+        let video_frames = self.video_frames + segment_video_frames;
+        let audio_end = self.audio_samples_at(video_frames);
+        if self.audio_samples > audio_end {
+            return None;
+        }
+
+        self.video_frames = video_frames;
+        self.split_requested = false;
+        self.previous_audio_end = Some(audio_end).filter(|&end| end > self.audio_samples);
+
+        let held = mem::take(&mut self.held_samples);
+        let previous = self.fill_previous(held);
+        self.audio_samples += held - previous;
+
+        Some(AudioSplit {
+            previous,
+            current: held - previous,
+        })
+    }
+
+    /// Stops holding back audio, returning the number of held samples.
+    fn release_held(&mut self) -> u64 {
+        let held = mem::take(&mut self.held_samples);
+        self.audio_samples += held;
+        self.split_requested = false;
+        held
+    }
+}
+
+/// Segmented output state of the recording thread.
+pub struct Segments {
+    limits: SegmentLimits,
+
+    /// Filename from the user which is numbered for every segment.
+    filename: String,
+
+    /// Index of the current segment.
+    index: usize,
+
+    splitter: Splitter,
+
+    /// Muxer of the previous segment while it's still receiving audio.
+    previous: Option<Muxer>,
+
+    /// Audio held back until the next segment starts.
+    held_audio: Vec<u8>,
+
+    width: u64,
+    height: u64,
+    fps: u64,
+    pixel_format: PixelFormat,
+    audio_format: AudioFormat,
+    custom_ffmpeg_args: Option<Vec<String>>,
+}
+
+impl Segments {
+    /// Creates a new [`Segments`] and the [`Muxer`] for the first segment.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        limits: SegmentLimits,
+        width: u64,
+        height: u64,
+        fps: u64,
+        pixel_format: PixelFormat,
+        audio_format: AudioFormat,
+        filename: &str,
+        custom_ffmpeg_args: Option<&[&str]>,
+    ) -> Result<(Self, Muxer), MuxerInitError> {
+        let segments = Self {
+            limits,
+            filename: filename.to_string(),
+            index: 1,
+            splitter: Splitter::new(fps, audio_format.sample_rate),
+            previous: None,
+            held_audio: Vec::new(),
+            width,
+            height,
+            fps,
+            pixel_format,
+            audio_format,
+            custom_ffmpeg_args: custom_ffmpeg_args
+                .map(|args| args.iter().map(|arg| arg.to_string()).collect()),
+        };
+
+        let muxer = segments.create_muxer()?;
+        Ok((segments, muxer))
+    }
+
+    fn create_muxer(&self) -> Result<Muxer, MuxerInitError> {
+        let custom_ffmpeg_args: Option<Vec<&str>> = self
+            .custom_ffmpeg_args
+            .as_ref()
+            .map(|args| args.iter().map(String::as_str).collect());
+
+        Muxer::new(
+            self.width,
+            self.height,
+            self.fps,
+            self.pixel_format,
+            self.audio_format,
+            &segment_filename(&self.filename, self.index),
+            custom_ffmpeg_args.as_deref(),
+        )
+    }
+
+    /// Returns `true` if the current segment has reached its duration or size limit.
+    fn is_full(&self, muxer: &Muxer) -> bool {
+        if self.limits.duration > 0.
+            && muxer.video_frames() as f64 >= self.limits.duration * self.fps as f64
+        {
+            return true;
+        }
+
+        self.limits.size > 0 && muxer.output_size() >= self.limits.size
+    }
+
+    /// Ends the current segment before the next video frame.
+    pub fn request_next(&mut self) {
+        self.splitter.split_requested = true;
+    }
+
+    /// Starts the next segment if needed before video frames are written into `muxer`.
+    pub fn before_video(&mut self, muxer: &mut Muxer) -> eyre::Result<()> {
+        if self.is_full(muxer) {
+            self.request_next();
+        }
+
+        let split = match self.splitter.split_before_video(muxer.video_frames()) {
+            Some(split) => split,
+            None => return Ok(()),
+        };
+
+        self.index += 1;
+        let new_muxer = self
+            .create_muxer()
+            .wrap_err("error starting the next segment")?;
+        let mut previous = mem::replace(muxer, new_muxer);
+
+        let held = mem::take(&mut self.held_audio);
+        let (previous_data, data) = held.split_at(self.sample_bytes(split.previous));
+        write_audio(&mut previous, previous_data)?;
+        write_audio(muxer, data)?;
+
+        if self.splitter.previous_audio_end.is_some() {
+            self.previous = Some(previous);
+        } else {
+            finish_segment(previous)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes audio samples into the segments they belong to.
+    pub fn write_audio(&mut self, muxer: &mut Muxer, data: &[u8]) -> eyre::Result<()> {
+        let split = self
+            .splitter
+            .add_audio((data.len() / self.sample_bytes(1)) as u64);
+        let (previous_data, data) = data.split_at(self.sample_bytes(split.previous));
+        let (data, held) = data.split_at(self.sample_bytes(split.current));
+
+        if let Some(previous) = &mut self.previous {
+            write_audio(previous, previous_data)?;
+
+            if self.splitter.previous_audio_end.is_none() {
+                finish_segment(self.previous.take().unwrap())?;
+            }
+        }
+
+        write_audio(muxer, data)?;
+        self.held_audio.extend_from_slice(held);
+
+        Ok(())
+    }
+
+    /// Finishes the previous segment and writes any held audio into `muxer`.
+    pub fn finish(&mut self, muxer: &mut Muxer) -> eyre::Result<()> {
+        if let Some(previous) = self.previous.take() {
+            finish_segment(previous)?;
+        }
+
+        self.splitter.release_held();
+        write_audio(muxer, &mem::take(&mut self.held_audio))?;
+
+        Ok(())
+    }
+
+    /// Returns the size in bytes of `samples` audio samples.
+    fn sample_bytes(&self, samples: u64) -> usize {
+        (samples * 2 * self.audio_format.channels) as usize
+    }
+}
+
+fn write_audio(muxer: &mut Muxer, data: &[u8]) -> eyre::Result<()> {
+    if !data.is_empty() {
+        muxer.write_audio_frame(data)?;
+    }
+
+    Ok(())
+}
+
+/// Flushes and closes the muxer of a finished segment.
+fn finish_segment(mut muxer: Muxer) -> eyre::Result<()> {
+    muxer.flush()?;
+
+    let output = muxer.close();
+    let output = output.trim();
+    if !output.is_empty() {
+        warn!("FFmpeg output:\n{}", output);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segment_filename_numbers() {
+        assert_eq!(segment_filename("output.mp4", 1), "output-001.mp4");
+        assert_eq!(segment_filename("output.mp4", 1234), "output-1234.mp4");
+    }
+
+    #[test]
+    fn segment_filename_no_extension() {
+        assert_eq!(segment_filename("output", 2), "output-002");
+    }
+
+    #[test]
+    fn segment_filename_directory() {
+        assert_eq!(
+            segment_filename("videos/run.mp4", 3),
+            Path::new("videos").join("run-003.mp4").to_string_lossy()
+        );
+    }
+
+    /// Returns the video frames and audio samples of every segment when audio arrives in chunks
+    /// `lead` seconds ahead of video and a new segment is requested every `segment_frames`
+    /// frames.
+    fn split(lead: f64, segment_frames: u64) -> Vec<(u64, u64)> {
+        const FPS: u64 = 3;
+        const SAMPLE_RATE: u64 = 1000;
+        const CHUNK: u64 = 250;
+
+        let mut splitter = Splitter::new(FPS, SAMPLE_RATE);
+        let mut segments = vec![(0, 0)];
+        let add = |segments: &mut Vec<(u64, u64)>, split: AudioSplit| {
+            let len = segments.len();
+            if split.previous > 0 {
+                segments[len - 2].1 += split.previous;
+            }
+            segments[len - 1].1 += split.current;
+        };
+
+        let mut chunks = 0;
+        for frame in 0..40 {
+            let time = frame as f64 / FPS as f64;
+            while (chunks * CHUNK) as f64 / SAMPLE_RATE as f64 <= time + lead {
+                chunks += 1;
+                add(&mut segments, splitter.add_audio(CHUNK));
+            }
+
+            let segment_video_frames = segments.last().unwrap().0;
+            if segment_video_frames >= segment_frames {
+                splitter.split_requested = true;
+            }
+
+            if let Some(split) = splitter.split_before_video(segment_video_frames) {
+                segments.push((0, 0));
+                add(&mut segments, split);
+            }
+
+            segments.last_mut().unwrap().0 += 1;
+        }
+
+        // Let the audio of the previous segment complete.
+        while splitter.previous_audio_end.is_some() {
+            add(&mut segments, splitter.add_audio(CHUNK));
+        }
+
+        segments
+    }
+
+    #[test]
+    fn segment_audio_matches_video_duration() {
+        for lead in [0., 0.1, 0.5, 2.] {
+            let segments = split(lead, 4);
+            assert!(segments.len() > 2, "lead {lead}: {segments:?}");
+
+            let splitter = Splitter::new(3, 1000);
+            let mut video_frames = 0;
+
+            // The last segment is still being recorded.
+            for &(video, audio) in &segments[..segments.len() - 1] {
+                let start = splitter.audio_samples_at(video_frames);
+                video_frames += video;
+                let end = splitter.audio_samples_at(video_frames);
+                assert_eq!(audio, end - start, "lead {lead}: {segments:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn audio_samples_at_rounds_to_nearest() {
+        let splitter = Splitter::new(3, 1000);
+        assert_eq!(splitter.audio_samples_at(1), 333);
+        assert_eq!(splitter.audio_samples_at(2), 667);
+        assert_eq!(splitter.audio_samples_at(3), 1000);
+    }
+}
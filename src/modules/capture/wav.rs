@@ -0,0 +1,166 @@
+//! WAV file output.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::muxer::AudioFormat;
+
+/// Size of the header in front of the audio data.
+const HEADER_LEN: u64 = 80;
+
+/// Writes interleaved little-endian 16-bit audio into a WAV file.
+///
+/// Files with more than 4 GiB of audio are written as RF64. The header reserves space for the
+/// RF64 size chunk with a JUNK chunk, which is turned into the size chunk in
+/// [`WavWriter::finish()`] if needed.
+pub struct WavWriter<W: Write + Seek = BufWriter<File>> {
+    writer: W,
+    block_align: u16,
+    /// Number of bytes of audio data written so far.
+    data_len: u64,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path, format: AudioFormat) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?), format)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut writer: W, format: AudioFormat) -> io::Result<Self> {
+        let channels = format.channels as u16;
+        let sample_rate = format.sample_rate as u32;
+        let block_align = channels * 2;
+
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&0u32.to_le_bytes())?; // Patched in finish().
+        writer.write_all(b"WAVE")?;
+
+        // Space for the ds64 chunk of RF64.
+        writer.write_all(b"JUNK")?;
+        writer.write_all(&28u32.to_le_bytes())?;
+        writer.write_all(&[0; 28])?;
+
+        writer.write_all(b"fmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;
+        writer.write_all(&1u16.to_le_bytes())?; // PCM
+        writer.write_all(&channels.to_le_bytes())?;
+        writer.write_all(&sample_rate.to_le_bytes())?;
+        writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?; // byte rate
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&16u16.to_le_bytes())?; // bits per sample
+
+        writer.write_all(b"data")?;
+        writer.write_all(&0u32.to_le_bytes())?; // Patched in finish().
+
+        Ok(Self {
+            writer,
+            block_align,
+            data_len: 0,
+        })
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.writer.write_all(data)?;
+        self.data_len += data.len() as u64;
+        Ok(())
+    }
+
+    /// Writes the final chunk sizes and returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        let riff_len = HEADER_LEN - 8 + self.data_len;
+
+        match u32::try_from(riff_len) {
+            Ok(riff_len) => {
+                self.writer.seek(SeekFrom::Start(4))?;
+                self.writer.write_all(&riff_len.to_le_bytes())?;
+                self.writer.seek(SeekFrom::Start(HEADER_LEN - 4))?;
+                self.writer
+                    .write_all(&(self.data_len as u32).to_le_bytes())?;
+            }
+            Err(_) => {
+                // The sizes don't fit, write an RF64 file with the sizes in the ds64 chunk.
+                self.writer.seek(SeekFrom::Start(0))?;
+                self.writer.write_all(b"RF64")?;
+                self.writer.write_all(&u32::MAX.to_le_bytes())?;
+                self.writer.seek(SeekFrom::Start(12))?;
+                self.writer.write_all(b"ds64")?;
+                self.writer.write_all(&28u32.to_le_bytes())?;
+                self.writer.write_all(&riff_len.to_le_bytes())?;
+                self.writer.write_all(&self.data_len.to_le_bytes())?;
+                let sample_count = self.data_len / u64::from(self.block_align);
+                self.writer.write_all(&sample_count.to_le_bytes())?;
+                self.writer.write_all(&0u32.to_le_bytes())?; // No table of other chunk sizes.
+                self.writer.seek(SeekFrom::Start(HEADER_LEN - 4))?;
+                self.writer.write_all(&u32::MAX.to_le_bytes())?;
+            }
+        }
+
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn wav_header() {
+        let format = AudioFormat {
+            sample_rate: 44100,
+            channels: 2,
+        };
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), format).unwrap();
+        wav.write(&[1, 2, 3, 4]).unwrap();
+        wav.write(&[5, 6, 7, 8]).unwrap();
+        let data = wav.finish().unwrap().into_inner();
+
+        assert_eq!(data.len(), 80 + 8);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(data[4..8].try_into().unwrap()), 80);
+        assert_eq!(&data[12..16], b"JUNK");
+        assert_eq!(&data[48..52], b"fmt ");
+        assert_eq!(u16::from_le_bytes(data[58..60].try_into().unwrap()), 2);
+        assert_eq!(u32::from_le_bytes(data[60..64].try_into().unwrap()), 44100);
+        assert_eq!(u32::from_le_bytes(data[64..68].try_into().unwrap()), 176400);
+        assert_eq!(&data[72..76], b"data");
+        assert_eq!(u32::from_le_bytes(data[76..80].try_into().unwrap()), 8);
+        assert_eq!(&data[80..], &[1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn rf64_header() {
+        let format = AudioFormat {
+            sample_rate: 44100,
+            channels: 2,
+        };
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), format).unwrap();
+        wav.write(&[1, 2, 3, 4]).unwrap();
+
+        // Pretend that more than 4 GiB have been written.
+        let data_len = 5 << 30;
+        wav.data_len = data_len;
+        let data = wav.finish().unwrap().into_inner();
+
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+        assert_eq!(&data[0..4], b"RF64");
+        assert_eq!(u32_at(4), u32::MAX);
+        assert_eq!(&data[12..16], b"ds64");
+        assert_eq!(u32_at(16), 28);
+        assert_eq!(u64_at(20), data_len + 72);
+        assert_eq!(u64_at(28), data_len);
+        assert_eq!(u64_at(36), data_len / 4);
+        assert_eq!(u32_at(44), 0);
+        assert_eq!(&data[48..52], b"fmt ");
+        assert_eq!(u32_at(76), u32::MAX);
+        assert_eq!(&data[80..], &[1, 2, 3, 4]);
+    }
+}
@@ -1,5 +1,6 @@
 //! Playing multiple demos at once.
 
+use std::collections::hash_map::{Entry, HashMap};
 use std::ffi::{CStr, OsStr};
 use std::path::{Path, PathBuf};
 
@@ -7,6 +8,7 @@ use byte_slice_cast::AsSliceOf;
 
 use super::commands::Command;
 use super::Module;
+use crate::demo::{self, Header};
 use crate::handler;
 use crate::hooks::engine::{self, con_print, prepend_command};
 use crate::utils::*;
@@ -18,7 +20,17 @@ impl Module for DemoPlayback {
     }
 
     fn commands(&self) -> &'static [&'static Command] {
-        static COMMANDS: &[&Command] = &[&BXT_PLAY_RUN];
+        static COMMANDS: &[&Command] = &[
+            &BXT_PLAY_RUN,
+            &BXT_PLAY_RUN_GOTO,
+            &BXT_PLAY_RUN_NEXT,
+            &BXT_PLAY_RUN_PREV,
+            &BXT_PLAY_RUN_SEEK,
+            &BXT_PLAY_RUN_SPEED,
+            &BXT_PLAY_RUN_PAUSE,
+            &BXT_PLAY_RUN_STATUS,
+            &BXT_DEMO_INFO,
+        ];
         COMMANDS
     }
 
@@ -30,49 +42,199 @@ impl Module for DemoPlayback {
     }
 }
 
+/// Demos of the queued run in order, with trailing null-bytes.
 static DEMOS: MainThreadRefCell<Vec<Vec<u8>>> = MainThreadRefCell::new(Vec::new());
+/// Index of the demo in the first `cls.demos` entry.
+static QUEUED_DEMO: MainThreadCell<Option<usize>> = MainThreadCell::new(None);
+/// Index of the demo that is playing.
+static CURRENT_DEMO: MainThreadCell<Option<usize>> = MainThreadCell::new(None);
+/// Playback time of the current demo in seconds, not counting the loading.
+static DEMO_TIME: MainThreadCell<f64> = MainThreadCell::new(0.);
+static SEEK: MainThreadCell<Seek> = MainThreadCell::new(Seek::NONE);
+static SPEED: MainThreadCell<f64> = MainThreadCell::new(1.);
+static PAUSED: MainThreadCell<bool> = MainThreadCell::new(false);
+/// Whether the client is disconnecting to play another demo of the run.
+static SWITCHING_DEMO: MainThreadCell<bool> = MainThreadCell::new(false);
+
+/// Playback times to fast forward to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Seek {
+    /// Playback time to fast forward the current demo to.
+    target: Option<f64>,
+    /// Playback time to fast forward to once the demo restarts.
+    pending: Option<f64>,
+}
+
+impl Seek {
+    const NONE: Self = Self {
+        target: None,
+        pending: None,
+    };
+
+    /// Seeks to `time`, after the demo restarts if `restart` is `true`.
+    fn seek(self, time: f64, restart: bool) -> Self {
+        if restart {
+            Self {
+                target: None,
+                pending: Some(time),
+            }
+        } else {
+            Self {
+                target: Some(time),
+                pending: None,
+            }
+        }
+    }
+
+    /// Returns the state for a demo that has just started.
+    fn demo_started(self) -> Self {
+        Self {
+            target: self.pending,
+            pending: None,
+        }
+    }
+
+    /// Returns the state after the client has stopped playing the current demo.
+    fn disconnected(self) -> Self {
+        Self {
+            target: None,
+            ..self
+        }
+    }
+}
+
+/// Largest frame time used for fast forwarding.
+const SEEK_FRAME_TIME: f64 = 0.1;
 
 static BXT_PLAY_RUN: Command = Command::new(
     b"bxt_play_run\0",
     handler!(
         "Usage: bxt_play_run <name>\n \
-          Plays back all name_N.dem demos in order.\n",
+          Plays back all name_N.dem demos in order.\n\n\
+          The demos are checked first: the numbers must have no gaps and all demos must be from \
+          the same game with the same version of every map.\n",
         play_run as fn(_, _)
     ),
 );
 
+static BXT_PLAY_RUN_GOTO: Command = Command::new(
+    b"bxt_play_run_goto\0",
+    handler!(
+        "Usage: bxt_play_run_goto <N>\n \
+          Starts playing the Nth demo of the run queued with bxt_play_run.\n",
+        play_run_goto as fn(_, _)
+    ),
+);
+
+static BXT_PLAY_RUN_NEXT: Command = Command::new(
+    b"bxt_play_run_next\0",
+    handler!(
+        "Usage: bxt_play_run_next\n \
+          Skips to the next demo of the run.\n",
+        play_run_next as fn(_)
+    ),
+);
+
+static BXT_PLAY_RUN_PREV: Command = Command::new(
+    b"bxt_play_run_prev\0",
+    handler!(
+        "Usage: bxt_play_run_prev\n \
+          Goes back to the previous demo of the run.\n",
+        play_run_prev as fn(_)
+    ),
+);
+
+static BXT_PLAY_RUN_SEEK: Command = Command::new(
+    b"bxt_play_run_seek\0",
+    handler!(
+        "Usage: bxt_play_run_seek <seconds>\n \
+          Fast forwards the current demo to the given playback time. Seeking backwards restarts \
+          the demo.\n",
+        play_run_seek as fn(_, _)
+    ),
+);
+
+static BXT_PLAY_RUN_SPEED: Command = Command::new(
+    b"bxt_play_run_speed\0",
+    handler!(
+        "Usage: bxt_play_run_speed <speed>\n \
+          Sets the demo playback speed, 1 is normal speed.\n",
+        play_run_speed as fn(_, _)
+    ),
+);
+
+static BXT_PLAY_RUN_PAUSE: Command = Command::new(
+    b"bxt_play_run_pause\0",
+    handler!(
+        "Usage: bxt_play_run_pause\n \
+          Pauses or resumes demo playback.\n",
+        play_run_pause as fn(_)
+    ),
+);
+
+static BXT_PLAY_RUN_STATUS: Command = Command::new(
+    b"bxt_play_run_status\0",
+    handler!(
+        "Usage: bxt_play_run_status\n \
+          Shows the current demo of the run and the playback time.\n",
+        play_run_status as fn(_)
+    ),
+);
+
+static BXT_DEMO_INFO: Command = Command::new(
+    b"bxt_demo_info\0",
+    handler!(
+        "Usage: bxt_demo_info <filename.dem>\n \
+          Prints the map, segments, duration and frame count of a demo.\n",
+        demo_info as fn(_, _)
+    ),
+);
+
+pub fn game_dir(marker: MainThreadMarker) -> PathBuf {
+    let game_dir = unsafe { CStr::from_ptr(engine::com_gamedir.get(marker).cast()) };
+    PathBuf::from(game_dir.to_str().unwrap())
+}
+
 fn play_run(marker: MainThreadMarker, prefix: PathBuf) {
+    queue_run(marker, prefix);
+}
+
+/// Starts playing back all `prefix_N.dem` demos in order, returns `false` on error.
+pub fn queue_run(marker: MainThreadMarker, prefix: PathBuf) -> bool {
     if !DemoPlayback.is_enabled(marker) {
-        return;
+        return false;
     }
 
-    let game_dir = Path::new(
-        unsafe { CStr::from_ptr(engine::com_gamedir.get(marker).cast()) }
-            .to_str()
-            .unwrap(),
-    );
-    let mut full_prefix: PathBuf = game_dir.into();
-    full_prefix.push(&prefix);
+    let game_dir = game_dir(marker);
+    let full_prefix = game_dir.join(prefix);
 
     let mut demos = DEMOS.borrow_mut(marker);
     demos.clear();
+    CURRENT_DEMO.set(marker, None);
+    PAUSED.set(marker, false);
+    SPEED.set(marker, 1.);
 
     let paths = match find_demos(full_prefix) {
         Ok(paths) => paths,
         Err(err) => {
             con_print(marker, &format!("Error: {}.\n", err));
-            return;
+            return false;
         }
     };
 
     if paths.is_empty() {
         con_print(marker, "Error: no demos found.\n");
-        return;
+        return false;
+    }
+
+    if let Err(err) = check_run(&paths) {
+        con_print(marker, &format!("Error: {}.\n", err));
+        return false;
     }
 
-    for (path, _) in paths.into_iter().rev() {
+    for (path, _) in paths {
         let mut path = path
-            .strip_prefix(game_dir)
+            .strip_prefix(&game_dir)
             .map(|path| path.into())
             .unwrap_or(path);
 
@@ -93,7 +255,7 @@ fn play_run(marker: MainThreadMarker, prefix: PathBuf) {
                 marker,
                 &format!("Error: filename {} is longer than 15 characters.\n", demo),
             );
-            return;
+            return false;
         }
 
         let mut demo = demo.into_bytes();
@@ -104,12 +266,148 @@ fn play_run(marker: MainThreadMarker, prefix: PathBuf) {
     con_print(marker, &format!("Playing {} demos.\n", demos.len()));
 
     drop(demos);
-    set_next_demo(marker);
+    play_demo(marker, 0);
+    true
+}
+
+/// Stops the current demo and starts playing the demo of the run at `index`.
+fn play_demo(marker: MainThreadMarker, index: usize) {
+    let count = DEMOS.borrow(marker).len();
+    if index >= count {
+        con_print(
+            marker,
+            &format!("Error: there are only {} demos in the run.\n", count),
+        );
+        return;
+    }
 
+    SEEK.set(marker, Seek::NONE);
+    SWITCHING_DEMO.set(marker, true);
+    queue_demo(marker, Some(index));
     prepend_command(marker, "demos\n");
 }
 
-fn find_demos(prefix: PathBuf) -> Result<Vec<(PathBuf, usize)>, String> {
+/// Returns the current demo and playback time for the console.
+fn position(marker: MainThreadMarker) -> String {
+    match CURRENT_DEMO.get(marker) {
+        Some(current) => format!(
+            "Demo {} of {}, {:.3} s\n",
+            current + 1,
+            DEMOS.borrow(marker).len(),
+            DEMO_TIME.get(marker)
+        ),
+        None => "No demo of the run is playing.\n".to_string(),
+    }
+}
+
+fn play_run_goto(marker: MainThreadMarker, number: usize) {
+    if !DemoPlayback.is_enabled(marker) {
+        return;
+    }
+
+    if number == 0 {
+        con_print(marker, "Error: demos are numbered from 1.\n");
+        return;
+    }
+
+    play_demo(marker, number - 1);
+}
+
+fn play_run_next(marker: MainThreadMarker) {
+    if !DemoPlayback.is_enabled(marker) {
+        return;
+    }
+
+    match CURRENT_DEMO.get(marker) {
+        Some(current) => play_demo(marker, current + 1),
+        None => con_print(marker, &position(marker)),
+    }
+}
+
+fn play_run_prev(marker: MainThreadMarker) {
+    if !DemoPlayback.is_enabled(marker) {
+        return;
+    }
+
+    match CURRENT_DEMO.get(marker) {
+        Some(0) => con_print(marker, "Error: this is the first demo of the run.\n"),
+        Some(current) => play_demo(marker, current - 1),
+        None => con_print(marker, &position(marker)),
+    }
+}
+
+fn play_run_seek(marker: MainThreadMarker, time: f64) {
+    if !DemoPlayback.is_enabled(marker) {
+        return;
+    }
+
+    if !engine::host_frametime.is_set(marker) || !engine::cls.is_set(marker) {
+        con_print(marker, "Error: seeking is not available.\n");
+        return;
+    }
+
+    let current = match CURRENT_DEMO.get(marker) {
+        Some(current) => current,
+        None => {
+            con_print(marker, &position(marker));
+            return;
+        }
+    };
+
+    // Demos can only be played forward.
+    let restart = time < DEMO_TIME.get(marker);
+    if restart {
+        play_demo(marker, current);
+    }
+
+    SEEK.set(marker, SEEK.get(marker).seek(time, restart));
+}
+
+fn play_run_speed(marker: MainThreadMarker, speed: f64) {
+    if !DemoPlayback.is_enabled(marker) {
+        return;
+    }
+
+    if !engine::host_frametime.is_set(marker) || !engine::cls.is_set(marker) {
+        con_print(marker, "Error: changing the speed is not available.\n");
+        return;
+    }
+
+    if !speed.is_finite() || speed <= 0. {
+        con_print(marker, "Error: the speed must be positive.\n");
+        return;
+    }
+
+    SPEED.set(marker, speed);
+}
+
+fn play_run_pause(marker: MainThreadMarker) {
+    if !DemoPlayback.is_enabled(marker) {
+        return;
+    }
+
+    if !engine::host_frametime.is_set(marker) || !engine::cls.is_set(marker) {
+        con_print(marker, "Error: pausing is not available.\n");
+        return;
+    }
+
+    let paused = !PAUSED.get(marker);
+    PAUSED.set(marker, paused);
+
+    con_print(marker, if paused { "Paused: " } else { "Resumed: " });
+    con_print(marker, &position(marker));
+}
+
+fn play_run_status(marker: MainThreadMarker) {
+    if !DemoPlayback.is_enabled(marker) {
+        return;
+    }
+
+    con_print(marker, &position(marker));
+}
+
+/// Returns all `prefix_N.dem` demos with their numbers, sorted by number.
+pub fn find_demos(prefix: PathBuf) -> Result<Vec<(PathBuf, usize)>, String> {
     let name_prefix = match prefix.file_name() {
         Some(prefix) => {
             format!(
@@ -143,14 +441,172 @@ fn find_demos(prefix: PathBuf) -> Result<Vec<(PathBuf, usize)>, String> {
     Ok(demos)
 }
 
+/// Checks that the demos of a run follow each other.
+fn check_run(paths: &[(PathBuf, usize)]) -> Result<(), String> {
+    let mut demos = Vec::with_capacity(paths.len());
+    for (path, number) in paths {
+        let header = demo::open_header(path)
+            .map_err(|err| format!("could not read {}: {}", path.to_string_lossy(), err))?;
+        demos.push((*number, header));
+    }
+
+    check_sequence(&demos)
+}
+
+/// Checks demo numbers for gaps and demo headers for mismatched games and maps.
+///
+/// `demos` must be sorted by number.
+fn check_sequence(demos: &[(usize, Header)]) -> Result<(), String> {
+    for pair in demos.windows(2) {
+        let (prev_number, prev) = &pair[0];
+        let (number, header) = &pair[1];
+
+        if number == prev_number {
+            return Err(format!("there are two demos with number {}", number));
+        }
+        if *number == prev_number + 2 {
+            return Err(format!("demo {} is missing", prev_number + 1));
+        }
+        if *number > prev_number + 2 {
+            return Err(format!(
+                "demos {} to {} are missing",
+                prev_number + 1,
+                number - 1
+            ));
+        }
+
+        if header.game_dir != prev.game_dir {
+            return Err(format!(
+                "demo {} is from {}, but demo {} is from {}",
+                number, header.game_dir, prev_number, prev.game_dir
+            ));
+        }
+    }
+
+    // A different CRC means that the map was changed between the demos.
+    let mut maps = HashMap::new();
+    for (number, header) in demos {
+        match maps.entry(header.map_name.to_ascii_lowercase()) {
+            Entry::Vacant(entry) => {
+                entry.insert((*number, header.map_crc));
+            }
+            Entry::Occupied(entry) => {
+                let (first_number, crc) = *entry.get();
+                if crc != header.map_crc {
+                    return Err(format!(
+                        "demo {} has a different version of {} than demo {}",
+                        number, header.map_name, first_number
+                    ));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn demo_info(marker: MainThreadMarker, path: PathBuf) {
+    if !DemoPlayback.is_enabled(marker) {
+        return;
+    }
+
+    let mut path = game_dir(marker).join(path);
+    if path.extension().is_none() {
+        path.set_extension("dem");
+    }
+
+    let demo = match demo::open(&path) {
+        Ok(demo) => demo,
+        Err(err) => {
+            con_print(marker, &format!("Error: {}.\n", err));
+            return;
+        }
+    };
+
+    let header = &demo.header;
+    let mut info = format!(
+        "Map: {} (CRC {})\nGame: {}\nProtocol: {}, network protocol: {}\n",
+        header.map_name,
+        header.map_crc,
+        header.game_dir,
+        header.demo_protocol,
+        header.network_protocol
+    );
+
+    for (i, entry) in demo.entries.iter().enumerate() {
+        info += &format!(
+            "Segment {}: {}, {:.3} s, {} frames\n",
+            i, entry.description, entry.track_time, entry.frame_count
+        );
+    }
+
+    info += &format!(
+        "Total: {:.3} s, {} frames\n",
+        demo.duration(),
+        demo.frame_count()
+    );
+
+    con_print(marker, &info);
+}
+
+/// Returns the index of the currently playing demo of the run.
+///
+/// This is `None` until the first demo of a queued run has started.
+pub fn current_demo(marker: MainThreadMarker) -> Option<usize> {
+    CURRENT_DEMO.get(marker)
+}
+
+/// Returns the name of the currently playing demo of the run, as passed to `playdemo`.
+pub fn current_demo_name(marker: MainThreadMarker) -> Option<String> {
+    let demos = DEMOS.borrow(marker);
+    let demo = demos.get(CURRENT_DEMO.get(marker)?)?.as_slice();
+    let demo = demo.strip_suffix(b"\0").unwrap_or(demo);
+    Some(String::from_utf8_lossy(demo).into_owned())
+}
+
+/// Queues the demo after the one that has just started.
 pub fn set_next_demo(marker: MainThreadMarker) {
-    let mut demos = DEMOS.borrow_mut(marker);
+    let current = QUEUED_DEMO.get(marker);
+    CURRENT_DEMO.set(marker, current);
+    DEMO_TIME.set(marker, 0.);
+    SEEK.set(marker, SEEK.get(marker).demo_started());
+    SWITCHING_DEMO.set(marker, false);
+
+    if current.is_some() {
+        con_print(marker, &position(marker));
+    }
+
+    queue_demo(marker, current.map(|current| current + 1));
+}
+
+/// Forgets the current demo when the client stops playing it.
+///
+/// Pausing and the playback speed carry over when switching to another demo of the run, otherwise
+/// they are reset as well.
+pub fn on_cl_disconnect(marker: MainThreadMarker) {
+    CURRENT_DEMO.set(marker, None);
+    DEMO_TIME.set(marker, 0.);
+
+    if SWITCHING_DEMO.get(marker) {
+        SEEK.set(marker, SEEK.get(marker).disconnected());
+    } else {
+        SEEK.set(marker, Seek::NONE);
+        PAUSED.set(marker, false);
+        SPEED.set(marker, 1.);
+    }
+}
+
+/// Puts the demo of the run at `index` into the first `cls.demos` entry, or clears it.
+fn queue_demo(marker: MainThreadMarker, index: Option<usize>) {
+    let demos = DEMOS.borrow(marker);
+    let demo = index.and_then(|index| demos.get(index));
+    QUEUED_DEMO.set(marker, demo.and(index));
 
     unsafe {
         // Safety: no engine functions are called while the reference is active.
         let cls_demos = &mut *engine::cls_demos.get(marker);
 
-        match demos.pop() {
+        match demo {
             Some(demo) => {
                 // Replace the first startdemos entry with the next demo and set the next demo as
                 // the first one.
@@ -167,3 +623,123 @@ pub fn set_next_demo(marker: MainThreadMarker) {
         }
     }
 }
+
+/// Changes the frame time for seeking, pausing and the playback speed.
+///
+/// # Safety
+///
+/// Must be called right after `host_frametime` is set for the frame, either by
+/// `Host_FilterTime()` or by the capture in its place. The capture then records the changed frame
+/// time, so pausing and speed changes apply to the video too.
+pub unsafe fn on_host_filter_time(marker: MainThreadMarker) {
+    if !DemoPlayback.is_enabled(marker)
+        || !engine::host_frametime.is_set(marker)
+        || !engine::cls.is_set(marker)
+    {
+        return;
+    }
+
+    if CURRENT_DEMO.get(marker).is_none() || (*engine::cls_demos.get(marker)).demoplayback == 0 {
+        return;
+    }
+
+    // Don't count the loading.
+    if (*engine::cls.get(marker)).state != 5 {
+        return;
+    }
+
+    let frame_time = &mut *engine::host_frametime.get(marker);
+    let time = DEMO_TIME.get(marker);
+
+    let seek = SEEK.get(marker);
+    match seek.target {
+        Some(target) if time < target => *frame_time = (target - time).min(SEEK_FRAME_TIME),
+        Some(_) => {
+            SEEK.set(
+                marker,
+                Seek {
+                    target: None,
+                    ..seek
+                },
+            );
+            con_print(marker, &position(marker));
+        }
+        None if PAUSED.get(marker) => *frame_time = 0.,
+        None => *frame_time *= SPEED.get(marker),
+    }
+
+    DEMO_TIME.set(marker, time + *frame_time);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(map_name: &str, map_crc: i32) -> Header {
+        Header {
+            demo_protocol: 5,
+            network_protocol: 48,
+            map_name: map_name.to_string(),
+            game_dir: "valve".to_string(),
+            map_crc,
+        }
+    }
+
+    #[test]
+    fn seek_forward() {
+        let seek = Seek::NONE.seek(5., false);
+        assert_eq!(seek.target, Some(5.));
+
+        // The seek doesn't carry over to the next demo.
+        assert_eq!(seek.demo_started(), Seek::NONE);
+        assert_eq!(seek.disconnected(), Seek::NONE);
+    }
+
+    #[test]
+    fn seek_backward() {
+        let seek = Seek::NONE.seek(5., false).seek(2., true);
+        assert_eq!(seek.target, None);
+
+        // Restarting the demo disconnects first.
+        let seek = seek.disconnected().demo_started();
+        assert_eq!(seek.target, Some(2.));
+        assert_eq!(seek.pending, None);
+
+        assert_eq!(seek.demo_started(), Seek::NONE);
+    }
+
+    #[test]
+    fn valid_sequence() {
+        let demos = [
+            (1, header("c1a0", 1)),
+            (2, header("c1a0a", 2)),
+            (3, header("c1a0", 1)),
+        ];
+        assert_eq!(check_sequence(&demos), Ok(()));
+    }
+
+    #[test]
+    fn sequence_with_gap() {
+        let demos = [(1, header("c1a0", 1)), (3, header("c1a0", 1))];
+        assert_eq!(check_sequence(&demos).unwrap_err(), "demo 2 is missing");
+
+        let demos = [(1, header("c1a0", 1)), (4, header("c1a0", 1))];
+        assert_eq!(
+            check_sequence(&demos).unwrap_err(),
+            "demos 2 to 3 are missing"
+        );
+    }
+
+    #[test]
+    fn sequence_with_mismatched_map() {
+        let demos = [
+            (1, header("c1a0", 1)),
+            (2, header("c1a0a", 2)),
+            (3, header("C1A0", 3)),
+        ];
+        assert_eq!(
+            check_sequence(&demos).unwrap_err(),
+            "demo 3 has a different version of C1A0 than demo 1"
+        );
+    }
+}
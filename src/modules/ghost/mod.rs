@@ -0,0 +1,317 @@
+//! Ghost playback.
+
+use std::path::PathBuf;
+
+use glam::Vec3;
+
+use super::commands::{self, Command};
+use super::cvars::{self, CVar};
+use super::demo_playback::game_dir;
+use super::tas_logging::TasLogFile;
+use super::triangle_drawing::triangle_api::{Primitive, RenderMode};
+use super::triangle_drawing::{self, TriangleApi};
+use super::{tas_editor, Module};
+use crate::demo;
+use crate::handler;
+use crate::hooks::engine::{self, con_print};
+use crate::utils::*;
+
+mod path;
+pub use path::{Ghost, GhostFrame, GhostPosition};
+
+pub struct GhostPlayback;
+impl Module for GhostPlayback {
+    fn name(&self) -> &'static str {
+        "Ghost playback"
+    }
+
+    fn commands(&self) -> &'static [&'static Command] {
+        static COMMANDS: &[&Command] = &[
+            &BXT_GHOST_LOAD_LOG,
+            &BXT_GHOST_LOAD_DEMO,
+            &BXT_GHOST_LOAD_TAS_EDITOR,
+            &BXT_GHOST_RESTART,
+            &BXT_GHOST_CLEAR,
+            &BXT_GHOST_DISTANCE,
+        ];
+        COMMANDS
+    }
+
+    fn cvars(&self) -> &'static [&'static CVar] {
+        static CVARS: &[&CVar] = &[&BXT_GHOST_SYNC_FRAMES, &BXT_GHOST_START_TIME];
+        CVARS
+    }
+
+    fn is_enabled(&self, marker: MainThreadMarker) -> bool {
+        commands::Commands.is_enabled(marker)
+            && cvars::CVars.is_enabled(marker)
+            && triangle_drawing::TriangleDrawing.is_enabled(marker)
+    }
+}
+
+static BXT_GHOST_LOAD_LOG: Command = Command::new(
+    b"bxt_ghost_load_log\0",
+    handler!(
+        "Usage: bxt_ghost_load_log <filename>\n \
+          Loads the player path of a TAS log as the ghost.\n",
+        ghost_load_log as fn(_, _)
+    ),
+);
+
+static BXT_GHOST_LOAD_DEMO: Command = Command::new(
+    b"bxt_ghost_load_demo\0",
+    handler!(
+        "Usage: bxt_ghost_load_demo <filename.dem>\n \
+          Loads the player path of a demo as the ghost.\n",
+        ghost_load_demo as fn(_, _)
+    ),
+);
+
+static BXT_GHOST_LOAD_TAS_EDITOR: Command = Command::new(
+    b"bxt_ghost_load_tas_editor\0",
+    handler!(
+        "Usage: bxt_ghost_load_tas_editor\n \
+          Loads the path simulated by the TAS editor as the ghost.\n",
+        ghost_load_tas_editor as fn(_)
+    ),
+);
+
+static BXT_GHOST_RESTART: Command = Command::new(
+    b"bxt_ghost_restart\0",
+    handler!(
+        "Usage: bxt_ghost_restart\n \
+          Restarts the ghost from its first frame when it is synchronized to command frames.\n",
+        ghost_restart as fn(_)
+    ),
+);
+
+static BXT_GHOST_CLEAR: Command = Command::new(
+    b"bxt_ghost_clear\0",
+    handler!(
+        "Usage: bxt_ghost_clear\n \
+          Removes the ghost.\n",
+        ghost_clear as fn(_)
+    ),
+);
+
+static BXT_GHOST_DISTANCE: Command = Command::new(
+    b"bxt_ghost_distance\0",
+    handler!(
+        "Usage: bxt_ghost_distance\n \
+          Prints how far the ghost is ahead of or behind the player along its path.\n",
+        ghost_distance as fn(_)
+    ),
+);
+
+static BXT_GHOST_SYNC_FRAMES: CVar = CVar::new(b"bxt_ghost_sync_frames\0", b"0\0");
+static BXT_GHOST_START_TIME: CVar = CVar::new(b"bxt_ghost_start_time\0", b"0\0");
+
+static GHOST: MainThreadRefCell<Option<Ghost>> = MainThreadRefCell::new(None);
+
+/// Number of command frames since the ghost was loaded or restarted.
+static CMD_FRAMES: MainThreadCell<usize> = MainThreadCell::new(0);
+
+/// Half of the player hull width.
+const HULL_HALF_WIDTH: f32 = 16.;
+/// Half of the standing and ducking player hull heights.
+const HULL_HALF_HEIGHTS: [f32; 2] = [36., 18.];
+
+/// Seconds of the path to draw before and after the current ghost position.
+const PATH_WINDOW: f32 = 5.;
+
+fn set_ghost(marker: MainThreadMarker, ghost: Ghost) {
+    if ghost.frames().is_empty() {
+        con_print(marker, "Error: the path is empty.\n");
+        return;
+    }
+
+    con_print(
+        marker,
+        &format!("Loaded a ghost with {} frames.\n", ghost.frames().len()),
+    );
+
+    *GHOST.borrow_mut(marker) = Some(ghost);
+    CMD_FRAMES.set(marker, 0);
+}
+
+fn ghost_load_log(marker: MainThreadMarker, path: PathBuf) {
+    if !GhostPlayback.is_enabled(marker) {
+        return;
+    }
+
+    match TasLogFile::open(&path) {
+        Ok(log) => set_ghost(marker, Ghost::from_tas_log(&log)),
+        Err(err) => con_print(marker, &format!("Error reading the log: {}\n", err)),
+    }
+}
+
+fn ghost_load_demo(marker: MainThreadMarker, path: PathBuf) {
+    if !GhostPlayback.is_enabled(marker) || !engine::com_gamedir.is_set(marker) {
+        return;
+    }
+
+    let mut path = game_dir(marker).join(path);
+    if path.extension().is_none() {
+        path.set_extension("dem");
+    }
+
+    match demo::open(&path) {
+        Ok(demo) => set_ghost(marker, Ghost::from_demo(&demo)),
+        Err(err) => con_print(marker, &format!("Error reading the demo: {}\n", err)),
+    }
+}
+
+fn ghost_load_tas_editor(marker: MainThreadMarker) {
+    if !GhostPlayback.is_enabled(marker) {
+        return;
+    }
+
+    match tas_editor::simulated_frames(marker) {
+        Some(frames) => set_ghost(marker, Ghost::from_simulation(&frames)),
+        None => con_print(marker, "Error: the TAS editor is not enabled.\n"),
+    }
+}
+
+fn ghost_restart(marker: MainThreadMarker) {
+    if !GhostPlayback.is_enabled(marker) {
+        return;
+    }
+
+    CMD_FRAMES.set(marker, 0);
+}
+
+fn ghost_clear(marker: MainThreadMarker) {
+    if !GhostPlayback.is_enabled(marker) {
+        return;
+    }
+
+    *GHOST.borrow_mut(marker) = None;
+}
+
+/// Returns the time along the ghost path that matches the current game state.
+fn current_time(marker: MainThreadMarker, ghost: &Ghost) -> Option<f32> {
+    if BXT_GHOST_SYNC_FRAMES.as_bool(marker) {
+        ghost.frame_time(CMD_FRAMES.get(marker))
+    } else {
+        // SAFETY: we're only reading a value.
+        let map_time = unsafe { (*engine::gGlobalVariables.get_opt(marker)?).time };
+
+        // The map time restarts on every map, so sync within the current map's section of the
+        // path. Paths without map names are synced from their start.
+        // SAFETY: we're only reading values.
+        let map_start = unsafe { engine::map_name(marker) }
+            .and_then(|map| ghost.map_start_time(&map))
+            .unwrap_or(0.);
+
+        Some(map_start + map_time - BXT_GHOST_START_TIME.as_f32(marker))
+    }
+}
+
+/// Returns the ghost position synchronized to the map time or to the command frames.
+fn current_position(marker: MainThreadMarker, ghost: &Ghost) -> Option<GhostPosition> {
+    ghost.position_at(current_time(marker, ghost)?)
+}
+
+fn ghost_distance(marker: MainThreadMarker) {
+    if !GhostPlayback.is_enabled(marker) {
+        return;
+    }
+
+    let ghost = GHOST.borrow(marker);
+    let ghost = match &*ghost {
+        Some(ghost) => ghost,
+        None => {
+            con_print(marker, "No ghost is loaded.\n");
+            return;
+        }
+    };
+
+    // SAFETY: we're not calling any engine functions while the reference is alive.
+    let player = unsafe { engine::player_edict(marker).map(|edict| edict.as_ref().v.origin) };
+    let ahead = match (current_position(marker, ghost), player) {
+        (Some(position), Some(player)) => ghost
+            .distance_near(Vec3::from(player))
+            .map(|distance| position.distance - distance),
+        _ => None,
+    };
+
+    let ahead = match ahead {
+        Some(ahead) => ahead,
+        None => {
+            con_print(marker, "Error: the game is not running.\n");
+            return;
+        }
+    };
+
+    if ahead >= 0. {
+        con_print(marker, &format!("The ghost is {:.1} units ahead.\n", ahead));
+    } else {
+        con_print(
+            marker,
+            &format!("The ghost is {:.1} units behind.\n", -ahead),
+        );
+    }
+}
+
+pub fn on_cmd_start(marker: MainThreadMarker) {
+    if !GhostPlayback.is_enabled(marker) {
+        return;
+    }
+
+    CMD_FRAMES.set(marker, CMD_FRAMES.get(marker) + 1);
+}
+
+pub fn draw(marker: MainThreadMarker, tri: &TriangleApi) {
+    if !GhostPlayback.is_enabled(marker) {
+        return;
+    }
+
+    let ghost = GHOST.borrow(marker);
+    let ghost = match &*ghost {
+        Some(ghost) => ghost,
+        None => return,
+    };
+
+    tri.render_mode(RenderMode::TransColor);
+    tri.color(1., 0.5, 0., 1.);
+
+    let time = match current_time(marker, ghost) {
+        Some(time) => time,
+        None => return,
+    };
+
+    tri.begin(Primitive::Lines);
+
+    for pair in ghost
+        .frames_between(time - PATH_WINDOW, time + PATH_WINDOW)
+        .windows(2)
+    {
+        tri.vertex(pair[0].origin);
+        tri.vertex(pair[1].origin);
+    }
+
+    if let Some(position) = ghost.position_at(time) {
+        let half_height = HULL_HALF_HEIGHTS[usize::from(position.ducking)];
+        let half_extents = Vec3::new(HULL_HALF_WIDTH, HULL_HALF_WIDTH, half_height);
+        let corner = |x: f32, y: f32, z: f32| position.origin + half_extents * Vec3::new(x, y, z);
+
+        // Bottom and top edges, then vertical edges.
+        for z in [-1., 1.] {
+            for (a, b) in [
+                ((-1., -1.), (1., -1.)),
+                ((1., -1.), (1., 1.)),
+                ((1., 1.), (-1., 1.)),
+                ((-1., 1.), (-1., -1.)),
+            ] {
+                tri.vertex(corner(a.0, a.1, z));
+                tri.vertex(corner(b.0, b.1, z));
+            }
+        }
+        for (x, y) in [(-1., -1.), (1., -1.), (1., 1.), (-1., 1.)] {
+            tri.vertex(corner(x, y, -1.));
+            tri.vertex(corner(x, y, 1.));
+        }
+    }
+
+    tri.end();
+}
@@ -0,0 +1,405 @@
+//! Ghost paths.
+
+use std::iter;
+use std::rc::Rc;
+
+use glam::Vec3;
+
+use crate::demo::Demo;
+use crate::ffi::buttons::Buttons;
+use crate::modules::tas_editor::Frame;
+use crate::modules::tas_logging::TasLogFile;
+
+/// A recorded player position.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GhostFrame {
+    /// Time since the start of the path in seconds.
+    pub time: f32,
+    /// Map the frame was recorded on, if known.
+    pub map: Option<Rc<str>>,
+    pub origin: Vec3,
+    pub ducking: bool,
+}
+
+/// A point on the path, possibly between two frames.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GhostPosition {
+    pub origin: Vec3,
+    pub ducking: bool,
+    /// Distance along the path from its start.
+    pub distance: f32,
+}
+
+/// A player path to replay.
+#[derive(Debug, Clone, Default)]
+pub struct Ghost {
+    frames: Vec<GhostFrame>,
+    /// Distance along the path from the first frame to every frame.
+    distances: Vec<f32>,
+}
+
+impl Ghost {
+    /// Creates a ghost from frames sorted by time.
+    pub fn new(frames: Vec<GhostFrame>) -> Self {
+        let mut distance = 0.;
+        let distances = iter::once(0.)
+            .chain(frames.windows(2).map(|pair| {
+                distance += pair[0].origin.distance(pair[1].origin);
+                distance
+            }))
+            .take(frames.len())
+            .collect();
+
+        Self { frames, distances }
+    }
+
+    /// Creates a ghost from the post-movement player states of a TAS log.
+    ///
+    /// Command frames without the player state, for example from a log written without the player
+    /// movement fields, are skipped. The time advances by the command frame durations, or if the
+    /// log was written without the usercmd fields, by the physics frame times split evenly between
+    /// their command frames. Frames from logs that don't record the map have no map name.
+    pub fn from_tas_log(log: &TasLogFile) -> Self {
+        let has_msec = log
+            .physics_frames
+            .iter()
+            .flat_map(|frame| &frame.cmd_frames)
+            .any(|cmd| cmd.msec != 0);
+
+        let mut time = 0.;
+        let mut map: Option<Rc<str>> = None;
+        let mut frames = Vec::new();
+
+        for physics_frame in &log.physics_frames {
+            if let Some(new_map) = &physics_frame.map {
+                map = Some(new_map.as_str().into());
+            }
+
+            let cmd_count = physics_frame.cmd_frames.len() as f32;
+
+            for cmd in &physics_frame.cmd_frames {
+                time += if has_msec {
+                    f32::from(cmd.msec) / 1000.
+                } else {
+                    physics_frame.frame_time.unwrap_or(0.) as f32 / cmd_count
+                };
+
+                if let Some(state) = &cmd.post_pm_state {
+                    frames.push(GhostFrame {
+                        time,
+                        map: map.clone(),
+                        origin: Vec3::from(state.position),
+                        ducking: state.duck_state == 2,
+                    });
+                }
+            }
+        }
+
+        Self::new(frames)
+    }
+
+    /// Creates a ghost from the network message frames of a demo.
+    ///
+    /// Paused frames don't advance the time. Demos don't store the duck state, so the ghost ducks
+    /// while the duck button is held.
+    pub fn from_demo(demo: &Demo) -> Self {
+        let map: Rc<str> = demo.header.map_name.as_str().into();
+        let frames = demo
+            .timed_net_msgs()
+            .map(|(time, msg)| {
+                let buttons = Buttons::from_bits_truncate(msg.usercmd.buttons);
+                GhostFrame {
+                    time: time as f32,
+                    map: Some(map.clone()),
+                    origin: Vec3::from(msg.sim_origin),
+                    ducking: buttons.contains(Buttons::IN_DUCK),
+                }
+            })
+            .collect();
+
+        Self::new(frames)
+    }
+
+    /// Creates a ghost from simulated frames.
+    ///
+    /// The simulation doesn't know the map, so the frames have no map names.
+    pub fn from_simulation(simulated: &[Frame]) -> Self {
+        let mut time = 0.;
+        let mut frames = Vec::with_capacity(simulated.len());
+
+        for (i, frame) in simulated.iter().enumerate() {
+            // The first frame is the initial state.
+            if i > 0 {
+                time += frame.parameters.frame_time;
+            }
+
+            let player = frame.state.player();
+            frames.push(GhostFrame {
+                time,
+                map: None,
+                origin: player.pos,
+                ducking: player.ducking,
+            });
+        }
+
+        Self::new(frames)
+    }
+
+    pub fn frames(&self) -> &[GhostFrame] {
+        &self.frames
+    }
+
+    /// Returns the time of the frame at `index`, or of the last frame if `index` is past the end.
+    pub fn frame_time(&self, index: usize) -> Option<f32> {
+        self.frames
+            .get(index)
+            .or_else(|| self.frames.last())
+            .map(|frame| frame.time)
+    }
+
+    /// Returns the time at which the path enters `map`.
+    ///
+    /// This is the time of the frame just before the first frame on `map`, or 0 if the path starts
+    /// on `map`. Returns `None` if no frame is known to be on `map`.
+    pub fn map_start_time(&self, map: &str) -> Option<f32> {
+        let first = self.frames.iter().position(|frame| {
+            frame
+                .map
+                .as_deref()
+                .map_or(false, |frame_map| frame_map.eq_ignore_ascii_case(map))
+        })?;
+
+        Some(
+            first
+                .checked_sub(1)
+                .map_or(0., |prev| self.frames[prev].time),
+        )
+    }
+
+    /// Returns the frames from `start` to `end` seconds, along with the frame just outside each end.
+    pub fn frames_between(&self, start: f32, end: f32) -> &[GhostFrame] {
+        let end =
+            (self.frames.partition_point(|frame| frame.time <= end) + 1).min(self.frames.len());
+        let start = self
+            .frames
+            .partition_point(|frame| frame.time < start)
+            .saturating_sub(1)
+            .min(end);
+        &self.frames[start..end]
+    }
+
+    fn position(&self, index: usize) -> GhostPosition {
+        let frame = &self.frames[index];
+        GhostPosition {
+            origin: frame.origin,
+            ducking: frame.ducking,
+            distance: self.distances[index],
+        }
+    }
+
+    /// Returns the position at `time`, interpolating between frames.
+    ///
+    /// Before the start and after the end of the path, the ghost stays at the first and the last
+    /// frame.
+    pub fn position_at(&self, time: f32) -> Option<GhostPosition> {
+        let last = self.frames.len().checked_sub(1)?;
+
+        let next = self.frames.partition_point(|frame| frame.time <= time);
+        if next == 0 {
+            return Some(self.position(0));
+        }
+        if next > last {
+            return Some(self.position(last));
+        }
+
+        let (prev_time, next_time) = (self.frames[next - 1].time, self.frames[next].time);
+        let t = (time - prev_time) / (next_time - prev_time);
+        let (prev, next) = (self.position(next - 1), self.position(next));
+
+        Some(GhostPosition {
+            origin: prev.origin.lerp(next.origin, t),
+            ducking: prev.ducking,
+            distance: prev.distance + (next.distance - prev.distance) * t,
+        })
+    }
+
+    /// Returns the distance along the path to the frame closest to `origin`.
+    pub fn distance_near(&self, origin: Vec3) -> Option<f32> {
+        self.frames
+            .iter()
+            .zip(&self.distances)
+            .min_by(|(a, _), (b, _)| {
+                a.origin
+                    .distance_squared(origin)
+                    .total_cmp(&b.origin.distance_squared(origin))
+            })
+            .map(|(_, distance)| *distance)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demo;
+    use crate::demo::test_demo::{self, TestFrame, TestNetMsg};
+
+    fn frame(time: f32, x: f32) -> GhostFrame {
+        GhostFrame {
+            time,
+            map: None,
+            origin: Vec3::new(x, 0., 0.),
+            ducking: false,
+        }
+    }
+
+    #[test]
+    fn interpolate_position() {
+        let ghost = Ghost::new(vec![frame(0., 0.), frame(1., 10.), frame(2., 30.)]);
+
+        let position = ghost.position_at(1.5).unwrap();
+        assert_eq!(position.origin, Vec3::new(20., 0., 0.));
+        assert_eq!(position.distance, 20.);
+
+        assert_eq!(ghost.position_at(-1.).unwrap().origin, Vec3::ZERO);
+        assert_eq!(ghost.position_at(5.).unwrap().distance, 30.);
+        assert_eq!(Ghost::default().position_at(0.), None);
+    }
+
+    #[test]
+    fn map_start_times_of_path_over_two_maps() {
+        let on_map = |map: &str, frame: GhostFrame| GhostFrame {
+            map: Some(map.into()),
+            ..frame
+        };
+        let ghost = Ghost::new(vec![
+            on_map("c1a0", frame(0., 0.)),
+            on_map("c1a0", frame(1., 10.)),
+            on_map("c1a0a", frame(1.5, 20.)),
+            on_map("c1a0a", frame(2.5, 30.)),
+        ]);
+
+        assert_eq!(ghost.map_start_time("c1a0"), Some(0.));
+        assert_eq!(ghost.map_start_time("C1A0A"), Some(1.));
+        assert_eq!(ghost.map_start_time("c1a1"), None);
+
+        // Half a second into the second map.
+        let map_start = ghost.map_start_time("c1a0a").unwrap();
+        let position = ghost.position_at(map_start + 0.5).unwrap();
+        assert_eq!(position.origin, Vec3::new(20., 0., 0.));
+    }
+
+    #[test]
+    fn distance_to_player() {
+        let ghost = Ghost::new(vec![frame(0., 0.), frame(1., 10.), frame(2., 30.)]);
+        assert_eq!(ghost.distance_near(Vec3::new(12., 5., 0.)), Some(10.));
+        assert_eq!(ghost.frame_time(7), Some(2.));
+    }
+
+    #[test]
+    fn distance_to_player_with_nan() {
+        let ghost = Ghost::new(vec![frame(0., f32::NAN), frame(1., 10.)]);
+        assert!(ghost.distance_near(Vec3::new(12., 0., 0.)).is_some());
+    }
+
+    #[test]
+    fn frames_in_window() {
+        let ghost = Ghost::new((0..5).map(|i| frame(i as f32, 0.)).collect());
+        let times = |start, end| -> Vec<f32> {
+            ghost
+                .frames_between(start, end)
+                .iter()
+                .map(|frame| frame.time)
+                .collect()
+        };
+
+        assert_eq!(times(1.5, 2.5), [1., 2., 3.]);
+        assert_eq!(times(2., 2.), [1., 2., 3.]);
+        assert_eq!(times(-5., 0.5), [0., 1.]);
+        assert_eq!(times(3.5, 10.), [3., 4.]);
+        assert!(Ghost::default().frames_between(0., 1.).is_empty());
+    }
+
+    #[test]
+    fn ghost_from_tas_log() {
+        let log = TasLogFile::parse(
+            r#"{"tool_ver":"test","pf":[{"ft":0.01,"cf":[
+                {"ms":250,"postpm":{"pos":[1.0,0.0,0.0],"vel":[0,0,0],"og":true}},
+                {"ms":250},
+                {"ms":250,"postpm":{"pos":[3.0,0.0,0.0],"vel":[0,0,0],"og":true,"dst":2}}
+            ]}]}"#,
+        )
+        .unwrap();
+
+        let ghost = Ghost::from_tas_log(&log);
+        assert_eq!(ghost.frames().len(), 2);
+        assert_eq!(ghost.frames()[1].time, 0.75);
+        assert!(ghost.frames()[1].ducking);
+    }
+
+    #[test]
+    fn ghost_from_tas_log_without_usercmd() {
+        let log = TasLogFile::parse(
+            r#"{"tool_ver":"test","pf":[
+                {"ft":0.25,"cf":[{"postpm":{"pos":[1.0,0.0,0.0],"vel":[0,0,0],"og":true}}]},
+                {"ft":0.1,"cf":[]},
+                {"ft":0.5,"cf":[
+                    {"postpm":{"pos":[2.0,0.0,0.0],"vel":[0,0,0],"og":true}},
+                    {"postpm":{"pos":[3.0,0.0,0.0],"vel":[0,0,0],"og":true}}
+                ]}
+            ]}"#,
+        )
+        .unwrap();
+
+        let ghost = Ghost::from_tas_log(&log);
+        let times: Vec<_> = ghost.frames().iter().map(|frame| frame.time).collect();
+        assert_eq!(times, [0.25, 0.5, 0.75]);
+    }
+
+    #[test]
+    fn ghost_from_tas_log_over_two_maps() {
+        let log = TasLogFile::parse(
+            r#"{"tool_ver":"test","pf":[
+                {"map":"c1a0","cf":[{"ms":10,"postpm":{"pos":[1.0,0.0,0.0],"vel":[0,0,0],"og":true}}]},
+                {"cf":[{"ms":10,"postpm":{"pos":[2.0,0.0,0.0],"vel":[0,0,0],"og":true}}]},
+                {"p":true,"cf":[]},
+                {"map":"c1a0a","cf":[{"ms":10,"postpm":{"pos":[3.0,0.0,0.0],"vel":[0,0,0],"og":true}}]}
+            ]}"#,
+        )
+        .unwrap();
+
+        let ghost = Ghost::from_tas_log(&log);
+        let maps: Vec<_> = ghost
+            .frames()
+            .iter()
+            .map(|frame| frame.map.as_deref().unwrap())
+            .collect();
+        assert_eq!(maps, ["c1a0", "c1a0", "c1a0a"]);
+        assert_eq!(ghost.map_start_time("c1a0a"), Some(0.02));
+    }
+
+    #[test]
+    fn ghost_from_demo() {
+        let msg = TestNetMsg {
+            frame_time: 0.25,
+            sim_origin: [1., 0., 0.],
+            ..Default::default()
+        };
+        let paused = TestNetMsg {
+            paused: true,
+            ..msg
+        };
+        let data = test_demo::write(
+            "c1a0",
+            &[
+                TestFrame::NetMsg(msg),
+                TestFrame::NetMsg(paused),
+                TestFrame::NetMsg(msg),
+            ],
+        );
+
+        let ghost = Ghost::from_demo(&demo::parse(&data).unwrap());
+        let times: Vec<f32> = ghost.frames().iter().map(|frame| frame.time).collect();
+        assert_eq!(times, [0.25, 0.25, 0.5]);
+        assert_eq!(ghost.map_start_time("c1a0"), Some(0.));
+    }
+}
@@ -21,11 +21,13 @@ pub mod demo_playback;
 pub mod disable_loading_text;
 pub mod fade_remove;
 pub mod force_fov;
+pub mod ghost;
 pub mod hud_scale;
 pub mod module_list;
 pub mod novis;
 pub mod player_movement_tracing;
 pub mod rng_set;
+pub mod run_timing;
 pub mod shake_remove;
 pub mod skybox_remove;
 pub mod tas_editor;
@@ -69,11 +71,13 @@ pub static MODULES: &[&dyn Module] = &[
     &disable_loading_text::DisableLoadingText,
     &fade_remove::FadeRemove,
     &force_fov::ForceFov,
+    &ghost::GhostPlayback,
     &hud_scale::HudScale,
     &module_list::ModuleList,
     &novis::NoVis,
     &player_movement_tracing::PlayerMovementTracing,
     &rng_set::RngSet,
+    &run_timing::RunTiming,
     &shake_remove::ShakeRemove,
     &skybox_remove::SkyboxRemove,
     &tas_editor::TasEditor,
@@ -0,0 +1,233 @@
+//! Timing runs from demos.
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use super::commands::{self, Command};
+use super::demo_playback::{find_demos, game_dir};
+use super::Module;
+use crate::demo::{self, Demo};
+use crate::handler;
+use crate::hooks::engine::{self, con_print};
+use crate::utils::*;
+
+pub struct RunTiming;
+impl Module for RunTiming {
+    fn name(&self) -> &'static str {
+        "Run timing"
+    }
+
+    fn commands(&self) -> &'static [&'static Command] {
+        static COMMANDS: &[&Command] = &[&BXT_RUN_TIME];
+        COMMANDS
+    }
+
+    fn is_enabled(&self, marker: MainThreadMarker) -> bool {
+        commands::Commands.is_enabled(marker) && engine::com_gamedir.is_set(marker)
+    }
+}
+
+static BXT_RUN_TIME: Command = Command::new(
+    b"bxt_run_time\0",
+    handler!(
+        "Usage: bxt_run_time <name> [filename.json]\n \
+          Prints the game time of every name_N.dem demo and of the whole run. Loading and paused \
+          frames are not counted.\n \
+          If a filename is given, also writes the segments there as JSON.\n",
+        run_time as fn(_, _),
+        run_time_with_json as fn(_, _, _)
+    ),
+);
+
+/// A demo of a run.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Segment {
+    pub number: usize,
+    pub filename: String,
+    pub start_map: String,
+    /// The map where the next demo starts, or `None` for the last demo.
+    pub end_map: Option<String>,
+    /// Game time of the demo in seconds.
+    pub time: f64,
+    /// Game time from the start of the run to the end of the demo in seconds.
+    pub total_time: f64,
+}
+
+/// Computes the segments of a run from its demos with their numbers, sorted by number.
+pub fn segments(demos: &[(PathBuf, usize, Demo)]) -> Vec<Segment> {
+    let mut total_time = 0.;
+
+    demos
+        .iter()
+        .enumerate()
+        .map(|(i, (path, number, demo))| {
+            let time = demo.game_time();
+            total_time += time;
+
+            let start_map = demo.header.map_name.clone();
+            let end_map = demos
+                .get(i + 1)
+                .map(|(_, _, next)| next.header.map_name.clone());
+
+            Segment {
+                number: *number,
+                filename: path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+                start_map,
+                end_map,
+                time,
+                total_time,
+            }
+        })
+        .collect()
+}
+
+/// Formats seconds as `h:mm:ss.sss`, leaving out zero hours and minutes.
+pub fn format_time(seconds: f64) -> String {
+    let millis = (seconds * 1000.).round() as u64;
+    let (hours, millis) = (millis / 3_600_000, millis % 3_600_000);
+    let (minutes, millis) = (millis / 60_000, millis % 60_000);
+    let (seconds, millis) = (millis / 1000, millis % 1000);
+
+    if hours > 0 {
+        format!("{}:{:02}:{:02}.{:03}", hours, minutes, seconds, millis)
+    } else if minutes > 0 {
+        format!("{}:{:02}.{:03}", minutes, seconds, millis)
+    } else {
+        format!("{}.{:03}", seconds, millis)
+    }
+}
+
+/// Returns the segments as a table for the console.
+fn table(segments: &[Segment]) -> String {
+    let mut table = format!(
+        "{:>4}  {:<20} {:<16} {:<16} {:>12} {:>12}\n",
+        "#", "Demo", "Start map", "End map", "Time", "Total"
+    );
+
+    for segment in segments {
+        table += &format!(
+            "{:>4}  {:<20} {:<16} {:<16} {:>12} {:>12}\n",
+            segment.number,
+            segment.filename,
+            segment.start_map,
+            segment.end_map.as_deref().unwrap_or("-"),
+            format_time(segment.time),
+            format_time(segment.total_time)
+        );
+    }
+
+    let total_time = segments.last().map_or(0., |segment| segment.total_time);
+    table += &format!("Total time: {}\n", format_time(total_time));
+    table
+}
+
+fn write_json(segments: &[Segment], path: &Path) -> Result<(), String> {
+    let file = File::create(path).map_err(|err| format!("could not create the file: {}", err))?;
+    serde_json::to_writer_pretty(BufWriter::new(file), segments)
+        .map_err(|err| format!("could not write the file: {}", err))
+}
+
+fn run_time(marker: MainThreadMarker, prefix: PathBuf) {
+    if let Some(segments) = time_run(marker, prefix) {
+        con_print(marker, &table(&segments));
+    }
+}
+
+fn run_time_with_json(marker: MainThreadMarker, prefix: PathBuf, filename: PathBuf) {
+    if let Some(segments) = time_run(marker, prefix) {
+        con_print(marker, &table(&segments));
+
+        if let Err(err) = write_json(&segments, &filename) {
+            con_print(marker, &format!("Error: {}.\n", err));
+        }
+    }
+}
+
+/// Parses all `prefix_N.dem` demos and computes their segments, printing any errors.
+fn time_run(marker: MainThreadMarker, prefix: PathBuf) -> Option<Vec<Segment>> {
+    if !RunTiming.is_enabled(marker) {
+        return None;
+    }
+
+    let paths = match find_demos(game_dir(marker).join(prefix)) {
+        Ok(paths) => paths,
+        Err(err) => {
+            con_print(marker, &format!("Error: {}.\n", err));
+            return None;
+        }
+    };
+
+    if paths.is_empty() {
+        con_print(marker, "Error: no demos found.\n");
+        return None;
+    }
+
+    let mut demos = Vec::with_capacity(paths.len());
+    for (path, number) in paths {
+        match demo::open(&path) {
+            Ok(demo) => demos.push((path, number, demo)),
+            Err(err) => {
+                con_print(
+                    marker,
+                    &format!("Error reading {}: {}.\n", path.to_string_lossy(), err),
+                );
+                return None;
+            }
+        }
+    }
+
+    Some(segments(&demos))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demo::test_demo::{self, TestFrame, TestNetMsg};
+
+    fn demo(map: &str, frame_count: usize) -> Demo {
+        let msg = TestFrame::NetMsg(TestNetMsg {
+            frame_time: 0.5,
+            ..Default::default()
+        });
+        demo::parse(&test_demo::write(map, &vec![msg; frame_count])).unwrap()
+    }
+
+    #[test]
+    fn run_segments() {
+        let demos = [
+            (PathBuf::from("valve/run_1.dem"), 1, demo("c1a0", 3)),
+            (PathBuf::from("valve/run_2.dem"), 2, demo("c1a0", 2)),
+            (PathBuf::from("valve/run_3.dem"), 3, demo("c1a0a", 5)),
+        ];
+
+        let segments = segments(&demos);
+        assert_eq!(
+            segments[0],
+            Segment {
+                number: 1,
+                filename: "run_1.dem".to_string(),
+                start_map: "c1a0".to_string(),
+                end_map: Some("c1a0".to_string()),
+                time: 1.5,
+                total_time: 1.5,
+            }
+        );
+        assert_eq!(segments[1].end_map.as_deref(), Some("c1a0a"));
+        assert_eq!(segments[1].total_time, 2.5);
+        assert_eq!(segments[2].end_map, None);
+        assert_eq!(segments[2].total_time, 5.);
+    }
+
+    #[test]
+    fn format_times() {
+        assert_eq!(format_time(1.5), "1.500");
+        assert_eq!(format_time(61.0004), "1:01.000");
+        assert_eq!(format_time(3723.25), "1:02:03.250");
+    }
+}
@@ -0,0 +1,446 @@
+//! Lossless script compaction.
+
+use std::cmp::Reverse;
+use std::num::NonZeroU32;
+
+use bxt_strafe::Trace;
+use hltas::types::{AutoMovement, FrameBulk, Line, StrafeDir, StrafeSettings, StrafeType};
+use tap::TryConv;
+
+use super::editor::Frame;
+use super::simulator::Simulator;
+
+/// Strafe settings to try in place of explicit yaws.
+pub const STRAFE_CANDIDATES: [StrafeSettings; 4] = [
+    StrafeSettings {
+        type_: StrafeType::MaxAccel,
+        dir: StrafeDir::Left,
+    },
+    StrafeSettings {
+        type_: StrafeType::MaxAccel,
+        dir: StrafeDir::Right,
+    },
+    StrafeSettings {
+        type_: StrafeType::MaxAngle,
+        dir: StrafeDir::Left,
+    },
+    StrafeSettings {
+        type_: StrafeType::MaxAngle,
+        dir: StrafeDir::Right,
+    },
+];
+
+/// Compacts `lines` without changing any simulated frame.
+///
+/// `suffix` is simulated after `lines` but is never changed. Runs of explicit yaws are replaced
+/// with equivalent strafing, adjacent frame bulks producing identical frames are merged, and
+/// [`Line::Change`]s which can never take effect are removed. Frame bulk changes are checked by
+/// re-simulating the affected frames, and the final result is checked by re-simulating the whole
+/// script. If that check fails, the original `lines` are returned.
+///
+/// The simulator ignores [`Line::Change`]s, so the checks say nothing about them. They are only
+/// removed when no frame follows them, counting the frames of `suffix`, or when another change of
+/// the same target replaces them before the next frame.
+pub fn compact<T: Trace>(
+    tracer: &T,
+    initial_frame: Frame,
+    lines: &[Line],
+    suffix: &[Line],
+) -> Vec<Line> {
+    let reference = simulate(tracer, initial_frame, lines, suffix);
+
+    let mut compacted = lines.to_vec();
+    replace_explicit_yaws(tracer, &reference, &mut compacted);
+    merge_frame_bulks(tracer, &reference, &mut compacted);
+    remove_redundant_changes(&mut compacted, suffix);
+
+    if simulate(tracer, reference[0].clone(), &compacted, suffix) == reference {
+        compacted
+    } else {
+        lines.to_vec()
+    }
+}
+
+/// Simulates `lines` followed by `suffix` and returns all frames, starting with `initial_frame`.
+fn simulate<T: Trace>(
+    tracer: &T,
+    initial_frame: Frame,
+    lines: &[Line],
+    suffix: &[Line],
+) -> Vec<Frame> {
+    let lines = [lines, suffix].concat();
+
+    let mut frames = vec![initial_frame];
+    let simulator = Simulator::new(tracer, &frames, &lines);
+    frames.extend(simulator);
+    frames
+}
+
+/// Returns `true` if `lines`, starting from frame `start`, simulate into the same frames as in
+/// `reference`.
+///
+/// If the last simulated frame matches, the frames after it will also match as long as the lines
+/// after `lines` are unchanged.
+fn is_equivalent<T: Trace>(tracer: &T, reference: &[Frame], lines: &[Line], start: usize) -> bool {
+    let mut simulated = 0;
+    let matches = Simulator::new(tracer, &reference[start..=start], lines)
+        .zip(&reference[start + 1..])
+        .all(|(frame, reference_frame)| {
+            simulated += 1;
+            frame == *reference_frame
+        });
+
+    matches && simulated == frame_count(lines)
+}
+
+/// Returns the number of frames in `lines`.
+fn frame_count(lines: &[Line]) -> usize {
+    lines
+        .iter()
+        .filter_map(|line| {
+            if let Line::FrameBulk(frame_bulk) = line {
+                Some(frame_bulk.frame_count.get().try_conv::<usize>().unwrap())
+            } else {
+                None
+            }
+        })
+        .sum()
+}
+
+/// Returns the index of the first frame of every line.
+fn line_start_frames(lines: &[Line]) -> Vec<usize> {
+    lines
+        .iter()
+        .scan(0, |frame, line| {
+            let start = *frame;
+            if let Line::FrameBulk(frame_bulk) = line {
+                *frame += frame_bulk.frame_count.get().try_conv::<usize>().unwrap();
+            }
+            Some(start)
+        })
+        .collect()
+}
+
+/// Returns `true` if the frame bulks only differ in fields that the simulator accounts for.
+fn differ_only_in_simulated_fields(a: &FrameBulk, b: &FrameBulk) -> bool {
+    let strip = |frame_bulk: &FrameBulk| {
+        let mut frame_bulk = frame_bulk.clone();
+        frame_bulk.frame_count = NonZeroU32::new(1).unwrap();
+        frame_bulk.auto_actions = Default::default();
+        frame_bulk.action_keys.jump = false;
+        frame_bulk.action_keys.duck = false;
+        frame_bulk.action_keys.use_ = false;
+        frame_bulk
+    };
+
+    strip(a) == strip(b)
+}
+
+/// Replaces runs of explicit yaws with strafing that simulates into the same frames.
+///
+/// Every explicit-yaw frame bulk is checked against every strafe candidate on its own. The frames
+/// before it match the reference, so this is the same as checking the whole run. Each run is then
+/// covered with as few strafe settings as possible so that [`merge_frame_bulks`] can merge it.
+///
+/// Explicit-yaw frame bulks with movement keys are left as they are: the simulator doesn't apply
+/// their keys, so their simulated frames say nothing about how they move in the game.
+fn replace_explicit_yaws<T: Trace>(tracer: &T, reference: &[Frame], lines: &mut [Line]) {
+    let start_frames = line_start_frames(lines);
+
+    // Strafe candidates that simulate into the same frames as each line.
+    let equivalent: Vec<[bool; STRAFE_CANDIDATES.len()]> = lines
+        .iter()
+        .zip(&start_frames)
+        .map(|(line, &start)| {
+            let mut equivalent = [false; STRAFE_CANDIDATES.len()];
+
+            let frame_bulk = match line {
+                Line::FrameBulk(frame_bulk) => frame_bulk,
+                _ => return equivalent,
+            };
+
+            let keys = &frame_bulk.movement_keys;
+            let has_keys =
+                keys.forward || keys.left || keys.back || keys.right || keys.up || keys.down;
+            if has_keys
+                || !matches!(
+                    frame_bulk.auto_actions.movement,
+                    Some(AutoMovement::SetYaw(_))
+                )
+            {
+                return equivalent;
+            }
+
+            for (i, settings) in STRAFE_CANDIDATES.into_iter().enumerate() {
+                let mut frame_bulk = frame_bulk.clone();
+                frame_bulk.auto_actions.movement = Some(AutoMovement::Strafe(settings));
+                equivalent[i] =
+                    is_equivalent(tracer, reference, &[Line::FrameBulk(frame_bulk)], start);
+            }
+
+            equivalent
+        })
+        .collect();
+
+    let mut l = 0;
+    while l < lines.len() {
+        // Pick the strafe candidate which covers the most lines from here, preferring earlier
+        // candidates.
+        let (candidate, len) = (0..STRAFE_CANDIDATES.len())
+            .map(|i| {
+                let len = equivalent[l..].iter().take_while(|e| e[i]).count();
+                (i, len)
+            })
+            .max_by_key(|&(i, len)| (len, Reverse(i)))
+            .unwrap();
+
+        if len == 0 {
+            l += 1;
+            continue;
+        }
+
+        for line in &mut lines[l..l + len] {
+            if let Line::FrameBulk(frame_bulk) = line {
+                frame_bulk.auto_actions.movement =
+                    Some(AutoMovement::Strafe(STRAFE_CANDIDATES[candidate]));
+            }
+        }
+
+        l += len;
+    }
+}
+
+fn merge_frame_bulks<T: Trace>(tracer: &T, reference: &[Frame], lines: &mut Vec<Line>) {
+    let mut l = 0;
+    let mut start = 0;
+
+    while l + 1 < lines.len() {
+        let (a, b) = match (&lines[l], &lines[l + 1]) {
+            (Line::FrameBulk(a), Line::FrameBulk(b)) => (a.clone(), b.clone()),
+            (Line::FrameBulk(a), _) => {
+                start += a.frame_count.get().try_conv::<usize>().unwrap();
+                l += 1;
+                continue;
+            }
+            _ => {
+                l += 1;
+                continue;
+            }
+        };
+
+        let merged_count = match a
+            .frame_count
+            .get()
+            .checked_add(b.frame_count.get())
+            .and_then(NonZeroU32::new)
+        {
+            Some(x) if differ_only_in_simulated_fields(&a, &b) => x,
+            _ => {
+                start += a.frame_count.get().try_conv::<usize>().unwrap();
+                l += 1;
+                continue;
+            }
+        };
+
+        // Try extending either of the two frame bulks over both.
+        let mut merged = false;
+        for mut candidate in [a.clone(), b] {
+            candidate.frame_count = merged_count;
+
+            let candidate = Line::FrameBulk(candidate);
+            if is_equivalent(tracer, reference, std::slice::from_ref(&candidate), start) {
+                lines[l] = candidate;
+                lines.remove(l + 1);
+                merged = true;
+                break;
+            }
+        }
+
+        // After a successful merge, try merging the result with the next frame bulk.
+        if !merged {
+            start += a.frame_count.get().try_conv::<usize>().unwrap();
+            l += 1;
+        }
+    }
+}
+
+/// Removes [`Line::Change`]s that are overridden before any frame, or that have no frames after
+/// them.
+///
+/// `suffix` follows `lines` and is never changed.
+fn remove_redundant_changes(lines: &mut Vec<Line>, suffix: &[Line]) {
+    let mut keep = vec![true; lines.len()];
+    let mut frames_follow = false;
+
+    // Go backwards from the end of the suffix, keeping track of which change targets are
+    // overridden before the next frame.
+    let mut overridden = Vec::new();
+    for (l, line) in lines.iter().chain(suffix).enumerate().rev() {
+        match line {
+            Line::FrameBulk(_) => {
+                frames_follow = true;
+                overridden.clear();
+            }
+            Line::Change(change) => {
+                if !frames_follow || overridden.contains(&change.target) {
+                    if l < keep.len() {
+                        keep[l] = false;
+                    }
+                } else {
+                    overridden.push(change.target);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    let mut keep = keep.into_iter();
+    lines.retain(|_| keep.next().unwrap());
+}
+
+#[cfg(test)]
+mod tests {
+    use bxt_strafe::DummyTracer;
+    use hltas::types::{Change, ChangeTarget};
+
+    use super::*;
+    use crate::modules::tas_editor::test_utils::default_frame;
+
+    fn strafe(dir: StrafeDir, frame_count: u32) -> Line {
+        let mut frame_bulk = FrameBulk::with_frame_time("0.001".to_string());
+        frame_bulk.frame_count = NonZeroU32::new(frame_count).unwrap();
+        frame_bulk.auto_actions.movement = Some(AutoMovement::Strafe(StrafeSettings {
+            type_: StrafeType::MaxAccel,
+            dir,
+        }));
+        Line::FrameBulk(frame_bulk)
+    }
+
+    fn set_yaw(yaw: f32) -> Line {
+        let mut frame_bulk = FrameBulk::with_frame_time("0.001".to_string());
+        frame_bulk.auto_actions.movement = Some(AutoMovement::SetYaw(yaw));
+        Line::FrameBulk(frame_bulk)
+    }
+
+    fn change() -> Line {
+        Line::Change(Change {
+            target: ChangeTarget::Yaw,
+            final_value: 0.,
+            over: 1.,
+        })
+    }
+
+    #[test]
+    fn compact_merges_identical_frame_bulks() {
+        let lines = [strafe(StrafeDir::Left, 2), strafe(StrafeDir::Left, 3)];
+        let compacted = compact(&DummyTracer, default_frame(), &lines, &[]);
+        assert_eq!(compacted, [strafe(StrafeDir::Left, 5)]);
+    }
+
+    #[test]
+    fn compact_keeps_different_frame_bulks() {
+        let lines = [strafe(StrafeDir::Left, 2), strafe(StrafeDir::Right, 3)];
+        let compacted = compact(&DummyTracer, default_frame(), &lines, &[]);
+        assert_eq!(compacted, lines);
+    }
+
+    #[test]
+    fn compact_does_not_touch_suffix() {
+        let lines = [strafe(StrafeDir::Left, 2)];
+        let suffix = [strafe(StrafeDir::Left, 3)];
+        let compacted = compact(&DummyTracer, default_frame(), &lines, &suffix);
+        assert_eq!(compacted, lines);
+    }
+
+    #[test]
+    fn compact_removes_redundant_changes() {
+        let lines = [
+            strafe(StrafeDir::Left, 1),
+            change(),
+            change(),
+            strafe(StrafeDir::Right, 1),
+            change(),
+        ];
+        let compacted = compact(&DummyTracer, default_frame(), &lines, &[]);
+        assert_eq!(
+            compacted,
+            [
+                strafe(StrafeDir::Left, 1),
+                change(),
+                strafe(StrafeDir::Right, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn compact_keeps_trailing_change_before_suffix() {
+        let lines = [strafe(StrafeDir::Left, 1), change()];
+        let suffix = [strafe(StrafeDir::Left, 1)];
+        let compacted = compact(&DummyTracer, default_frame(), &lines, &suffix);
+        assert_eq!(compacted, lines);
+
+        // A change at the start of the suffix still overrides it.
+        let suffix = [change(), strafe(StrafeDir::Left, 1)];
+        let compacted = compact(&DummyTracer, default_frame(), &lines, &suffix);
+        assert_eq!(compacted, [strafe(StrafeDir::Left, 1)]);
+    }
+
+    #[test]
+    fn replace_explicit_yaw_with_strafing() {
+        // Pretend that the explicit yaw moved the same way as strafing.
+        let reference = simulate(
+            &DummyTracer,
+            default_frame(),
+            &[strafe(StrafeDir::Left, 1)],
+            &[],
+        );
+
+        let mut lines = [set_yaw(0.)];
+        replace_explicit_yaws(&DummyTracer, &reference, &mut lines);
+        assert_eq!(lines, [strafe(StrafeDir::Left, 1)]);
+    }
+
+    #[test]
+    fn replace_run_of_explicit_yaws_with_one_strafe() {
+        let reference = simulate(
+            &DummyTracer,
+            default_frame(),
+            &[strafe(StrafeDir::Left, 3)],
+            &[],
+        );
+
+        let mut lines = vec![set_yaw(0.), set_yaw(10.), set_yaw(20.)];
+        replace_explicit_yaws(&DummyTracer, &reference, &mut lines);
+        merge_frame_bulks(&DummyTracer, &reference, &mut lines);
+        assert_eq!(lines, [strafe(StrafeDir::Left, 3)]);
+    }
+
+    #[test]
+    fn keep_explicit_yaw_with_movement_keys() {
+        let reference = simulate(
+            &DummyTracer,
+            default_frame(),
+            &[strafe(StrafeDir::Left, 1)],
+            &[],
+        );
+
+        let mut frame_bulk = FrameBulk::with_frame_time("0.001".to_string());
+        frame_bulk.auto_actions.movement = Some(AutoMovement::SetYaw(0.));
+        frame_bulk.movement_keys.forward = true;
+        let lines = [Line::FrameBulk(frame_bulk)];
+
+        let mut compacted = lines.clone();
+        replace_explicit_yaws(&DummyTracer, &reference, &mut compacted);
+        assert_eq!(compacted, lines);
+    }
+
+    #[test]
+    fn keep_explicit_yaw_without_equivalent_strafing() {
+        let lines = [set_yaw(0.)];
+        let reference = simulate(&DummyTracer, default_frame(), &lines, &[]);
+
+        let mut compacted = lines.clone();
+        replace_explicit_yaws(&DummyTracer, &reference, &mut compacted);
+        assert_eq!(compacted, lines);
+    }
+}
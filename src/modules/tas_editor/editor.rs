@@ -1,10 +1,13 @@
+use std::collections::VecDeque;
 use std::error::Error;
 use std::io::Write;
 use std::num::NonZeroU32;
 use std::result::Result;
+use std::str::FromStr;
 use std::{iter, mem};
 
-use bxt_strafe::{Parameters, State, Trace};
+use bxt_strafe::{NonSharedRng, Parameters, Place, State, Trace};
+use glam::{Vec3, Vec3Swizzles};
 use hltas::types::*;
 use hltas::HLTAS;
 use rand::distributions::Uniform;
@@ -13,6 +16,7 @@ use rand::Rng;
 use serde::{Deserialize, Serialize};
 use tap::{Conv, Pipe, Tap, TryConv};
 
+use super::compaction;
 use super::objective::{AttemptResult, Objective};
 use super::remote;
 use super::simulator::Simulator;
@@ -27,6 +31,55 @@ pub struct Frame {
 
     /// Final state after this frame.
     pub state: State,
+
+    /// Shared RNG seed used for this frame.
+    pub shared_seed: u32,
+
+    /// Non-shared RNG state after this frame.
+    ///
+    /// The simulator only changes it on [`Line::Reset`], since any other uses of the non-shared
+    /// RNG are not simulated.
+    pub non_shared_rng: NonSharedRng,
+}
+
+/// How to color the simulated path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathColoring {
+    /// The same color for the entire path.
+    Uniform,
+    /// A gradient from blue (stationary) through green to red (fast) by horizontal speed.
+    Speed,
+    /// Green on the ground, cyan in the air and blue in water.
+    Place,
+    /// Alternating colors for every frame bulk.
+    FrameBulk,
+}
+
+impl FromStr for PathColoring {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" | "uniform" => Ok(Self::Uniform),
+            "speed" => Ok(Self::Speed),
+            "place" => Ok(Self::Place),
+            "frame_bulk" => Ok(Self::FrameBulk),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Settings for drawing the simulated path.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DrawSettings {
+    /// How to color the path.
+    pub coloring: PathColoring,
+    /// Horizontal speed that corresponds to the end of the [`PathColoring::Speed`] gradient.
+    pub max_speed: f32,
+    /// Whether to draw markers at frame bulk boundaries.
+    pub frame_bulk_markers: bool,
+    /// Whether to draw markers at jumps and at the start of ducks.
+    pub action_markers: bool,
 }
 
 pub struct Editor {
@@ -36,7 +89,10 @@ pub struct Editor {
     /// The script being edited.
     hltas: HLTAS,
 
-    /// Movement frames, starting from the initial frame.
+    /// The last part of the script that we're not editing, but still simulate for validation.
+    suffix: Vec<Line>,
+
+    /// Movement frames, starting from the initial frame and including the suffix frames.
     frames: Vec<Frame>,
 
     /// Movement frames from the last mutation, starting from the initial frame.
@@ -44,6 +100,31 @@ pub struct Editor {
 
     /// Generation of this script for remote simulation.
     generation: u16,
+
+    /// Previous versions of the script, oldest first.
+    undo_stack: VecDeque<HistoryEntry>,
+
+    /// Undone versions of the script, most recently undone last.
+    redo_stack: Vec<HistoryEntry>,
+
+    /// Maximum number of entries kept in the undo stack.
+    max_history: usize,
+
+    /// Whether to record at most one history entry per optimization run.
+    collapse_optimization_run: bool,
+
+    /// Whether an improvement has already been recorded during the current optimization run.
+    recorded_optimization_run: bool,
+}
+
+/// A version of the edited script along with its simulated frames.
+#[derive(Clone)]
+struct HistoryEntry {
+    /// The script being edited.
+    hltas: HLTAS,
+
+    /// Movement frames that were simulated for this version of the script.
+    frames: Vec<Frame>,
 }
 
 trait HLTASExt {
@@ -117,14 +198,32 @@ impl HLTASExt for HLTAS {
 }
 
 impl Editor {
+    /// Creates a new [`Editor`] editing the script from `first_frame` up to `end_frame`.
+    ///
+    /// If `end_frame` is [`None`] or past the end of the script, the script is edited until the
+    /// end. Otherwise the frames starting from `end_frame` are kept frozen.
     pub fn new(
         mut hltas: HLTAS,
         first_frame: usize,
+        end_frame: Option<usize>,
         initial_frame: Frame,
         generation: u16,
     ) -> Self {
         let (l, _r) = hltas.line_and_repeat_at_frame(first_frame).unwrap();
 
+        // Make the suffix start with a whole frame bulk. There's nothing to split if end_frame is
+        // past the end of the script.
+        let has_suffix =
+            end_frame.map_or(false, |end_frame| hltas.split_at_frame(end_frame).is_some());
+
+        let suffix = match end_frame {
+            Some(end_frame) if has_suffix => {
+                let (end_l, _r) = hltas.line_and_repeat_at_frame(end_frame).unwrap();
+                hltas.lines.split_off(end_l)
+            }
+            _ => Vec::new(),
+        };
+
         let mut prefix = hltas.clone();
         prefix.lines.truncate(l);
 
@@ -142,25 +241,223 @@ impl Editor {
         Self {
             prefix,
             hltas,
+            suffix,
             frames: vec![initial_frame],
             last_mutation_frames: None,
             generation,
+            undo_stack: VecDeque::new(),
+            redo_stack: Vec::new(),
+            max_history: 100,
+            collapse_optimization_run: true,
+            recorded_optimization_run: false,
         }
     }
 
-    pub fn draw(&self, tri: &TriangleApi) {
-        tri.render_mode(RenderMode::TransColor);
-        tri.color(0., 1., 1., 1.);
+    /// Sets the maximum number of undo entries, dropping the oldest ones if needed.
+    pub fn set_max_history(&mut self, max_history: usize) {
+        self.max_history = max_history;
+
+        let excess = self.undo_stack.len().saturating_sub(max_history);
+        self.undo_stack.drain(..excess);
+    }
+
+    /// Starts a new optimization run.
+    ///
+    /// If `collapse` is `true`, only the first improvement of the run is recorded in the undo
+    /// history, so a single undo reverts the entire run.
+    pub fn start_optimization_run(&mut self, collapse: bool) {
+        self.collapse_optimization_run = collapse;
+        self.recorded_optimization_run = false;
+    }
 
+    /// Returns the simulated frames, starting with the initial frame.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// Returns the number of entries available to undo and redo.
+    pub fn history_len(&self) -> (usize, usize) {
+        (self.undo_stack.len(), self.redo_stack.len())
+    }
+
+    /// Returns the number of frames in the edited part of the script.
+    fn window_frame_count(&self) -> usize {
+        frame_count(&self.hltas.lines)
+    }
+
+    /// Returns the lines of the edited part of the script followed by the frozen suffix.
+    fn lines_with_suffix(&self, lines: &[Line]) -> Vec<Line> {
+        lines.iter().chain(&self.suffix).cloned().collect()
+    }
+
+    fn current_history_entry(&self) -> HistoryEntry {
+        HistoryEntry {
+            hltas: self.hltas.clone(),
+            frames: self.frames.clone(),
+        }
+    }
+
+    fn restore_history_entry(&mut self, entry: HistoryEntry, generation: u16) {
+        self.hltas = entry.hltas;
+        self.frames = entry.frames;
+        self.last_mutation_frames = None;
+
+        // Remote clients may still be simulating mutations of the replaced script. Their results
+        // must not overwrite the restored one.
+        self.generation = generation;
+
+        // The next improvement should be undoable on its own.
+        self.recorded_optimization_run = false;
+    }
+
+    /// Saves the current script to the undo history before it is replaced.
+    fn record_history(&mut self) {
+        self.push_history(self.current_history_entry());
+    }
+
+    /// Saves a previous version of the script to the undo history, clearing the redo history.
+    fn push_history(&mut self, entry: HistoryEntry) {
+        self.redo_stack.clear();
+        self.push_undo(entry);
+    }
+
+    fn push_undo(&mut self, entry: HistoryEntry) {
+        if self.max_history == 0 {
+            return;
+        }
+
+        if self.undo_stack.len() == self.max_history {
+            self.undo_stack.pop_front();
+        }
+
+        self.undo_stack.push_back(entry);
+    }
+
+    /// Saves the current script to the undo history before it is replaced by an optimizer
+    /// improvement.
+    fn record_optimizer_improvement(&mut self) {
+        if self.collapse_optimization_run && self.recorded_optimization_run {
+            return;
+        }
+
+        self.record_history();
+        self.recorded_optimization_run = true;
+    }
+
+    /// Reverts the script to its previous version.
+    ///
+    /// `generation` is the new generation of the script for remote simulation. Returns `false` if
+    /// there was nothing to undo.
+    pub fn undo(&mut self, generation: u16) -> bool {
+        let entry = match self.undo_stack.pop_back() {
+            Some(x) => x,
+            None => return false,
+        };
+
+        self.redo_stack.push(self.current_history_entry());
+        self.restore_history_entry(entry, generation);
+        true
+    }
+
+    /// Re-applies the most recently undone version of the script.
+    ///
+    /// `generation` is the new generation of the script for remote simulation. Returns `false` if
+    /// there was nothing to redo.
+    pub fn redo(&mut self, generation: u16) -> bool {
+        let entry = match self.redo_stack.pop() {
+            Some(x) => x,
+            None => return false,
+        };
+
+        self.push_undo(self.current_history_entry());
+        self.restore_history_entry(entry, generation);
+        true
+    }
+
+    pub fn draw(&self, tri: &TriangleApi, settings: &DrawSettings) {
+        tri.render_mode(RenderMode::TransColor);
         tri.begin(Primitive::Lines);
 
-        for pair in self.frames.windows(2) {
+        // Index of the frame bulk that produced each simulated frame.
+        let frame_bulk_indices: Vec<usize> = self
+            .hltas
+            .lines
+            .iter()
+            .chain(&self.suffix)
+            .filter_map(|line| {
+                if let Line::FrameBulk(frame_bulk) = line {
+                    Some(frame_bulk)
+                } else {
+                    None
+                }
+            })
+            .enumerate()
+            .flat_map(|(i, frame_bulk)| {
+                iter::repeat(i).take(frame_bulk.frame_count.get().try_conv().unwrap())
+            })
+            .collect();
+
+        for (i, pair) in self.frames.windows(2).enumerate() {
             let (prev, next) = (&pair[0], &pair[1]);
 
+            let [r, g, b] = match settings.coloring {
+                PathColoring::Uniform => [0., 1., 1.],
+                PathColoring::Speed => {
+                    speed_color(next.state.player().vel.xy().length(), settings.max_speed)
+                }
+                PathColoring::Place => place_color(next.state.place()),
+                PathColoring::FrameBulk => {
+                    if frame_bulk_indices.get(i).copied().unwrap_or(0) % 2 == 0 {
+                        [0., 1., 1.]
+                    } else {
+                        [1., 0.5, 0.]
+                    }
+                }
+            };
+            tri.color(r, g, b, 1.);
+
             tri.vertex(prev.state.player().pos);
             tri.vertex(next.state.player().pos);
         }
 
+        if settings.frame_bulk_markers {
+            tri.color(1., 1., 1., 1.);
+
+            for (i, pair) in frame_bulk_indices.windows(2).enumerate() {
+                if pair[0] == pair[1] {
+                    continue;
+                }
+
+                // Frame i + 1 is the last frame of the frame bulk pair[0].
+                if let Some(frame) = self.frames.get(i + 1) {
+                    let pos = frame.state.player().pos;
+                    tri.vertex(pos - Vec3::new(0., 0., 4.));
+                    tri.vertex(pos + Vec3::new(0., 0., 4.));
+                }
+            }
+        }
+
+        if settings.action_markers {
+            for pair in self.frames.windows(2) {
+                let (prev, next) = (&pair[0], &pair[1]);
+                let pos = next.state.player().pos;
+
+                if next.state.jumped() {
+                    tri.color(1., 1., 0., 1.);
+                    tri.vertex(pos);
+                    tri.vertex(pos + Vec3::new(0., 0., 16.));
+                }
+
+                if next.state.prev_frame_input().duck && !prev.state.prev_frame_input().duck {
+                    tri.color(1., 0., 1., 1.);
+                    tri.vertex(pos - Vec3::new(4., 0., 0.));
+                    tri.vertex(pos + Vec3::new(4., 0., 0.));
+                    tri.vertex(pos - Vec3::new(0., 4., 0.));
+                    tri.vertex(pos + Vec3::new(0., 4., 0.));
+                }
+            }
+        }
+
         if let Some(frames) = &self.last_mutation_frames {
             tri.color(0., 0.5, 0.5, 1.);
 
@@ -178,13 +475,15 @@ impl Editor {
     pub fn save<W: Write>(&mut self, writer: W) -> Result<(), Box<dyn Error>> {
         let len = self.prefix.lines.len();
         self.prefix.lines.extend(self.hltas.lines.iter().cloned());
+        self.prefix.lines.extend(self.suffix.iter().cloned());
         let rv = self.prefix.to_writer(writer);
         self.prefix.lines.truncate(len);
         Ok(rv?)
     }
 
     pub fn simulate_all<T: Trace>(&mut self, tracer: &T) {
-        let simulator = Simulator::new(tracer, &self.frames, &self.hltas.lines);
+        let lines = self.lines_with_suffix(&self.hltas.lines);
+        let simulator = Simulator::new(tracer, &self.frames, &lines);
         self.frames.extend(simulator);
     }
 
@@ -194,6 +493,7 @@ impl Editor {
         frames: usize,
         random_frames_to_change: usize,
         change_single_frames: bool,
+        mutate_shared_seeds: bool,
         objective: &'a Objective,
     ) -> Option<impl Iterator<Item = AttemptResult> + 'a> {
         self.simulate_all(tracer);
@@ -202,7 +502,8 @@ impl Editor {
             return None;
         }
 
-        let mut high = self.frames.len() - 1;
+        // Only mutate frames within the edited part of the script.
+        let mut high = self.window_frame_count();
         if frames > 0 {
             high = high.min(frames);
         }
@@ -216,7 +517,10 @@ impl Editor {
             // Change several frames.
             let mut stale_frame = self.frames.len() - 1;
             for _ in 0..random_frames_to_change {
-                let frame = if change_single_frames {
+                let frame = if mutate_shared_seeds && rng.gen::<f32>() < 0.1 {
+                    let frame = between.sample(&mut rng);
+                    mutate_shared_seed(&mut rng, &mut hltas, frame)
+                } else if change_single_frames {
                     // Pick a random frame and mutate it.
                     let frame = between.sample(&mut rng);
                     mutate_frame(&mut rng, &mut hltas, frame);
@@ -231,12 +535,14 @@ impl Editor {
             let mut frames = Vec::from(&self.frames[..stale_frame + 1]);
 
             // Simulate the result.
-            let simulator = Simulator::new(tracer, &frames, &hltas.lines);
+            let lines = self.lines_with_suffix(&hltas.lines);
+            let simulator = Simulator::new(tracer, &frames, &lines);
             frames.extend(simulator);
 
             // Check if we got an improvement.
             let result = objective.eval(&frames, &self.frames);
             if result.is_better() {
+                self.record_optimizer_improvement();
                 self.hltas = hltas;
                 self.frames = frames;
             } else {
@@ -250,6 +556,7 @@ impl Editor {
     fn prepare_hltas_for_sending(&mut self) -> HLTAS {
         let len = self.prefix.lines.len();
         self.prefix.lines.extend(self.hltas.lines.iter().cloned());
+        self.prefix.lines.extend(self.suffix.iter().cloned());
 
         // Replace the TAS editor / TAS optim commands with the start sending frames command.
         match &mut self.prefix.lines[len] {
@@ -272,6 +579,22 @@ impl Editor {
         hltas
     }
 
+    /// Returns the edited part of a script that was prepared with
+    /// [`Editor::prepare_hltas_for_sending()`].
+    fn edited_lines_from_remote(&self, mut hltas: HLTAS) -> Vec<Line> {
+        // Leave out the prefix, the suffix and the frame bulk with the done command.
+        let end = hltas.lines.len() - 1 - self.suffix.len();
+        let mut lines: Vec<Line> = hltas.lines.drain(self.prefix.lines.len()..end).collect();
+
+        // Remove the start sending frames command.
+        match &mut lines[0] {
+            Line::FrameBulk(frame_bulk) => frame_bulk.console_command = None,
+            _ => unreachable!(),
+        };
+
+        lines
+    }
+
     pub fn maybe_simulate_all_in_remote_client(&mut self) {
         if self.frames.len() > 1 {
             // Already simulated.
@@ -324,6 +647,7 @@ impl Editor {
         frames: usize,
         random_frames_to_change: usize,
         change_single_frames: bool,
+        mutate_shared_seeds: bool,
         objective: &Objective,
         mut on_improvement: impl FnMut(&str),
     ) {
@@ -334,7 +658,7 @@ impl Editor {
             return;
         }
 
-        remote::receive_simulation_result_from_clients(|mut hltas, generation, mut frames| {
+        remote::receive_simulation_result_from_clients(|hltas, generation, mut frames| {
             if generation != self.generation {
                 return;
             }
@@ -343,23 +667,14 @@ impl Editor {
             self.last_mutation_frames = Some(frames.clone());
 
             if let AttemptResult::Better { value } = objective.eval(&frames, &self.frames) {
-                self.hltas.lines = hltas
-                    .lines
-                    .drain(self.prefix.lines.len()..hltas.lines.len() - 1)
-                    .collect();
-
-                // Remove the start sending frames command.
-                match &mut self.hltas.lines[0] {
-                    Line::FrameBulk(frame_bulk) => frame_bulk.console_command = None,
-                    _ => unreachable!(),
-                };
-
+                self.record_optimizer_improvement();
+                self.hltas.lines = self.edited_lines_from_remote(hltas);
                 self.frames = frames;
                 on_improvement(&value);
             }
         });
 
-        let mut high = self.frames.len() - 1;
+        let mut high = self.window_frame_count();
         if frames > 0 {
             high = high.min(frames);
         }
@@ -372,7 +687,10 @@ impl Editor {
 
             // Change several frames.
             for _ in 0..random_frames_to_change {
-                if change_single_frames {
+                if mutate_shared_seeds && rng.gen::<f32>() < 0.1 {
+                    let frame = between.sample(&mut rng);
+                    mutate_shared_seed(&mut rng, &mut self.hltas, frame);
+                } else if change_single_frames {
                     let frame = between.sample(&mut rng);
                     let frame_bulk = self.hltas.split_single_at_frame(frame).unwrap();
                     mutate_frame_bulk(&mut rng, frame_bulk);
@@ -389,7 +707,31 @@ impl Editor {
         });
     }
 
+    /// Compacts the script without changing any of the simulated frames.
+    ///
+    /// Returns the number of lines that were removed.
+    pub fn compact<T: Trace>(&mut self, tracer: &T) -> usize {
+        let lines = compaction::compact(
+            tracer,
+            self.frames[0].clone(),
+            &self.hltas.lines,
+            &self.suffix,
+        );
+
+        if lines == self.hltas.lines {
+            return 0;
+        }
+
+        self.record_history();
+
+        let removed = self.hltas.lines.len() - lines.len();
+        self.hltas.lines = lines;
+        removed
+    }
+
     pub fn minimize<T: Trace>(&mut self, tracer: &T) {
+        let original = self.current_history_entry();
+
         // Remove unused keys and actions.
         let mut state = self.frames[0].state.clone();
         let mut parameters = self.frames[0].parameters;
@@ -511,7 +853,52 @@ impl Editor {
             }
         }
 
+        // Don't clear the redo history if nothing changed.
+        let changed = new_lines != original.hltas.lines;
         self.hltas.lines = new_lines;
+        if changed {
+            self.push_history(original);
+        }
+    }
+}
+
+/// Returns the number of frames in `lines`.
+fn frame_count(lines: &[Line]) -> usize {
+    lines
+        .iter()
+        .filter_map(|line| {
+            if let Line::FrameBulk(frame_bulk) = line {
+                Some(frame_bulk.frame_count.get().try_conv::<usize>().unwrap())
+            } else {
+                None
+            }
+        })
+        .sum()
+}
+
+/// Returns the path color for the given horizontal speed.
+fn speed_color(speed: f32, max_speed: f32) -> [f32; 3] {
+    let t = if max_speed > 0. {
+        (speed / max_speed).clamp(0., 1.)
+    } else {
+        1.
+    };
+
+    if t < 0.5 {
+        let t = t * 2.;
+        [0., t, 1. - t]
+    } else {
+        let t = (t - 0.5) * 2.;
+        [t, 1. - t, 0.]
+    }
+}
+
+/// Returns the path color for the given player position type.
+fn place_color(place: Place) -> [f32; 3] {
+    match place {
+        Place::Ground => [0., 1., 0.],
+        Place::Air => [0., 1., 1.],
+        Place::Water => [0., 0., 1.],
     }
 }
 
@@ -532,6 +919,27 @@ fn mutate_frame<R: Rng>(rng: &mut R, hltas: &mut HLTAS, frame: usize) {
     mutate_frame_bulk(rng, frame_bulk);
 }
 
+/// Sets a random shared seed starting from `frame` and returns the first changed frame.
+fn mutate_shared_seed<R: Rng>(rng: &mut R, hltas: &mut HLTAS, frame: usize) -> usize {
+    // Keep the first line a frame bulk.
+    let frame = frame.max(1);
+    if hltas.split_at_frame(frame).is_none() {
+        return frame;
+    }
+
+    let (l, _r) = hltas.line_and_repeat_at_frame(frame).unwrap();
+    let seed = rng.gen::<u32>();
+
+    // Change the seed directly preceding the frame bulk if there's one already.
+    if let Line::SharedSeed(existing) = &mut hltas.lines[l - 1] {
+        *existing = seed;
+    } else {
+        hltas.lines.insert(l, Line::SharedSeed(seed));
+    }
+
+    frame
+}
+
 fn mutate_frame_bulk<R: Rng>(rng: &mut R, frame_bulk: &mut FrameBulk) {
     let p = rng.gen::<f32>();
     let strafe_type = if p < 0.01 {
@@ -783,3 +1191,236 @@ fn mutate_auto_actions<R: Rng>(rng: &mut R, frame_bulk: &mut FrameBulk) {
 }
 
 // proptest: after simulating, self.frames.len() = frame count + 1
+
+#[cfg(test)]
+mod tests {
+    use bxt_strafe::DummyTracer;
+
+    use super::*;
+    use crate::modules::tas_editor::objective::{Direction, Variable};
+    use crate::modules::tas_editor::test_utils::default_frame;
+
+    fn frame_bulk(frame_count: u32) -> Line {
+        let mut frame_bulk = FrameBulk::with_frame_time("0.001".to_string());
+        frame_bulk.frame_count = NonZeroU32::new(frame_count).unwrap();
+        Line::FrameBulk(frame_bulk)
+    }
+
+    fn editor() -> Editor {
+        editor_with_window(vec![frame_bulk(1)], 0, None)
+    }
+
+    fn editor_with_window(
+        lines: Vec<Line>,
+        first_frame: usize,
+        end_frame: Option<usize>,
+    ) -> Editor {
+        let mut hltas = HLTAS::default();
+        hltas.lines = lines;
+        Editor::new(hltas, first_frame, end_frame, default_frame(), 0)
+    }
+
+    /// Records the current script in the history and replaces it.
+    fn change(editor: &mut Editor, frame_count: u32) {
+        editor.record_history();
+        editor.hltas.lines = vec![frame_bulk(frame_count)];
+    }
+
+    #[test]
+    fn undo_and_redo() {
+        let mut editor = editor();
+        change(&mut editor, 2);
+
+        assert!(editor.undo(1));
+        assert_eq!(editor.hltas.lines, [frame_bulk(1)]);
+        assert_eq!(editor.history_len(), (0, 1));
+        assert_eq!(editor.generation, 1);
+        assert!(!editor.undo(2));
+
+        assert!(editor.redo(3));
+        assert_eq!(editor.hltas.lines, [frame_bulk(2)]);
+        assert_eq!(editor.history_len(), (1, 0));
+        assert_eq!(editor.generation, 3);
+        assert!(!editor.redo(4));
+    }
+
+    #[test]
+    fn new_change_clears_redo() {
+        let mut editor = editor();
+        change(&mut editor, 2);
+        editor.undo(1);
+
+        change(&mut editor, 3);
+        assert_eq!(editor.history_len(), (1, 0));
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let mut editor = editor();
+        editor.set_max_history(2);
+
+        for frame_count in 2..5 {
+            change(&mut editor, frame_count);
+        }
+        assert_eq!(editor.history_len(), (2, 0));
+
+        editor.undo(1);
+        editor.undo(2);
+        assert_eq!(editor.hltas.lines, [frame_bulk(2)]);
+
+        editor.redo(3);
+        editor.set_max_history(0);
+        assert_eq!(editor.history_len(), (0, 1));
+
+        change(&mut editor, 5);
+        assert_eq!(editor.history_len(), (0, 0));
+    }
+
+    #[test]
+    fn optimization_run_is_collapsed() {
+        let mut editor = editor();
+        editor.start_optimization_run(true);
+
+        for frame_count in 2..5 {
+            editor.record_optimizer_improvement();
+            editor.hltas.lines = vec![frame_bulk(frame_count)];
+        }
+        assert_eq!(editor.history_len(), (1, 0));
+
+        editor.undo(1);
+        assert_eq!(editor.hltas.lines, [frame_bulk(1)]);
+
+        // The first improvement after an undo is undoable on its own.
+        editor.record_optimizer_improvement();
+        assert_eq!(editor.history_len(), (1, 0));
+    }
+
+    #[test]
+    fn optimization_run_is_not_collapsed() {
+        let mut editor = editor();
+        editor.start_optimization_run(false);
+
+        for frame_count in 2..5 {
+            editor.record_optimizer_improvement();
+            editor.hltas.lines = vec![frame_bulk(frame_count)];
+        }
+        assert_eq!(editor.history_len(), (3, 0));
+    }
+
+    #[test]
+    fn minimize_without_changes_keeps_history() {
+        let mut editor = editor();
+        change(&mut editor, 2);
+        editor.undo(1);
+
+        editor.minimize(&DummyTracer);
+        assert_eq!(editor.hltas.lines, [frame_bulk(1)]);
+        assert_eq!(editor.history_len(), (0, 1));
+    }
+
+    #[test]
+    fn minimize_records_changes() {
+        let mut editor = editor();
+        editor.hltas.lines = vec![frame_bulk(1), frame_bulk(2)];
+
+        editor.minimize(&DummyTracer);
+        assert_eq!(editor.hltas.lines, [frame_bulk(3)]);
+        assert_eq!(editor.history_len(), (1, 0));
+
+        editor.undo(1);
+        assert_eq!(editor.hltas.lines, [frame_bulk(1), frame_bulk(2)]);
+    }
+
+    #[test]
+    fn speed_colors() {
+        assert_eq!(speed_color(0., 320.), [0., 0., 1.]);
+        assert_eq!(speed_color(160., 320.), [0., 1., 0.]);
+        assert_eq!(speed_color(320., 320.), [1., 0., 0.]);
+
+        // Speeds outside of the range are clamped.
+        assert_eq!(speed_color(-10., 320.), [0., 0., 1.]);
+        assert_eq!(speed_color(1000., 320.), [1., 0., 0.]);
+
+        // Without a max speed everything is fast.
+        assert_eq!(speed_color(0., 0.), [1., 0., 0.]);
+    }
+
+    #[test]
+    fn place_colors() {
+        assert_eq!(place_color(Place::Ground), [0., 1., 0.]);
+        assert_eq!(place_color(Place::Air), [0., 1., 1.]);
+        assert_eq!(place_color(Place::Water), [0., 0., 1.]);
+    }
+
+    #[test]
+    fn window_frame_count() {
+        let lines = vec![frame_bulk(2), frame_bulk(3)];
+
+        let editor = editor_with_window(lines.clone(), 0, Some(3));
+        assert_eq!(editor.hltas.lines, [frame_bulk(2), frame_bulk(1)]);
+        assert_eq!(editor.suffix, [frame_bulk(2)]);
+        assert_eq!(editor.window_frame_count(), 3);
+
+        let editor = editor_with_window(lines.clone(), 2, None);
+        assert_eq!(editor.prefix.lines, [frame_bulk(2)]);
+        assert!(editor.suffix.is_empty());
+        assert_eq!(editor.window_frame_count(), 3);
+
+        // The end frame is past the end of the script.
+        let editor = editor_with_window(lines, 0, Some(10));
+        assert!(editor.suffix.is_empty());
+        assert_eq!(editor.window_frame_count(), 5);
+    }
+
+    #[test]
+    fn edited_lines_from_remote() {
+        let lines = vec![
+            frame_bulk(1),
+            Line::SharedSeed(5),
+            frame_bulk(2),
+            frame_bulk(3),
+        ];
+        let mut editor = editor_with_window(lines, 1, Some(4));
+        assert_eq!(editor.prefix.lines, [frame_bulk(1), Line::SharedSeed(5)]);
+        assert_eq!(editor.hltas.lines, [frame_bulk(2), frame_bulk(1)]);
+        assert_eq!(editor.suffix, [frame_bulk(2)]);
+
+        let sent = editor.prepare_hltas_for_sending();
+        assert_eq!(sent.lines.len(), 6);
+        assert_eq!(editor.prefix.lines.len(), 2);
+        assert_eq!(editor.edited_lines_from_remote(sent), editor.hltas.lines);
+    }
+
+    #[test]
+    fn optimization_keeps_suffix() {
+        let lines = vec![frame_bulk(5), frame_bulk(5), frame_bulk(5)];
+        let mut editor = editor_with_window(lines, 0, Some(10));
+        let objective = Objective::Console {
+            variable: Variable::PosX,
+            direction: Direction::Maximize,
+            constraint: None,
+        };
+
+        for (change_single_frames, mutate_shared_seeds) in
+            [(false, false), (true, false), (false, true)]
+        {
+            editor
+                .optimize(
+                    &DummyTracer,
+                    0,
+                    4,
+                    change_single_frames,
+                    mutate_shared_seeds,
+                    &objective,
+                )
+                .unwrap()
+                .take(100)
+                .for_each(drop);
+
+            // Mutations keep the number of edited frames, so the suffix stays in place.
+            assert_eq!(editor.suffix, [frame_bulk(5)]);
+            assert_eq!(editor.window_frame_count(), 10);
+            assert_eq!(editor.frames().len(), 16);
+        }
+    }
+}
@@ -4,11 +4,11 @@ use std::fs::{self, File};
 use std::path::PathBuf;
 use std::time::{Duration, Instant};
 
-use bxt_strafe::{Parameters, Player, State};
+use bxt_strafe::{NonSharedRng, Parameters, Player, State};
 use glam::Vec3;
 use hltas::HLTAS;
 
-use self::editor::Frame;
+use self::editor::{DrawSettings, PathColoring};
 use self::objective::{AttemptResult, Constraint, ConstraintType, Direction, Objective, Variable};
 use super::cvars::CVar;
 use super::triangle_drawing::{self, TriangleApi};
@@ -20,15 +20,22 @@ use crate::hooks::engine::{self, con_print};
 use crate::modules::commands::{self, Command};
 use crate::utils::*;
 
+mod compaction;
+pub use compaction::STRAFE_CANDIDATES;
+
 mod editor;
 use editor::Editor;
+pub use editor::Frame;
 
 mod objective;
 
 mod simulator;
 
+#[cfg(test)]
+pub mod test_utils;
+
 mod tracer;
-use tracer::Tracer;
+pub use tracer::Tracer;
 
 mod remote;
 pub use remote::{
@@ -49,8 +56,11 @@ impl Module for TasEditor {
             &BXT_TAS_OPTIM_STOP,
             &BXT_TAS_OPTIM_SAVE,
             &BXT_TAS_OPTIM_MINIMIZE,
+            &BXT_TAS_OPTIM_COMPACT,
             &BXT_TAS_OPTIM_SIMULATION_START_RECORDING_FRAMES,
             &BXT_TAS_OPTIM_SIMULATION_DONE,
+            &BXT_TAS_EDITOR_UNDO,
+            &BXT_TAS_EDITOR_REDO,
         ];
         COMMANDS
     }
@@ -59,6 +69,7 @@ impl Module for TasEditor {
         static CVARS: &[&CVar] = &[
             &BXT_TAS_OPTIM_RANDOM_FRAMES_TO_CHANGE,
             &BXT_TAS_OPTIM_CHANGE_SINGLE_FRAMES,
+            &BXT_TAS_OPTIM_MUTATE_SHARED_SEEDS,
             &BXT_TAS_OPTIM_FRAMES,
             &BXT_TAS_OPTIM_SIMULATION_ACCURACY,
             &BXT_TAS_OPTIM_MULTIPLE_GAMES,
@@ -68,6 +79,12 @@ impl Module for TasEditor {
             &BXT_TAS_OPTIM_DIRECTION,
             &BXT_TAS_OPTIM_VARIABLE,
             &BXT_TAS_OPTIM_RHAI_FILE,
+            &BXT_TAS_OPTIM_COLLAPSE_HISTORY,
+            &BXT_TAS_EDITOR_HISTORY_SIZE,
+            &BXT_TAS_EDITOR_PATH_COLOR,
+            &BXT_TAS_EDITOR_PATH_COLOR_MAX_SPEED,
+            &BXT_TAS_EDITOR_SHOW_FRAME_BULK_MARKERS,
+            &BXT_TAS_EDITOR_SHOW_ACTION_MARKERS,
         ];
         CVARS
     }
@@ -88,6 +105,12 @@ static OBJECTIVE: MainThreadRefCell<Objective> = MainThreadRefCell::new(Objectiv
     constraint: None,
 });
 
+/// Shared seed of the last command.
+static LAST_SHARED_SEED: MainThreadCell<u32> = MainThreadCell::new(0);
+
+/// Generation for the next edited script version sent to remote clients.
+static GENERATION: MainThreadCell<u16> = MainThreadCell::new(0);
+
 static OPTIM_STATS_LAST_PRINTED_AT: MainThreadCell<Option<Instant>> = MainThreadCell::new(None);
 static OPTIM_STATS_ITERATIONS: MainThreadCell<usize> = MainThreadCell::new(0);
 static OPTIM_STATS_ITERATIONS_INVALID: MainThreadCell<usize> = MainThreadCell::new(0);
@@ -97,6 +120,12 @@ static BXT_TAS_OPTIM_RANDOM_FRAMES_TO_CHANGE: CVar =
     CVar::new(b"bxt_tas_optim_random_frames_to_change\0", b"6\0");
 static BXT_TAS_OPTIM_CHANGE_SINGLE_FRAMES: CVar =
     CVar::new(b"bxt_tas_optim_change_single_frames\0", b"0\0");
+/// Whether the optimizer also tries random shared seeds.
+///
+/// The movement simulation doesn't depend on the seeds, so this only helps Rhai objectives that
+/// compute RNG-dependent outcomes with the shared random functions.
+static BXT_TAS_OPTIM_MUTATE_SHARED_SEEDS: CVar =
+    CVar::new(b"bxt_tas_optim_mutate_shared_seeds\0", b"0\0");
 
 static BXT_TAS_OPTIM_SIMULATION_ACCURACY: CVar =
     CVar::new(b"bxt_tas_optim_simulation_accuracy\0", b"0\0");
@@ -110,13 +139,27 @@ static BXT_TAS_OPTIM_CONSTRAINT_TYPE: CVar = CVar::new(b"bxt_tas_optim_constrain
 static BXT_TAS_OPTIM_CONSTRAINT_VALUE: CVar =
     CVar::new(b"bxt_tas_optim_constraint_value\0", b"0\0");
 static BXT_TAS_OPTIM_RHAI_FILE: CVar = CVar::new(b"bxt_tas_optim_rhai_file\0", b"\0");
+static BXT_TAS_OPTIM_COLLAPSE_HISTORY: CVar =
+    CVar::new(b"bxt_tas_optim_collapse_history\0", b"1\0");
+static BXT_TAS_EDITOR_HISTORY_SIZE: CVar = CVar::new(b"bxt_tas_editor_history_size\0", b"100\0");
+
+static BXT_TAS_EDITOR_PATH_COLOR: CVar = CVar::new(b"bxt_tas_editor_path_color\0", b"\0");
+static BXT_TAS_EDITOR_PATH_COLOR_MAX_SPEED: CVar =
+    CVar::new(b"bxt_tas_editor_path_color_max_speed\0", b"2000\0");
+static BXT_TAS_EDITOR_SHOW_FRAME_BULK_MARKERS: CVar =
+    CVar::new(b"bxt_tas_editor_show_frame_bulk_markers\0", b"0\0");
+static BXT_TAS_EDITOR_SHOW_ACTION_MARKERS: CVar =
+    CVar::new(b"bxt_tas_editor_show_action_markers\0", b"0\0");
 
 static BXT_TAS_OPTIM_INIT: Command = Command::new(
     b"_bxt_tas_optim_init\0",
     handler!(
-        "Usage: _bxt_tas_optim_init <script.hltas> <frame number>\n \
-          Initializes the optimization with the given script, starting from the given frame.\n",
-        optim_init as fn(_, _, _)
+        "Usage: _bxt_tas_optim_init <script.hltas> <frame number> [end frame number]\n \
+          Initializes the optimization with the given script, starting from the given frame.\n \
+          If the end frame is given, only the frames before it are optimized, and the rest of \
+          the script is kept as is.\n",
+        optim_init as fn(_, _, _),
+        optim_init_with_end as fn(_, _, _, _)
     ),
 );
 
@@ -138,6 +181,27 @@ unsafe fn get_cvar_f32(marker: MainThreadMarker, name: &str) -> Option<f32> {
 }
 
 fn optim_init(marker: MainThreadMarker, path: PathBuf, first_frame: usize) {
+    init(marker, path, first_frame, None);
+}
+
+fn optim_init_with_end(
+    marker: MainThreadMarker,
+    path: PathBuf,
+    first_frame: usize,
+    end_frame: usize,
+) {
+    if end_frame <= first_frame {
+        con_print(
+            marker,
+            "The end frame must be greater than the first frame.\n",
+        );
+        return;
+    }
+
+    init(marker, path, first_frame, Some(end_frame));
+}
+
+fn init(marker: MainThreadMarker, path: PathBuf, first_frame: usize, end_frame: Option<usize>) {
     if !TasEditor.is_enabled(marker) {
         return;
     }
@@ -179,28 +243,7 @@ fn optim_init(marker: MainThreadMarker, path: PathBuf, first_frame: usize) {
     };
 
     // TODO: get current parameters.
-    let parameters = unsafe {
-        Parameters {
-            frame_time: *engine::host_frametime.get(marker) as f32,
-            max_velocity: get_cvar_f32(marker, "sv_maxvelocity").unwrap_or(2000.),
-            max_speed: get_cvar_f32(marker, "sv_maxspeed").unwrap_or(320.),
-            stop_speed: get_cvar_f32(marker, "sv_stopspeed").unwrap_or(100.),
-            friction: get_cvar_f32(marker, "sv_friction").unwrap_or(4.),
-            edge_friction: get_cvar_f32(marker, "edgefriction").unwrap_or(2.),
-            ent_friction: engine::player_edict(marker)
-                .map(|x| x.as_ref().v.friction)
-                .unwrap_or(1.),
-            accelerate: get_cvar_f32(marker, "sv_accelerate").unwrap_or(10.),
-            air_accelerate: get_cvar_f32(marker, "sv_airaccelerate").unwrap_or(10.),
-            gravity: get_cvar_f32(marker, "sv_gravity").unwrap_or(800.),
-            ent_gravity: engine::player_edict(marker)
-                .map(|x| x.as_ref().v.gravity)
-                .unwrap_or(1.),
-            step_size: get_cvar_f32(marker, "sv_stepsize").unwrap_or(18.),
-            bounce: get_cvar_f32(marker, "sv_bounce").unwrap_or(1.),
-            bhop_cap: get_cvar_f32(marker, "bxt_bhopcap").unwrap_or(0.) != 0.,
-        }
-    };
+    let parameters = unsafe { current_parameters(marker) };
 
     // TODO: this is unsafe outside of gameplay.
     let tracer = unsafe { Tracer::new(marker, false) }.unwrap();
@@ -208,13 +251,14 @@ fn optim_init(marker: MainThreadMarker, path: PathBuf, first_frame: usize) {
     let initial_frame = Frame {
         state: State::new(&tracer, parameters, player),
         parameters,
+        shared_seed: LAST_SHARED_SEED.get(marker),
+        non_shared_rng: non_shared_rng(marker),
     };
 
-    static GENERATION: MainThreadCell<u16> = MainThreadCell::new(0);
-    let generation = GENERATION.get(marker);
-    GENERATION.set(marker, generation.wrapping_add(1));
-
-    *EDITOR.borrow_mut(marker) = Some(Editor::new(hltas, first_frame, initial_frame, generation));
+    let generation = next_generation(marker);
+    let mut editor = Editor::new(hltas, first_frame, end_frame, initial_frame, generation);
+    editor.set_max_history(BXT_TAS_EDITOR_HISTORY_SIZE.as_u64(marker) as usize);
+    *EDITOR.borrow_mut(marker) = Some(editor);
 
     if let Err(err) = remote::start_server() {
         con_print(
@@ -228,7 +272,11 @@ static BXT_TAS_OPTIM_RUN: Command = Command::new(
     b"bxt_tas_optim_run\0",
     handler!(
         "Usage: bxt_tas_optim_run\n \
-          Starts the optimization.\n",
+          Starts the optimization.\n \
+          Set bxt_tas_optim_mutate_shared_seeds to 1 to also try random shared seeds. Movement \
+          doesn't depend on the seeds, so this only helps Rhai objectives that compute outcomes \
+          like weapon spread with shared_random_float(), shared_random_long(), random_float() \
+          and random_long().\n",
         optim_run as fn(_)
     ),
 );
@@ -247,7 +295,7 @@ fn optim_run(marker: MainThreadMarker) {
     if !script_path.is_empty() {
         match fs::read_to_string(BXT_TAS_OPTIM_RHAI_FILE.to_os_string(marker)) {
             Ok(code) => {
-                let engine = rhai::Engine::new();
+                let engine = objective::rhai_engine();
                 match engine.compile(code) {
                     Ok(ast) => {
                         let does_function_exist = |name, args: &mut [rhai::Dynamic]| {
@@ -401,6 +449,11 @@ fn optim_run(marker: MainThreadMarker) {
         };
     }
 
+    if let Some(editor) = &mut *EDITOR.borrow_mut(marker) {
+        editor.set_max_history(BXT_TAS_EDITOR_HISTORY_SIZE.as_u64(marker) as usize);
+        editor.start_optimization_run(BXT_TAS_OPTIM_COLLAPSE_HISTORY.as_bool(marker));
+    }
+
     OPTIMIZE.set(marker, true);
 
     OPTIM_STATS_LAST_PRINTED_AT.set(marker, Some(Instant::now()));
@@ -465,6 +518,85 @@ fn optim_minimize(marker: MainThreadMarker) {
     }
 }
 
+static BXT_TAS_OPTIM_COMPACT: Command = Command::new(
+    b"bxt_tas_optim_compact\0",
+    handler!(
+        "Usage: bxt_tas_optim_compact\n \
+          Compacts the optimized script without changing any of the simulated frames.\n",
+        optim_compact as fn(_)
+    ),
+);
+
+fn optim_compact(marker: MainThreadMarker) {
+    if let Some(editor) = &mut *EDITOR.borrow_mut(marker) {
+        // TODO: this is unsafe outside of gameplay.
+        let tracer = unsafe { Tracer::new(marker, false) }.unwrap();
+        let removed = editor.compact(&tracer);
+        con_print(marker, &format!("Removed {removed} lines.\n"));
+    } else {
+        con_print(
+            marker,
+            "There's nothing to compact. Call _bxt_tas_optim_init first!\n",
+        );
+    }
+}
+
+static BXT_TAS_EDITOR_UNDO: Command = Command::new(
+    b"bxt_tas_editor_undo\0",
+    handler!(
+        "Usage: bxt_tas_editor_undo\n \
+          Reverts the last change to the edited script.\n",
+        editor_undo as fn(_)
+    ),
+);
+
+fn editor_undo(marker: MainThreadMarker) {
+    if let Some(editor) = &mut *EDITOR.borrow_mut(marker) {
+        if editor.undo(next_generation(marker)) {
+            let (undo, redo) = editor.history_len();
+            con_print(
+                marker,
+                &format!("Undone. {undo} more changes to undo, {redo} to redo.\n"),
+            );
+        } else {
+            con_print(marker, "There's nothing to undo.\n");
+        }
+    } else {
+        con_print(
+            marker,
+            "There's nothing to undo. Call _bxt_tas_optim_init first!\n",
+        );
+    }
+}
+
+static BXT_TAS_EDITOR_REDO: Command = Command::new(
+    b"bxt_tas_editor_redo\0",
+    handler!(
+        "Usage: bxt_tas_editor_redo\n \
+          Re-applies the last change reverted with bxt_tas_editor_undo.\n",
+        editor_redo as fn(_)
+    ),
+);
+
+fn editor_redo(marker: MainThreadMarker) {
+    if let Some(editor) = &mut *EDITOR.borrow_mut(marker) {
+        if editor.redo(next_generation(marker)) {
+            let (undo, redo) = editor.history_len();
+            con_print(
+                marker,
+                &format!("Redone. {undo} changes to undo, {redo} more to redo.\n"),
+            );
+        } else {
+            con_print(marker, "There's nothing to redo.\n");
+        }
+    } else {
+        con_print(
+            marker,
+            "There's nothing to redo. Call _bxt_tas_optim_init first!\n",
+        );
+    }
+}
+
 static BXT_TAS_OPTIM_SIMULATION_START_RECORDING_FRAMES: Command = Command::new(
     b"_bxt_tas_optim_simulation_start_recording_frames\0",
     handler!(
@@ -512,7 +644,11 @@ pub unsafe fn maybe_receive_messages_from_remote_server(marker: MainThreadMarker
     }
 }
 
-pub unsafe fn on_cmd_start(marker: MainThreadMarker) {
+pub unsafe fn on_cmd_start(marker: MainThreadMarker, random_seed: u32) {
+    // The state at the start of this command is the result of the previous command.
+    let shared_seed = LAST_SHARED_SEED.get(marker);
+    LAST_SHARED_SEED.set(marker, random_seed);
+
     remote::on_frame_simulated(|| {
         let player = player_data(marker).unwrap();
 
@@ -538,11 +674,57 @@ pub unsafe fn on_cmd_start(marker: MainThreadMarker) {
         Frame {
             state: State::new(&tracer, parameters, player),
             parameters,
+            shared_seed,
+            non_shared_rng: non_shared_rng(marker),
         }
     });
 }
 
-unsafe fn player_data(marker: MainThreadMarker) -> Option<Player> {
+/// Returns the current non-shared RNG state of the engine.
+fn non_shared_rng(marker: MainThreadMarker) -> NonSharedRng {
+    engine::rng_state(marker)
+        .map(|rng| NonSharedRng {
+            idum: rng.idum,
+            iy: rng.iy,
+            iv: rng.iv,
+        })
+        .unwrap_or_default()
+}
+
+/// Returns the current movement parameters.
+///
+/// # Safety
+///
+/// `host_frametime` and the player edict must be valid to read from.
+pub unsafe fn current_parameters(marker: MainThreadMarker) -> Parameters {
+    Parameters {
+        frame_time: *engine::host_frametime.get(marker) as f32,
+        max_velocity: get_cvar_f32(marker, "sv_maxvelocity").unwrap_or(2000.),
+        max_speed: get_cvar_f32(marker, "sv_maxspeed").unwrap_or(320.),
+        stop_speed: get_cvar_f32(marker, "sv_stopspeed").unwrap_or(100.),
+        friction: get_cvar_f32(marker, "sv_friction").unwrap_or(4.),
+        edge_friction: get_cvar_f32(marker, "edgefriction").unwrap_or(2.),
+        ent_friction: engine::player_edict(marker)
+            .map(|x| x.as_ref().v.friction)
+            .unwrap_or(1.),
+        accelerate: get_cvar_f32(marker, "sv_accelerate").unwrap_or(10.),
+        air_accelerate: get_cvar_f32(marker, "sv_airaccelerate").unwrap_or(10.),
+        gravity: get_cvar_f32(marker, "sv_gravity").unwrap_or(800.),
+        ent_gravity: engine::player_edict(marker)
+            .map(|x| x.as_ref().v.gravity)
+            .unwrap_or(1.),
+        step_size: get_cvar_f32(marker, "sv_stepsize").unwrap_or(18.),
+        bounce: get_cvar_f32(marker, "sv_bounce").unwrap_or(1.),
+        bhop_cap: get_cvar_f32(marker, "bxt_bhopcap").unwrap_or(0.) != 0.,
+    }
+}
+
+/// Returns the current player data.
+///
+/// # Safety
+///
+/// The player edict must be valid to read from.
+pub unsafe fn player_data(marker: MainThreadMarker) -> Option<Player> {
     // SAFETY: we're not calling any engine functions while the reference is alive.
     let edict = engine::player_edict(marker)?.as_ref();
 
@@ -556,6 +738,19 @@ unsafe fn player_data(marker: MainThreadMarker) -> Option<Player> {
     })
 }
 
+/// Returns a new generation, so remote simulation results of older scripts are ignored.
+fn next_generation(marker: MainThreadMarker) -> u16 {
+    let generation = GENERATION.get(marker);
+    GENERATION.set(marker, generation.wrapping_add(1));
+    generation
+}
+
+/// Returns the frames simulated by the TAS editor, if it's enabled.
+pub fn simulated_frames(marker: MainThreadMarker) -> Option<Vec<Frame>> {
+    let editor = EDITOR.borrow(marker);
+    editor.as_ref().map(|editor| editor.frames().to_vec())
+}
+
 pub fn draw(marker: MainThreadMarker, tri: &TriangleApi) {
     if let Some(editor) = &mut *EDITOR.borrow_mut(marker) {
         if BXT_TAS_OPTIM_MULTIPLE_GAMES.as_bool(marker) {
@@ -564,6 +759,7 @@ pub fn draw(marker: MainThreadMarker, tri: &TriangleApi) {
                     BXT_TAS_OPTIM_FRAMES.as_u64(marker) as usize,
                     BXT_TAS_OPTIM_RANDOM_FRAMES_TO_CHANGE.as_u64(marker) as usize,
                     BXT_TAS_OPTIM_CHANGE_SINGLE_FRAMES.as_bool(marker),
+                    BXT_TAS_OPTIM_MUTATE_SHARED_SEEDS.as_bool(marker),
                     &*OBJECTIVE.borrow(marker),
                     |value| {
                         con_print(marker, &format!("Found new best value: {value}\n"));
@@ -585,6 +781,7 @@ pub fn draw(marker: MainThreadMarker, tri: &TriangleApi) {
                     BXT_TAS_OPTIM_FRAMES.as_u64(marker) as usize,
                     BXT_TAS_OPTIM_RANDOM_FRAMES_TO_CHANGE.as_u64(marker) as usize,
                     BXT_TAS_OPTIM_CHANGE_SINGLE_FRAMES.as_bool(marker),
+                    BXT_TAS_OPTIM_MUTATE_SHARED_SEEDS.as_bool(marker),
                     &*OBJECTIVE.borrow(marker),
                 ) {
                     let start = Instant::now();
@@ -634,6 +831,16 @@ pub fn draw(marker: MainThreadMarker, tri: &TriangleApi) {
             editor.simulate_all(&tracer);
         }
 
-        editor.draw(tri);
+        let settings = DrawSettings {
+            // Fall back to the uniform color rather than spamming the console every frame.
+            coloring: BXT_TAS_EDITOR_PATH_COLOR
+                .to_string(marker)
+                .parse()
+                .unwrap_or(PathColoring::Uniform),
+            max_speed: BXT_TAS_EDITOR_PATH_COLOR_MAX_SPEED.as_f32(marker),
+            frame_bulk_markers: BXT_TAS_EDITOR_SHOW_FRAME_BULK_MARKERS.as_bool(marker),
+            action_markers: BXT_TAS_EDITOR_SHOW_ACTION_MARKERS.as_bool(marker),
+        };
+        editor.draw(tri, &settings);
     }
 }
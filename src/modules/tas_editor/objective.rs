@@ -2,9 +2,9 @@
 
 use std::str::FromStr;
 
-use bxt_strafe::State;
+use bxt_strafe::{shared_random_float, shared_random_long, NonSharedRng, State};
 use glam::Vec3Swizzles;
-use rhai::serde::to_dynamic;
+use rhai::serde::{from_dynamic, to_dynamic};
 
 use super::editor::Frame;
 
@@ -204,10 +204,10 @@ impl Objective {
                     if should_pass_all_frames {
                         frames
                             .iter()
-                            .map(|f| to_dynamic(f.state.player()).unwrap())
+                            .map(frame_to_dynamic)
                             .collect::<rhai::Dynamic>()
                     } else {
-                        to_dynamic(frames.last().unwrap().state.player()).unwrap()
+                        frame_to_dynamic(frames.last().unwrap())
                     }
                 };
 
@@ -293,3 +293,148 @@ impl Objective {
         }
     }
 }
+
+/// Creates the Rhai engine for objective scripts.
+///
+/// Besides the standard library, the engine has the game's random functions so that objectives can
+/// compute outcomes that depend on the RNG state of a frame, like weapon spread:
+///
+/// - `shared_random_long(seed, low, high)` and `shared_random_float(seed, low, high)` are
+///   `UTIL_SharedRandomLong()` and `UTIL_SharedRandomFloat()`. Pass them `shared_seed` with the
+///   offset that the weapon code adds.
+/// - `random_long(rng, low, high)` and `random_float(rng, low, high)` return the next value of the
+///   engine's `RandomLong()` and `RandomFloat()` for a frame's `non_shared_rng`.
+pub fn rhai_engine() -> rhai::Engine {
+    let mut engine = rhai::Engine::new();
+
+    engine.register_fn(
+        "shared_random_long",
+        |seed: rhai::INT, low: rhai::INT, high: rhai::INT| {
+            shared_random_long(seed as u32, low as i32, high as i32) as rhai::INT
+        },
+    );
+    engine.register_fn(
+        "shared_random_float",
+        |seed: rhai::INT, low: rhai::FLOAT, high: rhai::FLOAT| {
+            shared_random_float(seed as u32, low, high)
+        },
+    );
+    engine.register_fn("random_long", non_shared_random_long);
+    engine.register_fn("random_float", non_shared_random_float);
+
+    engine
+}
+
+fn non_shared_random_long(
+    rng: rhai::Map,
+    low: rhai::INT,
+    high: rhai::INT,
+) -> Result<rhai::INT, Box<rhai::EvalAltResult>> {
+    let mut rng: NonSharedRng = from_dynamic(&rng.into())?;
+    Ok(rng.random_long(low as i32, high as i32) as rhai::INT)
+}
+
+fn non_shared_random_float(
+    rng: rhai::Map,
+    low: rhai::FLOAT,
+    high: rhai::FLOAT,
+) -> Result<rhai::FLOAT, Box<rhai::EvalAltResult>> {
+    let mut rng: NonSharedRng = from_dynamic(&rng.into())?;
+    Ok(rng.random_float(low, high))
+}
+
+/// Converts a frame into a Rhai object.
+///
+/// This is the player data with the RNG state added as `shared_seed` and `non_shared_rng`.
+fn frame_to_dynamic(frame: &Frame) -> rhai::Dynamic {
+    let mut map = to_dynamic(frame.state.player())
+        .unwrap()
+        .cast::<rhai::Map>();
+    map.insert(
+        "shared_seed".into(),
+        (frame.shared_seed as rhai::INT).into(),
+    );
+    map.insert(
+        "non_shared_rng".into(),
+        to_dynamic(frame.non_shared_rng).unwrap(),
+    );
+    map.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::tas_editor::test_utils::default_frame;
+
+    fn frame(shared_seed: u32, non_shared_seed: i32) -> Frame {
+        Frame {
+            shared_seed,
+            non_shared_rng: NonSharedRng::seeded(non_shared_seed),
+            ..default_frame()
+        }
+    }
+
+    fn rhai_objective(code: &str) -> Objective {
+        let engine = rhai_engine();
+        let ast = engine.compile(code).unwrap();
+        Objective::Rhai { engine, ast }
+    }
+
+    #[test]
+    fn rhai_random_functions_are_reproducible() {
+        let objective = rhai_objective(
+            r#"
+            fn is_valid(curr) { true }
+            fn is_better(curr, best) { true }
+            fn to_string(curr) {
+                "" + shared_random_long(curr.shared_seed, 0, 1000)
+                    + " " + random_long(curr.non_shared_rng, 0, 1000)
+            }
+            "#,
+        );
+
+        let frames = [frame(1234, 5)];
+        let expected = format!(
+            "{} {}",
+            shared_random_long(1234, 0, 1000),
+            NonSharedRng::seeded(5).random_long(0, 1000)
+        );
+
+        for _ in 0..2 {
+            match objective.eval(&frames, &frames) {
+                AttemptResult::Better { value } => assert_eq!(value, expected),
+                result => panic!("unexpected result: {result:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn rhai_objective_compares_spread() {
+        let objective = rhai_objective(
+            r#"
+            fn is_valid(curr) { true }
+            fn is_better(curr, best) {
+                shared_random_float(curr.shared_seed, -0.5, 0.5).abs()
+                    < shared_random_float(best.shared_seed, -0.5, 0.5).abs()
+                    && random_float(curr.non_shared_rng, 0.0, 1.0) >= 0.0
+            }
+            fn to_string(curr) { "" }
+            "#,
+        );
+
+        let spread = |seed| shared_random_float(seed, -0.5, 0.5).abs();
+        let (worse, better) = (0..100)
+            .flat_map(|a| (0..100).map(move |b| (a, b)))
+            .find(|&(a, b)| spread(b) < spread(a))
+            .unwrap();
+
+        assert!(matches!(
+            objective.eval(&[frame(better, 0)], &[frame(worse, 0)]),
+            AttemptResult::Better { .. }
+        ));
+        assert!(matches!(
+            objective.eval(&[frame(worse, 0)], &[frame(better, 0)]),
+            AttemptResult::Worse
+        ));
+    }
+}
@@ -1,5 +1,5 @@
 /// Frame simulator.
-use bxt_strafe::Trace;
+use bxt_strafe::{NonSharedRng, Trace};
 use hltas::types::Line;
 
 use super::Frame;
@@ -16,6 +16,8 @@ pub struct Simulator<'a, T> {
     repeat: u32,
     /// Frame to simulate from.
     last_frame: Frame,
+    /// Shared seed set by a [`Line::SharedSeed`] for the next frame.
+    pending_shared_seed: Option<u32>,
 }
 
 impl<'a, T> Simulator<'a, T> {
@@ -47,6 +49,7 @@ impl<'a, T> Simulator<'a, T> {
                     lines: &lines[l..],
                     repeat: 0,
                     last_frame: existing_frames.last().unwrap().clone(),
+                    pending_shared_seed: None,
                 };
             }
 
@@ -58,6 +61,7 @@ impl<'a, T> Simulator<'a, T> {
                             lines: &lines[l..],
                             repeat,
                             last_frame: existing_frames.last().unwrap().clone(),
+                            pending_shared_seed: None,
                         };
                     }
 
@@ -78,6 +82,7 @@ impl<'a, T> Simulator<'a, T> {
             lines: &[],
             repeat: 0,
             last_frame: existing_frames.last().unwrap().clone(),
+            pending_shared_seed: None,
         }
     }
 }
@@ -86,12 +91,17 @@ impl<'a, T: Trace> Iterator for Simulator<'a, T> {
     type Item = Frame;
 
     fn next(&mut self) -> Option<Self::Item> {
-        for line in self.lines {
+        while let Some((line, rest)) = self.lines.split_first() {
             match line {
                 Line::FrameBulk(frame_bulk) => {
                     assert!(self.repeat < frame_bulk.frame_count.get());
 
-                    let Frame { parameters, state } = &mut self.last_frame;
+                    let Frame {
+                        parameters,
+                        state,
+                        shared_seed,
+                        ..
+                    } = &mut self.last_frame;
 
                     // Only set frame-time on the first repeat since subsequent repeats inherit it.
                     if self.repeat == 0 {
@@ -107,25 +117,39 @@ impl<'a, T: Trace> Iterator for Simulator<'a, T> {
 
                     *state = new_state;
 
+                    // The shared seed is the random_seed that the engine passes to CmdStart() and
+                    // the client weapon prediction. The engine increments it by one for every
+                    // command, which can be seen in the "ss" field of TAS logs, unless a
+                    // Line::SharedSeed sets it explicitly.
+                    *shared_seed = self
+                        .pending_shared_seed
+                        .take()
+                        .unwrap_or_else(|| shared_seed.wrapping_add(1));
+
                     self.repeat += 1;
                     if self.repeat == frame_bulk.frame_count.get() {
-                        self.lines = &self.lines[1..];
+                        self.lines = rest;
                         self.repeat = 0;
                     }
 
                     return Some(self.last_frame.clone());
                 }
                 Line::Save(_) => (),
-                Line::SharedSeed(_) => (),
+                Line::SharedSeed(seed) => self.pending_shared_seed = Some(*seed),
                 Line::Buttons(_) => (),
                 Line::LGAGSTMinSpeed(_) => (),
-                Line::Reset { non_shared_seed: _ } => (),
+                Line::Reset { non_shared_seed } => {
+                    self.last_frame.non_shared_rng = NonSharedRng::seeded(*non_shared_seed as i32);
+                }
                 Line::Comment(_) => (),
                 Line::VectorialStrafing(_) => (),
                 Line::VectorialStrafingConstraints(_) => (),
                 Line::Change(_) => (),
                 Line::TargetYawOverride(_) => (),
             }
+
+            // Non-frame-bulk lines must only be applied once.
+            self.lines = rest;
         }
 
         None
@@ -136,52 +160,11 @@ impl<'a, T: Trace> Iterator for Simulator<'a, T> {
 mod tests {
     use std::num::NonZeroU32;
 
-    use bxt_strafe::{DummyTracer, Parameters, Player, State};
-    use glam::Vec3;
+    use bxt_strafe::DummyTracer;
     use hltas::types::FrameBulk;
 
     use super::*;
-
-    fn default_parameters() -> Parameters {
-        Parameters {
-            frame_time: 0.010000001,
-            max_velocity: 2000.,
-            max_speed: 320.,
-            stop_speed: 100.,
-            friction: 4.,
-            edge_friction: 2.,
-            ent_friction: 1.,
-            accelerate: 10.,
-            air_accelerate: 10.,
-            gravity: 800.,
-            ent_gravity: 1.,
-            step_size: 18.,
-            bounce: 1.,
-            bhop_cap: false,
-        }
-    }
-
-    fn default_player() -> Player {
-        Player {
-            pos: Vec3::ZERO,
-            vel: Vec3::ZERO,
-            base_vel: Vec3::ZERO,
-            ducking: false,
-            in_duck_animation: false,
-            duck_time: 0,
-        }
-    }
-
-    fn default_state() -> State {
-        State::new(&DummyTracer, default_parameters(), default_player())
-    }
-
-    fn default_frame() -> Frame {
-        Frame {
-            parameters: default_parameters(),
-            state: default_state(),
-        }
-    }
+    use crate::modules::tas_editor::test_utils::default_frame;
 
     #[test]
     fn simulator_empty_lines() {
@@ -241,6 +224,55 @@ mod tests {
         assert_eq!(simulator.lines, &lines[1..]);
     }
 
+    #[test]
+    fn simulator_non_frame_bulk_lines_in_between() {
+        let lines = [
+            Line::FrameBulk(FrameBulk::with_frame_time("0.001".to_string())),
+            Line::LGAGSTMinSpeed(0.),
+            Line::FrameBulk(FrameBulk::with_frame_time("0.001".to_string())),
+        ];
+        let simulator = Simulator::new(&DummyTracer, &[default_frame()], &lines);
+        assert_eq!(simulator.count(), 2);
+    }
+
+    #[test]
+    fn simulator_increments_shared_seed() {
+        let lines = [Line::FrameBulk(FrameBulk {
+            frame_count: NonZeroU32::new(2).unwrap(),
+            ..FrameBulk::with_frame_time("0.001".to_string())
+        })];
+        let simulator = Simulator::new(&DummyTracer, &[default_frame()], &lines);
+        let seeds: Vec<_> = simulator.map(|frame| frame.shared_seed).collect();
+        assert_eq!(seeds, [1, 2]);
+    }
+
+    #[test]
+    fn simulator_sets_shared_seed() {
+        let lines = [
+            Line::SharedSeed(10),
+            Line::FrameBulk(FrameBulk {
+                frame_count: NonZeroU32::new(2).unwrap(),
+                ..FrameBulk::with_frame_time("0.001".to_string())
+            }),
+        ];
+        let simulator = Simulator::new(&DummyTracer, &[default_frame()], &lines);
+        let seeds: Vec<_> = simulator.map(|frame| frame.shared_seed).collect();
+        assert_eq!(seeds, [10, 11]);
+    }
+
+    #[test]
+    fn simulator_resets_non_shared_rng() {
+        let lines = [
+            Line::Reset { non_shared_seed: 5 },
+            Line::FrameBulk(FrameBulk::with_frame_time("0.001".to_string())),
+        ];
+        let mut simulator = Simulator::new(&DummyTracer, &[default_frame()], &lines);
+        assert_eq!(
+            simulator.next().unwrap().non_shared_rng,
+            NonSharedRng::seeded(5)
+        );
+    }
+
     #[test]
     fn simulator_middle_of_frame_bulk() {
         let lines = [
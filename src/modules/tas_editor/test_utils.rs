@@ -0,0 +1,49 @@
+//! Fixtures for tests that simulate movement.
+
+use bxt_strafe::{DummyTracer, NonSharedRng, Parameters, Player, State};
+use glam::Vec3;
+
+use super::Frame;
+
+pub fn default_parameters() -> Parameters {
+    Parameters {
+        frame_time: 0.010000001,
+        max_velocity: 2000.,
+        max_speed: 320.,
+        stop_speed: 100.,
+        friction: 4.,
+        edge_friction: 2.,
+        ent_friction: 1.,
+        accelerate: 10.,
+        air_accelerate: 10.,
+        gravity: 800.,
+        ent_gravity: 1.,
+        step_size: 18.,
+        bounce: 1.,
+        bhop_cap: false,
+    }
+}
+
+pub fn default_player() -> Player {
+    Player {
+        pos: Vec3::ZERO,
+        vel: Vec3::ZERO,
+        base_vel: Vec3::ZERO,
+        ducking: false,
+        in_duck_animation: false,
+        duck_time: 0,
+    }
+}
+
+pub fn default_state() -> State {
+    State::new(&DummyTracer, default_parameters(), default_player())
+}
+
+pub fn default_frame() -> Frame {
+    Frame {
+        parameters: default_parameters(),
+        state: default_state(),
+        shared_seed: 0,
+        non_shared_rng: NonSharedRng::default(),
+    }
+}
@@ -0,0 +1,323 @@
+//! Finding where two TAS logs diverge.
+
+use std::fmt::{self, Debug, Display};
+
+use super::reader::{CmdFrame, PhysicsFrame, PmState, TasLogFile};
+
+/// The first difference between two TAS logs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Index of the physics frame in the first log.
+    pub left_physics_frame: usize,
+
+    /// Index of the physics frame in the second log.
+    pub right_physics_frame: usize,
+
+    /// Index of the command frame inside the physics frame, if the difference is inside one.
+    pub cmd_frame: Option<usize>,
+
+    /// Name of the field that differs.
+    pub field: String,
+
+    /// Value in the first log.
+    pub left: String,
+
+    /// Value in the second log.
+    pub right: String,
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "physics frame {}", self.left_physics_frame)?;
+        if self.right_physics_frame != self.left_physics_frame {
+            write!(
+                f,
+                " (physics frame {} in the second log)",
+                self.right_physics_frame
+            )?;
+        }
+        if let Some(cmd_frame) = self.cmd_frame {
+            write!(f, ", command frame {}", cmd_frame)?;
+        }
+        write!(
+            f,
+            ": {} differs: {} vs. {}",
+            self.field, self.left, self.right
+        )
+    }
+}
+
+struct Difference {
+    field: String,
+    left: String,
+    right: String,
+}
+
+fn check<T: PartialEq + Debug>(field: &str, left: &T, right: &T) -> Result<(), Difference> {
+    if left == right {
+        Ok(())
+    } else {
+        Err(Difference {
+            field: field.to_string(),
+            left: format!("{:?}", left),
+            right: format!("{:?}", right),
+        })
+    }
+}
+
+fn check_pm_state(
+    name: &str,
+    left: &Option<PmState>,
+    right: &Option<PmState>,
+) -> Result<(), Difference> {
+    let (left, right) = match (left, right) {
+        (Some(left), Some(right)) => (left, right),
+        _ => return check(name, &left.is_some(), &right.is_some()),
+    };
+
+    check(&format!("{name}.pos"), &left.position, &right.position)?;
+    check(&format!("{name}.vel"), &left.velocity, &right.velocity)?;
+    check(&format!("{name}.og"), &left.on_ground, &right.on_ground)?;
+    check(
+        &format!("{name}.bvel"),
+        &left.base_velocity,
+        &right.base_velocity,
+    )?;
+    check(
+        &format!("{name}.wlvl"),
+        &left.water_level,
+        &right.water_level,
+    )?;
+    check(&format!("{name}.dst"), &left.duck_state, &right.duck_state)?;
+    check(&format!("{name}.lad"), &left.on_ladder, &right.on_ladder)?;
+    Ok(())
+}
+
+fn check_cmd_frame(left: &CmdFrame, right: &CmdFrame) -> Result<(), Difference> {
+    // Inputs first, they are usually the cause of the state differences.
+    check("ms", &left.msec, &right.msec)?;
+    check("btns", &left.buttons, &right.buttons)?;
+    check("impls", &left.impulse, &right.impulse)?;
+    check("fsu", &left.moves, &right.moves)?;
+    check("view", &left.view_angles, &right.view_angles)?;
+    check("ss", &left.shared_seed, &right.shared_seed)?;
+    check("efric", &left.entity_friction, &right.entity_friction)?;
+    check("egrav", &left.entity_gravity, &right.entity_gravity)?;
+    check("pview", &left.punch_angles, &right.punch_angles)?;
+    check("hp", &left.health, &right.health)?;
+    check("ap", &left.armor, &right.armor)?;
+    check_pm_state("prepm", &left.pre_pm_state, &right.pre_pm_state)?;
+    check_pm_state("postpm", &left.post_pm_state, &right.post_pm_state)?;
+    Ok(())
+}
+
+fn check_physics_frame_fields(left: &PhysicsFrame, right: &PhysicsFrame) -> Result<(), Difference> {
+    check("ft", &left.frame_time, &right.frame_time)?;
+    check("cls", &left.client_state, &right.client_state)?;
+    check("p", &left.is_paused, &right.is_paused)?;
+    check("cbuf", &left.command_buffer, &right.command_buffer)?;
+
+    match (&left.rng_state, &right.rng_state) {
+        (Some(left), Some(right)) => {
+            check("rng.idum", &left.idum, &right.idum)?;
+
+            // The full state is only written with _bxt_tas_log_write_full_rng_state.
+            if left.iy.is_some() && right.iy.is_some() {
+                check("rng.iy", &left.iy, &right.iy)?;
+            }
+            if left.iv.is_some() && right.iv.is_some() {
+                check("rng.iv", &left.iv, &right.iv)?;
+            }
+
+            Ok(())
+        }
+        (left, right) => check("rng", &left.is_some(), &right.is_some()),
+    }
+}
+
+fn check_physics_frame(
+    left: &PhysicsFrame,
+    right: &PhysicsFrame,
+) -> Result<(), (Option<usize>, Difference)> {
+    check_physics_frame_fields(left, right).map_err(|diff| (None, diff))?;
+
+    for (i, (left, right)) in left.cmd_frames.iter().zip(&right.cmd_frames).enumerate() {
+        check_cmd_frame(left, right).map_err(|diff| (Some(i), diff))?;
+    }
+
+    check(
+        "number of command frames",
+        &left.cmd_frames.len(),
+        &right.cmd_frames.len(),
+    )
+    .and_then(|()| check("dmg", &left.damage, &right.damage))
+    .and_then(|()| check("ents", &left.entities, &right.entities))
+    .map_err(|diff| (None, diff))
+}
+
+/// Returns whether the physics frame ran any player movement.
+///
+/// Frames without command frames, like loading or paused frames, depend on the loading speed and
+/// the frame rate, so their number can differ between two runs of the same TAS.
+fn is_active(frame: &PhysicsFrame) -> bool {
+    !frame.cmd_frames.is_empty()
+}
+
+/// Returns the first difference between two logs, or `None` if they match.
+///
+/// Physics frames without command frames are skipped, the remaining physics frames and their
+/// command frames are aligned by their order.
+pub fn find_divergence(left: &TasLogFile, right: &TasLogFile) -> Option<Divergence> {
+    let active_frames = |log: &TasLogFile| -> Vec<usize> {
+        log.physics_frames
+            .iter()
+            .enumerate()
+            .filter(|(_, frame)| is_active(frame))
+            .map(|(i, _)| i)
+            .collect()
+    };
+    let left_active = active_frames(left);
+    let right_active = active_frames(right);
+
+    for (&i, &j) in left_active.iter().zip(&right_active) {
+        let result = check_physics_frame(&left.physics_frames[i], &right.physics_frames[j]);
+        if let Err((cmd_frame, diff)) = result {
+            return Some(Divergence {
+                left_physics_frame: i,
+                right_physics_frame: j,
+                cmd_frame,
+                field: diff.field,
+                left: diff.left,
+                right: diff.right,
+            });
+        }
+    }
+
+    let left_len = left_active.len();
+    let right_len = right_active.len();
+    if left_len != right_len {
+        // Point at the first unmatched active frame, or past the end of the shorter log.
+        let position = |active: &[usize], log: &TasLogFile| {
+            active
+                .get(left_len.min(right_len))
+                .copied()
+                .unwrap_or(log.physics_frames.len())
+        };
+
+        return Some(Divergence {
+            left_physics_frame: position(&left_active, left),
+            right_physics_frame: position(&right_active, right),
+            cmd_frame: None,
+            field: "number of physics frames with command frames".to_string(),
+            left: left_len.to_string(),
+            right: right_len.to_string(),
+        });
+    }
+
+    None
+}
+
+/// Returns a one-line summary of a physics frame for showing context around a divergence.
+pub fn frame_summary(frame: &PhysicsFrame) -> String {
+    let mut summary = match frame.frame_time {
+        Some(frame_time) => format!("ft {}", frame_time),
+        None => "ft ?".to_string(),
+    };
+
+    if let Some(rng_state) = &frame.rng_state {
+        summary += &format!(", idum {}", rng_state.idum);
+    }
+
+    summary += &format!(", {} cmd", frame.cmd_frames.len());
+
+    let last_state = frame
+        .cmd_frames
+        .iter()
+        .rev()
+        .find_map(|cmd| cmd.post_pm_state.as_ref());
+    if let Some(state) = last_state {
+        summary += &format!(", pos {:?}, vel {:?}", state.position, state.velocity);
+    }
+
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(positions: &[f32]) -> TasLogFile {
+        let frames = positions
+            .iter()
+            .map(|x| {
+                format!(
+                    r#"{{"ft":0.001,"cf":[{{"ms":1,"btns":0,"impls":0,"fsu":[0,0,0],
+                        "view":[0,0,0],"ss":0,"postpm":{{"pos":[{x},0,0],"vel":[0,0,0],
+                        "og":true}}}}]}}"#
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        TasLogFile::parse(&format!(r#"{{"tool_ver":"test","pf":[{frames}]}}"#)).unwrap()
+    }
+
+    fn inactive_frames(count: usize) -> Vec<PhysicsFrame> {
+        let frames = vec![r#"{"ft":0.001,"cf":[]}"#; count].join(",");
+        TasLogFile::parse(&format!(r#"{{"tool_ver":"test","pf":[{frames}]}}"#))
+            .unwrap()
+            .physics_frames
+    }
+
+    #[test]
+    fn identical_logs() {
+        assert_eq!(find_divergence(&log(&[1., 2.]), &log(&[1., 2.])), None);
+    }
+
+    #[test]
+    fn position_divergence() {
+        let divergence = find_divergence(&log(&[1., 2., 3.]), &log(&[1., 2.5, 3.])).unwrap();
+        assert_eq!(divergence.left_physics_frame, 1);
+        assert_eq!(divergence.right_physics_frame, 1);
+        assert_eq!(divergence.cmd_frame, Some(0));
+        assert_eq!(divergence.field, "postpm.pos");
+        assert_eq!(divergence.left, "[2.0, 0.0, 0.0]");
+        assert_eq!(divergence.right, "[2.5, 0.0, 0.0]");
+    }
+
+    #[test]
+    fn length_divergence() {
+        let divergence = find_divergence(&log(&[1., 2.]), &log(&[1.])).unwrap();
+        assert_eq!(divergence.left_physics_frame, 1);
+        assert_eq!(divergence.right_physics_frame, 1);
+        assert_eq!(divergence.cmd_frame, None);
+        assert_eq!(
+            divergence.field,
+            "number of physics frames with command frames"
+        );
+    }
+
+    #[test]
+    fn skip_inactive_frames() {
+        let mut left = log(&[1., 2., 3.]);
+        let mut right = log(&[1., 2.5, 3.]);
+
+        // Loading frames at different places shift the physics frames differently in each log.
+        left.physics_frames.splice(1..1, inactive_frames(1));
+        right.physics_frames.splice(0..0, inactive_frames(2));
+
+        let divergence = find_divergence(&left, &right).unwrap();
+        assert_eq!(divergence.left_physics_frame, 2);
+        assert_eq!(divergence.right_physics_frame, 3);
+        assert_eq!(divergence.cmd_frame, Some(0));
+        assert_eq!(divergence.field, "postpm.pos");
+    }
+
+    #[test]
+    fn matching_logs_with_different_inactive_frames() {
+        let mut left = log(&[1., 2.]);
+        let right = log(&[1., 2.]);
+        left.physics_frames.splice(1..1, inactive_frames(3));
+
+        assert_eq!(find_divergence(&left, &right), None);
+    }
+}
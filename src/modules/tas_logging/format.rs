@@ -0,0 +1,408 @@
+//! TAS log file formats.
+
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// How often a compressed log is flushed at most.
+///
+/// Flushing a gzip stream ends the current deflate block, so flushing after every physics frame
+/// would leave almost nothing to compress. This is also how much of a compressed log is lost if
+/// the game crashes.
+const GZIP_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Writer for a TAS log file.
+///
+/// Files ending with `.gz` are compressed with gzip. Flushing writes out everything written so far
+/// in a form that can be read back.
+pub enum LogWriter {
+    Json(BufWriter<File>),
+    Gzip {
+        encoder: GzEncoder<BufWriter<File>>,
+        last_flush: Instant,
+    },
+}
+
+impl LogWriter {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let writer = BufWriter::new(File::create(path)?);
+
+        if path.extension() == Some(OsStr::new("gz")) {
+            Ok(Self::Gzip {
+                encoder: GzEncoder::new(writer, Compression::fast()),
+                last_flush: Instant::now(),
+            })
+        } else {
+            Ok(Self::Json(writer))
+        }
+    }
+
+    /// Marks the end of a complete physics frame.
+    ///
+    /// Compressed logs are flushed if [`GZIP_FLUSH_INTERVAL`] has passed since the last flush, so
+    /// a crash loses at most that much of the log. Plain logs are written out whenever their
+    /// buffer fills up.
+    pub fn end_physics_frame(&mut self) -> io::Result<()> {
+        match self {
+            Self::Json(_) => Ok(()),
+            Self::Gzip {
+                encoder,
+                last_flush,
+            } => {
+                if last_flush.elapsed() < GZIP_FLUSH_INTERVAL {
+                    return Ok(());
+                }
+
+                *last_flush = Instant::now();
+                encoder.flush()
+            }
+        }
+    }
+
+    /// Writes out everything and closes the file.
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            Self::Json(mut writer) => writer.flush(),
+            Self::Gzip { encoder, .. } => encoder.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Json(writer) => writer.write(buf),
+            Self::Gzip { encoder, .. } => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Json(writer) => writer.flush(),
+            Self::Gzip { encoder, .. } => encoder.flush(),
+        }
+    }
+}
+
+/// Opens a TAS log for reading, decompressing it if needed.
+///
+/// If the file was cut off (for example, because the game crashed), the log is read up to its last
+/// complete physics frame.
+pub fn open_log(path: &Path) -> io::Result<CompleteFrames<Box<dyn Read>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let is_gzip = reader.fill_buf()?.starts_with(&GZIP_MAGIC);
+
+    let reader: Box<dyn Read> = if is_gzip {
+        Box::new(GzDecoder::new(reader))
+    } else {
+        Box::new(reader)
+    };
+
+    Ok(CompleteFrames::new(reader))
+}
+
+/// Reader that returns a TAS log up to the end of its last complete physics frame.
+///
+/// A complete log is passed through unchanged. If the log was cut off inside of the physics frame
+/// array, the incomplete physics frame is dropped and the log is closed. Only the physics frame
+/// that is currently being read is held in memory.
+pub struct CompleteFrames<R> {
+    inner: R,
+    /// Bytes read from `inner` that haven't been returned yet, starting at `pos`.
+    buf: Vec<u8>,
+    pos: usize,
+    /// Length of the start of `buf` that can be returned.
+    ready: usize,
+    /// Length of the start of `buf` that has been scanned.
+    scanned: usize,
+    /// Depth 1 is the top-level object, depth 2 is the physics frame array.
+    depth: u32,
+    in_string: bool,
+    escaped: bool,
+    /// Whether the physics frame array was closed, after which everything is passed through.
+    array_closed: bool,
+    /// Whether anything was read from `inner`.
+    read_any: bool,
+    /// Set once `inner` has ended: the bytes that close a cut off log.
+    tail: Option<&'static [u8]>,
+}
+
+impl<R: Read> CompleteFrames<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buf: Vec::new(),
+            pos: 0,
+            ready: 0,
+            scanned: 0,
+            depth: 0,
+            in_string: false,
+            escaped: false,
+            array_closed: false,
+            read_any: false,
+            tail: None,
+        }
+    }
+
+    /// Scans the newly read bytes, marking everything up to the last physics frame end as ready.
+    fn scan(&mut self) {
+        if self.array_closed {
+            self.scanned = self.buf.len();
+            self.ready = self.buf.len();
+            return;
+        }
+
+        for i in self.scanned..self.buf.len() {
+            let c = self.buf[i];
+
+            if self.in_string {
+                if self.escaped {
+                    self.escaped = false;
+                } else if c == b'\\' {
+                    self.escaped = true;
+                } else if c == b'"' {
+                    self.in_string = false;
+                }
+                continue;
+            }
+
+            match c {
+                b'"' => self.in_string = true,
+                b'{' | b'[' => {
+                    self.depth += 1;
+
+                    if self.depth == 2 {
+                        // Start of the physics frame array.
+                        self.ready = i + 1;
+                    }
+                }
+                b'}' | b']' => {
+                    self.depth = self.depth.saturating_sub(1);
+
+                    if self.depth == 2 {
+                        self.ready = i + 1;
+                    } else if self.depth < 2 {
+                        // The array was closed, so the log isn't cut off inside of it.
+                        self.array_closed = true;
+                        self.scanned = self.buf.len();
+                        self.ready = self.buf.len();
+                        return;
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        self.scanned = self.buf.len();
+    }
+
+    /// Handles the end of `inner`, dropping the incomplete physics frame of a cut off log.
+    fn end(&mut self) -> io::Result<()> {
+        if self.array_closed {
+            self.ready = self.buf.len();
+            self.tail = Some(b"");
+        } else if self.depth >= 2 {
+            self.buf.truncate(self.ready);
+            self.tail = Some(b"]}");
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the TAS log ends before its physics frames",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for CompleteFrames<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.pos < self.ready {
+                let len = (self.ready - self.pos).min(out.len());
+                out[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+                self.pos += len;
+                return Ok(len);
+            }
+
+            if let Some(tail) = &mut self.tail {
+                let len = tail.len().min(out.len());
+                out[..len].copy_from_slice(&tail[..len]);
+                *tail = &tail[len..];
+                return Ok(len);
+            }
+
+            // Only the incomplete physics frame is left, move it to the start.
+            self.buf.drain(..self.pos);
+            self.ready -= self.pos;
+            self.scanned -= self.pos;
+            self.pos = 0;
+
+            let old_len = self.buf.len();
+            self.buf.resize(old_len + 64 * 1024, 0);
+            let result = self.inner.read(&mut self.buf[old_len..]);
+            self.buf.truncate(old_len + *result.as_ref().unwrap_or(&0));
+
+            match result {
+                Ok(0) => self.end()?,
+                Ok(_) => {
+                    self.read_any = true;
+                    self.scan();
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+                // A cut off gzip stream fails to decompress at the end, but everything before
+                // that is still there.
+                Err(_) if self.read_any => self.end()?,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+/// Converts a TAS log into the format of the output filename.
+///
+/// A log that was cut off is completed with its last complete physics frame. The log is streamed
+/// rather than read into memory, so this works for logs of any size.
+pub fn convert(input: &Path, output: &Path) -> io::Result<()> {
+    let mut reader = open_log(input)?;
+    let mut writer = LogWriter::create(output)?;
+    io::copy(&mut reader, &mut writer)?;
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::tas_logging::reader::TasLogFile;
+
+    fn complete_frames(log: &str) -> io::Result<String> {
+        let mut completed = String::new();
+        CompleteFrames::new(log.as_bytes()).read_to_string(&mut completed)?;
+        Ok(completed)
+    }
+
+    #[test]
+    fn complete_cut_off_log() {
+        let log = r#"{"tool_ver":"a}[","pf":[{"cf":[{"ms":1}]},{"cf":[{"ms"#;
+        assert_eq!(
+            complete_frames(log).unwrap(),
+            r#"{"tool_ver":"a}[","pf":[{"cf":[{"ms":1}]}]}"#
+        );
+    }
+
+    #[test]
+    fn complete_log_without_frames() {
+        assert_eq!(
+            complete_frames(r#"{"tool_ver":"a","pf":[{"#).unwrap(),
+            r#"{"tool_ver":"a","pf":[]}"#
+        );
+        assert!(complete_frames(r#"{"tool_ver":"#).is_err());
+    }
+
+    #[test]
+    fn complete_log_is_unchanged() {
+        let log = r#"{"tool_ver":"a","pf":[{"cf":[]},{"cf":[]}]}"#;
+        assert_eq!(complete_frames(log).unwrap(), log);
+    }
+
+    #[test]
+    fn complete_frames_across_reads() {
+        // Read one byte at a time so that frames span many reads.
+        struct OneByte<'a>(&'a [u8]);
+
+        impl Read for OneByte<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let len = self.0.len().min(buf.len()).min(1);
+                buf[..len].copy_from_slice(&self.0[..len]);
+                self.0 = &self.0[len..];
+                Ok(len)
+            }
+        }
+
+        let log = r#"{"tool_ver":"a","pf":[{"cf":[]},{"cf":["\"]"]},{"cf":[{"#;
+        let mut completed = String::new();
+        CompleteFrames::new(OneByte(log.as_bytes()))
+            .read_to_string(&mut completed)
+            .unwrap();
+        assert_eq!(
+            completed,
+            r#"{"tool_ver":"a","pf":[{"cf":[]},{"cf":["\"]"]}]}"#
+        );
+    }
+
+    #[test]
+    fn read_truncated_gzip_log() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!(
+            "bxt-rs-test-{}-truncated.log.gz",
+            std::process::id()
+        ));
+
+        let mut writer = LogWriter::create(&path).unwrap();
+        writer.write_all(br#"{"tool_ver":"test","pf":["#).unwrap();
+        writer.write_all(br#"{"ft":0.001,"cf":[]}"#).unwrap();
+        writer.flush().unwrap();
+        writer.write_all(br#",{"ft":0.002,"cf":[]}"#).unwrap();
+        writer.flush().unwrap();
+
+        // The game crashes while writing the third physics frame.
+        writer.write_all(br#",{"ft":0.003,"cf":["#).unwrap();
+        let truncated = std::fs::read(&path).unwrap();
+        drop(writer);
+        std::fs::write(&path, truncated).unwrap();
+
+        let log = TasLogFile::open(&path);
+        std::fs::remove_file(&path).unwrap();
+        let log = log.unwrap();
+        let frame_times: Vec<_> = log
+            .physics_frames
+            .iter()
+            .map(|frame| frame.frame_time)
+            .collect();
+        assert_eq!(frame_times, [Some(0.001), Some(0.002)]);
+    }
+
+    #[test]
+    fn gzip_round_trip() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("bxt-rs-test-{}.log.gz", std::process::id()));
+
+        let mut writer = LogWriter::create(&path).unwrap();
+        assert!(matches!(writer, LogWriter::Gzip { .. }));
+        writer.write_all(br#"{"pf":[]}"#).unwrap();
+        writer.finish().unwrap();
+
+        let mut log = String::new();
+        let result = open_log(&path).and_then(|mut reader| reader.read_to_string(&mut log));
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+        assert_eq!(log, r#"{"pf":[]}"#);
+    }
+
+    #[test]
+    fn gzip_is_not_flushed_every_physics_frame() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!(
+            "bxt-rs-test-{}-flush-interval.log.gz",
+            std::process::id()
+        ));
+
+        let mut writer = LogWriter::create(&path).unwrap();
+        writer.write_all(br#"{"pf":[{"cf":[]}"#).unwrap();
+        writer.end_physics_frame().unwrap();
+        let len = std::fs::metadata(&path).unwrap().len();
+        drop(writer);
+        std::fs::remove_file(&path).unwrap();
+
+        // Nothing has reached the file since the flush interval hasn't passed yet.
+        assert_eq!(len, 0);
+    }
+}
@@ -2,12 +2,17 @@
 
 use std::ffi::{CStr, OsString};
 use std::io;
-use std::path::Path;
+use std::mem;
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::ptr::NonNull;
+use std::slice;
 
 use git_version::git_version;
+use serde::Serialize;
 
 use super::Module;
-use crate::ffi::edict;
+use crate::ffi::edict::{self, edict_s};
 use crate::ffi::playermove::playermove_s;
 use crate::ffi::usercmd::usercmd_s;
 use crate::handler;
@@ -17,6 +22,10 @@ use crate::modules::commands::{self, Command};
 use crate::modules::cvars::{self, CVar};
 use crate::utils::*;
 
+mod diff;
+mod format;
+mod reader;
+pub use reader::TasLogFile;
 mod serializer;
 use serializer::Serializer;
 
@@ -27,12 +36,22 @@ impl Module for TasLogging {
     }
 
     fn commands(&self) -> &'static [&'static Command] {
-        static COMMANDS: &[&Command] = &[&BXT_TAS_LOG];
+        static COMMANDS: &[&Command] = &[&BXT_TAS_LOG, &BXT_TAS_LOG_CONVERT, &BXT_TAS_LOG_DIFF];
         COMMANDS
     }
 
     fn cvars(&self) -> &'static [&'static CVar] {
-        static CVARS: &[&CVar] = &[&BXT_TAS_LOG_FILENAME, &BXT_TAS_LOG_WRITE_FULL_RNG_STATE];
+        static CVARS: &[&CVar] = &[
+            &BXT_TAS_LOG_FILENAME,
+            &BXT_TAS_LOG_FIELDS,
+            &BXT_TAS_LOG_ENTITIES,
+            &BXT_TAS_LOG_START_FRAME,
+            &BXT_TAS_LOG_STOP_FRAME,
+            &BXT_TAS_LOG_START_MAP,
+            &BXT_TAS_LOG_STOP_MAP,
+            &BXT_TAS_LOG_AUTO,
+            &BXT_TAS_LOG_WRITE_FULL_RNG_STATE,
+        ];
         CVARS
     }
 
@@ -45,17 +64,65 @@ static BXT_TAS_LOG: Command = Command::new(
     b"bxt_tas_log\0",
     handler!(
         "Usage: bxt_tas_log <0|1>\n \
-          Enables or disables TAS logging into the file at bxt_tas_log_filename.\n",
+          Enables or disables TAS logging into the file at bxt_tas_log_filename. If the filename \
+          ends with \".gz\", the log is compressed with gzip and written out once a second, so a \
+          crash loses up to the last second of the log.\n\n\
+          bxt_tas_log_fields selects the field groups to log: rng, pm, cbuf and usercmd. Groups \
+          prefixed with - are left out, for example \"all -pm\". The command buffer (cbuf), the \
+          console output and the damage types are only available on Linux; on Windows they are \
+          left out of the log.\n\n\
+          Logging can also start and stop on its own: at the host frame numbers in \
+          bxt_tas_log_start_frame and bxt_tas_log_stop_frame, when the maps in \
+          bxt_tas_log_start_map and bxt_tas_log_stop_map load, or, with bxt_tas_log_auto 1, for \
+          the duration of every .hltas script playback.\n",
         tas_log as fn(_, _)
     ),
 );
 
 static BXT_TAS_LOG_FILENAME: CVar = CVar::new(b"bxt_tas_log_filename\0", b"taslogger.log\0");
+static BXT_TAS_LOG_FIELDS: CVar = CVar::new(b"bxt_tas_log_fields\0", b"all\0");
+static BXT_TAS_LOG_ENTITIES: CVar = CVar::new(b"bxt_tas_log_entities\0", b"\0");
+static BXT_TAS_LOG_START_FRAME: CVar = CVar::new(b"bxt_tas_log_start_frame\0", b"0\0");
+static BXT_TAS_LOG_STOP_FRAME: CVar = CVar::new(b"bxt_tas_log_stop_frame\0", b"0\0");
+static BXT_TAS_LOG_START_MAP: CVar = CVar::new(b"bxt_tas_log_start_map\0", b"\0");
+static BXT_TAS_LOG_STOP_MAP: CVar = CVar::new(b"bxt_tas_log_stop_map\0", b"\0");
+static BXT_TAS_LOG_AUTO: CVar = CVar::new(b"bxt_tas_log_auto\0", b"0\0");
 static BXT_TAS_LOG_WRITE_FULL_RNG_STATE: CVar =
     CVar::new(b"_bxt_tas_log_write_full_rng_state\0", b"0\0");
 
 static TAS_LOG: MainThreadRefCell<Option<TasLog>> = MainThreadRefCell::new(None);
 
+/// Whether console output should be collected for the TAS log.
+///
+/// Separate from `TAS_LOG` because the console can be printed to while it's borrowed.
+static COLLECT_CONSOLE_OUTPUT: MainThreadCell<bool> = MainThreadCell::new(false);
+
+/// Console output since the last physics frame was written.
+static CONSOLE_OUTPUT: MainThreadRefCell<String> = MainThreadRefCell::new(String::new());
+
+/// Index of the `Damage` user message.
+static DAMAGE_MESSAGE: MainThreadCell<Option<c_int>> = MainThreadCell::new(None);
+
+/// Damage from the `Damage` user message to the player that's being written.
+static DAMAGE_IN_MESSAGE: MainThreadRefCell<Option<Damage>> = MainThreadRefCell::new(None);
+
+/// Damage the game reported to the player since the last physics frame.
+static REPORTED_DAMAGE: MainThreadRefCell<Option<Damage>> = MainThreadRefCell::new(None);
+
+/// Host frame number at the last physics frame, for the start and stop frame triggers.
+static LAST_HOST_FRAME: MainThreadCell<Option<i32>> = MainThreadCell::new(None);
+
+/// Map at the last physics frame, for the start and stop map triggers.
+static LAST_MAP: MainThreadRefCell<Option<String>> = MainThreadRefCell::new(None);
+
+/// Whether Bunnymod XT is playing back a .hltas script.
+static TAS_PLAYBACK_RUNNING: MainThreadCell<bool> = MainThreadCell::new(false);
+
+/// Whether logging was started by `bxt_tas_log_auto` and should stop with the playback.
+static AUTO_STARTED: MainThreadCell<bool> = MainThreadCell::new(false);
+
+const MOVETYPE_FLY: i32 = 5;
+
 fn tas_log(marker: MainThreadMarker, enabled: i32) {
     if !TasLogging.is_enabled(marker) {
         return;
@@ -64,6 +131,11 @@ fn tas_log(marker: MainThreadMarker, enabled: i32) {
     let mut tas_log = TAS_LOG.borrow_mut(marker);
 
     if enabled == 0 {
+        AUTO_STARTED.set(marker, false);
+        COLLECT_CONSOLE_OUTPUT.set(marker, false);
+        CONSOLE_OUTPUT.borrow_mut(marker).clear();
+        REPORTED_DAMAGE.borrow_mut(marker).take();
+
         if let Some(tas_log) = tas_log.take() {
             if let Err(err) = tas_log.close() {
                 con_print(
@@ -83,10 +155,24 @@ fn tas_log(marker: MainThreadMarker, enabled: i32) {
         return;
     }
 
-    let filename = if cvars::CVars.is_enabled(marker) {
-        BXT_TAS_LOG_FILENAME.to_os_string(marker)
+    let (filename, fields) = if cvars::CVars.is_enabled(marker) {
+        (
+            BXT_TAS_LOG_FILENAME.to_os_string(marker),
+            LogFields::parse(&BXT_TAS_LOG_FIELDS.to_string(marker)),
+        )
     } else {
-        OsString::from("taslogger.log")
+        (OsString::from("taslogger.log"), Ok(LogFields::ALL))
+    };
+
+    let fields = match fields {
+        Ok(fields) => fields,
+        Err(err) => {
+            con_print(
+                marker,
+                &format!("Unable to start TAS logging: bxt_tas_log_fields: {}\n", err),
+            );
+            return;
+        }
     };
 
     let build_number = engine::build_number.get_opt(marker).map(|f| unsafe { f() });
@@ -106,6 +192,7 @@ fn tas_log(marker: MainThreadMarker, enabled: i32) {
         ),
         build_number,
         game_dir.as_deref(),
+        fields,
     ) {
         Ok(tas_log_new) => {
             con_print(
@@ -113,16 +200,211 @@ fn tas_log(marker: MainThreadMarker, enabled: i32) {
                 &format!("Started TAS logging into {}\n", filename.to_string_lossy()),
             );
 
-            *tas_log = Some(tas_log_new)
+            let missing = missing_fields(marker, fields);
+            if !missing.is_empty() {
+                con_print(
+                    marker,
+                    &format!(
+                        "Warning: these fields can't be logged with this engine and are left \
+                         out: {}.\n",
+                        missing.join(", ")
+                    ),
+                );
+            }
+
+            *tas_log = Some(tas_log_new);
+            COLLECT_CONSOLE_OUTPUT.set(marker, true);
         }
         Err(err) => con_print(marker, &format!("Unable to start TAS logging: {}\n", err)),
     }
 }
 
+/// Returns the names of the fields that can't be logged because their engine functions weren't
+/// found.
+///
+/// These are only found by symbol, so they are missing on Windows.
+fn missing_fields(marker: MainThreadMarker, fields: LogFields) -> Vec<&'static str> {
+    let mut missing = Vec::new();
+
+    if !engine::Con_Print.is_set(marker) {
+        missing.push("console output");
+    }
+
+    if fields.cbuf && !engine::cmd_text.is_set(marker) {
+        missing.push("command buffer");
+    }
+
+    if !(engine::PF_MessageBegin_I.is_set(marker)
+        && engine::PF_WriteLong_I.is_set(marker)
+        && engine::PF_MessageEnd_I.is_set(marker)
+        && engine::RegUserMsg.is_set(marker))
+    {
+        missing.push("damage types");
+    }
+
+    missing
+}
+
+static BXT_TAS_LOG_CONVERT: Command = Command::new(
+    b"bxt_tas_log_convert\0",
+    handler!(
+        "Usage: bxt_tas_log_convert <input.log> <output.log>\n \
+          Converts a TAS log between plain JSON and gzip-compressed JSON (if the output filename \
+          ends with \".gz\"). A log that was cut off, for example by a crash, is converted up to \
+          the last complete physics frame.\n",
+        tas_log_convert as fn(_, _, _)
+    ),
+);
+
+fn tas_log_convert(marker: MainThreadMarker, input: PathBuf, output: PathBuf) {
+    match format::convert(&input, &output) {
+        Ok(()) => con_print(
+            marker,
+            &format!("Converted the TAS log into {}.\n", output.display()),
+        ),
+        Err(err) => con_print(marker, &format!("Error converting the TAS log: {}\n", err)),
+    }
+}
+
+static BXT_TAS_LOG_DIFF: Command = Command::new(
+    b"bxt_tas_log_diff\0",
+    handler!(
+        "Usage: bxt_tas_log_diff <first.log> <second.log>\n \
+          Compares two TAS logs and prints the first physics or command frame where they \
+          diverge. Physics frames without command frames, such as loading frames, are skipped.\n",
+        tas_log_diff as fn(_, _, _)
+    ),
+);
+
+fn tas_log_diff(marker: MainThreadMarker, first: PathBuf, second: PathBuf) {
+    let open = |path: &Path| {
+        TasLogFile::open(path).map_err(|err| {
+            con_print(
+                marker,
+                &format!("Error reading {}: {}\n", path.display(), err),
+            );
+        })
+    };
+
+    let (first, second) = match (open(&first), open(&second)) {
+        (Ok(first), Ok(second)) => (first, second),
+        _ => return,
+    };
+
+    let divergence = match diff::find_divergence(&first, &second) {
+        Some(divergence) => divergence,
+        None => {
+            con_print(
+                marker,
+                &format!(
+                    "The logs match ({} physics frames).\n",
+                    first.physics_frames.len()
+                ),
+            );
+            return;
+        }
+    };
+
+    let mut output = format!("Logs diverge at {}.\n", divergence);
+
+    // Show a few physics frames leading up to the divergence.
+    for (name, log, physics_frame) in [
+        ("First", &first, divergence.left_physics_frame),
+        ("Second", &second, divergence.right_physics_frame),
+    ] {
+        output += &format!("{} log:\n", name);
+
+        let start = physics_frame.saturating_sub(2);
+        let end = (physics_frame + 1).min(log.physics_frames.len());
+        for (i, frame) in log.physics_frames[start..end].iter().enumerate() {
+            let summary = diff::frame_summary(frame);
+            output += &format!("  {}: {}\n", start + i, summary);
+        }
+    }
+
+    con_print(marker, &output);
+}
+
+/// Starts or stops logging when the host frame or map triggers are hit.
+///
 /// # Safety
 ///
-/// `host_frametime`, `cls` and `sv` must be valid to read from.
+/// `host_framecount` and `sv` must be valid to read from.
+unsafe fn check_triggers(marker: MainThreadMarker) {
+    if !cvars::CVars.is_enabled(marker) {
+        return;
+    }
+
+    let host_frame = engine::host_framecount.get_opt(marker).map(|count| *count);
+    let last_host_frame = LAST_HOST_FRAME.get(marker);
+    LAST_HOST_FRAME.set(marker, host_frame);
+
+    // The host frame number can jump when the server isn't running, so check if it went past the
+    // trigger rather than for equality.
+    let reached_frame = |cvar: &CVar| {
+        let frame = cvar.as_u64(marker) as i64;
+        frame > 0
+            && matches!(
+                (last_host_frame, host_frame),
+                (Some(last), Some(current)) if i64::from(last) < frame && frame <= i64::from(current)
+            )
+    };
+
+    let map = engine::map_name(marker);
+    let loaded_map = {
+        let mut last_map = LAST_MAP.borrow_mut(marker);
+        if *last_map == map {
+            None
+        } else {
+            *last_map = map.clone();
+            map
+        }
+    };
+
+    let loaded = |cvar: &CVar| {
+        let name = cvar.to_string(marker);
+        !name.is_empty()
+            && matches!(loaded_map.as_deref(), Some(map) if map.eq_ignore_ascii_case(&name))
+    };
+
+    let is_logging = TAS_LOG.borrow(marker).is_some();
+    if is_logging {
+        if reached_frame(&BXT_TAS_LOG_STOP_FRAME) || loaded(&BXT_TAS_LOG_STOP_MAP) {
+            tas_log(marker, 0);
+        }
+    } else if reached_frame(&BXT_TAS_LOG_START_FRAME) || loaded(&BXT_TAS_LOG_START_MAP) {
+        tas_log(marker, 1);
+    }
+}
+
+pub fn on_tas_playback_frame(marker: MainThreadMarker) {
+    if TAS_PLAYBACK_RUNNING.get(marker) {
+        return;
+    }
+
+    TAS_PLAYBACK_RUNNING.set(marker, true);
+
+    let auto = cvars::CVars.is_enabled(marker) && BXT_TAS_LOG_AUTO.as_bool(marker);
+    if auto && TAS_LOG.borrow(marker).is_none() {
+        tas_log(marker, 1);
+        AUTO_STARTED.set(marker, TAS_LOG.borrow(marker).is_some());
+    }
+}
+
+pub fn on_tas_playback_stopped(marker: MainThreadMarker) {
+    TAS_PLAYBACK_RUNNING.set(marker, false);
+
+    if AUTO_STARTED.get(marker) {
+        tas_log(marker, 0);
+    }
+}
+
+/// # Safety
+///
+/// `host_framecount`, `host_frametime`, `cls`, `sv` and `cmd_text` must be valid to read from.
 pub unsafe fn begin_physics_frame(marker: MainThreadMarker) {
+    check_triggers(marker);
+
     if let Some(tas_log) = TAS_LOG.borrow_mut(marker).as_mut() {
         let frame_time = engine::host_frametime
             .get_opt(marker)
@@ -130,12 +412,15 @@ pub unsafe fn begin_physics_frame(marker: MainThreadMarker) {
         let client_state = engine::cls.get_opt(marker).map(|cls| (*cls).state);
         let is_paused = engine::sv.get_opt(marker).map(|sv| *sv.offset(4).cast());
 
-        // TODO: command_buffer
+        let command_buffer = command_buffer(marker);
+        let map = engine::map_name(marker);
+
         if let Err(err) = tas_log.begin_physics_frame(
             frame_time,
             client_state,
             is_paused,
-            None,
+            map.as_deref(),
+            command_buffer.as_deref(),
             engine::rng_state(marker),
             BXT_TAS_LOG_WRITE_FULL_RNG_STATE.as_bool(marker),
         ) {
@@ -144,22 +429,182 @@ pub unsafe fn begin_physics_frame(marker: MainThreadMarker) {
     }
 }
 
-pub fn end_physics_frame(marker: MainThreadMarker) {
-    if let Some(tas_log) = TAS_LOG.borrow_mut(marker).as_mut() {
-        if let Err(err) = tas_log.end_physics_frame() {
-            con_print(marker, &format!("Error writing to the TAS log: {}", err));
-        }
+/// Returns the commands waiting in the command buffer, if there are any.
+///
+/// # Safety
+///
+/// `cmd_text` must be valid to read from.
+unsafe fn command_buffer(marker: MainThreadMarker) -> Option<String> {
+    let cmd_text = &*engine::cmd_text.get_opt(marker)?;
+    if cmd_text.data.is_null() || cmd_text.cursize <= 0 {
+        return None;
+    }
+
+    // The buffer isn't null-terminated.
+    let data = slice::from_raw_parts(cmd_text.data, cmd_text.cursize as usize);
+    Some(String::from_utf8_lossy(data).into_owned())
+}
+
+pub fn on_con_print(marker: MainThreadMarker, text: &CStr) {
+    if COLLECT_CONSOLE_OUTPUT.get(marker) {
+        CONSOLE_OUTPUT
+            .borrow_mut(marker)
+            .push_str(&text.to_string_lossy());
+    }
+}
+
+pub fn on_reg_user_msg(marker: MainThreadMarker, name: &CStr, index: c_int) {
+    if name.to_bytes() == b"Damage" {
+        DAMAGE_MESSAGE.set(marker, Some(index));
+    }
+}
+
+/// # Safety
+///
+/// `edict` must be null or valid to read from.
+pub unsafe fn on_message_begin(marker: MainThreadMarker, type_: c_int, edict: *mut edict_s) {
+    if TAS_LOG.borrow(marker).is_none() || DAMAGE_MESSAGE.get(marker) != Some(type_) {
+        return;
+    }
+
+    let player = match engine::player_edict(marker) {
+        Some(player) if player.as_ptr() == edict => player,
+        _ => return,
+    };
+
+    // The game clears the damage right after sending the message.
+    *DAMAGE_IN_MESSAGE.borrow_mut(marker) = player_damage(marker, player);
+}
+
+pub fn on_write_long(marker: MainThreadMarker, value: c_int) {
+    if let Some(damage) = DAMAGE_IN_MESSAGE.borrow_mut(marker).as_mut() {
+        // The damage type is the only long in the message.
+        damage.type_ = Some(value);
+    }
+}
+
+pub fn on_message_end(marker: MainThreadMarker) {
+    let damage = match DAMAGE_IN_MESSAGE.borrow_mut(marker).take() {
+        Some(damage) => damage,
+        None => return,
+    };
+
+    let mut reported = REPORTED_DAMAGE.borrow_mut(marker);
+    match reported.as_mut() {
+        Some(reported) => reported.add(damage),
+        None => *reported = Some(damage),
+    }
+}
+
+/// Returns the damage the player has taken since the game last reported it.
+///
+/// # Safety
+///
+/// `player` must be valid to read from.
+unsafe fn player_damage(marker: MainThreadMarker, player: NonNull<edict_s>) -> Option<Damage> {
+    let pev = &player.as_ref().v;
+    if pev.dmg_take == 0. && pev.dmg_save == 0. {
+        return None;
+    }
+
+    let inflictor = (!pev.dmg_inflictor.is_null())
+        .then(|| engine::string_from_index(marker, (*pev.dmg_inflictor).v.classname))
+        .flatten();
+
+    Some(Damage {
+        take: pev.dmg_take,
+        save: pev.dmg_save,
+        inflictor,
+        type_: None,
+    })
+}
+
+/// # Safety
+///
+/// The server edicts must be valid to read from.
+pub unsafe fn end_physics_frame(marker: MainThreadMarker) {
+    let mut tas_log = TAS_LOG.borrow_mut(marker);
+    let tas_log = match tas_log.as_mut() {
+        Some(tas_log) => tas_log,
+        None => return,
+    };
+
+    let console_output = mem::take(&mut *CONSOLE_OUTPUT.borrow_mut(marker));
+    let console_output = Some(console_output).filter(|output| !output.is_empty());
+
+    // The damage type is only stored inside the game DLL's player class, it's sent to the client
+    // in the Damage user message. Without the message hooks, log the damage without it.
+    let damage = if engine::PF_MessageBegin_I.is_set(marker)
+        && engine::PF_WriteLong_I.is_set(marker)
+        && engine::PF_MessageEnd_I.is_set(marker)
+        && DAMAGE_MESSAGE.get(marker).is_some()
+    {
+        REPORTED_DAMAGE.borrow_mut(marker).take()
+    } else {
+        engine::player_edict(marker).and_then(|player| player_damage(marker, player))
+    };
+
+    let classnames = if cvars::CVars.is_enabled(marker) {
+        BXT_TAS_LOG_ENTITIES.to_string(marker)
+    } else {
+        String::new()
+    };
+    let classnames: Vec<&str> = classnames.split_ascii_whitespace().collect();
+    let entities = tracked_entities(marker, &classnames);
+
+    if let Err(err) = tas_log.end_physics_frame(console_output.as_deref(), damage, &entities) {
+        con_print(marker, &format!("Error writing to the TAS log: {}", err));
     }
 }
 
-pub fn begin_cmd_frame(marker: MainThreadMarker, cmd: usercmd_s, random_seed: u32) {
+/// Returns the origins of entities with the given classnames.
+unsafe fn tracked_entities(marker: MainThreadMarker, classnames: &[&str]) -> Vec<EntityOrigin> {
+    if classnames.is_empty() {
+        return Vec::new();
+    }
+
+    let edicts = match engine::edicts(marker) {
+        Some(edicts) => edicts,
+        None => return Vec::new(),
+    };
+
+    edicts
+        .iter()
+        .enumerate()
+        .filter(|(_, edict)| edict.free == 0 && edict.v.classname != 0)
+        .filter_map(|(id, edict)| {
+            let classname = engine::string_from_index(marker, edict.v.classname)?;
+            classnames
+                .contains(&classname.as_str())
+                .then(|| EntityOrigin {
+                    id,
+                    classname,
+                    origin: edict.v.origin,
+                })
+        })
+        .collect()
+}
+
+/// # Safety
+///
+/// `player` must be null or valid to read from.
+pub unsafe fn begin_cmd_frame(
+    marker: MainThreadMarker,
+    player: *const edict_s,
+    cmd: usercmd_s,
+    random_seed: u32,
+) {
     // PM_Move is required because it ends the cmd frame JSON object.
     if !server::PM_Move.is_set(marker) {
         return;
     }
 
     if let Some(tas_log) = TAS_LOG.borrow_mut(marker).as_mut() {
-        if let Err(err) = tas_log.begin_cmd_frame(None, None, &cmd, random_seed) {
+        let pev = player.as_ref().map(|player| &player.v);
+        let health = pev.map(|pev| pev.health);
+        let armor = pev.map(|pev| pev.armorvalue);
+
+        if let Err(err) = tas_log.begin_cmd_frame(None, None, &cmd, random_seed, health, armor) {
             con_print(marker, &format!("Error writing to the TAS log: {}", err));
         }
     }
@@ -210,8 +655,112 @@ pub fn end_cmd_frame(marker: MainThreadMarker) {
     }
 }
 
+/// Damage taken by the player since the game last reported it to the client.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct Damage {
+    /// Damage to health.
+    take: f32,
+    /// Damage absorbed by armor.
+    save: f32,
+    /// Classname of the entity that dealt the damage.
+    #[serde(rename = "inf", skip_serializing_if = "Option::is_none")]
+    inflictor: Option<String>,
+    /// `DMG_*` bits of the damage types shown on the HUD.
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    type_: Option<i32>,
+}
+
+impl Damage {
+    /// Adds damage reported later in the same physics frame.
+    fn add(&mut self, other: Damage) {
+        self.take += other.take;
+        self.save += other.save;
+        self.inflictor = other.inflictor.or_else(|| self.inflictor.take());
+        self.type_ = match (self.type_, other.type_) {
+            (Some(a), Some(b)) => Some(a | b),
+            (a, b) => a.or(b),
+        };
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct EntityOrigin {
+    id: usize,
+    #[serde(rename = "cls")]
+    classname: String,
+    #[serde(rename = "pos")]
+    origin: [f32; 3],
+}
+
+/// Groups of fields to write into the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct LogFields {
+    /// RNG state in physics frames.
+    rng: bool,
+    /// Player movement state and the entity friction, gravity and punch angles.
+    pm: bool,
+    /// Command buffer contents.
+    cbuf: bool,
+    /// Inputs of the command frames.
+    usercmd: bool,
+}
+
+impl LogFields {
+    const ALL: Self = Self {
+        rng: true,
+        pm: true,
+        cbuf: true,
+        usercmd: true,
+    };
+
+    const NONE: Self = Self {
+        rng: false,
+        pm: false,
+        cbuf: false,
+        usercmd: false,
+    };
+
+    /// Parses a list of field groups such as `"rng pm"` or `"all -cbuf"`.
+    ///
+    /// A list that is empty or starts with an exclusion starts out with all groups.
+    fn parse(value: &str) -> Result<Self, String> {
+        let mut groups = value.split_ascii_whitespace().peekable();
+
+        let mut fields = match groups.peek() {
+            Some(group) if !group.starts_with('-') => Self::NONE,
+            _ => Self::ALL,
+        };
+
+        for group in groups {
+            let (include, name) = match group.strip_prefix('-') {
+                Some(name) => (false, name),
+                None => (true, group),
+            };
+
+            match name {
+                "all" => fields = if include { Self::ALL } else { Self::NONE },
+                "rng" => fields.rng = include,
+                "pm" => fields.pm = include,
+                "cbuf" => fields.cbuf = include,
+                "usercmd" => fields.usercmd = include,
+                _ => return Err(format!("unknown field group \"{}\"", name)),
+            }
+        }
+
+        Ok(fields)
+    }
+}
+
 struct TasLog {
     ser: Serializer,
+    fields: LogFields,
+    /// Damage written in the last physics frame, cleared on every command frame.
+    ///
+    /// The game resets the damage when the player runs a command, so until then it can be the
+    /// same damage as the one already written.
+    last_damage: Option<Damage>,
+    /// Map written in the last physics frame which had it.
+    last_map: Option<String>,
 }
 
 impl TasLog {
@@ -220,6 +769,7 @@ impl TasLog {
         tool_version: &str,
         build_number: Option<i32>,
         game_dir: Option<&str>,
+        fields: LogFields,
     ) -> Result<Self, io::Error> {
         let mut ser = Serializer::new(path)?;
 
@@ -237,21 +787,28 @@ impl TasLog {
         ser.begin_object_value()?;
         ser.begin_array()?;
 
-        Ok(Self { ser })
+        Ok(Self {
+            ser,
+            fields,
+            last_damage: None,
+            last_map: None,
+        })
     }
 
     fn close(mut self) -> Result<(), io::Error> {
         self.ser.end_array()?;
         self.ser.end_object_value()?;
         self.ser.end_object()?;
-        Ok(())
+        self.ser.finish()
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn begin_physics_frame(
         &mut self,
         frame_time: Option<f64>,
         client_state: Option<i32>,
         is_paused: Option<bool>,
+        map: Option<&str>,
         command_buffer: Option<&str>,
         rng_state: Option<RngState>,
         write_full_rng_state: bool,
@@ -275,11 +832,19 @@ impl TasLog {
             }
         }
 
-        if let Some(command_buffer) = command_buffer {
+        // The map is only written when it changes.
+        if let Some(map) = map {
+            if self.last_map.as_deref() != Some(map) {
+                self.ser.entry("map", map)?;
+                self.last_map = Some(map.to_owned());
+            }
+        }
+
+        if let Some(command_buffer) = command_buffer.filter(|_| self.fields.cbuf) {
             self.ser.entry("cbuf", command_buffer)?;
         }
 
-        if let Some(rng_state) = rng_state {
+        if let Some(rng_state) = rng_state.filter(|_| self.fields.rng) {
             self.ser.key("rng")?;
             self.ser.begin_object_value()?;
             self.ser.begin_object()?;
@@ -301,15 +866,32 @@ impl TasLog {
         Ok(())
     }
 
-    fn end_physics_frame(&mut self) -> Result<(), io::Error> {
+    fn end_physics_frame(
+        &mut self,
+        console_output: Option<&str>,
+        damage: Option<Damage>,
+        entities: &[EntityOrigin],
+    ) -> Result<(), io::Error> {
         self.ser.end_array()?;
         self.ser.end_object_value()?;
 
-        // TODO: console messages, damage, object move.
+        if let Some(console_output) = console_output {
+            self.ser.entry("con", console_output)?;
+        }
+
+        if damage.is_some() && damage != self.last_damage {
+            self.ser.entry("dmg", &damage)?;
+            self.last_damage = damage;
+        }
+
+        if !entities.is_empty() {
+            self.ser.entry("ents", entities)?;
+        }
 
         self.ser.end_object()?;
         self.ser.end_array_value()?;
-        Ok(())
+
+        self.ser.end_physics_frame()
     }
 
     fn begin_cmd_frame(
@@ -318,7 +900,11 @@ impl TasLog {
         frame_time_remainder: Option<f64>,
         cmd: &usercmd_s,
         shared_seed: u32,
+        health: Option<f32>,
+        armor: Option<f32>,
     ) -> Result<(), io::Error> {
+        self.last_damage = None;
+
         self.ser.begin_array_value()?;
         self.ser.begin_object()?;
 
@@ -329,19 +915,26 @@ impl TasLog {
             self.ser.entry("rem", &frame_time_remainder)?;
         }
 
-        self.ser.entry("ms", &cmd.msec)?;
-        self.ser.entry("btns", &cmd.buttons)?;
-        self.ser.entry("impls", &cmd.impulse)?;
-        self.ser
-            .entry("fsu", &[cmd.forwardmove, cmd.sidemove, cmd.upmove])?;
-        self.ser.entry(
-            "view",
-            &[cmd.viewangles[1], cmd.viewangles[0], cmd.viewangles[2]],
-        )?;
+        if self.fields.usercmd {
+            self.ser.entry("ms", &cmd.msec)?;
+            self.ser.entry("btns", &cmd.buttons)?;
+            self.ser.entry("impls", &cmd.impulse)?;
+            self.ser
+                .entry("fsu", &[cmd.forwardmove, cmd.sidemove, cmd.upmove])?;
+            self.ser.entry(
+                "view",
+                &[cmd.viewangles[1], cmd.viewangles[0], cmd.viewangles[2]],
+            )?;
 
-        self.ser.entry("ss", &shared_seed)?;
+            self.ser.entry("ss", &shared_seed)?;
+        }
 
-        // TODO: health, armor.
+        if let Some(health) = health {
+            self.ser.entry("hp", &health)?;
+        }
+        if let Some(armor) = armor {
+            self.ser.entry("ap", &armor)?;
+        }
 
         Ok(())
     }
@@ -353,6 +946,10 @@ impl TasLog {
     }
 
     fn write_pre_pm_state(&mut self, pmove: &playermove_s) -> Result<(), io::Error> {
+        if !self.fields.pm {
+            return Ok(());
+        }
+
         if pmove.friction != 1. {
             self.ser.entry("efric", &pmove.friction)?;
         }
@@ -383,6 +980,10 @@ impl TasLog {
     }
 
     fn write_post_pm_state(&mut self, pmove: &playermove_s) -> Result<(), io::Error> {
+        if !self.fields.pm {
+            return Ok(());
+        }
+
         self.ser.key("postpm")?;
         self.ser.begin_object_value()?;
         self.ser.begin_object()?;
@@ -410,9 +1011,66 @@ impl TasLog {
         } else if pmove.bInDuck != 0 {
             self.ser.entry("dst", &1)?;
         }
-
-        // TODO: ladder
+        if pmove.movetype == MOVETYPE_FLY {
+            self.ser.entry("lad", &true)?;
+        }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_damage() {
+        let mut damage = Damage {
+            take: 10.,
+            save: 2.,
+            inflictor: Some("trigger_hurt".to_string()),
+            type_: Some(1 << 4),
+        };
+        damage.add(Damage {
+            take: 5.,
+            save: 0.,
+            inflictor: None,
+            type_: Some(1 << 5),
+        });
+
+        assert_eq!(
+            damage,
+            Damage {
+                take: 15.,
+                save: 2.,
+                inflictor: Some("trigger_hurt".to_string()),
+                type_: Some(1 << 4 | 1 << 5),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_log_fields() {
+        assert_eq!(LogFields::parse(""), Ok(LogFields::ALL));
+        assert_eq!(LogFields::parse("all"), Ok(LogFields::ALL));
+        assert_eq!(
+            LogFields::parse("rng usercmd"),
+            Ok(LogFields {
+                rng: true,
+                pm: false,
+                cbuf: false,
+                usercmd: true,
+            })
+        );
+        assert_eq!(
+            LogFields::parse("-pm -cbuf"),
+            Ok(LogFields {
+                rng: true,
+                pm: false,
+                cbuf: false,
+                usercmd: true,
+            })
+        );
+        assert!(LogFields::parse("all -foo").is_err());
+    }
+}
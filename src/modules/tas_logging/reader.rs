@@ -0,0 +1,192 @@
+//! Reading TAS logs back.
+
+use std::io::{self, BufReader};
+use std::path::Path;
+
+use serde::Deserialize;
+
+use super::format::{open_log, CompleteFrames};
+
+/// A TAS log as written by `TasLog`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TasLogFile {
+    #[serde(rename = "tool_ver")]
+    pub tool_version: String,
+    #[serde(rename = "build", default)]
+    pub build_number: Option<i32>,
+    #[serde(rename = "mod", default)]
+    pub game_dir: Option<String>,
+    #[serde(rename = "pf")]
+    pub physics_frames: Vec<PhysicsFrame>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PhysicsFrame {
+    #[serde(rename = "ft", default)]
+    pub frame_time: Option<f64>,
+    /// Client state, only written when it isn't 5 (active).
+    #[serde(rename = "cls", default)]
+    pub client_state: Option<i32>,
+    #[serde(rename = "p", default)]
+    pub is_paused: bool,
+    /// Name of the map, only written when it changes.
+    #[serde(rename = "map", default)]
+    pub map: Option<String>,
+    #[serde(rename = "cbuf", default)]
+    pub command_buffer: Option<String>,
+    #[serde(rename = "rng", default)]
+    pub rng_state: Option<RngState>,
+    #[serde(rename = "cf")]
+    pub cmd_frames: Vec<CmdFrame>,
+    /// Console output printed since the previous physics frame.
+    #[serde(rename = "con", default)]
+    pub console_output: Option<String>,
+    #[serde(rename = "dmg", default)]
+    pub damage: Option<Damage>,
+    /// Origins of the entities selected with `bxt_tas_log_entities`.
+    #[serde(rename = "ents", default)]
+    pub entities: Vec<EntityOrigin>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Damage {
+    pub take: f32,
+    pub save: f32,
+    #[serde(rename = "inf", default)]
+    pub inflictor: Option<String>,
+    /// `DMG_*` bits of the damage types shown on the HUD.
+    #[serde(rename = "type", default)]
+    pub type_: Option<i32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct EntityOrigin {
+    pub id: usize,
+    #[serde(rename = "cls")]
+    pub classname: String,
+    #[serde(rename = "pos")]
+    pub origin: [f32; 3],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct RngState {
+    pub idum: i32,
+    #[serde(default)]
+    pub iy: Option<i32>,
+    #[serde(default)]
+    pub iv: Option<Vec<i32>>,
+}
+
+/// Inputs are zero if the log was written without the usercmd field group.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct CmdFrame {
+    #[serde(rename = "bid", default)]
+    pub frame_bulk_id: Option<usize>,
+    #[serde(rename = "rem", default)]
+    pub frame_time_remainder: Option<f64>,
+    #[serde(rename = "ms", default)]
+    pub msec: u8,
+    #[serde(rename = "btns", default)]
+    pub buttons: u16,
+    #[serde(rename = "impls", default)]
+    pub impulse: u8,
+    /// Forward, side and up move.
+    #[serde(rename = "fsu", default)]
+    pub moves: [f32; 3],
+    /// Yaw, pitch and roll.
+    #[serde(rename = "view", default)]
+    pub view_angles: [f32; 3],
+    #[serde(rename = "ss", default)]
+    pub shared_seed: u32,
+    #[serde(rename = "hp", default)]
+    pub health: Option<f32>,
+    #[serde(rename = "ap", default)]
+    pub armor: Option<f32>,
+    #[serde(rename = "efric", default)]
+    pub entity_friction: Option<f32>,
+    #[serde(rename = "egrav", default)]
+    pub entity_gravity: Option<f32>,
+    /// Yaw, pitch and roll.
+    #[serde(rename = "pview", default)]
+    pub punch_angles: Option<[f32; 3]>,
+    #[serde(rename = "prepm", default)]
+    pub pre_pm_state: Option<PmState>,
+    #[serde(rename = "postpm", default)]
+    pub post_pm_state: Option<PmState>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PmState {
+    #[serde(rename = "pos")]
+    pub position: [f32; 3],
+    #[serde(rename = "vel")]
+    pub velocity: [f32; 3],
+    #[serde(rename = "og")]
+    pub on_ground: bool,
+    #[serde(rename = "bvel", default)]
+    pub base_velocity: Option<[f32; 3]>,
+    #[serde(rename = "wlvl", default)]
+    pub water_level: i32,
+    /// 1 if ducking is in progress, 2 if fully ducked.
+    #[serde(rename = "dst", default)]
+    pub duck_state: i32,
+    #[serde(rename = "lad", default)]
+    pub on_ladder: bool,
+}
+
+impl TasLogFile {
+    /// Reads a TAS log from a file.
+    ///
+    /// The file is parsed as it is read, so only the parsed log is kept in memory.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(serde_json::from_reader(BufReader::new(open_log(path)?))?)
+    }
+
+    /// Parses a TAS log from a string.
+    ///
+    /// A log that was cut off is read up to the last complete physics frame.
+    pub fn parse(log: &str) -> serde_json::Result<Self> {
+        serde_json::from_reader(CompleteFrames::new(log.as_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_log() {
+        let log = TasLogFile::parse(
+            r#"{"tool_ver":"bxt-rs 1.0","build":8684,"mod":"valve","pf":[
+                {"ft":0.001,"cls":4,"cf":[]},
+                {"ft":0.001,"rng":{"idum":-5},"cf":[{"ms":1,"btns":2,"impls":0,
+                 "fsu":[400.0,0.0,0.0],"view":[90.0,0.0,0.0],"ss":7,
+                 "prepm":{"pos":[1.0,2.0,3.0],"vel":[0.0,0.0,0.0],"og":true},
+                 "postpm":{"pos":[1.0,2.0,3.0],"vel":[4.0,0.0,0.0],"og":true,"dst":2}}],
+                 "dmg":{"take":10.0,"save":0.0,"inf":"trigger_hurt","type":16}}
+            ]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(log.build_number, Some(8684));
+        assert_eq!(log.physics_frames.len(), 2);
+        assert_eq!(log.physics_frames[0].client_state, Some(4));
+        assert_eq!(log.physics_frames[1].rng_state.as_ref().unwrap().idum, -5);
+
+        let cmd = &log.physics_frames[1].cmd_frames[0];
+        assert_eq!(cmd.moves, [400., 0., 0.]);
+        assert_eq!(cmd.post_pm_state.as_ref().unwrap().velocity, [4., 0., 0.]);
+        assert_eq!(cmd.post_pm_state.as_ref().unwrap().duck_state, 2);
+
+        let damage = log.physics_frames[1].damage.as_ref().unwrap();
+        assert_eq!(damage.inflictor.as_deref(), Some("trigger_hurt"));
+        assert_eq!(damage.type_, Some(16));
+    }
+
+    #[test]
+    fn parse_cut_off_log() {
+        let log =
+            TasLogFile::parse(r#"{"tool_ver":"a","pf":[{"cf":[]},{"ft":0.01,"cf":[{"#).unwrap();
+        assert_eq!(log.physics_frames.len(), 1);
+    }
+}
@@ -1,15 +1,16 @@
 //! Helper for JSON serialization.
 
-use std::fs::File;
-use std::io::{self, BufWriter};
+use std::io;
 use std::path::Path;
 
 use serde::Serialize;
 use serde_json::ser::{CompactFormatter, Formatter};
 
+use super::format::LogWriter;
+
 /// Convenience wrapper over an instance of `serde_json::ser::Formatter`.
 pub struct Serializer {
-    writer: BufWriter<File>,
+    writer: LogWriter,
     fmt: CompactFormatter,
     first_stack: Vec<bool>,
 }
@@ -18,12 +19,22 @@ impl Serializer {
     /// Creates a new `Serializer`.
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, io::Error> {
         Ok(Self {
-            writer: BufWriter::new(File::create(path)?),
+            writer: LogWriter::create(path.as_ref())?,
             fmt: CompactFormatter,
             first_stack: vec![],
         })
     }
 
+    /// Writes out everything and closes the file.
+    pub fn finish(self) -> Result<(), io::Error> {
+        self.writer.finish()
+    }
+
+    /// Marks the end of a complete physics frame.
+    pub fn end_physics_frame(&mut self) -> Result<(), io::Error> {
+        self.writer.end_physics_frame()
+    }
+
     /// Begins an object.
     pub fn begin_object(&mut self) -> Result<(), io::Error> {
         self.fmt.begin_object(&mut self.writer)?;
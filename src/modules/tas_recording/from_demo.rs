@@ -0,0 +1,135 @@
+//! Converting demos into HLTAS scripts.
+
+use std::mem;
+
+use hltas::types::Line;
+use hltas::HLTAS;
+
+use super::{is_input_command, FrameBuilder};
+use crate::demo::{Demo, FrameData};
+
+/// Converts the player commands of a demo into a HLTAS script.
+///
+/// Every network message frame becomes a frame bulk with the client frame time of that frame. The
+/// shared seed is the outgoing sequence number the command was sent with. Console commands are
+/// added to the next frame bulk.
+pub fn convert(demo: &Demo) -> HLTAS {
+    let mut builder = FrameBuilder::default();
+    let mut console_commands = Vec::new();
+
+    // The loading segment only has the connection messages.
+    let frames = demo.playback_entries().flat_map(|entry| &entry.frames);
+
+    for frame in frames {
+        let msg = match &frame.data {
+            FrameData::NetMsg(msg) => msg,
+            FrameData::ConsoleCommand(command) => {
+                let command = command.trim_end_matches(&['\n', ';'][..]);
+                if !command.is_empty() && !is_input_command(command) {
+                    console_commands.push(command.to_string());
+                }
+                continue;
+            }
+            _ => continue,
+        };
+
+        let frame_bulk = builder.frame_bulk(
+            &msg.usercmd,
+            msg.outgoing_sequence as u32,
+            msg.paused,
+            || None,
+        );
+        let mut frame_bulk = match frame_bulk {
+            Some(frame_bulk) => frame_bulk,
+            None => continue,
+        };
+
+        frame_bulk.frame_time = msg.frame_time.to_string();
+
+        // Prepend player commands so they don't override ours.
+        let mut commands = mem::take(&mut console_commands);
+        let command = frame_bulk.console_command.take();
+        commands.extend(command.filter(|command| !command.is_empty()));
+        frame_bulk.console_command = Some(commands.join(";")).filter(|command| !command.is_empty());
+
+        builder.hltas.lines.push(Line::FrameBulk(frame_bulk));
+    }
+
+    builder.hltas
+}
+
+#[cfg(test)]
+mod tests {
+    use hltas::types::AutoMovement;
+
+    use super::*;
+    use crate::demo::test_demo::{self, TestFrame, TestNetMsg};
+    use crate::ffi::buttons::Buttons;
+
+    #[test]
+    fn convert_demo() {
+        let msg = TestNetMsg {
+            frame_time: 0.01,
+            msec: 10,
+            view_angles: [5., 90., 0.],
+            forward_move: 400.,
+            buttons: Buttons::IN_FORWARD.bits(),
+            outgoing_sequence: 100,
+            ..Default::default()
+        };
+        let data = test_demo::write(
+            "c1a0",
+            &[
+                TestFrame::NetMsg(msg),
+                TestFrame::ConsoleCommand("+jump 32"),
+                TestFrame::ConsoleCommand("impulse 101"),
+                TestFrame::NetMsg(TestNetMsg {
+                    outgoing_sequence: 101,
+                    ..msg
+                }),
+                TestFrame::NetMsg(TestNetMsg {
+                    paused: true,
+                    outgoing_sequence: 150,
+                    ..msg
+                }),
+                TestFrame::NetMsg(TestNetMsg {
+                    outgoing_sequence: 160,
+                    ..msg
+                }),
+            ],
+        );
+
+        let hltas = convert(&crate::demo::parse(&data).unwrap());
+        assert_eq!(
+            hltas.properties.seeds.as_ref().map(|seeds| seeds.shared),
+            Some(100)
+        );
+        assert_eq!(hltas.lines.len(), 4);
+        assert_eq!(hltas.lines[2], Line::SharedSeed(59));
+
+        let frame_bulk = |index: usize| match &hltas.lines[index] {
+            Line::FrameBulk(frame_bulk) => frame_bulk,
+            line => panic!("expected a frame bulk, got {:?}", line),
+        };
+
+        let first = frame_bulk(0);
+        assert_eq!(first.frame_time, "0.01");
+        assert!(first.movement_keys.forward);
+        assert_eq!(first.auto_actions.movement, Some(AutoMovement::SetYaw(90.)));
+        assert_eq!(first.pitch, Some(5.));
+        // The key was just pressed, so it only counts for half.
+        assert_eq!(
+            first.console_command.as_deref(),
+            Some("cl_forwardspeed 800")
+        );
+
+        assert_eq!(
+            frame_bulk(1).console_command.as_deref(),
+            Some("impulse 101;cl_forwardspeed 400")
+        );
+        assert_eq!(
+            frame_bulk(3).console_command.as_deref(),
+            Some("cl_forwardspeed 400")
+        );
+    }
+}
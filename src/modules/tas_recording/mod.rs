@@ -0,0 +1,725 @@
+//! TAS recording.
+
+use std::convert::TryInto;
+use std::ffi::CStr;
+use std::fs::File;
+use std::mem;
+use std::os::raw::c_char;
+use std::path::PathBuf;
+
+use bxt_strafe::Parameters;
+use hltas::types::FrameBulk;
+use hltas::HLTAS;
+
+use super::Module;
+use crate::ffi::buttons::Buttons;
+use crate::ffi::usercmd::usercmd_s;
+use crate::handler;
+use crate::hooks::engine::{self, con_print};
+use crate::hooks::server;
+use crate::modules::commands::{self, Command};
+use crate::modules::cvars::{self, CVar};
+use crate::modules::tas_editor::{self, Tracer};
+use crate::utils::*;
+
+mod from_demo;
+mod strafe_detection;
+use strafe_detection::{RecordedFrame, StrafeRuns};
+
+pub struct TasRecording;
+impl Module for TasRecording {
+    fn name(&self) -> &'static str {
+        "TAS recording"
+    }
+
+    fn commands(&self) -> &'static [&'static Command] {
+        static COMMANDS: &[&Command] = &[
+            &BXT_TAS_RECORDING_START,
+            &BXT_TAS_RECORDING_STOP,
+            &BXT_TAS_RECORDING_FROM_DEMO,
+        ];
+        COMMANDS
+    }
+
+    fn cvars(&self) -> &'static [&'static CVar] {
+        static CVARS: &[&CVar] = &[&BXT_TAS_RECORDING_DETECT_STRAFES];
+        CVARS
+    }
+
+    fn is_enabled(&self, marker: MainThreadMarker) -> bool {
+        commands::Commands.is_enabled(marker)
+            && engine::CL_Move.is_set(marker)
+            && engine::cls.is_set(marker)
+            && engine::frametime_remainder.is_set(marker)
+            && engine::host_frametime.is_set(marker)
+            && engine::SV_Frame.is_set(marker)
+            && engine::sv.is_set(marker)
+    }
+}
+
+static BXT_TAS_RECORDING_START: Command = Command::new(
+    b"bxt_tas_recording_start\0",
+    handler!(
+        "Usage: bxt_tas_recording_start <filename.hltas>\n \
+          Starts recording gameplay into a HLTAS script.\n\n\
+          Set bxt_tas_recording_detect_strafes to 1 before starting to rewrite frames that strafe \
+          into strafing frame bulks when the recording stops. A frame is only rewritten if strafing \
+          produces its exact view angle and movement, so frames recorded with other movement \
+          speeds, for example with +speed, are kept as they are. Every frame is checked as it is \
+          recorded: a frame only joins the strafing frame bulk before it if strafing on from the \
+          start of that frame bulk reproduces it.\n",
+        tas_recording_start as fn(_, _)
+    ),
+);
+
+static BXT_TAS_RECORDING_STOP: Command = Command::new(
+    b"bxt_tas_recording_stop\0",
+    handler!(
+        "Usage: bxt_tas_recording_stop\n \
+          Stops gameplay recording.\n",
+        tas_recording_stop as fn(_)
+    ),
+);
+
+static BXT_TAS_RECORDING_FROM_DEMO: Command = Command::new(
+    b"bxt_tas_recording_from_demo\0",
+    handler!(
+        "Usage: bxt_tas_recording_from_demo <demo.dem> <filename.hltas>\n \
+          Converts the player inputs recorded in a demo into a HLTAS script.\n",
+        tas_recording_from_demo as fn(_, _, _)
+    ),
+);
+
+static BXT_TAS_RECORDING_DETECT_STRAFES: CVar =
+    CVar::new(b"bxt_tas_recording_detect_strafes\0", b"0\0");
+
+enum State {
+    Idle,
+    Recording(Recorder),
+}
+
+#[derive(Default)]
+struct Recorder {
+    builder: FrameBuilder,
+    /// Whether to rewrite strafing frames when the recording stops.
+    detect_strafes: bool,
+    /// Recorded frame for every line of `builder.hltas`, if `detect_strafes` is set.
+    frames: Vec<Option<RecordedFrame>>,
+    /// Strafing runs up to the last recorded frame.
+    strafe_runs: StrafeRuns,
+    filename: PathBuf,
+    pending_frame_times: Vec<f64>,
+    pending_remainders: Vec<f64>,
+    pending_bound_commands: Vec<String>,
+    pending_console_commands: Vec<String>,
+}
+
+static STATE: MainThreadRefCell<State> = MainThreadRefCell::new(State::Idle);
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Key {
+    state: u8,
+}
+
+impl Key {
+    fn down(&mut self) {
+        self.state |= 1 + 2;
+    }
+
+    fn up(&mut self) {
+        self.state = 4;
+    }
+
+    fn is_down(self) -> bool {
+        (self.state & 1) != 0
+    }
+
+    fn update(&mut self, down: bool) {
+        if down && !self.is_down() {
+            self.down();
+        } else if !down && self.is_down() {
+            self.up();
+        }
+    }
+
+    fn clear_impulses(&mut self) {
+        self.state &= !(2 + 4);
+    }
+
+    fn multiplier(self) -> f64 {
+        if (self.state & 2) != 0 {
+            if (self.state & 4) != 0 {
+                0.75
+            } else {
+                0.5
+            }
+        } else {
+            1.
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Keys {
+    forward: Key,
+    back: Key,
+    left: Key,
+    right: Key,
+}
+
+impl Keys {
+    fn clear_impulses(&mut self) {
+        self.forward.clear_impulses();
+        self.back.clear_impulses();
+        self.left.clear_impulses();
+        self.right.clear_impulses();
+    }
+}
+
+fn tas_recording_start(marker: MainThreadMarker, filename: PathBuf) {
+    if !TasRecording.is_enabled(marker) {
+        return;
+    }
+
+    let mut state = STATE.borrow_mut(marker);
+    if matches!(*state, State::Idle) {
+        *state = State::Recording(Recorder {
+            filename,
+            detect_strafes: cvars::CVars.is_enabled(marker)
+                && BXT_TAS_RECORDING_DETECT_STRAFES.as_bool(marker),
+            ..Default::default()
+        });
+        con_print(marker, "Recording started\n");
+    } else {
+        con_print(marker, "Already recording\n");
+    }
+}
+
+fn tas_recording_stop(marker: MainThreadMarker) {
+    if !TasRecording.is_enabled(marker) {
+        return;
+    }
+
+    let mut state = STATE.borrow_mut(marker);
+    if let State::Recording(Recorder {
+        builder: FrameBuilder { mut hltas, .. },
+        filename,
+        detect_strafes,
+        frames,
+        ..
+    }) = mem::replace(&mut *state, State::Idle)
+    {
+        if detect_strafes {
+            let lines = mem::take(&mut hltas.lines);
+            let (lines, rewritten) = strafe_detection::detect_strafes(lines, &frames);
+            hltas.lines = lines;
+
+            con_print(
+                marker,
+                &format!("Rewrote {} frames as strafing\n", rewritten),
+            );
+        }
+
+        let file = match File::create(filename) {
+            Ok(file) => file,
+            Err(err) => {
+                con_print(marker, &format!("Error opening the output file: {}\n", err));
+                return;
+            }
+        };
+
+        if let Err(err) = hltas.to_writer(file) {
+            con_print(
+                marker,
+                &format!("Error writing to the output file: {}\n", err),
+            );
+        }
+
+        con_print(marker, "Recording stopped\n");
+    } else {
+        con_print(marker, "No recording in progress\n");
+    }
+}
+
+fn tas_recording_from_demo(marker: MainThreadMarker, demo: PathBuf, filename: PathBuf) {
+    let demo = match crate::demo::open(&demo) {
+        Ok(demo) => demo,
+        Err(err) => {
+            con_print(marker, &format!("Error reading the demo: {}\n", err));
+            return;
+        }
+    };
+
+    let hltas = from_demo::convert(&demo);
+
+    let file = match File::create(filename) {
+        Ok(file) => file,
+        Err(err) => {
+            con_print(marker, &format!("Error opening the output file: {}\n", err));
+            return;
+        }
+    };
+
+    if let Err(err) = hltas.to_writer(file) {
+        con_print(
+            marker,
+            &format!("Error writing to the output file: {}\n", err),
+        );
+        return;
+    }
+
+    con_print(
+        marker,
+        &format!("Converted the demo into {} lines\n", hltas.lines.len()),
+    );
+}
+
+pub unsafe fn on_cl_move(marker: MainThreadMarker) {
+    if !server::CmdStart.is_set(marker) {
+        return;
+    }
+
+    let mut state = STATE.borrow_mut(marker);
+    let recorder = match &mut *state {
+        State::Recording(recorder) => recorder,
+        State::Idle => return,
+    };
+
+    let client_state = (*engine::cls.get(marker)).state;
+    if client_state != 4 && client_state != 5 {
+        return;
+    }
+
+    recorder
+        .pending_remainders
+        .push(*engine::frametime_remainder.get(marker));
+}
+
+pub unsafe fn on_sv_frame_start(marker: MainThreadMarker) {
+    if !server::CmdStart.is_set(marker) {
+        return;
+    }
+
+    let mut state = STATE.borrow_mut(marker);
+    let recorder = match &mut *state {
+        State::Recording(recorder) => recorder,
+        State::Idle => return,
+    };
+
+    let client_state = (*engine::cls.get(marker)).state;
+    if client_state != 4 && client_state != 5 {
+        return;
+    }
+
+    recorder
+        .pending_frame_times
+        .push(*engine::host_frametime.get(marker));
+
+    recorder
+        .pending_console_commands
+        .push(recorder.pending_bound_commands.join(";"));
+    recorder.pending_bound_commands.clear();
+}
+
+/// Converts player commands into frame bulks.
+#[derive(Default)]
+struct FrameBuilder {
+    hltas: HLTAS,
+    keys: Keys,
+    last_cmd_was_zero_ms: bool,
+    was_loading: bool,
+    last_shared_seed_before_load: u32,
+}
+
+impl FrameBuilder {
+    /// Returns the frame bulk for `cmd` with an empty frame time, or `None` if it doesn't need one.
+    ///
+    /// The frame bulk isn't added to `self.hltas`, but a shared seed line after a load is.
+    /// `rng_state` is called for the first frame bulk to set the RNG state.
+    fn frame_bulk(
+        &mut self,
+        cmd: &usercmd_s,
+        random_seed: u32,
+        is_paused: bool,
+        rng_state: impl FnOnce() -> Option<String>,
+    ) -> Option<FrameBulk> {
+        if self.hltas.properties.seeds.is_none() {
+            self.hltas.properties.seeds = Some(hltas::types::Seeds {
+                shared: random_seed,
+                non_shared: 1337,
+            });
+        }
+
+        if let Some(hltas::types::Line::FrameBulk(last_frame_bulk)) = self.hltas.lines.last_mut() {
+            if last_frame_bulk.frame_time.is_empty() && cmd.msec != 0 && !self.last_cmd_was_zero_ms
+            {
+                // This command is a part of a command-split sequence that we already made a frame
+                // bulk for.
+                return None;
+            }
+        }
+
+        if is_paused {
+            // TODO: pauses which aren't loads.
+            self.was_loading = true;
+            return None;
+        }
+
+        if self.was_loading {
+            // Loads can vary in length, thus record the seed change.
+            self.hltas.lines.push(hltas::types::Line::SharedSeed(
+                random_seed.wrapping_sub(self.last_shared_seed_before_load),
+            ));
+        }
+
+        self.last_cmd_was_zero_ms = cmd.msec == 0;
+        self.was_loading = false;
+        self.last_shared_seed_before_load = random_seed;
+
+        let mut frame_bulk = hltas::types::FrameBulk {
+            auto_actions: Default::default(),
+            movement_keys: Default::default(),
+            action_keys: Default::default(),
+            frame_time: Default::default(), // Will be set in on_sv_frame_end().
+            pitch: Default::default(),
+            frame_count: 1.try_into().unwrap(),
+            console_command: Default::default(),
+        };
+
+        let buttons = Buttons::from_bits_truncate(cmd.buttons);
+
+        self.keys
+            .forward
+            .update(buttons.contains(Buttons::IN_FORWARD));
+        self.keys.back.update(buttons.contains(Buttons::IN_BACK));
+        self.keys
+            .left
+            .update(buttons.contains(Buttons::IN_MOVELEFT));
+        self.keys
+            .right
+            .update(buttons.contains(Buttons::IN_MOVERIGHT));
+
+        if buttons.contains(Buttons::IN_FORWARD) {
+            frame_bulk.movement_keys.forward = true;
+        }
+        if buttons.contains(Buttons::IN_BACK) {
+            frame_bulk.movement_keys.back = true;
+        }
+        if buttons.contains(Buttons::IN_MOVELEFT) {
+            frame_bulk.movement_keys.left = true;
+        }
+        if buttons.contains(Buttons::IN_MOVERIGHT) {
+            frame_bulk.movement_keys.right = true;
+        }
+        if buttons.contains(Buttons::IN_JUMP) {
+            frame_bulk.action_keys.jump = true;
+        }
+        if buttons.contains(Buttons::IN_DUCK) {
+            frame_bulk.action_keys.duck = true;
+        }
+        if buttons.contains(Buttons::IN_USE) {
+            frame_bulk.action_keys.use_ = true;
+        }
+        if buttons.contains(Buttons::IN_ATTACK) {
+            frame_bulk.action_keys.attack_1 = true;
+        }
+        if buttons.contains(Buttons::IN_ATTACK2) {
+            frame_bulk.action_keys.attack_2 = true;
+        }
+        if buttons.contains(Buttons::IN_RELOAD) {
+            frame_bulk.action_keys.reload = true;
+        }
+        frame_bulk.auto_actions.movement =
+            Some(hltas::types::AutoMovement::SetYaw(cmd.viewangles[1]));
+        frame_bulk.pitch = Some(cmd.viewangles[0]);
+
+        let mut commands = Vec::new();
+
+        // Handle different combinations of *move and buttons. HLTAS cannot quite do any unusual actions
+        // (e.g. left and right down at once with non-zero sidemove), so filter those out for now.
+        if cmd.forwardmove != 0.
+            || (frame_bulk.movement_keys.forward || frame_bulk.movement_keys.back)
+        {
+            if frame_bulk.movement_keys.forward && frame_bulk.movement_keys.back {
+                if cmd.forwardmove > 0. {
+                    frame_bulk.movement_keys.back = false;
+                    self.keys.back.update(false);
+                    commands.push(format!(
+                        "cl_forwardspeed {}",
+                        cmd.forwardmove as f64 / self.keys.forward.multiplier()
+                    ));
+                } else {
+                    frame_bulk.movement_keys.forward = false;
+                    self.keys.forward.update(false);
+                    commands.push(format!(
+                        "cl_backspeed {}",
+                        -cmd.forwardmove as f64 / self.keys.back.multiplier()
+                    ));
+                }
+            } else if frame_bulk.movement_keys.back {
+                commands.push(format!(
+                    "cl_backspeed {}",
+                    -cmd.forwardmove as f64 / self.keys.back.multiplier()
+                ));
+            } else {
+                frame_bulk.movement_keys.forward = true;
+                self.keys.forward.update(true);
+                commands.push(format!(
+                    "cl_forwardspeed {}",
+                    cmd.forwardmove as f64 / self.keys.forward.multiplier()
+                ));
+            }
+        }
+
+        if cmd.sidemove != 0. || (frame_bulk.movement_keys.right || frame_bulk.movement_keys.left) {
+            if frame_bulk.movement_keys.right && frame_bulk.movement_keys.left {
+                if cmd.sidemove > 0. {
+                    frame_bulk.movement_keys.left = false;
+                    self.keys.left.update(false);
+                    commands.push(format!(
+                        "cl_sidespeed {}",
+                        cmd.sidemove as f64 / self.keys.right.multiplier()
+                    ));
+                } else {
+                    frame_bulk.movement_keys.right = false;
+                    self.keys.right.update(false);
+                    commands.push(format!(
+                        "cl_sidespeed {}",
+                        -cmd.sidemove as f64 / self.keys.left.multiplier()
+                    ));
+                }
+            } else if frame_bulk.movement_keys.left {
+                commands.push(format!(
+                    "cl_sidespeed {}",
+                    -cmd.sidemove as f64 / self.keys.left.multiplier()
+                ));
+            } else {
+                frame_bulk.movement_keys.right = true;
+                self.keys.right.update(true);
+                commands.push(format!(
+                    "cl_sidespeed {}",
+                    cmd.sidemove as f64 / self.keys.right.multiplier()
+                ));
+            }
+        }
+
+        if self.hltas.lines.is_empty() {
+            if let Some(rng_state) = rng_state() {
+                commands.push(format!("_bxt_rng_set \"{}\"", rng_state));
+            }
+        }
+
+        // TODO: upmove.
+        // TODO: non-shared RNG.
+        // TODO: confirming selection in invnext, invprev.
+
+        frame_bulk.console_command = Some(commands.join(";"));
+
+        self.keys.clear_impulses();
+
+        Some(frame_bulk)
+    }
+}
+
+pub unsafe fn on_cmd_start(marker: MainThreadMarker, cmd: usercmd_s, random_seed: u32) {
+    let mut state = STATE.borrow_mut(marker);
+    let recorder = match &mut *state {
+        State::Recording(recorder) => recorder,
+        State::Idle => return,
+    };
+
+    let is_paused = *engine::sv.get(marker).offset(4).cast();
+    let frame_bulk = recorder
+        .builder
+        .frame_bulk(&cmd, random_seed, is_paused, || engine::rng_state(marker));
+    let frame_bulk = match frame_bulk {
+        Some(frame_bulk) => frame_bulk,
+        None => return,
+    };
+
+    // Keep the frames aligned with the lines, a shared seed line might have been added after a
+    // load. Strafing runs can't continue across it.
+    let lines = &mut recorder.builder.hltas.lines;
+    if recorder.frames.len() != lines.len() {
+        recorder.frames.resize_with(lines.len(), || None);
+        recorder.strafe_runs = StrafeRuns::default();
+    }
+
+    let frame = if recorder.detect_strafes {
+        recorded_frame(marker, &cmd, &frame_bulk, &mut recorder.strafe_runs)
+    } else {
+        None
+    };
+    if frame.is_none() {
+        recorder.strafe_runs = StrafeRuns::default();
+    }
+
+    recorder.frames.push(frame);
+    lines.push(hltas::types::Line::FrameBulk(frame_bulk));
+}
+
+/// Checks the strafe candidates against the command from the current player state and the
+/// strafing runs of the previous frames.
+///
+/// # Safety
+///
+/// Must be called from `CmdStart`, when the player state and player-movement tracing are valid.
+unsafe fn recorded_frame(
+    marker: MainThreadMarker,
+    cmd: &usercmd_s,
+    frame_bulk: &FrameBulk,
+    strafe_runs: &mut StrafeRuns,
+) -> Option<RecordedFrame> {
+    let tracer = Tracer::new(marker, false)?;
+    let player = tas_editor::player_data(marker)?;
+
+    // The player moves by the command's msec rather than the host frame time.
+    let parameters = Parameters {
+        frame_time: cmd.msec as f32 / 1000.,
+        ..tas_editor::current_parameters(marker)
+    };
+    let state = bxt_strafe::State::new(&tracer, parameters, player);
+
+    Some(RecordedFrame::new(
+        &tracer,
+        parameters,
+        &state,
+        frame_bulk,
+        cmd.viewangles[1],
+        cmd.forwardmove,
+        cmd.sidemove,
+        strafe_runs,
+    ))
+}
+
+pub unsafe fn on_sv_frame_end(marker: MainThreadMarker) {
+    let mut state = STATE.borrow_mut(marker);
+    let recorder = match &mut *state {
+        State::Recording(recorder) => recorder,
+        State::Idle => return,
+    };
+
+    // With 0 ms frames, we might have built up a few "unused" frame times and a few frame bulks
+    // with empty frame times to fill. Fill the frame times starting from the end and discard the
+    // rest.
+    let mut had_cmd = false;
+    for frame_bulk in recorder
+        .builder
+        .hltas
+        .lines
+        .iter_mut()
+        .rev()
+        .filter_map(|line| {
+            if let hltas::types::Line::FrameBulk(frame_bulk) = line {
+                Some(frame_bulk)
+            } else {
+                None
+            }
+        })
+        .take_while(|frame_bulk| frame_bulk.frame_time.is_empty())
+    {
+        had_cmd = true;
+
+        frame_bulk.frame_time = recorder
+            .pending_frame_times
+            .pop()
+            .expect("unexpected more commands than physics frames")
+            .to_string();
+
+        let console_command = frame_bulk.console_command.as_mut().unwrap();
+        if !console_command.is_empty() {
+            console_command.push(';');
+        }
+        console_command.push_str(&format!(
+            "_bxt_set_frametime_remainder {}",
+            recorder
+                .pending_remainders
+                .pop()
+                .expect("unexpected more commands than frame time remainders"),
+        ));
+
+        let player_command = recorder
+            .pending_console_commands
+            .pop()
+            .expect("unexpected more commands than console commands");
+        if !player_command.is_empty() {
+            // Prepend player commands so they don't override ours.
+            *console_command = format!("{};{}", &player_command, &console_command);
+        }
+    }
+
+    if had_cmd {
+        recorder.pending_frame_times.clear();
+        recorder.pending_console_commands.clear();
+        recorder.pending_remainders.clear();
+    }
+}
+
+static INSIDE_KEY_EVENT: MainThreadCell<bool> = MainThreadCell::new(false);
+
+pub fn on_key_event_start(marker: MainThreadMarker) {
+    INSIDE_KEY_EVENT.set(marker, true);
+}
+
+pub fn on_key_event_end(marker: MainThreadMarker) {
+    INSIDE_KEY_EVENT.set(marker, false);
+}
+
+pub unsafe fn on_cbuf_addtext(marker: MainThreadMarker, text: *const c_char) {
+    if !INSIDE_KEY_EVENT.get(marker) {
+        return;
+    }
+
+    let mut state = STATE.borrow_mut(marker);
+    let recorder = match &mut *state {
+        State::Recording(recorder) => recorder,
+        State::Idle => return,
+    };
+
+    let text = match CStr::from_ptr(text).to_str() {
+        Ok(text) => text,
+        Err(_) => return,
+    };
+
+    let text = text.trim_end_matches(&['\n', ';'][..]);
+    if text.is_empty() {
+        return;
+    }
+
+    // Ignore commands that we handle with frame bulk inputs.
+    if is_input_command(text) {
+        return;
+    }
+
+    recorder.pending_bound_commands.push(text.to_string());
+}
+
+/// Returns `true` if `text` is a key command that frame bulk inputs already cover.
+///
+/// Key commands from bindings carry the key code as an argument, like `+forward 87`.
+fn is_input_command(text: &str) -> bool {
+    let name = match text.strip_prefix(&['+', '-'][..]) {
+        Some(text) => text.split_whitespace().next().unwrap_or_default(),
+        None => return false,
+    };
+
+    matches!(
+        name,
+        "forward"
+            | "back"
+            | "moveright"
+            | "moveleft"
+            | "moveup"
+            | "movedown"
+            | "jump"
+            | "duck"
+            | "use"
+            | "attack"
+            | "attack2"
+            | "reload"
+            | "left"
+            | "right"
+            | "lookup"
+            | "lookdown"
+    )
+}
@@ -0,0 +1,465 @@
+//! Rewriting recorded frames as strafing frame bulks.
+
+use std::f32::consts::TAU;
+use std::num::NonZeroU32;
+
+use bxt_strafe::{Input, Parameters, State, Trace};
+use hltas::types::{AutoMovement, FrameBulk, Line, StrafeSettings};
+
+use crate::modules::tas_editor::STRAFE_CANDIDATES;
+
+/// Largest difference between the recorded and the simulated yaw for a frame to count as
+/// strafing, in radians.
+const YAW_TOLERANCE: f32 = 1e-3;
+
+const REMAINDER_COMMAND: &str = "_bxt_set_frametime_remainder ";
+
+/// Simulated state after the last frame of every strafing run that is still going.
+///
+/// A strafing run is a sequence of consecutive recorded frames that strafing with one of the
+/// [`STRAFE_CANDIDATES`] reproduces, simulating from the state at the start of the first frame.
+pub type StrafeRuns = [Option<State>; STRAFE_CANDIDATES.len()];
+
+/// A recorded command checked against every strafe candidate.
+#[derive(Debug, Clone)]
+pub struct RecordedFrame {
+    /// Yaw in degrees.
+    yaw: f32,
+    forward: f32,
+    side: f32,
+    /// Whether each strafe candidate produces the command from the player state at the start of
+    /// the frame.
+    starts: [bool; STRAFE_CANDIDATES.len()],
+    /// Whether each strafe candidate produces the command when continuing its strafing run from
+    /// the previous frame.
+    continues: [bool; STRAFE_CANDIDATES.len()],
+}
+
+impl RecordedFrame {
+    /// Checks the strafe candidates against a recorded command, from the player state at the start
+    /// of the frame and from the strafing runs of the previous frames.
+    ///
+    /// `yaw`, `forward` and `side` are the view angle and the movement of the recorded command.
+    /// `runs` is updated with this frame. It must be reset when frames are skipped, for example
+    /// at a load, since runs can't continue across them.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new<T: Trace>(
+        tracer: &T,
+        parameters: Parameters,
+        state: &State,
+        frame_bulk: &FrameBulk,
+        yaw: f32,
+        forward: f32,
+        side: f32,
+        runs: &mut StrafeRuns,
+    ) -> Self {
+        let mut frame = Self {
+            yaw,
+            forward,
+            side,
+            starts: [false; STRAFE_CANDIDATES.len()],
+            continues: [false; STRAFE_CANDIDATES.len()],
+        };
+
+        // Frames without movement don't strafe.
+        if forward == 0. && side == 0. {
+            *runs = Default::default();
+            return frame;
+        }
+
+        for (i, settings) in STRAFE_CANDIDATES.into_iter().enumerate() {
+            let frame_bulk = strafing_frame_bulk(frame_bulk, settings);
+
+            let (started, input) = state.clone().simulate(tracer, parameters, &frame_bulk);
+            frame.starts[i] = frame.matches(&input);
+
+            let continued = runs[i]
+                .take()
+                .map(|run| run.simulate(tracer, parameters, &frame_bulk))
+                .filter(|(_, input)| frame.matches(input));
+            frame.continues[i] = continued.is_some();
+
+            runs[i] = match continued {
+                Some((state, _)) => Some(state),
+                None if frame.starts[i] => Some(started),
+                None => None,
+            };
+        }
+
+        frame
+    }
+
+    /// Returns `true` if `input` has the same view angle and movement as the recorded command.
+    ///
+    /// The engine sends movement as whole numbers, so the simulated movement is quantized the same
+    /// way before comparing. It must still match exactly, so frames recorded with different
+    /// movement speeds, for example with `+speed`, never match.
+    fn matches(&self, input: &Input) -> bool {
+        quantize_move(input.forward) == quantize_move(self.forward)
+            && quantize_move(input.side) == quantize_move(self.side)
+            && angle_difference(self.yaw.to_radians(), input.yaw) < YAW_TOLERANCE
+    }
+}
+
+/// Quantizes movement the way the engine sends it in a usercmd.
+///
+/// The delta description of `usercmd_t` sends `forwardmove`, `sidemove` and `upmove` as signed
+/// 12-bit integers, truncating the fraction.
+fn quantize_move(value: f32) -> f32 {
+    (value as i32).clamp(-2047, 2047) as f32
+}
+
+fn angle_difference(a: f32, b: f32) -> f32 {
+    let difference = (a - b).rem_euclid(TAU);
+    difference.min(TAU - difference)
+}
+
+fn strafing_frame_bulk(frame_bulk: &FrameBulk, settings: StrafeSettings) -> FrameBulk {
+    let mut frame_bulk = frame_bulk.clone();
+    frame_bulk.auto_actions.movement = Some(AutoMovement::Strafe(settings));
+    frame_bulk.movement_keys = Default::default();
+    frame_bulk
+}
+
+/// Removes the movement speed commands, strafing doesn't need them.
+fn strip_move_speeds(command: &str) -> Option<String> {
+    let command = command
+        .split(';')
+        .filter(|command| {
+            !command.starts_with("cl_forwardspeed ")
+                && !command.starts_with("cl_backspeed ")
+                && !command.starts_with("cl_sidespeed ")
+        })
+        .collect::<Vec<_>>()
+        .join(";");
+
+    Some(command).filter(|command| !command.is_empty())
+}
+
+fn remainder_command(command: Option<&str>) -> Option<&str> {
+    command?
+        .split(';')
+        .rev()
+        .find(|command| command.starts_with(REMAINDER_COMMAND))
+}
+
+/// Returns `true` if `frame_bulk` can be merged into the end of `run`.
+///
+/// `run_remainder` is the frame time remainder command of the last frame of `run`.
+fn can_extend(run: &FrameBulk, run_remainder: Option<&str>, frame_bulk: &FrameBulk) -> bool {
+    // The console command runs only on the first frame of a frame bulk. The frame time remainder
+    // carries over by itself, so a frame that sets it to the previous frame's value and does
+    // nothing else doesn't need its command.
+    if frame_bulk.console_command.as_deref() != run_remainder {
+        return false;
+    }
+
+    // Pitch doesn't affect movement, only aiming.
+    let keys = &frame_bulk.action_keys;
+    let aims = keys.attack_1 || keys.attack_2 || keys.use_;
+    if aims && frame_bulk.pitch != run.pitch {
+        return false;
+    }
+
+    run.auto_actions == frame_bulk.auto_actions
+        && run.action_keys == frame_bulk.action_keys
+        && run.frame_time == frame_bulk.frame_time
+}
+
+/// Rewrites recorded frames that strafe as strafing frame bulks, merging consecutive ones.
+///
+/// `frames` has an entry for every line, `None` for lines that aren't recorded frames. A frame
+/// that follows a rewritten frame keeps strafing the same way only if that continues the strafing
+/// run, otherwise it starts a new frame bulk with settings that produce its command from its own
+/// player state. Returns the new lines and the number of rewritten frames.
+pub fn detect_strafes(lines: Vec<Line>, frames: &[Option<RecordedFrame>]) -> (Vec<Line>, usize) {
+    assert_eq!(lines.len(), frames.len());
+
+    let mut output: Vec<Line> = Vec::with_capacity(lines.len());
+    let mut rewritten = 0;
+
+    // Strafe candidate and remainder command of the last frame, if the last line is a rewritten
+    // frame bulk.
+    let mut last: Option<(usize, Option<String>)> = None;
+
+    for (line, frame) in lines.into_iter().zip(frames) {
+        let (frame_bulk, frame) = match (line, frame) {
+            (Line::FrameBulk(frame_bulk), Some(frame)) => (frame_bulk, frame),
+            (line, _) => {
+                last = None;
+                output.push(line);
+                continue;
+            }
+        };
+
+        let last_candidate = last.as_ref().map(|(candidate, _)| *candidate);
+        let continued = last_candidate.filter(|&i| frame.continues[i]);
+
+        // A frame that doesn't continue the run of the previous frame can't start a new run with
+        // the same settings, the previous frame bulk's state carries over.
+        let candidate = continued.or_else(|| {
+            (0..STRAFE_CANDIDATES.len()).find(|&i| frame.starts[i] && Some(i) != last_candidate)
+        });
+        let candidate = match candidate {
+            Some(candidate) => candidate,
+            None => {
+                last = None;
+                output.push(Line::FrameBulk(frame_bulk));
+                continue;
+            }
+        };
+
+        rewritten += 1;
+
+        let mut frame_bulk = strafing_frame_bulk(&frame_bulk, STRAFE_CANDIDATES[candidate]);
+        frame_bulk.console_command = frame_bulk
+            .console_command
+            .as_deref()
+            .and_then(strip_move_speeds);
+        let remainder = remainder_command(frame_bulk.console_command.as_deref()).map(str::to_owned);
+
+        if let (Some((_, last_remainder)), Some(Line::FrameBulk(last_frame_bulk)), Some(_)) =
+            (&last, output.last_mut(), continued)
+        {
+            let frame_count = last_frame_bulk.frame_count.get().checked_add(1);
+            if let Some(frame_count) = frame_count.and_then(NonZeroU32::new) {
+                if can_extend(last_frame_bulk, last_remainder.as_deref(), &frame_bulk) {
+                    last_frame_bulk.frame_count = frame_count;
+                    last = Some((candidate, remainder));
+                    continue;
+                }
+            }
+        }
+
+        last = Some((candidate, remainder));
+        output.push(Line::FrameBulk(frame_bulk));
+    }
+
+    (output, rewritten)
+}
+
+#[cfg(test)]
+mod tests {
+    use bxt_strafe::{DummyTracer, Player};
+    use glam::Vec3;
+    use hltas::types::{StrafeDir, StrafeType};
+
+    use super::*;
+    use crate::modules::tas_editor::test_utils::{default_parameters, default_player};
+
+    fn recorded_frame_bulk(remainder: &str) -> FrameBulk {
+        let mut frame_bulk = FrameBulk::with_frame_time("0.01".to_string());
+        frame_bulk.auto_actions.movement = Some(AutoMovement::SetYaw(0.));
+        frame_bulk.movement_keys.forward = true;
+        frame_bulk.pitch = Some(0.);
+        frame_bulk.console_command = Some(format!(
+            "cl_forwardspeed 400;{}{}",
+            REMAINDER_COMMAND, remainder
+        ));
+        frame_bulk
+    }
+
+    fn initial_state() -> State {
+        let player = Player {
+            vel: Vec3::new(300., 0., 0.),
+            ..default_player()
+        };
+        State::new(&DummyTracer, default_parameters(), player)
+    }
+
+    /// Returns the command of the strafe candidate at `index` from `state`, along with the state
+    /// after it.
+    fn strafe(state: &State, index: usize) -> (Input, State) {
+        let frame_bulk = strafing_frame_bulk(&recorded_frame_bulk("0"), STRAFE_CANDIDATES[index]);
+        let (state, input) =
+            state
+                .clone()
+                .simulate(&DummyTracer, default_parameters(), &frame_bulk);
+        (input, state)
+    }
+
+    /// Returns a frame recorded from `state` with the command `input`.
+    fn recorded_frame(state: &State, input: Input, runs: &mut StrafeRuns) -> RecordedFrame {
+        RecordedFrame::new(
+            &DummyTracer,
+            default_parameters(),
+            state,
+            &recorded_frame_bulk("0"),
+            input.yaw.to_degrees(),
+            input.forward,
+            input.side,
+            runs,
+        )
+    }
+
+    /// Returns consecutive recorded frames which moved like the strafe candidates at `indices`.
+    fn recorded_frames(indices: &[usize]) -> Vec<Option<RecordedFrame>> {
+        let mut state = initial_state();
+        let mut runs = StrafeRuns::default();
+        indices
+            .iter()
+            .map(|&index| {
+                let (input, next_state) = strafe(&state, index);
+                let frame = recorded_frame(&state, input, &mut runs);
+                state = next_state;
+                Some(frame)
+            })
+            .collect()
+    }
+
+    fn strafing(dir: StrafeDir, frame_count: u32) -> Line {
+        let mut frame_bulk = FrameBulk::with_frame_time("0.01".to_string());
+        frame_bulk.auto_actions.movement = Some(AutoMovement::Strafe(StrafeSettings {
+            type_: StrafeType::MaxAccel,
+            dir,
+        }));
+        frame_bulk.pitch = Some(0.);
+        frame_bulk.frame_count = NonZeroU32::new(frame_count).unwrap();
+        frame_bulk.console_command = Some(format!("{}0", REMAINDER_COMMAND));
+        Line::FrameBulk(frame_bulk)
+    }
+
+    #[test]
+    fn detect_and_merge_strafes() {
+        let lines = vec![Line::FrameBulk(recorded_frame_bulk("0")); 4];
+        let frames = recorded_frames(&[0, 0, 0, 1]);
+
+        let (lines, rewritten) = detect_strafes(lines, &frames);
+        assert_eq!(rewritten, 4);
+        assert_eq!(
+            lines,
+            [strafing(StrafeDir::Left, 3), strafing(StrafeDir::Right, 1)]
+        );
+    }
+
+    #[test]
+    fn keep_frames_that_do_not_strafe() {
+        let state = initial_state();
+        let (mut input, _) = strafe(&state, 0);
+        input.yaw += 10f32.to_radians();
+        let frames = vec![Some(recorded_frame(
+            &state,
+            input,
+            &mut StrafeRuns::default(),
+        ))];
+
+        let lines = vec![Line::FrameBulk(recorded_frame_bulk("0"))];
+        let (new_lines, rewritten) = detect_strafes(lines.clone(), &frames);
+        assert_eq!(rewritten, 0);
+        assert_eq!(new_lines, lines);
+    }
+
+    #[test]
+    fn keep_frames_with_different_move_speed() {
+        // Same direction as strafing, but at half the speed, like with cl_forwardspeed 200.
+        let state = initial_state();
+        let (mut input, _) = strafe(&state, 0);
+        input.forward /= 2.;
+        input.side /= 2.;
+        let frames = vec![Some(recorded_frame(
+            &state,
+            input,
+            &mut StrafeRuns::default(),
+        ))];
+
+        let lines = vec![Line::FrameBulk(recorded_frame_bulk("0"))];
+        let (new_lines, rewritten) = detect_strafes(lines.clone(), &frames);
+        assert_eq!(rewritten, 0);
+        assert_eq!(new_lines, lines);
+    }
+
+    #[test]
+    fn detect_strafes_with_quantized_usercmds() {
+        let mut state = initial_state();
+        let mut runs = StrafeRuns::default();
+        let mut frames = Vec::new();
+        for index in [0, 0, 1] {
+            let (mut input, next_state) = strafe(&state, index);
+
+            // The engine sends movement as whole numbers and the yaw in 16 bits.
+            input.forward = input.forward.trunc();
+            input.side = input.side.trunc();
+            input.yaw = (input.yaw.to_degrees() * 65536. / 360.).trunc() * 360. / 65536.;
+            input.yaw = input.yaw.to_radians();
+
+            frames.push(Some(recorded_frame(&state, input, &mut runs)));
+            state = next_state;
+        }
+
+        let lines = vec![Line::FrameBulk(recorded_frame_bulk("0")); 3];
+        let (lines, rewritten) = detect_strafes(lines, &frames);
+        assert_eq!(rewritten, 3);
+        assert_eq!(
+            lines,
+            [strafing(StrafeDir::Left, 2), strafing(StrafeDir::Right, 1)]
+        );
+    }
+
+    #[test]
+    fn keep_frames_that_do_not_continue_the_run() {
+        // Every frame strafes on its own, but the recorded movement didn't follow from strafing on
+        // the previous frames.
+        let state = initial_state();
+        let (input, _) = strafe(&state, 0);
+        let mut runs = StrafeRuns::default();
+        let frames: Vec<_> = (0..3)
+            .map(|_| Some(recorded_frame(&state, input, &mut runs)))
+            .collect();
+
+        let lines = vec![Line::FrameBulk(recorded_frame_bulk("0")); 3];
+        let (new_lines, rewritten) = detect_strafes(lines.clone(), &frames);
+        assert_eq!(rewritten, 2);
+        assert_eq!(
+            new_lines,
+            [
+                strafing(StrafeDir::Left, 1),
+                lines[1].clone(),
+                strafing(StrafeDir::Left, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn do_not_merge_across_loads() {
+        let mut frames = recorded_frames(&[0]);
+        frames.push(None);
+
+        // The runs are reset at a load.
+        let (_, state) = strafe(&initial_state(), 0);
+        let (input, _) = strafe(&state, 0);
+        frames.push(Some(recorded_frame(
+            &state,
+            input,
+            &mut StrafeRuns::default(),
+        )));
+
+        let lines = vec![
+            Line::FrameBulk(recorded_frame_bulk("0")),
+            Line::SharedSeed(0),
+            Line::FrameBulk(recorded_frame_bulk("0")),
+        ];
+        let (new_lines, rewritten) = detect_strafes(lines, &frames);
+        assert_eq!(rewritten, 2);
+        assert_eq!(
+            new_lines,
+            [
+                strafing(StrafeDir::Left, 1),
+                Line::SharedSeed(0),
+                strafing(StrafeDir::Left, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn split_on_remainder_change() {
+        let lines = vec![
+            Line::FrameBulk(recorded_frame_bulk("0")),
+            Line::FrameBulk(recorded_frame_bulk("0.0005")),
+        ];
+        let frames = recorded_frames(&[0, 0]);
+
+        let (lines, rewritten) = detect_strafes(lines, &frames);
+        assert_eq!(rewritten, 2);
+        assert_eq!(lines.len(), 2);
+    }
+}
@@ -1,6 +1,6 @@
 //! Triangle drawing.
 
-use super::{tas_editor, Module};
+use super::{ghost, tas_editor, Module};
 use crate::hooks::engine::{self};
 use crate::utils::*;
 
@@ -28,6 +28,7 @@ pub unsafe fn on_draw_transparent_triangles(marker: MainThreadMarker) {
     // TODO: set white texture.
 
     tas_editor::draw(marker, &tri);
+    ghost::draw(marker, &tri);
 
     // Required for the WON DLLs.
     tri.render_mode(triangle_api::RenderMode::Normal);